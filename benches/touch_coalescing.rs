@@ -0,0 +1,158 @@
+// Store writes of the /auth hot path with and without coalescing of last access updates.
+// Run: cargo bench --bench touch_coalescing
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use authware::{
+    model::{self, auth::User, config::EvictPolicy, data::SessionData},
+    store::{coalescing::CoalescingSessionStore, memory::InMemorySessionStore},
+    SessionStore,
+};
+use chrono::Utc;
+
+const SESSIONS: usize = 200;
+const REQUEST_EVERY: i64 = 200; // millis per session
+const RUN_FOR: i64 = 10 * 60 * 1000; // simulated millis
+const INACTIVITY: i64 = 30 * 60 * 1000;
+
+struct Counting {
+    inner: InMemorySessionStore,
+    writes: Arc<AtomicUsize>,
+}
+
+#[async_trait]
+impl SessionStore for Counting {
+    async fn add(&self, id: &str, data: SessionData) -> Result<(), model::store::Error> {
+        self.inner.add(id, data).await
+    }
+    async fn get(&self, id: &str) -> Result<SessionData, model::store::Error> {
+        self.inner.get(id).await
+    }
+    async fn remove(&self, id: &str) -> Result<(), model::store::Error> {
+        self.inner.remove(id).await
+    }
+    async fn mark_last_used(&self, id: &str, now: i64) -> Result<(), model::store::Error> {
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        self.inner.mark_last_used(id, now).await
+    }
+    async fn update_user(
+        &self,
+        id: &str,
+        data: SessionData,
+        current: i64,
+    ) -> Result<bool, model::store::Error> {
+        self.inner.update_user(id, data, current).await
+    }
+    async fn add_limited(
+        &self,
+        id: &str,
+        data: SessionData,
+        max: usize,
+        policy: EvictPolicy,
+    ) -> Result<usize, model::store::Error> {
+        self.inner.add_limited(id, data, max, policy).await
+    }
+    async fn list_by_user(
+        &self,
+        user_id: &str,
+    ) -> Result<Vec<(String, SessionData)>, model::store::Error> {
+        self.inner.list_by_user(user_id).await
+    }
+    async fn remove_all_for_user(&self, user_id: &str) -> Result<usize, model::store::Error> {
+        self.inner.remove_all_for_user(user_id).await
+    }
+}
+
+fn session_data(i: usize, now: i64) -> SessionData {
+    SessionData {
+        user: User {
+            id: format!("user{i}"),
+            name: format!("user{i}"),
+            department: String::new(),
+            roles: vec!["USER".to_string()],
+            backend: String::new(),
+        },
+        ip: "1.1.1.1".to_string(),
+        valid_till: now + RUN_FOR + INACTIVITY,
+        last_access: now,
+        refreshed: now,
+    }
+}
+
+// Replays the requests of all sessions on a simulated clock, the flusher runs
+// every half of the granularity. Returns the requests and the store writes
+async fn run(granularity: Option<Duration>) -> (usize, usize) {
+    let writes = Arc::new(AtomicUsize::new(0));
+    let counting: Box<dyn SessionStore + Send + Sync> = Box::new(Counting {
+        inner: InMemorySessionStore::new(),
+        writes: writes.clone(),
+    });
+    let (store, coalescing): (Box<dyn SessionStore + Send + Sync>, _) = match granularity {
+        None => (counting, None),
+        Some(g) => {
+            let res = CoalescingSessionStore::new(counting, g);
+            (Box::new(res.clone()), Some(res))
+        }
+    };
+    let flush_every = granularity.map_or(i64::MAX, |g| g.as_millis() as i64 / 2);
+
+    let start = Utc::now().timestamp_millis();
+    for i in 0..SESSIONS {
+        store
+            .add(&format!("s{i}"), session_data(i, start))
+            .await
+            .unwrap();
+    }
+    let mut requests = 0;
+    let mut now = start;
+    while now < start + RUN_FOR {
+        now += REQUEST_EVERY;
+        for i in 0..SESSIONS {
+            let id = format!("s{i}");
+            let data = store.get(&id).await.unwrap();
+            data.check_inactivity(now, INACTIVITY).unwrap();
+            store.mark_last_used(&id, now).await.unwrap();
+            requests += 1;
+        }
+        if (now - start) % flush_every == 0 {
+            if let Some(c) = &coalescing {
+                c.flush().await;
+            }
+        }
+    }
+    if let Some(c) = &coalescing {
+        c.flush().await;
+    }
+    (requests, writes.load(Ordering::Relaxed))
+}
+
+#[tokio::main]
+async fn main() {
+    println!(
+        "{SESSIONS} sessions, a request every {REQUEST_EVERY}ms each, {}s simulated",
+        RUN_FOR / 1000
+    );
+    println!(
+        "{:>12} {:>10} {:>10} {:>10} {:>12}",
+        "granularity", "requests", "writes", "saved", "time"
+    );
+    for granularity in [None, Some(1), Some(10), Some(60)] {
+        let started = Instant::now();
+        let (requests, writes) = run(granularity.map(Duration::from_secs)).await;
+        let elapsed = started.elapsed();
+        println!(
+            "{:>12} {:>10} {:>10} {:>9.2}% {:>12?}",
+            granularity.map_or("off".to_string(), |g| format!("{g}s")),
+            requests,
+            writes,
+            100.0 * (1.0 - writes as f64 / requests as f64),
+            elapsed
+        );
+    }
+}
@@ -1,6 +1,5 @@
-use std::time::Duration;
+use std::{str::FromStr, time::Duration};
 
-use again::RetryPolicy;
 use async_trait::async_trait;
 use reqwest::Client;
 use serde::{de::DeserializeOwned, Deserialize};
@@ -8,22 +7,68 @@ use serde_xml_rs::from_str;
 use urlencoding::encode;
 
 use crate::{model::auth, utils::secret_str::SecretString, AuthService};
+
+pub const PASS_HEADER: &str = "X-Auth-Password";
+const REDACTED: &str = "****";
+
+// How the user's password is passed to the ws
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Credentials {
+    // GET .../authenticate_details/{app}/{user}/{pass}, the legacy api
+    Path,
+    // POST .../authenticate_details/{app} with a form body user=..&pass=..
+    Body,
+    // GET .../authenticate_details/{app}/{user} with the password in the X-Auth-Password header
+    Header,
+}
+
+impl FromStr for Credentials {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "path" => Ok(Credentials::Path),
+            "body" => Ok(Credentials::Body),
+            "header" => Ok(Credentials::Header),
+            _ => Err(anyhow::anyhow!(
+                "wrong credentials mode {s}, expected path, body or header"
+            )),
+        }
+    }
+}
+
 pub struct Auth {
     ws_url: String,
     ws_user: String,
     ws_pass: SecretString,
     app_code: String,
+    credentials: Credentials,
     client: reqwest::Client,
 }
 
+struct Request {
+    url: String,
+    body: Option<String>,
+    pass_header: bool,
+}
+
 impl Auth {
     pub fn new(
         ws_url: &str,
         ws_user: &str,
         ws_pass: SecretString,
         app_code: &str,
+        credentials: Credentials,
+        timeout: Duration,
     ) -> anyhow::Result<Self> {
-        tracing::debug!(ws_url, ws_user, app_code, "init auth");
+        tracing::debug!(
+            ws_url,
+            ws_user,
+            app_code,
+            ?credentials,
+            ?timeout,
+            "init auth"
+        );
         if ws_url.is_empty()
             || ws_user.is_empty()
             || ws_pass.reveal_secret().is_empty()
@@ -31,23 +76,46 @@ impl Auth {
         {
             return Err(anyhow::anyhow!("Empty auth params"));
         }
+        if credentials == Credentials::Path {
+            tracing::warn!("admin3ws passwords are sent in the url path");
+        }
         Ok(Auth {
             ws_url: ws_url.to_string(),
             ws_user: ws_user.to_string(),
             ws_pass,
             app_code: app_code.to_string(),
-            client: Client::builder().timeout(Duration::from_secs(5)).build()?,
+            credentials,
+            client: Client::builder().timeout(timeout).build()?,
         })
     }
 
-    fn make_details_url(&self, user: &str, pass: &SecretString) -> String {
-        format!(
-            "{}/authenticate_details/{}/{}/{}",
+    fn make_details_request(&self, user: &str, pass: &SecretString) -> Request {
+        let url = format!(
+            "{}/authenticate_details/{}",
             self.ws_url,
-            encode(&self.app_code),
-            encode(user),
-            encode(pass.reveal_secret())
-        )
+            encode(&self.app_code)
+        );
+        match self.credentials {
+            Credentials::Path => Request {
+                url: format!("{url}/{}/{}", encode(user), encode(pass.reveal_secret())),
+                body: None,
+                pass_header: false,
+            },
+            Credentials::Body => Request {
+                url,
+                body: Some(format!(
+                    "user={}&pass={}",
+                    encode(user),
+                    encode(pass.reveal_secret())
+                )),
+                pass_header: false,
+            },
+            Credentials::Header => Request {
+                url: format!("{url}/{}", encode(user)),
+                body: None,
+                pass_header: true,
+            },
+        }
     }
 
     fn make_roles_url(&self, user: &str) -> String {
@@ -59,50 +127,81 @@ impl Auth {
         )
     }
 
-    async fn make_call_int(&self, url: &str) -> anyhow::Result<String> {
-        let response = self
-            .client
-            .get(url)
+    // Removes the ws and user passwords, raw or url encoded, from text going to logs or errors
+    fn redact(&self, text: &str, pass: &SecretString) -> String {
+        let mut res = text.to_string();
+        for secret in [&self.ws_pass, pass] {
+            let secret = secret.reveal_secret();
+            if secret.is_empty() {
+                continue;
+            }
+            res = res
+                .replace(secret, REDACTED)
+                .replace(encode(secret).as_ref(), REDACTED);
+        }
+        res
+    }
+
+    async fn make_call_int(&self, req: &Request, pass: &SecretString) -> anyhow::Result<String> {
+        let mut builder = match &req.body {
+            Some(body) => self
+                .client
+                .post(&req.url)
+                .header(
+                    reqwest::header::CONTENT_TYPE,
+                    "application/x-www-form-urlencoded",
+                )
+                .body(body.clone()),
+            None => self.client.get(&req.url),
+        };
+        if req.pass_header {
+            builder = builder.header(PASS_HEADER, pass.reveal_secret());
+        }
+        let response = builder
             .basic_auth(&self.ws_user, Some(self.ws_pass.reveal_secret()))
             .send()
             .await
-            .map_err(|e| anyhow::anyhow!("url call error: {:?}", e))?;
-        response
-            .error_for_status_ref()
-            .map_err(|e| anyhow::anyhow!("ws error: {:?}", e))?;
+            .map_err(|e| anyhow::anyhow!("url call error: {:?}", e.without_url()))?;
+        if let Err(e) = response.error_for_status_ref() {
+            return Err(anyhow::anyhow!("ws error: {:?}", e.without_url()));
+        }
         let response_body = response
             .text()
             .await
-            .map_err(|e| anyhow::anyhow!("can't get body: {:?}", e))?;
+            .map_err(|e| anyhow::anyhow!("can't get body: {:?}", e.without_url()))?;
         tracing::trace!(response = response_body, "response");
         Ok(response_body)
     }
 
-    async fn make_call(&self, url: &str) -> anyhow::Result<String> {
-        let policy = RetryPolicy::exponential(Duration::from_millis(200))
-            .with_max_retries(3)
-            .with_jitter(true);
-        policy
-            .retry(|| self.make_call_int(url))
-            .await
-            .map_err(|e| anyhow::anyhow!("Failed after retries: {:?}", e))
+    // retries are done by resilience::Retry
+    async fn make_call(&self, req: &Request, pass: &SecretString) -> anyhow::Result<String> {
+        self.make_call_int(req, pass).await.map_err(|e| {
+            anyhow::anyhow!(
+                "Call failed, url {}: {}",
+                self.redact(&req.url, pass),
+                self.redact(&format!("{e:?}"), pass)
+            )
+        })
     }
-}
 
-#[async_trait]
-impl AuthService for Auth {
-    async fn login(&self, user: &str, pass: &SecretString) -> Result<auth::User, auth::Error> {
-        tracing::trace!(
-            url = self.make_details_url(user, &"----".into()),
-            "call auth details"
-        );
-        let user_details = self.make_call(&self.make_details_url(user, pass)).await?;
+    async fn login_int(&self, user: &str, pass: &SecretString) -> Result<auth::User, auth::Error> {
+        let req = self.make_details_request(user, pass);
+        tracing::trace!(url = self.redact(&req.url, pass), "call auth details");
+        let user_details = self.make_call(&req, pass).await?;
         let user_data: User = process_body(&user_details)?;
         tracing::trace!("got user");
+        let roles = self.get_roles(user, pass).await?;
+        map_res(user, user_data, roles)
+    }
 
-        let r_url = self.make_roles_url(user);
-        tracing::trace!(url = r_url, "call roles");
-        let roles_details = self.make_call(&r_url).await?;
+    async fn get_roles(&self, user: &str, pass: &SecretString) -> Result<Roles, auth::Error> {
+        let req = Request {
+            url: self.make_roles_url(user),
+            body: None,
+            pass_header: false,
+        };
+        tracing::trace!(url = self.redact(&req.url, pass), "call roles");
+        let roles_details = self.make_call(&req, pass).await?;
         let roles: Roles = process_body(&roles_details)?;
         tracing::trace!(
             len = roles.roles.as_ref().map_or(0, |vec| vec.len()),
@@ -111,15 +210,52 @@ impl AuthService for Auth {
         if roles.roles.as_ref().is_none_or(|vec| vec.is_empty()) {
             return Err(auth::Error::NoAccess());
         }
-        map_res(user, user_data, roles)
+        Ok(roles)
+    }
+
+    fn redact_err(&self, e: auth::Error, pass: &SecretString) -> auth::Error {
+        match e {
+            auth::Error::ServiceError(err) => {
+                auth::Error::ServiceError(anyhow::anyhow!(self.redact(&format!("{err:#}"), pass)))
+            }
+            auth::Error::OtherAuth(msg) => auth::Error::OtherAuth(self.redact(&msg, pass)),
+            e => e,
+        }
     }
 }
 
-fn map_res(id: &str, user_data: User, roles: Roles) -> Result<auth::User, auth::Error> {
-    let roles_str: Vec<String> = match roles.roles {
+#[async_trait]
+impl AuthService for Auth {
+    async fn login(&self, user: &str, pass: &SecretString) -> Result<auth::User, auth::Error> {
+        // last line of defence, errors are logged by the callers
+        self.login_int(user, pass)
+            .await
+            .map_err(|e| self.redact_err(e, pass))
+    }
+
+    // Only roles are reloaded, the details call needs the password
+    async fn refresh_user(&self, user: &auth::User) -> Result<Option<auth::User>, auth::Error> {
+        let pass: SecretString = "".into();
+        let roles = self
+            .get_roles(&user.id, &pass)
+            .await
+            .map_err(|e| self.redact_err(e, &pass))?;
+        Ok(Some(auth::User {
+            roles: roles_names(roles),
+            ..user.clone()
+        }))
+    }
+}
+
+fn roles_names(roles: Roles) -> Vec<String> {
+    match roles.roles {
         Some(roles) => roles.into_iter().map(|r| r.name).collect(),
         None => Vec::new(), // or vec![] to create an empty Vec<String>
-    };
+    }
+}
+
+fn map_res(id: &str, user_data: User, roles: Roles) -> Result<auth::User, auth::Error> {
+    let roles_str = roles_names(roles);
     let dep = user_data
         .organization_unit
         .as_ref() // Convert Option<Department> to Option<&Department>
@@ -129,6 +265,7 @@ fn map_res(id: &str, user_data: User, roles: Roles) -> Result<auth::User, auth::
         name: user_data.first_name + " " + &user_data.last_name,
         department: dep.to_string(),
         roles: roles_str,
+        backend: String::new(),
     };
     Ok(res)
 }
@@ -192,8 +329,260 @@ fn map_err_codes(res_code: i32) -> auth::Error {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{
+        auth::resilience::{Retry, Timeout},
+        testing::admin3ws::{self as mock, Mock},
+    };
+    use axum::{http::StatusCode, Router};
+    use std::{collections::HashMap, sync::Arc};
     use test_case::test_case;
 
+    const PASS: &str = "p@ss/w rd";
+    const WS_PASS: &str = "ws&pass";
+
+    fn mock_user(roles: &[&str], code: Option<i32>) -> mock::User {
+        mock::User {
+            pass: PASS.to_string(),
+            first_name: "Olia".to_string(),
+            last_name: "Olialia".to_string(),
+            department: "IT".to_string(),
+            roles: roles.iter().map(|r| r.to_string()).collect(),
+            code,
+            ..Default::default()
+        }
+    }
+
+    fn mock_config() -> mock::Config {
+        let mut users = HashMap::from([
+            ("olia".to_string(), mock_user(&["R1"], None)),
+            ("gone".to_string(), mock_user(&[], None)),
+            (
+                "flaky".to_string(),
+                mock::User {
+                    failures: 2,
+                    ..mock_user(&["R1"], None)
+                },
+            ),
+            (
+                "slow".to_string(),
+                mock::User {
+                    latency_ms: 500,
+                    ..mock_user(&["R1"], None)
+                },
+            ),
+        ]);
+        for code in 1..=10 {
+            users.insert(format!("code{code}"), mock_user(&["R1"], Some(code)));
+        }
+        mock::Config {
+            ws_user: "svc".to_string(),
+            ws_pass: WS_PASS.to_string(),
+            app_code: "app".to_string(),
+            users,
+            latency_ms: 0,
+        }
+    }
+
+    // local mock of the ws under /ws
+    async fn start_server() -> (String, Arc<Mock>) {
+        let mock = Mock::new(mock_config());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let app = Router::new()
+            .nest("/ws", mock.router())
+            .fallback(|| async { (StatusCode::INTERNAL_SERVER_ERROR, "failed") });
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (url, mock)
+    }
+
+    fn make_auth(url: &str, ws_pass: &str, credentials: Credentials) -> Auth {
+        Auth::new(
+            &format!("{url}/ws"),
+            "svc",
+            ws_pass.into(),
+            "app",
+            credentials,
+            Duration::from_secs(5),
+        )
+        .unwrap()
+    }
+
+    fn result(res: Result<auth::User, auth::Error>) -> String {
+        match res {
+            Ok(user) => format!("ok {}", user.roles.join(",")),
+            Err(auth::Error::ServiceError(_)) => "service error".to_string(),
+            Err(auth::Error::OtherAuth(msg)) => msg,
+            Err(err) => err.to_string(),
+        }
+    }
+
+    #[test_case(Credentials::Path; "path")]
+    #[test_case(Credentials::Body; "body")]
+    #[test_case(Credentials::Header; "header")]
+    #[tokio::test]
+    async fn test_login(credentials: Credentials) {
+        let (url, _) = start_server().await;
+        let auth = make_auth(&url, WS_PASS, credentials);
+        let res = auth.login("olia", &PASS.into()).await.unwrap();
+        assert_eq!(
+            res,
+            auth::User {
+                id: "olia".to_string(),
+                name: "Olia Olialia".to_string(),
+                department: "IT".to_string(),
+                roles: vec!["R1".to_string()],
+                backend: String::new(),
+            }
+        );
+        let res = auth.login("olia", &"other".into()).await;
+        assert!(matches!(res, Err(auth::Error::WrongUserPass())));
+    }
+
+    #[test_case("olia", "ok R1"; "roles")]
+    #[test_case("gone", "No access"; "no roles")]
+    #[test_case("unknown", "Wrong password"; "unknown")]
+    #[tokio::test]
+    async fn test_refresh_user(user: &str, expected: &str) {
+        let (url, _) = start_server().await;
+        let auth = make_auth(&url, WS_PASS, Credentials::Header);
+        let old = auth::User {
+            id: user.to_string(),
+            name: "Olia".to_string(),
+            department: "IT".to_string(),
+            roles: vec!["OLD".to_string()],
+            backend: String::new(),
+        };
+        let res = match auth.refresh_user(&old).await {
+            Ok(Some(res)) => {
+                assert_eq!(res.name, "Olia");
+                format!("ok {}", res.roles.join(","))
+            }
+            Ok(None) => "none".to_string(),
+            Err(err) => err.to_string(),
+        };
+        assert_eq!(res, expected);
+    }
+
+    #[test_case("/broken", Credentials::Path; "status path")]
+    #[test_case("/broken", Credentials::Body; "status body")]
+    #[test_case("/broken", Credentials::Header; "status header")]
+    #[test_case("http://127.0.0.1:1/ws", Credentials::Path; "no service path")]
+    #[test_case("http://127.0.0.1:1/ws", Credentials::Body; "no service body")]
+    #[tokio::test]
+    async fn test_login_error_no_password(ws: &str, credentials: Credentials) {
+        let (url, _) = start_server().await;
+        let ws_url = if ws.starts_with("http") {
+            ws.to_string()
+        } else {
+            format!("{url}{ws}")
+        };
+        let auth = Auth::new(
+            &ws_url,
+            "svc",
+            WS_PASS.into(),
+            "app",
+            credentials,
+            Duration::from_secs(5),
+        )
+        .unwrap();
+        let err = match auth.login("olia", &PASS.into()).await {
+            Err(auth::Error::ServiceError(err)) => err,
+            other => panic!("expected service error, got {other:?}"),
+        };
+        for msg in [format!("{err}"), format!("{err:?}"), format!("{err:#}")] {
+            for secret in [PASS, WS_PASS] {
+                assert!(!msg.contains(secret), "{msg}");
+                assert!(!msg.contains(encode(secret).as_ref()), "{msg}");
+            }
+        }
+    }
+
+    #[test_case("code1", "Wrong password"; "code 1")]
+    #[test_case("code2", "Expired password"; "code 2")]
+    #[test_case("code3", "Expired password"; "code 3")]
+    #[test_case("code4", "Wrong password"; "code 4")]
+    #[test_case("code5", "Wrong password"; "code 5")]
+    #[test_case("code6", "Code 6"; "code 6")]
+    #[test_case("code9", "Code 9"; "code 9")]
+    #[test_case("code10", "service error"; "code 10")]
+    #[test_case("nobody", "Wrong password"; "unknown user")]
+    #[test_case("gone", "No access"; "no roles")]
+    #[tokio::test]
+    async fn test_login_codes(user: &str, expected: &str) {
+        let (url, _) = start_server().await;
+        let auth = make_auth(&url, WS_PASS, Credentials::Body);
+        let res = auth.login(user, &PASS.into()).await;
+        assert!(result(res).contains(expected), "{user}");
+    }
+
+    #[tokio::test]
+    async fn test_login_wrong_ws_pass() {
+        let (url, _) = start_server().await;
+        let auth = make_auth(&url, "other", Credentials::Body);
+        let res = auth.login("olia", &PASS.into()).await;
+        assert_eq!(result(res), "service error");
+    }
+
+    #[tokio::test]
+    async fn test_login_retry_recovers() {
+        let (url, mock) = start_server().await;
+        let auth = Retry::new(
+            "ws",
+            Box::new(make_auth(&url, WS_PASS, Credentials::Body)),
+            2,
+            Duration::from_millis(1),
+        );
+        let res = auth.login("flaky", &PASS.into()).await;
+        assert_eq!(result(res), "ok R1");
+        assert_eq!(mock.calls(), 4);
+
+        mock.fail_next(3);
+        let res = auth.login("olia", &PASS.into()).await;
+        assert_eq!(result(res), "service error");
+    }
+
+    #[tokio::test]
+    async fn test_login_latency_timeout() {
+        let (url, _) = start_server().await;
+        let auth = Timeout::new(
+            "ws",
+            Box::new(make_auth(&url, WS_PASS, Credentials::Body)),
+            Duration::from_millis(100),
+        );
+        match auth.login("slow", &PASS.into()).await {
+            Err(auth::Error::ServiceError(err)) => {
+                assert!(err.downcast_ref::<auth::Unavailable>().is_some())
+            }
+            other => panic!("expected unavailable, got {other:?}"),
+        }
+        assert_eq!(result(auth.login("olia", &PASS.into()).await), "ok R1");
+    }
+
+    #[test_case("/a/p%40ss%2Fw%20rd/b", "/a/****/b"; "encoded")]
+    #[test_case("x p@ss/w rd ws&pass y", "x **** **** y"; "raw")]
+    #[test_case("ws%26pass", "****"; "ws pass")]
+    #[test_case("nothing", "nothing"; "none")]
+    fn test_redact(input: &str, expected: &str) {
+        let auth = Auth::new(
+            "http://ws",
+            "svc",
+            WS_PASS.into(),
+            "app",
+            Credentials::Path,
+            Duration::from_secs(5),
+        )
+        .unwrap();
+        assert_eq!(auth.redact(input, &PASS.into()), expected);
+    }
+
+    #[test_case("path", Some(Credentials::Path); "path")]
+    #[test_case("Body", Some(Credentials::Body); "body")]
+    #[test_case("header", Some(Credentials::Header); "header")]
+    #[test_case("url", None; "wrong")]
+    fn test_parse_credentials(input: &str, expected: Option<Credentials>) {
+        assert_eq!(input.parse::<Credentials>().ok(), expected);
+    }
+
     #[test_case(r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
 <user>
   <firstName>oooo</firstName>
@@ -0,0 +1,190 @@
+use std::{collections::HashMap, net::IpAddr, path::Path};
+
+use axum::http::{header, HeaderMap};
+use ipnet::IpNet;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::model::auth;
+
+const SCHEME: &str = "ApiKey";
+// backend name of the key users
+pub const BACKEND: &str = "api_key";
+
+// Static API keys for machine clients, loaded from a json file:
+//   `[{"name": "ci", "hash": "<sha256 hex of the key>", "department": "", "roles": [""], "cidrs": ["10.0.0.0/8"]}]`
+// the hash can be generated with `echo -n "$KEY" | sha256sum`
+pub struct ApiKeys {
+    keys: HashMap<String, Entry>,
+    header: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Entry {
+    name: String,
+    hash: String,
+    #[serde(default)]
+    department: String,
+    #[serde(default)]
+    roles: Vec<String>,
+    // allowed caller networks, empty - any
+    #[serde(default)]
+    cidrs: Vec<IpNet>,
+}
+
+impl ApiKeys {
+    // `header` is an optional custom header carrying the key, besides `Authorization: ApiKey <key>`
+    pub fn new(path: &str, header: &str) -> anyhow::Result<Self> {
+        tracing::debug!(path, header, "init api keys");
+        let content = std::fs::read_to_string(Path::new(path))
+            .map_err(|e| anyhow::anyhow!("can't read {path}: {e}"))?;
+        Self::from_json(&content, header)
+    }
+
+    fn from_json(content: &str, header: &str) -> anyhow::Result<Self> {
+        let entries: Vec<Entry> = serde_json::from_str(content)
+            .map_err(|e| anyhow::anyhow!("can't parse api keys: {e}"))?;
+        let mut keys = HashMap::new();
+        for entry in entries {
+            if entry.name.is_empty() {
+                return Err(anyhow::anyhow!("api key without name"));
+            }
+            let hash = entry.hash.to_lowercase();
+            if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(anyhow::anyhow!(
+                    "api key '{}': hash is not sha256 hex",
+                    entry.name
+                ));
+            }
+            tracing::debug!(name = entry.name, roles = ?entry.roles, cidrs = ?entry.cidrs, "api key");
+            if keys.insert(hash, entry.clone()).is_some() {
+                return Err(anyhow::anyhow!("api key '{}': duplicate hash", entry.name));
+            }
+        }
+        tracing::info!(len = keys.len(), "loaded api keys");
+        Ok(ApiKeys {
+            keys,
+            header: header.to_string(),
+        })
+    }
+
+    // Returns the key from `Authorization: ApiKey <key>` or the custom header
+    pub fn extract<'a>(&self, headers: &'a HeaderMap) -> Option<&'a str> {
+        let from_auth = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.split_once(' '))
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case(SCHEME))
+            .map(|(_, key)| key.trim());
+        from_auth
+            .or_else(|| {
+                if self.header.is_empty() {
+                    return None;
+                }
+                headers
+                    .get(self.header.as_str())
+                    .and_then(|h| h.to_str().ok())
+                    .map(|h| h.trim())
+            })
+            .filter(|key| !key.is_empty())
+    }
+
+    pub fn check(&self, key: &str, ip: &str) -> Result<auth::User, auth::Error> {
+        let hash = format!("{:x}", Sha256::digest(key.as_bytes()));
+        let entry = self.keys.get(&hash).ok_or(auth::Error::WrongUserPass())?;
+        if !entry.cidrs.is_empty() {
+            let allowed = ip
+                .parse::<IpAddr>()
+                .is_ok_and(|ip| entry.cidrs.iter().any(|net| net.contains(&ip)));
+            if !allowed {
+                tracing::warn!(name = entry.name, ip, "api key used from not allowed ip");
+                return Err(auth::Error::NoAccess());
+            }
+        }
+        Ok(auth::User {
+            id: entry.name.clone(),
+            name: entry.name.clone(),
+            department: entry.department.clone(),
+            roles: entry.roles.clone(),
+            backend: BACKEND.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+    use test_case::test_case;
+
+    fn keys() -> ApiKeys {
+        // the hash is case insensitive
+        let keys = format!(
+            r#"[
+                {{"name": "ci", "hash": "{:x}", "roles": ["CI"]}},
+                {{"name": "svc", "hash": "{:X}", "department": "IT", "roles": ["A", "B"], "cidrs": ["10.0.0.0/8", "::1/128"]}}
+            ]"#,
+            Sha256::digest(b"secret-key"),
+            Sha256::digest(b"other-key")
+        );
+        ApiKeys::from_json(&keys, "X-Api-Key").unwrap()
+    }
+
+    #[test_case("secret-key", "1.1.1.1", Some("ci"); "any ip")]
+    #[test_case("secret-key", "", Some("ci"); "no ip")]
+    #[test_case("other-key", "10.1.2.3", Some("svc"); "allowed ip")]
+    #[test_case("other-key", "::1", Some("svc"); "allowed ipv6")]
+    #[test_case("other-key", "11.1.2.3", None; "not allowed ip")]
+    #[test_case("other-key", "", None; "unknown ip")]
+    #[test_case("secret-key2", "", None; "wrong key")]
+    #[test_case("", "", None; "empty key")]
+    fn test_check(key: &str, ip: &str, expected: Option<&str>) {
+        assert_eq!(
+            keys().check(key, ip).ok().map(|u| u.id).as_deref(),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_check_user() {
+        let user = keys().check("other-key", "10.0.0.1").unwrap();
+        assert_eq!(user.department, "IT");
+        assert_eq!(user.roles, vec!["A", "B"]);
+        assert!(matches!(
+            keys().check("other-key", "1.1.1.1"),
+            Err(auth::Error::NoAccess())
+        ));
+        assert!(matches!(
+            keys().check("xxx", "1.1.1.1"),
+            Err(auth::Error::WrongUserPass())
+        ));
+    }
+
+    #[test_case(&[("Authorization", "ApiKey abc")], Some("abc"); "authorization")]
+    #[test_case(&[("Authorization", "apikey  abc ")], Some("abc"); "case")]
+    #[test_case(&[("Authorization", "Bearer abc")], None; "bearer")]
+    #[test_case(&[("X-Api-Key", "abc")], Some("abc"); "custom header")]
+    #[test_case(&[("Authorization", "ApiKey abc"), ("X-Api-Key", "def")], Some("abc"); "both")]
+    #[test_case(&[("Authorization", "ApiKey ")], None; "empty")]
+    #[test_case(&[], None; "none")]
+    fn test_extract(input: &[(&str, &str)], expected: Option<&str>) {
+        let mut headers = HeaderMap::new();
+        for (k, v) in input {
+            headers.insert(
+                axum::http::HeaderName::from_bytes(k.as_bytes()).unwrap(),
+                HeaderValue::from_str(v).unwrap(),
+            );
+        }
+        assert_eq!(keys().extract(&headers), expected);
+    }
+
+    #[test_case(r#"[{"name": "", "hash": "00"}]"#; "no name")]
+    #[test_case(r#"[{"name": "a", "hash": "00"}]"#; "short hash")]
+    #[test_case(r#"[{"name": "a", "hash": "0000000000000000000000000000000000000000000000000000000000000000", "cidrs": ["x"]}]"#; "cidr")]
+    #[test_case(r#"[{"name": "a", "hash": "0000000000000000000000000000000000000000000000000000000000000000"},
+        {"name": "b", "hash": "0000000000000000000000000000000000000000000000000000000000000000"}]"#; "duplicate")]
+    #[test_case("olia"; "not json")]
+    fn test_from_json_fails(input: &str) {
+        assert!(ApiKeys::from_json(input, "").is_err());
+    }
+}
@@ -0,0 +1,623 @@
+use std::{collections::HashMap, str::FromStr, sync::Arc, time::Duration};
+
+use axum::http::HeaderMap;
+use base64::{prelude::BASE64_STANDARD, Engine};
+use chrono::Utc;
+use rustls::{
+    pki_types::{pem::PemObject, CertificateDer, UnixTime},
+    server::{danger::ClientCertVerifier, WebPkiClientVerifier},
+    RootCertStore,
+};
+use serde::Deserialize;
+use tokio::sync::Mutex;
+use x509_parser::{
+    der_parser::asn1_rs::{Any, FromDer},
+    extensions::GeneralName,
+    prelude::X509Certificate,
+};
+
+use crate::{model::auth, utils::secret_str::ct_eq, AuthService};
+
+pub const CERT_HEADER: &str = "X-Forwarded-Tls-Client-Cert";
+pub const INFO_HEADER: &str = "X-Forwarded-Tls-Client-Cert-Info";
+// set by the proxy on the routers verifying client certificates, cert headers of other
+// requests may come from the client and are ignored
+pub const PROXY_SECRET_HEADER: &str = "X-Client-Cert-Proxy-Secret";
+// backend name of the certificate users from the users file
+pub const BACKEND: &str = "client_cert";
+// Microsoft user principal name, used in smartcard certificates
+const OID_UPN: &str = "1.3.6.1.4.1.311.20.2.3";
+
+// Certificate field used as the user id
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UserField {
+    CommonName,
+    Email,
+    Dns,
+    Upn,
+}
+
+impl FromStr for UserField {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "cn" => Ok(UserField::CommonName),
+            "email" => Ok(UserField::Email),
+            "dns" => Ok(UserField::Dns),
+            "upn" => Ok(UserField::Upn),
+            _ => Err(anyhow::anyhow!(
+                "unknown cert user field '{s}', expected cn, email, dns or upn"
+            )),
+        }
+    }
+}
+
+pub struct Config {
+    // PEM bundle of trusted CAs
+    pub ca_file: String,
+    pub user_field: UserField,
+    // json users file: `{"<user>": {"name": "", "department": "", "roles": [""]}}`
+    pub users_file: String,
+    // look up users missing in the users file via the auth service
+    pub lookup: bool,
+    pub lookup_cache: Duration,
+    // accept the info header without the certificate, the proxy must verify the chain then
+    pub trust_info_header: bool,
+    // expected value of PROXY_SECRET_HEADER
+    pub proxy_secret: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Entry {
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    department: String,
+    roles: Vec<String>,
+}
+
+// Authenticates users by client certificates forwarded by Traefik's passTLSClientCert middleware
+pub struct ClientCert {
+    verifier: Arc<dyn ClientCertVerifier>,
+    user_field: UserField,
+    users: HashMap<String, Entry>,
+    lookup: bool,
+    lookup_cache: Duration,
+    trust_info_header: bool,
+    proxy_secret: String,
+    // user and the cache expiration time in millis
+    cache: Mutex<HashMap<String, (auth::User, i64)>>,
+}
+
+impl ClientCert {
+    pub fn new(config: Config) -> anyhow::Result<Self> {
+        tracing::debug!(
+            ca = config.ca_file,
+            field = ?config.user_field,
+            users = config.users_file,
+            lookup = config.lookup,
+            "init client cert auth"
+        );
+        if config.proxy_secret.is_empty() {
+            return Err(anyhow::anyhow!(
+                "client cert auth needs a proxy secret, cert headers can be sent by any client"
+            ));
+        }
+        let mut roots = RootCertStore::empty();
+        for cert in CertificateDer::pem_file_iter(&config.ca_file)
+            .map_err(|e| anyhow::anyhow!("can't read {}: {e}", config.ca_file))?
+        {
+            let cert = cert.map_err(|e| anyhow::anyhow!("can't parse {}: {e}", config.ca_file))?;
+            roots.add(cert)?;
+        }
+        if roots.is_empty() {
+            return Err(anyhow::anyhow!("no CA certificates in {}", config.ca_file));
+        }
+        let verifier = WebPkiClientVerifier::builder_with_provider(
+            Arc::new(roots),
+            Arc::new(rustls::crypto::aws_lc_rs::default_provider()),
+        )
+        .build()?;
+        let users = if config.users_file.is_empty() {
+            HashMap::new()
+        } else {
+            let content = std::fs::read_to_string(&config.users_file)
+                .map_err(|e| anyhow::anyhow!("can't read {}: {e}", config.users_file))?;
+            serde_json::from_str(&content)
+                .map_err(|e| anyhow::anyhow!("can't parse {}: {e}", config.users_file))?
+        };
+        if users.is_empty() && !config.lookup {
+            return Err(anyhow::anyhow!("no cert users file and lookup is disabled"));
+        }
+        Ok(ClientCert {
+            verifier,
+            user_field: config.user_field,
+            users,
+            lookup: config.lookup,
+            lookup_cache: config.lookup_cache,
+            trust_info_header: config.trust_info_header,
+            proxy_secret: config.proxy_secret,
+            cache: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // Returns None if the request has no client certificate
+    pub async fn authenticate(
+        &self,
+        headers: &HeaderMap,
+        auth_service: &(dyn AuthService + Send + Sync),
+    ) -> Option<Result<auth::User, auth::Error>> {
+        let header = |name: &str| headers.get(name).and_then(|h| h.to_str().ok());
+        if header(CERT_HEADER).is_none() && header(INFO_HEADER).is_none() {
+            return None;
+        }
+        if !header(PROXY_SECRET_HEADER)
+            .is_some_and(|v| ct_eq(v.as_bytes(), self.proxy_secret.as_bytes()))
+        {
+            tracing::warn!("client cert headers without the proxy secret, ignored");
+            return None;
+        }
+        let user_id = match (header(CERT_HEADER), header(INFO_HEADER)) {
+            (Some(certs), _) => self.user_from_certs(certs, UnixTime::now()),
+            (None, Some(info)) if self.trust_info_header => self.user_from_info(info),
+            _ => return None,
+        };
+        Some(match user_id {
+            Ok(user_id) => self.find_user(&user_id, auth_service).await,
+            Err(err) => Err(err),
+        })
+    }
+
+    fn user_from_certs(&self, value: &str, now: UnixTime) -> Result<String, auth::Error> {
+        let chain = parse_cert_header(value)?;
+        let (end_entity, intermediates) = chain
+            .split_first()
+            .ok_or_else(|| auth::Error::OtherAuth("no client certificate".to_string()))?;
+        self.verifier
+            .verify_client_cert(end_entity, intermediates, now)
+            .map_err(|e| auth::Error::OtherAuth(format!("client certificate: {e}")))?;
+        let (_, cert) = X509Certificate::from_der(end_entity)
+            .map_err(|e| auth::Error::OtherAuth(format!("client certificate: {e}")))?;
+        cert_user(&cert, self.user_field).ok_or_else(|| {
+            auth::Error::OtherAuth(format!("no {:?} in client certificate", self.user_field))
+        })
+    }
+
+    fn user_from_info(&self, value: &str) -> Result<String, auth::Error> {
+        let value = urlencoding::decode(value)
+            .map_err(|e| auth::Error::OtherAuth(format!("client cert info: {e}")))?;
+        info_user(&parse_info(&value), self.user_field).ok_or_else(|| {
+            auth::Error::OtherAuth(format!("no {:?} in client cert info", self.user_field))
+        })
+    }
+
+    async fn find_user(
+        &self,
+        user_id: &str,
+        auth_service: &(dyn AuthService + Send + Sync),
+    ) -> Result<auth::User, auth::Error> {
+        if let Some(entry) = self.users.get(user_id) {
+            return Ok(auth::User {
+                id: user_id.to_string(),
+                name: if entry.name.is_empty() {
+                    user_id.to_string()
+                } else {
+                    entry.name.clone()
+                },
+                department: entry.department.clone(),
+                roles: entry.roles.clone(),
+                backend: BACKEND.to_string(),
+            });
+        }
+        if !self.lookup {
+            tracing::warn!(user = user_id, "unknown client cert user");
+            return Err(auth::Error::NoAccess());
+        }
+        let now = Utc::now().timestamp_millis();
+        if let Some((user, till)) = self.cache.lock().await.get(user_id) {
+            if *till > now {
+                return Ok(user.clone());
+            }
+        }
+        tracing::debug!(user = user_id, "lookup client cert user");
+        let user = auth_service.lookup(user_id).await?;
+        let mut cache = self.cache.lock().await;
+        cache.retain(|_, (_, till)| *till > now);
+        cache.insert(
+            user_id.to_string(),
+            (user.clone(), now + self.lookup_cache.as_millis() as i64),
+        );
+        Ok(user)
+    }
+}
+
+// Traefik sends url escaped base64 DER certificates without PEM markers, separated by comma
+fn parse_cert_header(value: &str) -> Result<Vec<CertificateDer<'static>>, auth::Error> {
+    let value = urlencoding::decode(value)
+        .map_err(|e| auth::Error::OtherAuth(format!("client cert header: {e}")))?;
+    value
+        .split(',')
+        .map(|cert| {
+            let cert: String = cert
+                .replace("-----BEGIN CERTIFICATE-----", "")
+                .replace("-----END CERTIFICATE-----", "")
+                .chars()
+                .filter(|c| !c.is_whitespace())
+                .collect();
+            BASE64_STANDARD
+                .decode(cert)
+                .map(CertificateDer::from)
+                .map_err(|e| auth::Error::OtherAuth(format!("client cert header: {e}")))
+        })
+        .collect()
+}
+
+fn cert_user(cert: &X509Certificate, field: UserField) -> Option<String> {
+    let sans: Vec<&GeneralName> = cert
+        .subject_alternative_name()
+        .ok()
+        .flatten()
+        .map(|ext| ext.value.general_names.iter().collect())
+        .unwrap_or_default();
+    match field {
+        UserField::CommonName => cert
+            .subject()
+            .iter_common_name()
+            .next()
+            .and_then(|cn| cn.as_str().ok())
+            .map(|s| s.to_string()),
+        UserField::Email => sans.iter().find_map(|n| match n {
+            GeneralName::RFC822Name(email) => Some(email.to_string()),
+            _ => None,
+        }),
+        UserField::Dns => sans.iter().find_map(|n| match n {
+            GeneralName::DNSName(dns) => Some(dns.to_string()),
+            _ => None,
+        }),
+        UserField::Upn => sans.iter().find_map(|n| match n {
+            GeneralName::OtherName(oid, value) if oid.to_id_string() == OID_UPN => parse_upn(value),
+            _ => None,
+        }),
+    }
+}
+
+// [0] EXPLICIT UTF8String
+fn parse_upn(value: &[u8]) -> Option<String> {
+    let (_, explicit) = Any::from_der(value).ok()?;
+    let (_, inner) = Any::from_der(explicit.data).ok()?;
+    std::str::from_utf8(inner.data).ok().map(|s| s.to_string())
+}
+
+// Parses the first certificate of `Subject="...";Issuer="...";SAN="..."`
+fn parse_info(value: &str) -> HashMap<String, String> {
+    let mut res = HashMap::new();
+    let (mut key, mut current, mut quoted) = (String::new(), String::new(), false);
+    for c in value.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            '=' if !quoted && key.is_empty() => key = std::mem::take(&mut current),
+            ';' | ',' if !quoted => {
+                if !key.is_empty() {
+                    res.insert(std::mem::take(&mut key), std::mem::take(&mut current));
+                }
+                current.clear();
+                if c == ',' {
+                    break;
+                }
+            }
+            _ => current.push(c),
+        }
+    }
+    if !key.is_empty() {
+        res.insert(key, current);
+    }
+    res
+}
+
+fn info_user(info: &HashMap<String, String>, field: UserField) -> Option<String> {
+    let sans = || {
+        info.get("SAN")
+            .map(|s| s.split(',').map(|s| s.trim()).collect::<Vec<_>>())
+            .unwrap_or_default()
+    };
+    let res = match field {
+        UserField::CommonName => info.get("Subject").and_then(|s| {
+            s.split(',')
+                .filter_map(|p| p.trim().split_once('='))
+                .find(|(k, _)| k.eq_ignore_ascii_case("CN"))
+                .map(|(_, v)| v.to_string())
+        }),
+        UserField::Email => sans()
+            .into_iter()
+            .find(|s| s.contains('@'))
+            .map(|s| s.to_string()),
+        UserField::Dns => sans()
+            .into_iter()
+            .find(|s| !s.contains('@') && s.parse::<std::net::IpAddr>().is_err())
+            .map(|s| s.to_string()),
+        // not present in the info header
+        UserField::Upn => None,
+    };
+    res.filter(|s| !s.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{auth::sample::Sample, utils::secret_str::SecretString};
+    use async_trait::async_trait;
+    use rcgen::{
+        BasicConstraints, Certificate, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa,
+        KeyPair, SanType,
+    };
+    use std::io::Write;
+    use test_case::test_case;
+
+    struct Ca {
+        cert: Certificate,
+        key: KeyPair,
+    }
+
+    fn make_ca(name: &str) -> Ca {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec![]).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.distinguished_name.push(DnType::CommonName, name);
+        Ca {
+            cert: params.self_signed(&key).unwrap(),
+            key,
+        }
+    }
+
+    fn make_client(ca: &Ca, cn: &str) -> Certificate {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec![]).unwrap();
+        params.distinguished_name.push(DnType::CommonName, cn);
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+        params.subject_alt_names = vec![
+            SanType::Rfc822Name(format!("{cn}@corp.local").try_into().unwrap()),
+            SanType::DnsName(format!("{cn}.corp.local").try_into().unwrap()),
+            SanType::OtherName((
+                vec![1, 3, 6, 1, 4, 1, 311, 20, 2, 3],
+                format!("{cn}@CORP").into(),
+            )),
+        ];
+        params.signed_by(&key, &ca.cert, &ca.key).unwrap()
+    }
+
+    // as sent by traefik
+    fn header(cert: &Certificate) -> String {
+        urlencoding::encode(&BASE64_STANDARD.encode(cert.der())).to_string()
+    }
+
+    fn write_file(name: &str, content: &str) -> String {
+        let path = std::env::temp_dir().join(format!(
+            "authware-cert-{name}-{}-{}",
+            std::process::id(),
+            rand::random::<u32>()
+        ));
+        let mut file = std::fs::File::create(&path).unwrap();
+        file.write_all(content.as_bytes()).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    fn make_auth(ca: &Ca, field: UserField, lookup: bool) -> ClientCert {
+        ClientCert::new(Config {
+            ca_file: write_file("ca", &ca.cert.pem()),
+            user_field: field,
+            users_file: write_file(
+                "users",
+                r#"{"olia": {"name": "Olia", "department": "IT", "roles": ["OPS"]}}"#,
+            ),
+            lookup,
+            lookup_cache: Duration::from_secs(60),
+            trust_info_header: true,
+            proxy_secret: "proxy".to_string(),
+        })
+        .unwrap()
+    }
+
+    // as forwarded from a router verifying client certificates
+    fn headers(values: &[(&'static str, String)]) -> HeaderMap {
+        let mut res = HeaderMap::new();
+        res.insert(PROXY_SECRET_HEADER, "proxy".parse().unwrap());
+        for (k, v) in values {
+            res.insert(*k, v.parse().unwrap());
+        }
+        res
+    }
+
+    fn sample() -> Sample {
+        Sample::new("jonas:pass:HR:USER").unwrap()
+    }
+
+    #[test_case(UserField::CommonName, "olia"; "cn")]
+    #[test_case(UserField::Email, "olia@corp.local"; "email")]
+    #[test_case(UserField::Dns, "olia.corp.local"; "dns")]
+    #[test_case(UserField::Upn, "olia@CORP"; "upn")]
+    fn test_cert_user(field: UserField, expected: &str) {
+        let ca = make_ca("ca");
+        let cert = make_client(&ca, "olia");
+        let (_, cert) = X509Certificate::from_der(cert.der()).unwrap();
+        assert_eq!(cert_user(&cert, field).as_deref(), Some(expected));
+    }
+
+    #[tokio::test]
+    async fn test_authenticate() {
+        let ca = make_ca("ca");
+        let auth = make_auth(&ca, UserField::CommonName, false);
+        let cert = make_client(&ca, "olia");
+        let res = auth
+            .authenticate(&headers(&[(CERT_HEADER, header(&cert))]), &sample())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            res,
+            auth::User {
+                id: "olia".to_string(),
+                name: "Olia".to_string(),
+                department: "IT".to_string(),
+                roles: vec!["OPS".to_string()],
+                backend: BACKEND.to_string(),
+            }
+        );
+        assert!(auth
+            .authenticate(&HeaderMap::new(), &sample())
+            .await
+            .is_none());
+    }
+
+    #[test_case(None; "no secret")]
+    #[test_case(Some("wrong"); "wrong secret")]
+    #[tokio::test]
+    async fn test_not_from_proxy(secret: Option<&str>) {
+        let ca = make_ca("ca");
+        let auth = make_auth(&ca, UserField::CommonName, false);
+        let cert = make_client(&ca, "olia");
+        let mut headers = headers(&[(CERT_HEADER, header(&cert))]);
+        headers.remove(PROXY_SECRET_HEADER);
+        if let Some(secret) = secret {
+            headers.insert(PROXY_SECRET_HEADER, secret.parse().unwrap());
+        }
+        assert!(auth.authenticate(&headers, &sample()).await.is_none());
+        let info = urlencoding::encode(r#"Subject="CN=olia""#).to_string();
+        headers.insert(INFO_HEADER, info.parse().unwrap());
+        headers.remove(CERT_HEADER);
+        assert!(auth.authenticate(&headers, &sample()).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_authenticate_chain() {
+        let ca = make_ca("ca");
+        let auth = make_auth(&ca, UserField::CommonName, false);
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec![]).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.distinguished_name.push(DnType::CommonName, "sub");
+        let sub = Ca {
+            cert: params.signed_by(&key, &ca.cert, &ca.key).unwrap(),
+            key,
+        };
+        let cert = make_client(&sub, "olia");
+        let value = format!("{},{}", header(&cert), header(&sub.cert));
+        let res = auth
+            .authenticate(&headers(&[(CERT_HEADER, value)]), &sample())
+            .await
+            .unwrap();
+        assert_eq!(res.unwrap().id, "olia");
+        // no intermediate
+        let res = auth
+            .authenticate(&headers(&[(CERT_HEADER, header(&cert))]), &sample())
+            .await
+            .unwrap();
+        assert!(matches!(res, Err(auth::Error::OtherAuth(_))));
+    }
+
+    #[tokio::test]
+    async fn test_authenticate_fails() {
+        let ca = make_ca("ca");
+        let auth = make_auth(&ca, UserField::CommonName, false);
+        let other = make_ca("ca");
+        let cert = make_client(&other, "olia");
+        let res = auth
+            .authenticate(&headers(&[(CERT_HEADER, header(&cert))]), &sample())
+            .await
+            .unwrap();
+        assert!(matches!(res, Err(auth::Error::OtherAuth(_))));
+
+        let res = auth
+            .authenticate(&headers(&[(CERT_HEADER, "olia".to_string())]), &sample())
+            .await
+            .unwrap();
+        assert!(matches!(res, Err(auth::Error::OtherAuth(_))));
+
+        let cert = make_client(&ca, "jonas");
+        let res = auth
+            .authenticate(&headers(&[(CERT_HEADER, header(&cert))]), &sample())
+            .await
+            .unwrap();
+        assert!(matches!(res, Err(auth::Error::NoAccess())));
+    }
+
+    struct CountingLookup {
+        calls: std::sync::atomic::AtomicU32,
+    }
+
+    #[async_trait]
+    impl AuthService for CountingLookup {
+        async fn login(&self, _: &str, _: &SecretString) -> Result<auth::User, auth::Error> {
+            Err(auth::Error::WrongUserPass())
+        }
+
+        async fn lookup(&self, user: &str) -> Result<auth::User, auth::Error> {
+            self.calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            sample().lookup(user).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lookup() {
+        let ca = make_ca("ca");
+        let auth = make_auth(&ca, UserField::CommonName, true);
+        let service = CountingLookup {
+            calls: Default::default(),
+        };
+        let cert = make_client(&ca, "jonas");
+        for _ in 0..2 {
+            let res = auth
+                .authenticate(&headers(&[(CERT_HEADER, header(&cert))]), &service)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(res.department, "HR");
+        }
+        assert_eq!(service.calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+        let cert = make_client(&ca, "petras");
+        let res = auth
+            .authenticate(&headers(&[(CERT_HEADER, header(&cert))]), &service)
+            .await
+            .unwrap();
+        assert!(matches!(res, Err(auth::Error::WrongUserPass())));
+    }
+
+    #[tokio::test]
+    async fn test_info_header() {
+        let ca = make_ca("ca");
+        let auth = make_auth(&ca, UserField::CommonName, false);
+        let info =
+            urlencoding::encode(r#"Subject="C=LT,CN=olia";Issuer="CN=ca";SAN="olia@corp.local""#)
+                .to_string();
+        let res = auth
+            .authenticate(&headers(&[(INFO_HEADER, info.clone())]), &sample())
+            .await
+            .unwrap();
+        assert_eq!(res.unwrap().id, "olia");
+
+        let mut auth = auth;
+        auth.trust_info_header = false;
+        assert!(auth
+            .authenticate(&headers(&[(INFO_HEADER, info)]), &sample())
+            .await
+            .is_none());
+    }
+
+    #[test_case(r#"Subject="C=LT,CN=olia";SAN="a.lt,olia@corp.local""#, UserField::CommonName, Some("olia"); "cn")]
+    #[test_case(r#"Subject="C=LT,CN=olia";SAN="a.lt,olia@corp.local""#, UserField::Email, Some("olia@corp.local"); "email")]
+    #[test_case(r#"Subject="C=LT,CN=olia";SAN="10.0.0.1,a.lt,olia@corp.local""#, UserField::Dns, Some("a.lt"); "dns")]
+    #[test_case(r#"Subject="C=LT,CN=olia";SAN="a.lt",Subject="CN=ca""#, UserField::CommonName, Some("olia"); "chain")]
+    #[test_case(r#"Subject="C=LT""#, UserField::CommonName, None; "no cn")]
+    #[test_case(r#"Subject="CN=olia""#, UserField::Upn, None; "upn")]
+    #[test_case("", UserField::CommonName, None; "empty")]
+    fn test_info_user(input: &str, field: UserField, expected: Option<&str>) {
+        assert_eq!(info_user(&parse_info(input), field).as_deref(), expected);
+    }
+
+    #[test_case("cn", Some(UserField::CommonName); "cn")]
+    #[test_case("EMAIL", Some(UserField::Email); "email")]
+    #[test_case("olia", None; "unknown")]
+    fn test_user_field(input: &str, expected: Option<UserField>) {
+        assert_eq!(input.parse::<UserField>().ok(), expected);
+    }
+}
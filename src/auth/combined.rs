@@ -1,30 +1,598 @@
+use std::{str::FromStr, time::Duration};
+
 use async_trait::async_trait;
+use futures::{stream::FuturesUnordered, StreamExt};
 
 use crate::{model::auth, utils::secret_str::SecretString, AuthService};
 
+pub struct Backend {
+    pub name: String,
+    pub auth: Box<dyn AuthService + Send + Sync>,
+}
+
+// How the backends are tried when the user is not routed to one
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Strategy {
+    // one by one until the first success
+    Sequential,
+    // the next one only if the previous one failed with a service error
+    Fallback,
+    // all at once, the first success wins
+    Parallel,
+}
+
+impl FromStr for Strategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "sequential" => Ok(Strategy::Sequential),
+            "fallback" => Ok(Strategy::Fallback),
+            "parallel" => Ok(Strategy::Parallel),
+            _ => Err(anyhow::anyhow!(
+                "wrong auth strategy {s}, expected sequential, fallback or parallel"
+            )),
+        }
+    }
+}
+
+// Sends users matching the pattern to one backend, e.g. `*@corp=ldap`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Route {
+    pub pattern: String,
+    pub backend: String,
+}
+
+impl FromStr for Route {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pattern, backend) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("wrong route {s}, expected pattern=backend"))?;
+        let (pattern, backend) = (pattern.trim(), backend.trim());
+        if pattern.is_empty() || backend.is_empty() {
+            return Err(anyhow::anyhow!("wrong route {s}, expected pattern=backend"));
+        }
+        Ok(Route {
+            pattern: pattern.to_lowercase(),
+            backend: backend.to_string(),
+        })
+    }
+}
+
+// Parses comma separated routes: `*@corp=ldap,svc-*=file`
+pub fn parse_routes(s: &str) -> anyhow::Result<Vec<Route>> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(Route::from_str)
+        .collect()
+}
+
+pub struct Config {
+    pub strategy: Strategy,
+    // max wait for the parallel strategy
+    pub deadline: Duration,
+    pub routes: Vec<Route>,
+    // allows the client to pick a backend by name on login
+    pub allow_select: bool,
+}
+
 pub struct Auths {
-    auths: Vec<Box<dyn AuthService + Send + Sync>>,
+    backends: Vec<Backend>,
+    config: Config,
 }
 
 impl Auths {
-    pub fn new(auths: Vec<Box<dyn AuthService + Send + Sync>>) -> anyhow::Result<Self> {
-        if auths.is_empty() {
+    pub fn new(backends: Vec<Backend>, config: Config) -> anyhow::Result<Self> {
+        if backends.is_empty() {
             return Err(anyhow::anyhow!("No auth services provided"));
         }
-        Ok(Auths { auths })
+        for (i, backend) in backends.iter().enumerate() {
+            if backends[..i].iter().any(|b| b.name == backend.name) {
+                return Err(anyhow::anyhow!("duplicate auth backend {}", backend.name));
+            }
+        }
+        for route in &config.routes {
+            if !backends.iter().any(|b| b.name == route.backend) {
+                return Err(anyhow::anyhow!(
+                    "unknown auth backend {} in route {}",
+                    route.backend,
+                    route.pattern
+                ));
+            }
+        }
+        tracing::debug!(
+            backends = ?backends.iter().map(|b| b.name.as_str()).collect::<Vec<_>>(),
+            strategy = ?config.strategy,
+            routes = config.routes.len(),
+            "init combined auth"
+        );
+        Ok(Auths { backends, config })
+    }
+
+    fn get(&self, name: &str) -> Option<&Backend> {
+        self.backends.iter().find(|b| b.name == name)
+    }
+
+    fn find_route(&self, user: &str) -> Option<&Route> {
+        let user = user.to_lowercase();
+        self.config
+            .routes
+            .iter()
+            .find(|r| matches(&r.pattern, &user))
+    }
+
+    fn route(&self, user: &str) -> Vec<&Backend> {
+        if let Some(route) = self.find_route(user) {
+            tracing::trace!(backend = route.backend, "routed");
+            return self.get(&route.backend).into_iter().collect();
+        }
+        self.backends.iter().collect()
+    }
+
+    async fn login_int(
+        &self,
+        backends: Vec<&Backend>,
+        user: &str,
+        pass: &SecretString,
+    ) -> Result<auth::User, auth::Error> {
+        if backends.len() == 1 {
+            return login_one(backends[0], user, pass).await;
+        }
+        match self.config.strategy {
+            Strategy::Sequential => {
+                let mut errors = Vec::new();
+                for backend in backends {
+                    match login_one(backend, user, pass).await {
+                        Ok(res) => return Ok(res),
+                        Err(err) => errors.push(err),
+                    }
+                }
+                Err(merge_errors(errors))
+            }
+            Strategy::Fallback => {
+                let mut last_err = None;
+                for backend in backends {
+                    match login_one(backend, user, pass).await {
+                        Ok(res) => return Ok(res),
+                        Err(err @ auth::Error::ServiceError(_)) => last_err = Some(err),
+                        Err(err) => return Err(err),
+                    }
+                }
+                Err(last_err.unwrap_or_else(auth::Error::NoAccess))
+            }
+            Strategy::Parallel => {
+                let mut calls: FuturesUnordered<_> = backends
+                    .into_iter()
+                    .map(|b| login_one(b, user, pass))
+                    .collect();
+                let mut errors = Vec::new();
+                let race = async {
+                    while let Some(res) = calls.next().await {
+                        match res {
+                            Ok(res) => return Some(res),
+                            Err(err) => errors.push(err),
+                        }
+                    }
+                    None
+                };
+                match tokio::time::timeout(self.config.deadline, race).await {
+                    Ok(Some(res)) => Ok(res),
+                    Ok(None) => Err(merge_errors(errors)),
+                    Err(_) => {
+                        tracing::warn!(deadline = ?self.config.deadline, "auth deadline exceeded");
+                        errors.push(auth::Error::ServiceError(anyhow::anyhow!(
+                            "auth deadline exceeded"
+                        )));
+                        Err(merge_errors(errors))
+                    }
+                }
+            }
+        }
     }
 }
 
 #[async_trait]
 impl AuthService for Auths {
     async fn login(&self, user: &str, pass: &SecretString) -> Result<auth::User, auth::Error> {
-        let mut last_err: Option<auth::Error> = None;
-        for auth in &self.auths {
-            match auth.login(user, pass).await {
-                Ok(user) => return Ok(user),
-                Err(err) => last_err = Some(err),
+        self.login_int(self.route(user), user, pass).await
+    }
+
+    async fn login_backend(
+        &self,
+        backend: &str,
+        user: &str,
+        pass: &SecretString,
+    ) -> Result<auth::User, auth::Error> {
+        if !self.config.allow_select {
+            return Err(auth::Error::OtherAuth(
+                "auth backend selection is disabled".to_string(),
+            ));
+        }
+        let backend = self
+            .get(backend)
+            .ok_or_else(|| auth::Error::OtherAuth(format!("unknown auth backend {backend}")))?;
+        // a routed user can pick the backend of the route only
+        if let Some(route) = self.find_route(user) {
+            if route.backend != backend.name {
+                tracing::debug!(
+                    backend = backend.name,
+                    routed = route.backend,
+                    "wrong backend"
+                );
+                return Err(auth::Error::OtherAuth(format!(
+                    "user is not served by auth backend {}",
+                    backend.name
+                )));
+            }
+        }
+        login_one(backend, user, pass).await
+    }
+
+    async fn lookup(&self, user: &str) -> Result<auth::User, auth::Error> {
+        let mut errors = Vec::new();
+        for backend in self.route(user) {
+            match backend.auth.lookup(user).await {
+                Ok(res) => {
+                    return Ok(auth::User {
+                        backend: backend.name.clone(),
+                        ..res
+                    })
+                }
+                Err(err) => errors.push(err),
+            }
+        }
+        Err(merge_errors(errors))
+    }
+
+    // Asks the backend that authenticated the user
+    async fn refresh_user(&self, user: &auth::User) -> Result<Option<auth::User>, auth::Error> {
+        let backend = match self.get(&user.backend) {
+            Some(backend) => backend,
+            None => return Ok(None),
+        };
+        let res = backend.auth.refresh_user(user).await?;
+        Ok(res.map(|res| auth::User {
+            backend: backend.name.clone(),
+            ..res
+        }))
+    }
+}
+
+async fn login_one(
+    backend: &Backend,
+    user: &str,
+    pass: &SecretString,
+) -> Result<auth::User, auth::Error> {
+    match backend.auth.login(user, pass).await {
+        Ok(res) => {
+            tracing::debug!(backend = backend.name, user, "authenticated");
+            Ok(auth::User {
+                backend: backend.name.clone(),
+                ..res
+            })
+        }
+        Err(err) => {
+            match &err {
+                auth::Error::ServiceError(e) => {
+                    tracing::warn!(backend = backend.name, error = %e, "auth backend failed")
+                }
+                e => tracing::debug!(backend = backend.name, error = %e, "auth failed"),
+            }
+            Err(err)
+        }
+    }
+}
+
+// A definite answer from a backend that knows the user (expired, no access) wins,
+// then an outage, so a wrong password from one backend can't hide another one being down.
+// A backend not supporting the call goes last
+fn merge_errors(errors: Vec<auth::Error>) -> auth::Error {
+    let rank = |e: &auth::Error| match e {
+        auth::Error::ExpiredPass() | auth::Error::NoAccess() | auth::Error::OtherAuth(_) => 0,
+        auth::Error::ServiceError(_) => 1,
+        auth::Error::WrongUserPass() => 2,
+        auth::Error::Unsupported(_) => 3,
+    };
+    let mut res: Option<auth::Error> = None;
+    for err in errors {
+        if res.as_ref().is_none_or(|r| rank(&err) < rank(r)) {
+            res = Some(err);
+        }
+    }
+    res.unwrap_or_else(auth::Error::NoAccess)
+}
+
+// Case insensitive glob with `*` wildcards, the pattern is lower cased already
+fn matches(pattern: &str, value: &str) -> bool {
+    let parts: Vec<&str> = pattern.split('*').collect();
+    if parts.len() == 1 {
+        return pattern == value;
+    }
+    let (first, last) = (parts[0], parts[parts.len() - 1]);
+    if !value.starts_with(first) || !value.ends_with(last) || value.len() < first.len() + last.len()
+    {
+        return false;
+    }
+    let mut rest = &value[first.len()..value.len() - last.len()];
+    for part in &parts[1..parts.len() - 1] {
+        match rest.find(part) {
+            Some(pos) => rest = &rest[pos + part.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use super::*;
+    use test_case::test_case;
+
+    #[derive(Clone, Copy)]
+    enum Res {
+        Ok,
+        Wrong,
+        Expired,
+        Down,
+        Unsupported,
+    }
+
+    struct Mock {
+        res: Res,
+        delay: Duration,
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl Mock {
+        async fn answer(&self, user: &str) -> Result<auth::User, auth::Error> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(self.delay).await;
+            match self.res {
+                Res::Ok => Ok(auth::User {
+                    id: user.to_string(),
+                    name: user.to_string(),
+                    department: String::new(),
+                    roles: vec!["USER".to_string()],
+                    backend: String::new(),
+                }),
+                Res::Wrong => Err(auth::Error::WrongUserPass()),
+                Res::Expired => Err(auth::Error::ExpiredPass()),
+                Res::Down => Err(auth::Error::ServiceError(anyhow::anyhow!("down"))),
+                Res::Unsupported => Err(auth::Error::Unsupported("unsupported".to_string())),
             }
         }
-        last_err.map_or_else(|| Err(auth::Error::NoAccess()), Err)
+    }
+
+    #[async_trait]
+    impl AuthService for Mock {
+        async fn login(&self, user: &str, _: &SecretString) -> Result<auth::User, auth::Error> {
+            self.answer(user).await
+        }
+
+        async fn lookup(&self, user: &str) -> Result<auth::User, auth::Error> {
+            self.answer(user).await
+        }
+
+        async fn refresh_user(&self, user: &auth::User) -> Result<Option<auth::User>, auth::Error> {
+            Ok(Some(user.clone()))
+        }
+    }
+
+    fn auths(
+        backends: &[(&str, Res, u64)],
+        strategy: Strategy,
+        routes: &str,
+    ) -> (Auths, Vec<Arc<AtomicUsize>>) {
+        let mut calls = vec![];
+        let backends = backends
+            .iter()
+            .map(|(name, res, delay)| {
+                let counter = Arc::new(AtomicUsize::new(0));
+                calls.push(counter.clone());
+                Backend {
+                    name: name.to_string(),
+                    auth: Box::new(Mock {
+                        res: *res,
+                        delay: Duration::from_millis(*delay),
+                        calls: counter,
+                    }),
+                }
+            })
+            .collect();
+        let config = Config {
+            strategy,
+            deadline: Duration::from_millis(200),
+            routes: parse_routes(routes).unwrap(),
+            allow_select: true,
+        };
+        (Auths::new(backends, config).unwrap(), calls)
+    }
+
+    fn result(res: Result<auth::User, auth::Error>) -> String {
+        match res {
+            Ok(user) => user.backend,
+            Err(err) => err.to_string(),
+        }
+    }
+
+    #[test_case(&[("a", Res::Wrong, 0), ("b", Res::Ok, 0)], Strategy::Sequential, "b", &[1, 1]; "sequential next")]
+    #[test_case(&[("a", Res::Ok, 0), ("b", Res::Ok, 0)], Strategy::Sequential, "a", &[1, 0]; "sequential first")]
+    #[test_case(&[("a", Res::Wrong, 0), ("b", Res::Down, 0)], Strategy::Sequential, "down", &[1, 1]; "sequential outage not hidden")]
+    #[test_case(&[("a", Res::Expired, 0), ("b", Res::Down, 0)], Strategy::Sequential, "Expired password", &[1, 1]; "sequential definite error")]
+    #[test_case(&[("a", Res::Wrong, 0), ("b", Res::Ok, 0)], Strategy::Fallback, "Wrong password", &[1, 0]; "fallback stops")]
+    #[test_case(&[("a", Res::Down, 0), ("b", Res::Ok, 0)], Strategy::Fallback, "b", &[1, 1]; "fallback on outage")]
+    #[test_case(&[("a", Res::Down, 0), ("b", Res::Down, 0)], Strategy::Fallback, "down", &[1, 1]; "fallback all down")]
+    #[test_case(&[("a", Res::Ok, 100), ("b", Res::Ok, 0)], Strategy::Parallel, "b", &[1, 1]; "parallel fastest")]
+    #[test_case(&[("a", Res::Wrong, 0), ("b", Res::Ok, 50)], Strategy::Parallel, "b", &[1, 1]; "parallel first success")]
+    #[test_case(&[("a", Res::Wrong, 0), ("b", Res::Ok, 1000)], Strategy::Parallel, "auth deadline exceeded", &[1, 1]; "parallel deadline")]
+    #[test_case(&[("a", Res::Wrong, 0), ("b", Res::Wrong, 0)], Strategy::Parallel, "Wrong password", &[1, 1]; "parallel all fail")]
+    #[test_case(&[("a", Res::Unsupported, 0), ("b", Res::Wrong, 0)], Strategy::Sequential, "Wrong password", &[1, 1]; "sequential unsupported last")]
+    #[test_case(&[("a", Res::Unsupported, 0), ("b", Res::Unsupported, 0)], Strategy::Parallel, "Not supported", &[1, 1]; "parallel all unsupported")]
+    #[tokio::test]
+    async fn test_strategy(
+        backends: &[(&str, Res, u64)],
+        strategy: Strategy,
+        expected: &str,
+        expected_calls: &[usize],
+    ) {
+        let (auths, calls) = auths(backends, strategy, "");
+        let res = auths.login("olia", &"pass".into()).await;
+        assert_eq!(result(res), expected);
+        let calls: Vec<usize> = calls.iter().map(|c| c.load(Ordering::SeqCst)).collect();
+        assert_eq!(calls, expected_calls);
+    }
+
+    #[test_case("olia@corp", "ldap"; "realm")]
+    #[test_case("OLIA@CORP", "ldap"; "realm case")]
+    #[test_case("svc-ci", "file"; "prefix")]
+    #[test_case("olia", "Wrong password"; "not routed")]
+    #[tokio::test]
+    async fn test_route(user: &str, expected: &str) {
+        let (auths, _) = auths(
+            &[
+                ("sample", Res::Wrong, 0),
+                ("ldap", Res::Ok, 0),
+                ("file", Res::Ok, 0),
+            ],
+            Strategy::Fallback,
+            "*@corp=ldap, svc-*=file",
+        );
+        let res = auths.login(user, &"pass".into()).await;
+        assert_eq!(result(res), expected);
+    }
+
+    #[test_case(Res::Wrong, "Wrong password"; "wrong")]
+    #[test_case(Res::Expired, "Expired password"; "expired")]
+    #[test_case(Res::Unsupported, "Not supported"; "unsupported")]
+    #[test_case(Res::Ok, "b"; "found")]
+    #[tokio::test]
+    async fn test_lookup(res: Res, expected: &str) {
+        let (auths, _) = auths(
+            &[("a", Res::Unsupported, 0), ("b", res, 0)],
+            Strategy::Sequential,
+            "",
+        );
+        let res = auths.lookup("olia").await;
+        assert_eq!(result(res), expected);
+    }
+
+    #[test_case("b", "b"; "own backend")]
+    #[test_case("", "none"; "no backend")]
+    #[test_case("c", "none"; "unknown backend")]
+    #[tokio::test]
+    async fn test_refresh_user(backend: &str, expected: &str) {
+        let (auths, calls) = auths(
+            &[("a", Res::Ok, 0), ("b", Res::Ok, 0)],
+            Strategy::Sequential,
+            "",
+        );
+        let user = auth::User {
+            id: "olia".to_string(),
+            name: "olia".to_string(),
+            department: String::new(),
+            roles: vec![],
+            backend: backend.to_string(),
+        };
+        let res = match auths.refresh_user(&user).await.unwrap() {
+            Some(res) => res.backend,
+            None => "none".to_string(),
+        };
+        assert_eq!(res, expected);
+        assert_eq!(calls[0].load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_select() {
+        let (auths, calls) = auths(
+            &[("a", Res::Ok, 0), ("b", Res::Ok, 0)],
+            Strategy::Sequential,
+            "",
+        );
+        let res = auths.login_backend("b", "olia", &"pass".into()).await;
+        assert_eq!(result(res), "b");
+        assert_eq!(calls[0].load(Ordering::SeqCst), 0);
+        let res = auths.login_backend("c", "olia", &"pass".into()).await;
+        assert!(matches!(res, Err(auth::Error::OtherAuth(_))));
+    }
+
+    #[test_case("a", "olia@corp", "a"; "routed backend")]
+    #[test_case("b", "olia@corp", "Other Auth error"; "other backend")]
+    #[test_case("b", "olia", "b"; "not routed")]
+    #[tokio::test]
+    async fn test_select_routed(backend: &str, user: &str, expected: &str) {
+        let (auths, calls) = auths(
+            &[("a", Res::Ok, 0), ("b", Res::Ok, 0)],
+            Strategy::Sequential,
+            "*@corp=a",
+        );
+        let res = auths.login_backend(backend, user, &"pass".into()).await;
+        assert_eq!(result(res), expected);
+        if expected != backend {
+            assert_eq!(calls[1].load(Ordering::SeqCst), 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_select_disabled() {
+        let (mut auths, _) = auths(&[("a", Res::Ok, 0)], Strategy::Sequential, "");
+        auths.config.allow_select = false;
+        let res = auths.login_backend("a", "olia", &"pass".into()).await;
+        assert!(matches!(res, Err(auth::Error::OtherAuth(_))));
+    }
+
+    #[test]
+    fn test_new_fails() {
+        let backend = |name: &str| Backend {
+            name: name.to_string(),
+            auth: Box::new(Mock {
+                res: Res::Ok,
+                delay: Duration::ZERO,
+                calls: Arc::new(AtomicUsize::new(0)),
+            }),
+        };
+        let config = |routes: &str| Config {
+            strategy: Strategy::Sequential,
+            deadline: Duration::from_secs(1),
+            routes: parse_routes(routes).unwrap(),
+            allow_select: false,
+        };
+        assert!(Auths::new(vec![], config("")).is_err());
+        assert!(Auths::new(vec![backend("a"), backend("a")], config("")).is_err());
+        assert!(Auths::new(vec![backend("a")], config("*=b")).is_err());
+        assert!(Auths::new(vec![backend("a")], config("*=a")).is_ok());
+    }
+
+    #[test_case("*@corp", "olia@corp", true; "suffix")]
+    #[test_case("*@corp", "olia@corp.lt", false; "suffix no")]
+    #[test_case("svc-*", "svc-ci", true; "prefix")]
+    #[test_case("svc-*", "svc", false; "prefix short")]
+    #[test_case("a*b*c", "axxbyyc", true; "middle")]
+    #[test_case("a*b*c", "ac", false; "middle no")]
+    #[test_case("ab*ba", "aba", false; "overlap")]
+    #[test_case("*", "", true; "any")]
+    #[test_case("olia", "olia", true; "exact")]
+    fn test_matches(pattern: &str, value: &str, expected: bool) {
+        assert_eq!(matches(pattern, value), expected);
+    }
+
+    #[test_case("*@corp=ldap,svc-*=file", 2; "two")]
+    #[test_case(" , ", 0; "empty")]
+    fn test_parse_routes(input: &str, expected: usize) {
+        assert_eq!(parse_routes(input).unwrap().len(), expected);
+    }
+
+    #[test_case("ldap"; "no pattern")]
+    #[test_case("=ldap"; "empty pattern")]
+    #[test_case("*@corp="; "empty backend")]
+    fn test_parse_routes_fail(input: &str) {
+        assert!(parse_routes(input).is_err());
     }
 }
@@ -0,0 +1,407 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use async_trait::async_trait;
+use serde::Deserialize;
+
+use crate::{model::auth, utils::secret_str::SecretString, AuthService};
+
+// Auth service reading users from a file with bcrypt or argon2 password hashes.
+// Supported formats:
+//   htpasswd like - `user:hash[:department[:role1,role2]]` per line, `#` starts a comment
+//   json (by `.json` extension) - `[{"user": "", "hash": "", "department": "", "roles": [""]}]`
+pub struct Auth {
+    path: PathBuf,
+    users: Arc<RwLock<Arc<Users>>>,
+}
+
+#[derive(Default)]
+struct Users {
+    users: HashMap<String, Entry>,
+    // hash verified for unknown users, so the timing does not reveal whether the user exists
+    dummy_hash: Option<String>,
+    modified: Option<SystemTime>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+struct Entry {
+    user: String,
+    hash: String,
+    #[serde(default)]
+    department: String,
+    #[serde(default = "default_roles")]
+    roles: Vec<String>,
+}
+
+impl Entry {
+    fn to_auth_user(&self) -> auth::User {
+        auth::User {
+            id: self.user.clone(),
+            name: self.user.clone(),
+            department: self.department.clone(),
+            roles: self.roles.clone(),
+            backend: String::new(),
+        }
+    }
+}
+
+fn default_roles() -> Vec<String> {
+    vec!["USER".to_string()]
+}
+
+impl Auth {
+    // Loads users from the file, if `reload_interval` is not zero the file is checked for changes
+    pub fn new(path: &str, reload_interval: Duration) -> anyhow::Result<Self> {
+        tracing::debug!(path, reload = ?reload_interval, "init user file auth");
+        if path.is_empty() {
+            return Err(anyhow::anyhow!("Empty user file path"));
+        }
+        let path = PathBuf::from(path);
+        let users = Arc::new(RwLock::new(Arc::new(load(&path)?)));
+        let res = Auth { path, users };
+        if !reload_interval.is_zero() {
+            let path = res.path.clone();
+            let users = res.users.clone();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(reload_interval);
+                interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+                loop {
+                    interval.tick().await;
+                    if let Err(err) = reload_if_changed(&path, &users) {
+                        tracing::error!(err = %err, "reload user file, keeping old users");
+                    }
+                }
+            });
+        }
+        Ok(res)
+    }
+
+    fn current(&self) -> Arc<Users> {
+        self.users.read().expect("users lock").clone()
+    }
+}
+
+fn reload_if_changed(path: &Path, users: &RwLock<Arc<Users>>) -> anyhow::Result<bool> {
+    let modified = modified(path)?;
+    if users.read().expect("users lock").modified == Some(modified) {
+        return Ok(false);
+    }
+    tracing::info!(path = %path.display(), "user file changed, reloading");
+    let new_users = load(path)?;
+    *users.write().expect("users lock") = Arc::new(new_users);
+    Ok(true)
+}
+
+fn modified(path: &Path) -> anyhow::Result<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|m| m.modified())
+        .map_err(|e| anyhow::anyhow!("can't stat {}: {e}", path.display()))
+}
+
+fn load(path: &Path) -> anyhow::Result<Users> {
+    let modified = modified(path)?;
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("can't read {}: {e}", path.display()))?;
+    let entries = if path
+        .extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("json"))
+    {
+        parse_json(&content)?
+    } else {
+        parse_htpasswd(&content)?
+    };
+    let mut users = HashMap::new();
+    for entry in entries {
+        check_hash(&entry.hash).map_err(|e| anyhow::anyhow!("user '{}': {e}", entry.user))?;
+        tracing::debug!(user = entry.user, dep = entry.department, roles = ?entry.roles, "user file");
+        if let Some(old) = users.insert(entry.user.clone(), entry) {
+            return Err(anyhow::anyhow!("duplicate user '{}'", old.user));
+        }
+    }
+    let dummy_hash = users
+        .keys()
+        .min()
+        .and_then(|k| users.get(k))
+        .map(|e| e.hash.clone());
+    tracing::info!(len = users.len(), "loaded users");
+    Ok(Users {
+        users,
+        dummy_hash,
+        modified: Some(modified),
+    })
+}
+
+fn parse_json(content: &str) -> anyhow::Result<Vec<Entry>> {
+    let entries: Vec<Entry> =
+        serde_json::from_str(content).map_err(|e| anyhow::anyhow!("invalid json: {e}"))?;
+    if let Some(e) = entries.iter().find(|e| e.user.is_empty()) {
+        return Err(anyhow::anyhow!("empty user with hash '{}'", e.hash));
+    }
+    Ok(entries)
+}
+
+fn parse_htpasswd(content: &str) -> anyhow::Result<Vec<Entry>> {
+    let mut res = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let parsed: Vec<&str> = line.split(':').collect();
+        if parsed.len() < 2 || parsed.len() > 4 || parsed[0].is_empty() || parsed[1].is_empty() {
+            return Err(anyhow::anyhow!(
+                "Invalid user:hash format in line {}",
+                i + 1
+            ));
+        }
+        let roles = match parsed.get(3) {
+            Some(roles) => roles
+                .split(',')
+                .map(|r| r.trim().to_string())
+                .filter(|r| !r.is_empty())
+                .collect(),
+            None => default_roles(),
+        };
+        res.push(Entry {
+            user: parsed[0].to_string(),
+            hash: parsed[1].to_string(),
+            department: parsed.get(2).unwrap_or(&"").to_string(),
+            roles,
+        });
+    }
+    Ok(res)
+}
+
+fn check_hash(hash: &str) -> anyhow::Result<()> {
+    if hash.starts_with("$argon2") {
+        let parsed =
+            PasswordHash::new(hash).map_err(|e| anyhow::anyhow!("invalid argon2 hash: {e}"))?;
+        if parsed.salt.is_none() || parsed.hash.is_none() {
+            return Err(anyhow::anyhow!("invalid argon2 hash: no salt or hash"));
+        }
+        return Ok(());
+    }
+    if ["$2a$", "$2b$", "$2x$", "$2y$"]
+        .iter()
+        .any(|p| hash.starts_with(p))
+        && hash.len() == 60
+    {
+        return Ok(());
+    }
+    Err(anyhow::anyhow!("unsupported hash, use bcrypt or argon2"))
+}
+
+// Both libs compare the computed hash in constant time
+fn verify(hash: &str, pass: &str) -> bool {
+    if hash.starts_with("$argon2") {
+        return PasswordHash::new(hash)
+            .and_then(|h| Argon2::default().verify_password(pass.as_bytes(), &h))
+            .is_ok();
+    }
+    bcrypt::verify(pass, hash).unwrap_or(false)
+}
+
+#[async_trait]
+impl AuthService for Auth {
+    async fn login(&self, user: &str, pass: &SecretString) -> Result<auth::User, auth::Error> {
+        let users = self.current();
+        let (hash, found) = match users.users.get(user) {
+            Some(entry) => (entry.hash.clone(), Some(entry.to_auth_user())),
+            None => match &users.dummy_hash {
+                Some(hash) => (hash.clone(), None),
+                None => return Err(auth::Error::WrongUserPass()),
+            },
+        };
+        let pass = pass.clone();
+        let ok = tokio::task::spawn_blocking(move || verify(&hash, pass.reveal_secret()))
+            .await
+            .map_err(|e| anyhow::anyhow!("verify task: {e}"))?;
+        match found {
+            Some(user) if ok => Ok(user),
+            _ => Err(auth::Error::WrongUserPass()),
+        }
+    }
+
+    async fn lookup(&self, user: &str) -> Result<auth::User, auth::Error> {
+        let users = self.current();
+        users
+            .users
+            .get(user)
+            .map(|entry| entry.to_auth_user())
+            .ok_or(auth::Error::WrongUserPass())
+    }
+
+    async fn refresh_user(&self, user: &auth::User) -> Result<Option<auth::User>, auth::Error> {
+        self.lookup(&user.id).await.map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use argon2::{
+        password_hash::{rand_core::OsRng, SaltString},
+        Algorithm, Params, PasswordHasher, Version,
+    };
+    use test_case::test_case;
+
+    use super::*;
+
+    fn bcrypt_hash(pass: &str) -> String {
+        bcrypt::hash(pass, 4).unwrap()
+    }
+
+    fn argon2_hash(pass: &str) -> String {
+        let argon2 = Argon2::new(
+            Algorithm::Argon2id,
+            Version::V0x13,
+            Params::new(1024, 1, 1, None).unwrap(),
+        );
+        argon2
+            .hash_password(pass.as_bytes(), &SaltString::generate(&mut OsRng))
+            .unwrap()
+            .to_string()
+    }
+
+    fn write_file(name: &str, content: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "authware-{}-{}-{name}",
+            std::process::id(),
+            rand::random::<u32>()
+        ));
+        let mut f = std::fs::File::create(&path).unwrap();
+        f.write_all(content.as_bytes()).unwrap();
+        path
+    }
+
+    #[test_case("", &[]; "empty")]
+    #[test_case("# comment\n\n", &[]; "comment")]
+    #[test_case("olia:$2y$h", &[("olia", "$2y$h", "", &["USER"])]; "htpasswd")]
+    #[test_case("olia:$2y$h:IT:ADMIN, USER\nvv:$argon2id$x:HR",
+        &[("olia", "$2y$h", "IT", &["ADMIN", "USER"]), ("vv", "$argon2id$x", "HR", &["USER"])]; "extended")]
+    fn test_parse_htpasswd(input: &str, expected: &[(&str, &str, &str, &[&str])]) {
+        let expected: Vec<Entry> = expected
+            .iter()
+            .map(|(user, hash, dep, roles)| Entry {
+                user: user.to_string(),
+                hash: hash.to_string(),
+                department: dep.to_string(),
+                roles: roles.iter().map(|r| r.to_string()).collect(),
+            })
+            .collect();
+        assert_eq!(parse_htpasswd(input).unwrap(), expected);
+    }
+
+    #[test_case("olia"; "no hash")]
+    #[test_case(":hash"; "no user")]
+    #[test_case("olia:"; "empty hash")]
+    #[test_case("a:b:c:d:e"; "too many")]
+    fn test_parse_htpasswd_failure(input: &str) {
+        assert!(parse_htpasswd(input).is_err());
+    }
+
+    #[test]
+    fn test_parse_json() {
+        let res = parse_json(
+            r#"[{"user": "olia", "hash": "h", "roles": ["ADMIN"]}, {"user": "vv", "hash": "h2"}]"#,
+        )
+        .unwrap();
+        assert_eq!(res[0].roles, vec!["ADMIN".to_string()]);
+        assert_eq!(res[1].roles, vec!["USER".to_string()]);
+        assert!(parse_json(r#"[{"user": "", "hash": "h"}]"#).is_err());
+    }
+
+    #[test_case("olia"; "plain")]
+    #[test_case("{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g="; "sha")]
+    #[test_case("$apr1$salt$hash"; "md5")]
+    #[test_case("$argon2id$broken"; "broken argon")]
+    fn test_check_hash_failure(input: &str) {
+        assert!(check_hash(input).is_err());
+    }
+
+    #[test_case("bcrypt"; "bcrypt")]
+    #[test_case("argon2"; "argon2")]
+    fn test_verify(alg: &str) {
+        let hash = match alg {
+            "bcrypt" => bcrypt_hash("olia"),
+            _ => argon2_hash("olia"),
+        };
+        assert!(check_hash(&hash).is_ok());
+        assert!(verify(&hash, "olia"));
+        assert!(!verify(&hash, "olia1"));
+        assert!(!verify(&hash, ""));
+    }
+
+    #[tokio::test]
+    async fn test_login() {
+        let content = format!(
+            "admin:{}:IT:ADMIN,USER\nuser:{}\n",
+            bcrypt_hash("admin-pass"),
+            argon2_hash("user-pass")
+        );
+        let path = write_file("users", &content);
+        let auth = Auth::new(path.to_str().unwrap(), Duration::ZERO).unwrap();
+
+        let res = auth.login("admin", &"admin-pass".into()).await.unwrap();
+        assert_eq!(res.department, "IT");
+        assert_eq!(res.roles, vec!["ADMIN".to_string(), "USER".to_string()]);
+        let res = auth.login("user", &"user-pass".into()).await.unwrap();
+        assert_eq!(res.roles, vec!["USER".to_string()]);
+        for (user, pass) in [
+            ("admin", "user-pass"),
+            ("nobody", "admin-pass"),
+            ("user", ""),
+        ] {
+            let res = auth.login(user, &pass.into()).await;
+            assert!(matches!(res, Err(auth::Error::WrongUserPass())));
+        }
+        assert_eq!(auth.lookup("admin").await.unwrap().department, "IT");
+        assert!(matches!(
+            auth.lookup("nobody").await,
+            Err(auth::Error::WrongUserPass())
+        ));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_login_json() {
+        let content = format!(
+            r#"[{{"user": "olia", "hash": "{}", "department": "HR"}}]"#,
+            bcrypt_hash("pass")
+        );
+        let path = write_file("users.json", &content);
+        let auth = Auth::new(path.to_str().unwrap(), Duration::ZERO).unwrap();
+        let res = auth.login("olia", &"pass".into()).await.unwrap();
+        assert_eq!(res.department, "HR");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let path = write_file("users", &format!("olia:{}\n", bcrypt_hash("old")));
+        let auth = Auth::new(path.to_str().unwrap(), Duration::ZERO).unwrap();
+        assert!(!reload_if_changed(&auth.path, &auth.users).unwrap());
+
+        std::fs::write(&path, format!("olia:{}\n", bcrypt_hash("new"))).unwrap();
+        let f = std::fs::File::options().write(true).open(&path).unwrap();
+        f.set_modified(SystemTime::now() + Duration::from_secs(10))
+            .unwrap();
+        assert!(reload_if_changed(&auth.path, &auth.users).unwrap());
+        assert!(auth.login("olia", &"new".into()).await.is_ok());
+        assert!(auth.login("olia", &"old".into()).await.is_err());
+
+        // broken file keeps the old users
+        std::fs::write(&path, "olia:plain\n").unwrap();
+        f.set_modified(SystemTime::now() + Duration::from_secs(20))
+            .unwrap();
+        assert!(reload_if_changed(&auth.path, &auth.users).is_err());
+        assert!(auth.login("olia", &"new".into()).await.is_ok());
+        std::fs::remove_file(path).unwrap();
+    }
+}
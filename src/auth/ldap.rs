@@ -0,0 +1,786 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use ldap3::{ldap_escape, LdapConnAsync, LdapConnSettings, LdapResult, Scope, SearchEntry};
+
+use crate::{model::auth, utils::secret_str::SecretString, AuthService};
+
+// ldap result code for invalidCredentials
+const RC_INVALID_CREDENTIALS: u32 = 49;
+// result codes of groups out of reach: in another domain or deleted
+const RC_REFERRAL: u32 = 10;
+const RC_NO_SUCH_OBJECT: u32 = 32;
+// AD userAccountControl flag of disabled accounts
+const UAC_ACCOUNTDISABLE: u32 = 0x2;
+// max depth of nested groups to follow
+const MAX_GROUP_DEPTH: usize = 10;
+
+pub struct Config {
+    pub url: String,
+    pub bind_dn: String,
+    pub bind_pass: SecretString,
+    pub base_dn: String,
+    // filter to find user, `{user}` is replaced with escaped user name
+    pub user_filter: String,
+    pub name_attr: String,
+    pub department_attr: String,
+    pub nested_groups: bool,
+    pub timeout: Duration,
+}
+
+// Search answered with a non success result code
+#[derive(Debug, thiserror::Error)]
+#[error("ldap search failed: rc={rc}, {text}")]
+pub struct SearchFailed {
+    pub rc: u32,
+    pub text: String,
+}
+
+// Abstraction of ldap connection, allows to test the flow without a real ldap server
+#[async_trait]
+pub trait Connection: Send {
+    async fn simple_bind(&mut self, dn: &str, pass: &str) -> anyhow::Result<LdapResult>;
+    async fn search(
+        &mut self,
+        base: &str,
+        scope: Scope,
+        filter: &str,
+        attrs: &[&str],
+    ) -> anyhow::Result<Vec<SearchEntry>>;
+    async fn unbind(&mut self) -> anyhow::Result<()>;
+}
+
+#[async_trait]
+pub trait Connector {
+    async fn connect(&self) -> anyhow::Result<Box<dyn Connection>>;
+}
+
+pub struct Auth {
+    config: Config,
+    connector: Box<dyn Connector + Send + Sync>,
+}
+
+impl Auth {
+    pub fn new(config: Config) -> anyhow::Result<Self> {
+        let connector = Box::new(LdapConnector {
+            url: config.url.clone(),
+            timeout: config.timeout,
+        });
+        Self::new_with_connector(config, connector)
+    }
+
+    pub fn new_with_connector(
+        config: Config,
+        connector: Box<dyn Connector + Send + Sync>,
+    ) -> anyhow::Result<Self> {
+        tracing::debug!(
+            url = config.url,
+            bind_dn = config.bind_dn,
+            base_dn = config.base_dn,
+            user_filter = config.user_filter,
+            nested_groups = config.nested_groups,
+            "init ldap auth"
+        );
+        if config.url.is_empty()
+            || config.bind_dn.is_empty()
+            || config.bind_pass.reveal_secret().is_empty()
+            || config.base_dn.is_empty()
+        {
+            return Err(anyhow::anyhow!("Empty ldap params"));
+        }
+        if !config.user_filter.contains("{user}") {
+            return Err(anyhow::anyhow!(
+                "ldap user filter must contain {{user}}: {}",
+                config.user_filter
+            ));
+        }
+        Ok(Auth { config, connector })
+    }
+
+    async fn bind_service(&self, conn: &mut dyn Connection) -> Result<(), auth::Error> {
+        let res = conn
+            .simple_bind(&self.config.bind_dn, self.config.bind_pass.reveal_secret())
+            .await?;
+        if res.rc != 0 {
+            return Err(auth::Error::ServiceError(anyhow::anyhow!(
+                "ldap service bind failed: rc={}, {}",
+                res.rc,
+                res.text
+            )));
+        }
+        Ok(())
+    }
+
+    async fn find_user(
+        &self,
+        conn: &mut dyn Connection,
+        user: &str,
+    ) -> Result<SearchEntry, auth::Error> {
+        let filter = self
+            .config
+            .user_filter
+            .replace("{user}", &ldap_escape(user));
+        tracing::trace!(filter, "search user");
+        let mut entries = conn
+            .search(
+                &self.config.base_dn,
+                Scope::Subtree,
+                &filter,
+                &[
+                    self.config.name_attr.as_str(),
+                    self.config.department_attr.as_str(),
+                    "memberOf",
+                    "userAccountControl",
+                ],
+            )
+            .await?;
+        match entries.len() {
+            0 => {
+                tracing::debug!(user, "ldap user not found");
+                Err(auth::Error::WrongUserPass())
+            }
+            1 => Ok(entries.remove(0)),
+            n => Err(auth::Error::ServiceError(anyhow::anyhow!(
+                "ldap filter returned {n} users"
+            ))),
+        }
+    }
+
+    async fn collect_groups(
+        &self,
+        conn: &mut dyn Connection,
+        entry: &SearchEntry,
+    ) -> Result<Vec<String>, auth::Error> {
+        let mut res: Vec<String> = Vec::new();
+        let mut seen: HashSet<String> = HashSet::new();
+        let mut queue: VecDeque<(String, usize)> = attr_values(entry, "memberOf")
+            .iter()
+            .map(|g| (g.clone(), 1))
+            .collect();
+        while let Some((group, depth)) = queue.pop_front() {
+            if !seen.insert(group.to_lowercase()) {
+                continue;
+            }
+            res.push(group.clone());
+            if !self.config.nested_groups || depth >= MAX_GROUP_DEPTH {
+                continue;
+            }
+            let parents = match conn
+                .search(&group, Scope::Base, "(objectClass=*)", &["memberOf"])
+                .await
+            {
+                Ok(parents) => parents,
+                Err(err) => match err.downcast_ref::<SearchFailed>() {
+                    // the group still counts, its parents are not followed
+                    Some(failed) if [RC_REFERRAL, RC_NO_SUCH_OBJECT].contains(&failed.rc) => {
+                        tracing::warn!(group, rc = failed.rc, "can't resolve ldap group");
+                        continue;
+                    }
+                    _ => return Err(err.into()),
+                },
+            };
+            for parent in parents.iter().flat_map(|e| attr_values(e, "memberOf")) {
+                queue.push_back((parent.clone(), depth + 1));
+            }
+        }
+        Ok(res)
+    }
+
+    async fn login_int(
+        &self,
+        conn: &mut dyn Connection,
+        user: &str,
+        pass: &SecretString,
+    ) -> Result<auth::User, auth::Error> {
+        self.bind_service(conn).await?;
+        let entry = self.find_user(conn, user).await?;
+        tracing::trace!(dn = entry.dn, "found user");
+
+        let res = conn.simple_bind(&entry.dn, pass.reveal_secret()).await?;
+        map_bind_result(&res)?;
+        tracing::trace!("user bind ok");
+
+        self.bind_service(conn).await?;
+        self.user_info(conn, user, &entry).await
+    }
+
+    async fn lookup_int(
+        &self,
+        conn: &mut dyn Connection,
+        user: &str,
+    ) -> Result<auth::User, auth::Error> {
+        self.bind_service(conn).await?;
+        let entry = self.find_user(conn, user).await?;
+        // no bind to refuse a disabled account here
+        if is_disabled(&entry) {
+            tracing::debug!(user, "ldap user disabled");
+            return Err(auth::Error::NoAccess());
+        }
+        self.user_info(conn, user, &entry).await
+    }
+
+    async fn user_info(
+        &self,
+        conn: &mut dyn Connection,
+        user: &str,
+        entry: &SearchEntry,
+    ) -> Result<auth::User, auth::Error> {
+        let groups = self.collect_groups(conn, entry).await?;
+        tracing::trace!(len = groups.len(), "got groups");
+        let roles: Vec<String> = groups.iter().filter_map(|g| group_name(g)).collect();
+        if roles.is_empty() {
+            return Err(auth::Error::NoAccess());
+        }
+        let name = attr_values(entry, &self.config.name_attr)
+            .first()
+            .cloned()
+            .unwrap_or_else(|| user.to_string());
+        let department = attr_values(entry, &self.config.department_attr)
+            .first()
+            .cloned()
+            .or_else(|| ou_name(&entry.dn))
+            .unwrap_or_default();
+        Ok(auth::User {
+            id: user.to_string(),
+            name,
+            department,
+            roles,
+            backend: String::new(),
+        })
+    }
+}
+
+#[async_trait]
+impl AuthService for Auth {
+    async fn login(&self, user: &str, pass: &SecretString) -> Result<auth::User, auth::Error> {
+        // ldap treats a bind with an empty password as anonymous and succeeds
+        if user.is_empty() || pass.reveal_secret().is_empty() {
+            return Err(auth::Error::WrongUserPass());
+        }
+        let mut conn = self.connector.connect().await?;
+        let res = self.login_int(conn.as_mut(), user, pass).await;
+        if let Err(err) = conn.unbind().await {
+            tracing::warn!(err = %err, "ldap unbind");
+        }
+        res
+    }
+
+    async fn lookup(&self, user: &str) -> Result<auth::User, auth::Error> {
+        if user.is_empty() {
+            return Err(auth::Error::WrongUserPass());
+        }
+        let mut conn = self.connector.connect().await?;
+        let res = self.lookup_int(conn.as_mut(), user).await;
+        if let Err(err) = conn.unbind().await {
+            tracing::warn!(err = %err, "ldap unbind");
+        }
+        res
+    }
+
+    async fn refresh_user(&self, user: &auth::User) -> Result<Option<auth::User>, auth::Error> {
+        self.lookup(&user.id).await.map(Some)
+    }
+}
+
+fn map_bind_result(res: &LdapResult) -> Result<(), auth::Error> {
+    match res.rc {
+        0 => Ok(()),
+        RC_INVALID_CREDENTIALS => Err(map_ad_data_code(&res.text)),
+        rc => Err(auth::Error::ServiceError(anyhow::anyhow!(
+            "ldap user bind failed: rc={rc}, {}",
+            res.text
+        ))),
+    }
+}
+
+// AD puts the reason into the diagnostic message, e.g. `... data 532, v4563`
+fn map_ad_data_code(text: &str) -> auth::Error {
+    let code = text
+        .split_once("data ")
+        .and_then(|(_, rest)| rest.split(',').next())
+        .map(|s| s.trim())
+        .unwrap_or("");
+    match code {
+        "532" | "773" => auth::Error::ExpiredPass(),
+        "530" | "531" | "533" | "701" | "775" => auth::Error::NoAccess(),
+        _ => auth::Error::WrongUserPass(),
+    }
+}
+
+fn attr_values<'a>(entry: &'a SearchEntry, name: &str) -> &'a [String] {
+    entry
+        .attrs
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(name))
+        .map_or(&[], |(_, v)| v.as_slice())
+}
+
+fn is_disabled(entry: &SearchEntry) -> bool {
+    attr_values(entry, "userAccountControl")
+        .first()
+        .and_then(|v| v.trim().parse::<u32>().ok())
+        .is_some_and(|flags| flags & UAC_ACCOUNTDISABLE != 0)
+}
+
+// Splits the DN into attribute/value pairs, one per value of multi-valued RDNs.
+// RFC 4514 escapes are decoded: `\,`, `\+` and other specials, `\XX` hex bytes
+fn dn_attrs(dn: &str) -> Vec<(String, String)> {
+    let bytes = dn.as_bytes();
+    let mut res = Vec::new();
+    let mut key: Option<String> = None;
+    let mut value: Vec<u8> = Vec::new();
+    // escaped bytes are not trimmed, the value is kept from the first to the last one
+    let mut kept = (usize::MAX, 0);
+    let mut i = 0;
+    while i <= bytes.len() {
+        match bytes.get(i) {
+            Some(b'\\') if i + 1 < bytes.len() => {
+                let hex = bytes
+                    .get(i + 1..i + 3)
+                    .filter(|h| h.iter().all(u8::is_ascii_hexdigit))
+                    .and_then(|h| u8::from_str_radix(std::str::from_utf8(h).ok()?, 16).ok());
+                match hex {
+                    Some(b) => {
+                        value.push(b);
+                        i += 3;
+                    }
+                    None => {
+                        value.push(bytes[i + 1]);
+                        i += 2;
+                    }
+                }
+                kept = (kept.0.min(value.len() - 1), value.len());
+                continue;
+            }
+            Some(b'=') if key.is_none() => {
+                key = Some(String::from_utf8_lossy(&value).trim().to_string());
+                value.clear();
+                kept = (usize::MAX, 0);
+            }
+            Some(b',' | b'+' | b';') | None => {
+                if let Some(key) = key.take() {
+                    let start = value.iter().take_while(|b| **b == b' ').count();
+                    let end = value.len()
+                        - value[kept.1..]
+                            .iter()
+                            .rev()
+                            .take_while(|b| **b == b' ')
+                            .count();
+                    let value = &value[start.min(kept.0).min(end)..end];
+                    res.push((key, String::from_utf8_lossy(value).to_string()));
+                }
+                value.clear();
+                kept = (usize::MAX, 0);
+            }
+            Some(b) => value.push(*b),
+        }
+        i += 1;
+    }
+    res
+}
+
+fn rdn_value(dn: &str, attr: &str) -> Option<String> {
+    dn_attrs(dn)
+        .into_iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(attr))
+        .map(|(_, v)| v)
+        .filter(|v| !v.is_empty())
+}
+
+// group role name is the CN of the group DN
+fn group_name(dn: &str) -> Option<String> {
+    rdn_value(dn, "cn")
+}
+
+fn ou_name(dn: &str) -> Option<String> {
+    rdn_value(dn, "ou")
+}
+
+struct LdapConnector {
+    url: String,
+    timeout: Duration,
+}
+
+struct LdapConnection {
+    ldap: ldap3::Ldap,
+    timeout: Duration,
+}
+
+#[async_trait]
+impl Connector for LdapConnector {
+    async fn connect(&self) -> anyhow::Result<Box<dyn Connection>> {
+        let settings = LdapConnSettings::new().set_conn_timeout(self.timeout);
+        let (conn, ldap) = LdapConnAsync::with_settings(settings, &self.url)
+            .await
+            .map_err(|e| anyhow::anyhow!("ldap connect error: {}", e))?;
+        ldap3::drive!(conn);
+        Ok(Box::new(LdapConnection {
+            ldap,
+            timeout: self.timeout,
+        }))
+    }
+}
+
+#[async_trait]
+impl Connection for LdapConnection {
+    async fn simple_bind(&mut self, dn: &str, pass: &str) -> anyhow::Result<LdapResult> {
+        self.ldap
+            .with_timeout(self.timeout)
+            .simple_bind(dn, pass)
+            .await
+            .map_err(|e| anyhow::anyhow!("ldap bind error: {}", e))
+    }
+
+    async fn search(
+        &mut self,
+        base: &str,
+        scope: Scope,
+        filter: &str,
+        attrs: &[&str],
+    ) -> anyhow::Result<Vec<SearchEntry>> {
+        let res = self
+            .ldap
+            .with_timeout(self.timeout)
+            .search(base, scope, filter, attrs.to_vec())
+            .await
+            .map_err(|e| anyhow::anyhow!("ldap search error: {}", e))?;
+        let (entries, result) = (res.0, res.1);
+        if result.rc != 0 {
+            return Err(SearchFailed {
+                rc: result.rc,
+                text: result.text,
+            }
+            .into());
+        }
+        Ok(entries
+            .into_iter()
+            .filter(|e| !e.is_ref() && !e.is_intermediate())
+            .map(SearchEntry::construct)
+            .collect())
+    }
+
+    async fn unbind(&mut self) -> anyhow::Result<()> {
+        self.ldap
+            .unbind()
+            .await
+            .map_err(|e| anyhow::anyhow!("ldap unbind error: {}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use super::*;
+    use test_case::test_case;
+
+    const BASE: &str = "DC=corp,DC=local";
+
+    // in-process ldap stand-in: entries by DN and passwords by DN
+    #[derive(Default)]
+    struct Directory {
+        entries: HashMap<String, HashMap<String, Vec<String>>>,
+        passwords: HashMap<String, (String, String)>,
+    }
+
+    impl Directory {
+        fn sample() -> Self {
+            let mut dir = Directory::default();
+            dir.add("CN=svc,DC=corp,DC=local", &[], Some(("svc-pass", "")));
+            dir.add(
+                "CN=Olia,OU=IT,DC=corp,DC=local",
+                &[
+                    ("sAMAccountName", "olia"),
+                    ("displayName", "Olia Olialia"),
+                    ("memberOf", "CN=Devs,OU=Groups,DC=corp,DC=local"),
+                ],
+                Some(("olia-pass", "")),
+            );
+            dir.add(
+                "CN=Old,OU=HR,DC=corp,DC=local",
+                &[
+                    ("sAMAccountName", "old"),
+                    ("memberOf", "CN=Users,OU=Groups,DC=corp,DC=local"),
+                ],
+                Some((
+                    "old-pass",
+                    "80090308: LdapErr: DSID-0C09044E, comment: AcceptSecurityContext error, data 532, v4563",
+                )),
+            );
+            dir.add(
+                "CN=Hr,OU=HR,DC=corp,DC=local",
+                &[
+                    ("sAMAccountName", "hr"),
+                    ("department", "Human resources"),
+                    ("memberOf", "CN=Users,OU=Groups,DC=corp,DC=local"),
+                ],
+                Some(("hr-pass", "")),
+            );
+            dir.add(
+                "CN=Lonely,OU=IT,DC=corp,DC=local",
+                &[("sAMAccountName", "lonely")],
+                Some(("lonely-pass", "")),
+            );
+            dir.add(
+                "CN=Far,OU=IT,DC=corp,DC=local",
+                &[
+                    ("sAMAccountName", "far"),
+                    ("memberOf", "CN=Partners,OU=Groups,DC=other,DC=local"),
+                    ("memberOf", "CN=Deleted,OU=Groups,DC=corp,DC=local"),
+                    ("memberOf", "CN=Devs,OU=Groups,DC=corp,DC=local"),
+                ],
+                Some(("far-pass", "")),
+            );
+            dir.add(
+                "CN=Gone,OU=IT,DC=corp,DC=local",
+                &[
+                    ("sAMAccountName", "gone"),
+                    ("userAccountControl", "514"),
+                    ("memberOf", "CN=Devs,OU=Groups,DC=corp,DC=local"),
+                ],
+                Some(("gone-pass", "")),
+            );
+            dir.add(
+                "CN=Devs,OU=Groups,DC=corp,DC=local",
+                &[("memberOf", "CN=Users,OU=Groups,DC=corp,DC=local")],
+                None,
+            );
+            dir.add(
+                "CN=Users,OU=Groups,DC=corp,DC=local",
+                &[("memberOf", "CN=Devs,OU=Groups,DC=corp,DC=local")],
+                None,
+            );
+            dir
+        }
+
+        fn add(&mut self, dn: &str, attrs: &[(&str, &str)], pass: Option<(&str, &str)>) {
+            let mut map: HashMap<String, Vec<String>> = HashMap::new();
+            for (k, v) in attrs {
+                map.entry(k.to_string()).or_default().push(v.to_string());
+            }
+            self.entries.insert(dn.to_string(), map);
+            if let Some((pass, err)) = pass {
+                self.passwords
+                    .insert(dn.to_string(), (pass.to_string(), err.to_string()));
+            }
+        }
+    }
+
+    struct Conn {
+        dir: Arc<Directory>,
+    }
+
+    #[async_trait]
+    impl Connection for Conn {
+        async fn simple_bind(&mut self, dn: &str, pass: &str) -> anyhow::Result<LdapResult> {
+            let (rc, text) = match self.dir.passwords.get(dn) {
+                Some((p, err)) if p == pass && err.is_empty() => (0, "".to_string()),
+                Some((p, err)) if p == pass => (RC_INVALID_CREDENTIALS, err.clone()),
+                _ => (
+                    RC_INVALID_CREDENTIALS,
+                    "AcceptSecurityContext error, data 52e, v4563".to_string(),
+                ),
+            };
+            Ok(LdapResult {
+                rc,
+                matched: "".to_string(),
+                text,
+                refs: vec![],
+                ctrls: vec![],
+            })
+        }
+
+        async fn search(
+            &mut self,
+            base: &str,
+            scope: Scope,
+            filter: &str,
+            _attrs: &[&str],
+        ) -> anyhow::Result<Vec<SearchEntry>> {
+            let entry = |dn: &str, attrs: &HashMap<String, Vec<String>>| SearchEntry {
+                dn: dn.to_string(),
+                attrs: attrs.clone(),
+                bin_attrs: HashMap::new(),
+            };
+            if let Scope::Base = scope {
+                let rc = if base.ends_with(BASE) {
+                    RC_NO_SUCH_OBJECT
+                } else {
+                    RC_REFERRAL
+                };
+                return match self.dir.entries.get(base) {
+                    Some(attrs) => Ok(vec![entry(base, attrs)]),
+                    None => Err(SearchFailed {
+                        rc,
+                        text: String::new(),
+                    }
+                    .into()),
+                };
+            }
+            let user = filter
+                .strip_prefix("(sAMAccountName=")
+                .and_then(|s| s.strip_suffix(')'))
+                .ok_or_else(|| anyhow::anyhow!("unsupported filter {filter}"))?;
+            Ok(self
+                .dir
+                .entries
+                .iter()
+                .filter(|(_, attrs)| {
+                    attrs
+                        .get("sAMAccountName")
+                        .is_some_and(|v| v.iter().any(|v| v == user))
+                })
+                .map(|(dn, attrs)| entry(dn, attrs))
+                .collect())
+        }
+
+        async fn unbind(&mut self) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct FakeConnector {
+        dir: Arc<Directory>,
+    }
+
+    #[async_trait]
+    impl Connector for FakeConnector {
+        async fn connect(&self) -> anyhow::Result<Box<dyn Connection>> {
+            Ok(Box::new(Conn {
+                dir: self.dir.clone(),
+            }))
+        }
+    }
+
+    fn make_auth(nested_groups: bool) -> Auth {
+        Auth::new_with_connector(
+            Config {
+                url: "ldap://localhost:389".to_string(),
+                bind_dn: "CN=svc,DC=corp,DC=local".to_string(),
+                bind_pass: "svc-pass".into(),
+                base_dn: BASE.to_string(),
+                user_filter: "(sAMAccountName={user})".to_string(),
+                name_attr: "displayName".to_string(),
+                department_attr: "department".to_string(),
+                nested_groups,
+                timeout: Duration::from_secs(1),
+            },
+            Box::new(FakeConnector {
+                dir: Arc::new(Directory::sample()),
+            }),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_login() {
+        let auth = make_auth(true);
+        let res = auth.login("olia", &"olia-pass".into()).await.unwrap();
+        assert_eq!(
+            res,
+            auth::User {
+                id: "olia".to_string(),
+                name: "Olia Olialia".to_string(),
+                department: "IT".to_string(),
+                roles: vec!["Devs".to_string(), "Users".to_string()],
+                backend: String::new(),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_lookup() {
+        let auth = make_auth(true);
+        let res = auth.lookup("olia").await.unwrap();
+        assert_eq!(res.name, "Olia Olialia");
+        assert_eq!(res.roles, vec!["Devs".to_string(), "Users".to_string()]);
+        assert!(matches!(
+            auth.lookup("nobody").await,
+            Err(auth::Error::WrongUserPass())
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_lookup_disabled() {
+        let auth = make_auth(true);
+        assert!(matches!(
+            auth.lookup("gone").await,
+            Err(auth::Error::NoAccess())
+        ));
+        let user = auth::User {
+            id: "gone".to_string(),
+            name: "gone".to_string(),
+            department: "IT".to_string(),
+            roles: vec!["Devs".to_string()],
+            backend: String::new(),
+        };
+        assert!(matches!(
+            auth.refresh_user(&user).await,
+            Err(auth::Error::NoAccess())
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_login_unresolved_groups() {
+        let auth = make_auth(true);
+        let res = auth.login("far", &"far-pass".into()).await.unwrap();
+        assert_eq!(res.roles, vec!["Partners", "Deleted", "Devs", "Users"]);
+    }
+
+    #[tokio::test]
+    async fn test_login_no_nested() {
+        let auth = make_auth(false);
+        let res = auth.login("olia", &"olia-pass".into()).await.unwrap();
+        assert_eq!(res.roles, vec!["Devs".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_login_department_attr() {
+        let auth = make_auth(true);
+        let res = auth.login("hr", &"hr-pass".into()).await.unwrap();
+        assert_eq!(res.name, "hr");
+        assert_eq!(res.department, "Human resources");
+        assert_eq!(res.roles, vec!["Users".to_string(), "Devs".to_string()]);
+    }
+
+    #[test_case("olia", "wrong", auth::Error::WrongUserPass(); "wrong pass")]
+    #[test_case("olia", "", auth::Error::WrongUserPass(); "empty pass")]
+    #[test_case("nobody", "olia-pass", auth::Error::WrongUserPass(); "no user")]
+    #[test_case("olia)(sAMAccountName=*", "olia-pass", auth::Error::WrongUserPass(); "injection")]
+    #[test_case("lonely", "lonely-pass", auth::Error::NoAccess(); "no groups")]
+    #[test_case("old", "old-pass", auth::Error::ExpiredPass(); "expired")]
+    #[tokio::test]
+    async fn test_login_fail(user: &str, pass: &str, wanted: auth::Error) {
+        let auth = make_auth(true);
+        let res = auth.login(user, &pass.into()).await;
+        assert_eq!(res.err().unwrap().to_string(), wanted.to_string());
+    }
+
+    #[test_case("", auth::Error::WrongUserPass(); "empty")]
+    #[test_case("AcceptSecurityContext error, data 52e, v4563", auth::Error::WrongUserPass(); "wrong pass")]
+    #[test_case("AcceptSecurityContext error, data 532, v4563", auth::Error::ExpiredPass(); "expired")]
+    #[test_case("AcceptSecurityContext error, data 773, v4563", auth::Error::ExpiredPass(); "must reset")]
+    #[test_case("AcceptSecurityContext error, data 533, v4563", auth::Error::NoAccess(); "disabled")]
+    #[test_case("AcceptSecurityContext error, data 775, v4563", auth::Error::NoAccess(); "locked")]
+    fn test_map_ad_data_code(input: &str, wanted: auth::Error) {
+        assert_eq!(map_ad_data_code(input).to_string(), wanted.to_string());
+    }
+
+    #[test_case("CN=Devs,OU=Groups,DC=corp,DC=local", Some("Devs"); "cn")]
+    #[test_case("cn = Devs , ou=Groups", Some("Devs"); "spaces")]
+    #[test_case("OU=Groups,DC=corp", None; "no cn")]
+    #[test_case("", None; "empty")]
+    #[test_case(r"CN=Smith\, John,OU=Groups", Some("Smith, John"); "escaped comma")]
+    #[test_case(r"CN=a\2Cb,OU=Groups", Some("a,b"); "hex comma")]
+    #[test_case(r"CN=R\C3\A9mi,OU=Groups", Some("Rémi"); "hex utf8")]
+    #[test_case(r"CN=x\+y+UID=1,OU=Groups", Some("x+y"); "multi-valued")]
+    #[test_case(r"CN=R\&D\\Ops,OU=Groups", Some("R&D\\Ops"); "other escapes")]
+    #[test_case(r"CN=\ pad\ ,OU=Groups", Some(" pad "); "escaped spaces")]
+    #[test_case(r"OU=a\,CN=b,DC=corp", None; "escaped separator")]
+    fn test_group_name(input: &str, wanted: Option<&str>) {
+        assert_eq!(group_name(input).as_deref(), wanted);
+    }
+}
@@ -0,0 +1,282 @@
+use std::time::Duration;
+
+use argon2::{
+    password_hash::{rand_core::OsRng, SaltString},
+    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
+};
+use async_trait::async_trait;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use crate::{model::auth, utils::secret_str::SecretString, AuthService, Encryptor, KeyValueStore};
+
+const PREFIX: &str = "login-cache:";
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    hash: String,
+    user: auth::User,
+    time: i64, // Unix timestamp
+}
+
+// Remembers successful logins of the wrapped backend and lets users in by them while the
+// backend is unavailable. Any other answer from the backend wins and drops the entry
+pub struct LoginCache {
+    name: String,
+    inner: Box<dyn AuthService + Send + Sync>,
+    store: Box<dyn KeyValueStore + Send + Sync>,
+    encryptor: Box<dyn Encryptor + Send + Sync>,
+    staleness: Duration,
+    argon2: Argon2<'static>,
+}
+
+impl LoginCache {
+    pub fn new(
+        name: &str,
+        inner: Box<dyn AuthService + Send + Sync>,
+        store: Box<dyn KeyValueStore + Send + Sync>,
+        encryptor: Box<dyn Encryptor + Send + Sync>,
+        staleness: Duration,
+    ) -> anyhow::Result<Self> {
+        Self::new_with_hasher(name, inner, store, encryptor, staleness, Argon2::default())
+    }
+
+    fn new_with_hasher(
+        name: &str,
+        inner: Box<dyn AuthService + Send + Sync>,
+        store: Box<dyn KeyValueStore + Send + Sync>,
+        encryptor: Box<dyn Encryptor + Send + Sync>,
+        staleness: Duration,
+        argon2: Argon2<'static>,
+    ) -> anyhow::Result<Self> {
+        tracing::debug!(name, staleness = ?staleness, "init login cache");
+        if staleness.is_zero() {
+            return Err(anyhow::anyhow!("Empty login cache staleness"));
+        }
+        Ok(LoginCache {
+            name: name.to_string(),
+            inner,
+            store,
+            encryptor,
+            staleness,
+            argon2,
+        })
+    }
+
+    fn key(&self, user: &str) -> String {
+        format!("{PREFIX}{}:{}", self.name, self.encryptor.encrypt(user))
+    }
+
+    async fn remember(
+        &self,
+        user: &str,
+        pass: &SecretString,
+        res: &auth::User,
+    ) -> anyhow::Result<()> {
+        let argon2 = self.argon2.clone();
+        let pass = pass.clone();
+        let hash = tokio::task::spawn_blocking(move || {
+            let salt = SaltString::generate(&mut OsRng);
+            argon2
+                .hash_password(pass.reveal_secret().as_bytes(), &salt)
+                .map(|h| h.to_string())
+                .map_err(|e| anyhow::anyhow!("can't hash password: {e}"))
+        })
+        .await??;
+        let entry = Entry {
+            hash,
+            user: res.clone(),
+            time: Utc::now().timestamp_millis(),
+        };
+        let value = serde_json::to_string(&entry)?;
+        self.store
+            .set(
+                &self.key(user),
+                &self.encryptor.encrypt(&value),
+                Some(self.staleness),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn forget(&self, user: &str) {
+        if let Err(err) = self.store.remove(&self.key(user)).await {
+            tracing::warn!(name = self.name, error = %err, "can't drop login cache entry");
+        }
+    }
+
+    async fn recall(&self, user: &str, pass: &SecretString) -> anyhow::Result<Option<auth::User>> {
+        let value = match self.store.get(&self.key(user)).await? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        let entry: Entry = serde_json::from_str(&self.encryptor.decrypt(&value)?)?;
+        if entry.time + (self.staleness.as_millis() as i64) < Utc::now().timestamp_millis() {
+            return Ok(None);
+        }
+        let argon2 = self.argon2.clone();
+        let pass = pass.clone();
+        let hash = entry.hash;
+        let ok = tokio::task::spawn_blocking(move || {
+            PasswordHash::new(&hash)
+                .and_then(|h| argon2.verify_password(pass.reveal_secret().as_bytes(), &h))
+                .is_ok()
+        })
+        .await?;
+        Ok(ok.then_some(entry.user))
+    }
+}
+
+#[async_trait]
+impl AuthService for LoginCache {
+    async fn login(&self, user: &str, pass: &SecretString) -> Result<auth::User, auth::Error> {
+        match self.inner.login(user, pass).await {
+            Ok(res) => {
+                if let Err(err) = self.remember(user, pass, &res).await {
+                    tracing::warn!(name = self.name, error = %err, "can't save login cache entry");
+                }
+                Ok(res)
+            }
+            Err(auth::Error::ServiceError(err)) => match self.recall(user, pass).await {
+                Ok(Some(res)) => {
+                    tracing::warn!(name = self.name, user, error = %err, "backend down, login by cache");
+                    Ok(res)
+                }
+                Ok(None) => Err(auth::Error::ServiceError(err)),
+                Err(cache_err) => {
+                    tracing::warn!(name = self.name, error = %cache_err, "login cache failed");
+                    Err(auth::Error::ServiceError(err))
+                }
+            },
+            Err(err) => {
+                // the backend knows better, e.g. the password was changed
+                self.forget(user).await;
+                Err(err)
+            }
+        }
+    }
+
+    async fn lookup(&self, user: &str) -> Result<auth::User, auth::Error> {
+        self.inner.lookup(user).await
+    }
+
+    async fn refresh_user(&self, user: &auth::User) -> Result<Option<auth::User>, auth::Error> {
+        let res = self.inner.refresh_user(user).await;
+        if matches!(&res, Err(err) if !matches!(err, auth::Error::ServiceError(_))) {
+            self.forget(&user.id).await;
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use argon2::{Algorithm, Params, Version};
+    use test_case::test_case;
+
+    use super::*;
+    use crate::store::{encryptor::MagicEncryptor, memory::InMemoryKeyValueStore};
+
+    #[derive(Clone, Copy, PartialEq, Debug)]
+    enum Res {
+        Ok,
+        Wrong,
+        Expired,
+        Down,
+    }
+
+    struct Mock {
+        res: Arc<Mutex<Res>>,
+    }
+
+    #[async_trait]
+    impl AuthService for Mock {
+        async fn login(&self, user: &str, pass: &SecretString) -> Result<auth::User, auth::Error> {
+            match *self.res.lock().unwrap() {
+                Res::Ok if pass.reveal_secret() == "pass" => Ok(auth::User {
+                    id: user.to_string(),
+                    name: user.to_string(),
+                    department: String::new(),
+                    roles: vec!["USER".to_string()],
+                    backend: "ws".to_string(),
+                }),
+                Res::Ok | Res::Wrong => Err(auth::Error::WrongUserPass()),
+                Res::Expired => Err(auth::Error::ExpiredPass()),
+                Res::Down => Err(auth::Error::ServiceError(anyhow::anyhow!("down"))),
+            }
+        }
+    }
+
+    fn make_cache(staleness: Duration) -> (LoginCache, Arc<Mutex<Res>>) {
+        let res = Arc::new(Mutex::new(Res::Ok));
+        let argon2 = Argon2::new(
+            Algorithm::Argon2id,
+            Version::V0x13,
+            Params::new(1024, 1, 1, None).unwrap(),
+        );
+        let cache = LoginCache::new_with_hasher(
+            "ws",
+            Box::new(Mock { res: res.clone() }),
+            Box::new(InMemoryKeyValueStore::new()),
+            Box::new(MagicEncryptor::new("0123456789abcdef").unwrap()),
+            staleness,
+            argon2,
+        )
+        .unwrap();
+        (cache, res)
+    }
+
+    fn result(res: Result<auth::User, auth::Error>) -> String {
+        match res {
+            Ok(user) => format!("ok {}", user.id),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_outage_uses_cache() {
+        let (cache, res) = make_cache(Duration::from_secs(60));
+        *res.lock().unwrap() = Res::Down;
+        assert_eq!(result(cache.login("olia", &"pass".into()).await), "down");
+        *res.lock().unwrap() = Res::Ok;
+        assert_eq!(result(cache.login("olia", &"pass".into()).await), "ok olia");
+        *res.lock().unwrap() = Res::Down;
+        assert_eq!(result(cache.login("olia", &"pass".into()).await), "ok olia");
+        assert_eq!(result(cache.login("olia", &"other".into()).await), "down");
+        assert_eq!(result(cache.login("other", &"pass".into()).await), "down");
+    }
+
+    #[test_case(Res::Wrong, "Wrong password"; "wrong")]
+    #[test_case(Res::Expired, "Expired password"; "expired")]
+    #[tokio::test]
+    async fn test_backend_wins(backend: Res, expected: &str) {
+        let (cache, res) = make_cache(Duration::from_secs(60));
+        assert_eq!(result(cache.login("olia", &"pass".into()).await), "ok olia");
+        *res.lock().unwrap() = backend;
+        assert_eq!(result(cache.login("olia", &"pass".into()).await), expected);
+        *res.lock().unwrap() = Res::Down;
+        assert_eq!(result(cache.login("olia", &"pass".into()).await), "down");
+    }
+
+    #[tokio::test]
+    async fn test_stale() {
+        let (cache, res) = make_cache(Duration::from_millis(50));
+        assert_eq!(result(cache.login("olia", &"pass".into()).await), "ok olia");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        *res.lock().unwrap() = Res::Down;
+        assert_eq!(result(cache.login("olia", &"pass".into()).await), "down");
+    }
+
+    #[tokio::test]
+    async fn test_stored_encrypted() {
+        let store = InMemoryKeyValueStore::new();
+        let (mut cache, _) = make_cache(Duration::from_secs(60));
+        cache.store = Box::new(store.clone());
+        cache.login("olia", &"pass".into()).await.unwrap();
+        let value = store.get(&cache.key("olia")).await.unwrap().unwrap();
+        assert!(!value.contains("olia"));
+        assert!(!value.contains("argon2"));
+    }
+}
@@ -1,3 +1,15 @@
 pub mod admin3ws;
+pub mod api_key;
+pub mod client_cert;
 pub mod combined;
+pub mod file;
+pub mod ldap;
+pub mod login_cache;
+pub mod oidc;
+pub mod resilience;
+pub mod rest;
+pub mod roles;
 pub mod sample;
+pub mod sqlite;
+pub mod totp;
+pub mod webauthn;
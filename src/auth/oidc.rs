@@ -0,0 +1,671 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use rand::{rngs::OsRng, RngCore};
+use reqwest::Client as HttpClient;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tokio::sync::RwLock;
+
+use crate::{model::auth, utils::secret_str::SecretString, Encryptor};
+
+pub const COOKIE_NAME: &str = "authware_oidc";
+// backend name of the idp users
+pub const BACKEND: &str = "oidc";
+// how long the login at the IdP may take
+const PENDING_TTL_MS: i64 = 10 * 60 * 1000;
+const WELL_KNOWN: &str = "/.well-known/openid-configuration";
+
+pub struct Config {
+    // issuer url or the full `.well-known/openid-configuration` url
+    pub discovery_url: String,
+    pub client_id: String,
+    pub client_secret: SecretString,
+    pub redirect_url: String,
+    pub scopes: Vec<String>,
+    pub user_claim: String,
+    pub name_claim: String,
+    pub department_claim: String,
+    // dot separated path to roles, e.g. `realm_access.roles` for Keycloak
+    pub roles_claim: String,
+    // idp role -> authware role, if empty roles are passed as is
+    pub role_map: HashMap<String, String>,
+    // where to redirect the browser after login, session id is passed in the url fragment
+    pub post_login_url: String,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+struct Metadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+struct Provider {
+    metadata: Metadata,
+    jwks: JwkSet,
+}
+
+// login state kept in an encrypted cookie between start and callback
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+struct Pending {
+    state: String,
+    nonce: String,
+    verifier: String,
+    created: i64,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+pub struct Start {
+    pub url: String,
+    pub cookie: String,
+}
+
+pub struct Client {
+    config: Config,
+    encryptor: Box<dyn Encryptor + Send + Sync>,
+    http: HttpClient,
+    provider: RwLock<Option<Arc<Provider>>>,
+}
+
+impl Client {
+    pub fn new(
+        config: Config,
+        encryptor: Box<dyn Encryptor + Send + Sync>,
+    ) -> anyhow::Result<Self> {
+        tracing::debug!(
+            discovery_url = config.discovery_url,
+            client_id = config.client_id,
+            redirect_url = config.redirect_url,
+            scopes = ?config.scopes,
+            roles_claim = config.roles_claim,
+            "init oidc"
+        );
+        if config.discovery_url.is_empty()
+            || config.client_id.is_empty()
+            || config.redirect_url.is_empty()
+        {
+            return Err(anyhow::anyhow!("Empty oidc params"));
+        }
+        if !config.scopes.iter().any(|s| s == "openid") {
+            return Err(anyhow::anyhow!("oidc scopes must contain openid"));
+        }
+        Ok(Client {
+            config,
+            encryptor,
+            http: HttpClient::builder()
+                .timeout(Duration::from_secs(5))
+                .build()?,
+            provider: RwLock::new(None),
+        })
+    }
+
+    pub fn post_login_url(&self) -> &str {
+        &self.config.post_login_url
+    }
+
+    // Prepares the authorization url and the cookie value to keep the pending login
+    pub async fn start(&self, now: i64) -> Result<Start, auth::Error> {
+        let provider = self.provider(false).await?;
+        let pending = Pending {
+            state: random_str(),
+            nonce: random_str(),
+            verifier: random_str(),
+            created: now,
+        };
+        let mut url = url::Url::parse(&provider.metadata.authorization_endpoint)
+            .map_err(|e| anyhow::anyhow!("invalid authorization endpoint: {e}"))?;
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.config.client_id)
+            .append_pair("redirect_uri", &self.config.redirect_url)
+            .append_pair("scope", &self.config.scopes.join(" "))
+            .append_pair("state", &pending.state)
+            .append_pair("nonce", &pending.nonce)
+            .append_pair("code_challenge", &pkce_challenge(&pending.verifier))
+            .append_pair("code_challenge_method", "S256");
+        let cookie = serde_json::to_string(&pending)
+            .map_err(|e| anyhow::anyhow!("serialize pending login: {e}"))?;
+        Ok(Start {
+            url: url.to_string(),
+            cookie: self.encryptor.encrypt(&cookie),
+        })
+    }
+
+    // Exchanges the code for tokens and maps the validated id token to a user
+    pub async fn finish(
+        &self,
+        cookie: &str,
+        state: &str,
+        code: &str,
+        now: i64,
+    ) -> Result<auth::User, auth::Error> {
+        let pending = self.decode_pending(cookie)?;
+        if pending.state != state {
+            return Err(auth::Error::OtherAuth("oidc state mismatch".to_string()));
+        }
+        if pending.created + PENDING_TTL_MS < now {
+            return Err(auth::Error::OtherAuth("oidc login expired".to_string()));
+        }
+        let provider = self.provider(false).await?;
+        let id_token = self
+            .exchange_code(&provider.metadata, code, &pending.verifier)
+            .await?;
+        let claims = self.validate_id_token(&id_token, &pending.nonce).await?;
+        self.map_claims(&claims)
+    }
+
+    fn decode_pending(&self, cookie: &str) -> Result<Pending, auth::Error> {
+        let value = self
+            .encryptor
+            .decrypt(cookie)
+            .map_err(|e| auth::Error::OtherAuth(format!("oidc invalid cookie: {e}")))?;
+        serde_json::from_str(&value)
+            .map_err(|e| auth::Error::OtherAuth(format!("oidc invalid cookie: {e}")))
+    }
+
+    async fn provider(&self, refresh: bool) -> Result<Arc<Provider>, auth::Error> {
+        if !refresh {
+            if let Some(provider) = self.provider.read().await.as_ref() {
+                return Ok(provider.clone());
+            }
+        }
+        let mut guard = self.provider.write().await;
+        let url = if self.config.discovery_url.ends_with(WELL_KNOWN) {
+            self.config.discovery_url.clone()
+        } else {
+            format!(
+                "{}{WELL_KNOWN}",
+                self.config.discovery_url.trim_end_matches('/')
+            )
+        };
+        tracing::debug!(url, "load oidc metadata");
+        let metadata: Metadata = self.get_json(&url).await?;
+        let jwks: JwkSet = self.get_json(&metadata.jwks_uri).await?;
+        tracing::debug!(
+            issuer = metadata.issuer,
+            keys = jwks.keys.len(),
+            "loaded oidc metadata"
+        );
+        let provider = Arc::new(Provider { metadata, jwks });
+        *guard = Some(provider.clone());
+        Ok(provider)
+    }
+
+    async fn get_json<T: DeserializeOwned>(&self, url: &str) -> anyhow::Result<T> {
+        let response = self
+            .http
+            .get(url)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| anyhow::anyhow!("oidc call {url}: {e}"))?;
+        let body = response
+            .text()
+            .await
+            .map_err(|e| anyhow::anyhow!("can't get body: {e}"))?;
+        serde_json::from_str(&body).map_err(|e| anyhow::anyhow!("can't deserialize {url}: {e}"))
+    }
+
+    async fn exchange_code(
+        &self,
+        metadata: &Metadata,
+        code: &str,
+        verifier: &str,
+    ) -> Result<String, auth::Error> {
+        let mut request = self.http.post(&metadata.token_endpoint).form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &self.config.redirect_url),
+            ("client_id", &self.config.client_id),
+            ("code_verifier", verifier),
+        ]);
+        if !self.config.client_secret.reveal_secret().is_empty() {
+            request = request.basic_auth(
+                &self.config.client_id,
+                Some(self.config.client_secret.reveal_secret()),
+            );
+        }
+        let response = request
+            .send()
+            .await
+            .map_err(|e| anyhow::anyhow!("oidc token call: {e}"))?;
+        let status = response.status();
+        let body = response
+            .text()
+            .await
+            .map_err(|e| anyhow::anyhow!("can't get body: {e}"))?;
+        if status.is_client_error() {
+            tracing::warn!(status = %status, body, "oidc token response");
+            return Err(auth::Error::OtherAuth(format!(
+                "oidc token exchange: {status}"
+            )));
+        }
+        if !status.is_success() {
+            return Err(anyhow::anyhow!("oidc token exchange: {status}").into());
+        }
+        let res: TokenResponse = serde_json::from_str(&body)
+            .map_err(|e| anyhow::anyhow!("can't deserialize token response: {e}"))?;
+        Ok(res.id_token)
+    }
+
+    async fn validate_id_token(&self, token: &str, nonce: &str) -> Result<Value, auth::Error> {
+        let header = jsonwebtoken::decode_header(token)
+            .map_err(|e| auth::Error::OtherAuth(format!("oidc invalid id token: {e}")))?;
+        if matches!(
+            header.alg,
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+        ) {
+            return Err(auth::Error::OtherAuth(format!(
+                "oidc unsupported id token alg {:?}",
+                header.alg
+            )));
+        }
+        let kid = header.kid.unwrap_or_default();
+        let mut provider = self.provider(false).await?;
+        if provider.jwks.find(&kid).is_none() {
+            tracing::debug!(kid, "unknown kid, reloading jwks");
+            provider = self.provider(true).await?;
+        }
+        let jwk = if kid.is_empty() && provider.jwks.keys.len() == 1 {
+            provider.jwks.keys.first()
+        } else {
+            provider.jwks.find(&kid)
+        }
+        .ok_or_else(|| auth::Error::OtherAuth(format!("oidc no key for kid '{kid}'")))?;
+        let key = DecodingKey::from_jwk(jwk)
+            .map_err(|e| anyhow::anyhow!("oidc invalid jwk {kid}: {e}"))?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_audience(&[&self.config.client_id]);
+        validation.set_issuer(&[&provider.metadata.issuer]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+        let data = jsonwebtoken::decode::<Value>(token, &key, &validation)
+            .map_err(|e| auth::Error::OtherAuth(format!("oidc invalid id token: {e}")))?;
+        if data.claims.get("nonce").and_then(Value::as_str) != Some(nonce) {
+            return Err(auth::Error::OtherAuth("oidc nonce mismatch".to_string()));
+        }
+        Ok(data.claims)
+    }
+
+    fn map_claims(&self, claims: &Value) -> Result<auth::User, auth::Error> {
+        let id = claim_str(claims, &self.config.user_claim)
+            .or_else(|| claim_str(claims, "sub"))
+            .ok_or_else(|| auth::Error::OtherAuth("oidc no user claim".to_string()))?;
+        let name = claim_str(claims, &self.config.name_claim).unwrap_or_else(|| id.clone());
+        let department = claim_str(claims, &self.config.department_claim).unwrap_or_default();
+        let roles = map_roles(
+            claim_strs(claims, &self.config.roles_claim),
+            &self.config.role_map,
+        );
+        if roles.is_empty() {
+            return Err(auth::Error::NoAccess());
+        }
+        Ok(auth::User {
+            id,
+            name,
+            department,
+            roles,
+            backend: BACKEND.to_string(),
+        })
+    }
+}
+
+// Parses role map in format `idp_role=ROLE;idp_role2=ROLE2`
+pub fn parse_role_map(input: &str) -> anyhow::Result<HashMap<String, String>> {
+    let mut res = HashMap::new();
+    for pair in input.split(';').filter(|p| !p.trim().is_empty()) {
+        match pair.split_once('=') {
+            Some((from, to)) if !from.trim().is_empty() && !to.trim().is_empty() => {
+                res.insert(from.trim().to_string(), to.trim().to_string());
+            }
+            _ => return Err(anyhow::anyhow!("Invalid role map format in: {}", pair)),
+        }
+    }
+    Ok(res)
+}
+
+fn map_roles(values: Vec<String>, role_map: &HashMap<String, String>) -> Vec<String> {
+    let mut res: Vec<String> = Vec::new();
+    for value in values {
+        let role = if role_map.is_empty() {
+            Some(value)
+        } else {
+            role_map.get(&value).cloned()
+        };
+        if let Some(role) = role {
+            if !res.contains(&role) {
+                res.push(role);
+            }
+        }
+    }
+    res
+}
+
+fn claim<'a>(claims: &'a Value, path: &str) -> Option<&'a Value> {
+    if path.is_empty() {
+        return None;
+    }
+    path.split('.').try_fold(claims, |v, key| v.get(key))
+}
+
+fn claim_str(claims: &Value, path: &str) -> Option<String> {
+    claim(claims, path)
+        .and_then(Value::as_str)
+        .filter(|s| !s.is_empty())
+        .map(|s| s.to_string())
+}
+
+fn claim_strs(claims: &Value, path: &str) -> Vec<String> {
+    match claim(claims, path) {
+        Some(Value::Array(values)) => values
+            .iter()
+            .filter_map(Value::as_str)
+            .map(|s| s.to_string())
+            .collect(),
+        Some(Value::String(value)) => value
+            .split([',', ' '])
+            .filter(|s| !s.is_empty())
+            .map(|s| s.to_string())
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn random_str() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    BASE64_URL_SAFE_NO_PAD.encode(bytes)
+}
+
+fn pkce_challenge(verifier: &str) -> String {
+    BASE64_URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use axum::{
+        extract::State,
+        routing::{get, post},
+        Form, Json, Router,
+    };
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::json;
+    use test_case::test_case;
+
+    use super::*;
+    use crate::store::encryptor::MagicEncryptor;
+
+    const NOW: i64 = 1_000_000;
+
+    // local mock IdP serving discovery, jwks and token endpoints
+    struct MockIdp {
+        url: String,
+        signer: EncodingKey,
+        jwk: Value,
+        claims: Value,
+        // code -> (code_challenge, nonce)
+        codes: Mutex<HashMap<String, (String, String)>>,
+    }
+
+    impl MockIdp {
+        async fn start(claims: Value, signer: Option<rcgen::KeyPair>) -> Arc<Self> {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("http://{}", listener.local_addr().unwrap());
+            let key = rcgen::KeyPair::generate().unwrap();
+            let raw = key.public_key_raw();
+            let jwk = json!({
+                "kty": "EC", "crv": "P-256", "kid": "k1", "alg": "ES256", "use": "sig",
+                "x": BASE64_URL_SAFE_NO_PAD.encode(&raw[1..33]),
+                "y": BASE64_URL_SAFE_NO_PAD.encode(&raw[33..65]),
+            });
+            let signer = signer.unwrap_or(key);
+            let idp = Arc::new(MockIdp {
+                url,
+                signer: EncodingKey::from_ec_pem(signer.serialize_pem().as_bytes()).unwrap(),
+                jwk,
+                claims,
+                codes: Mutex::new(HashMap::new()),
+            });
+            let app = Router::new()
+                .route(WELL_KNOWN, get(metadata))
+                .route("/jwks", get(jwks))
+                .route("/token", post(token))
+                .with_state(idp.clone());
+            tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+            idp
+        }
+
+        fn authorize(&self, auth_url: &str) -> (String, String) {
+            let url = url::Url::parse(auth_url).unwrap();
+            let params: HashMap<String, String> = url.query_pairs().into_owned().collect();
+            assert_eq!(params["code_challenge_method"], "S256");
+            assert_eq!(params["client_id"], "authware");
+            let code = random_str();
+            self.codes.lock().unwrap().insert(
+                code.clone(),
+                (params["code_challenge"].clone(), params["nonce"].clone()),
+            );
+            (code, params["state"].clone())
+        }
+    }
+
+    async fn metadata(State(idp): State<Arc<MockIdp>>) -> Json<Value> {
+        Json(json!({
+            "issuer": idp.url,
+            "authorization_endpoint": format!("{}/authorize", idp.url),
+            "token_endpoint": format!("{}/token", idp.url),
+            "jwks_uri": format!("{}/jwks", idp.url),
+        }))
+    }
+
+    async fn jwks(State(idp): State<Arc<MockIdp>>) -> Json<Value> {
+        Json(json!({ "keys": [idp.jwk] }))
+    }
+
+    async fn token(
+        State(idp): State<Arc<MockIdp>>,
+        Form(form): Form<HashMap<String, String>>,
+    ) -> Result<Json<Value>, reqwest::StatusCode> {
+        let (challenge, nonce) = idp
+            .codes
+            .lock()
+            .unwrap()
+            .remove(&form["code"])
+            .ok_or(reqwest::StatusCode::BAD_REQUEST)?;
+        if pkce_challenge(&form["code_verifier"]) != challenge {
+            return Err(reqwest::StatusCode::BAD_REQUEST);
+        }
+        let mut claims = json!({
+            "iss": idp.url,
+            "aud": "authware",
+            "sub": "0000-1111",
+            "exp": chrono::Utc::now().timestamp() + 60,
+            "nonce": nonce,
+        });
+        for (k, v) in idp.claims.as_object().unwrap() {
+            claims[k] = v.clone();
+        }
+        let mut header = Header::new(Algorithm::ES256);
+        header.kid = Some("k1".to_string());
+        let id_token = jsonwebtoken::encode(&header, &claims, &idp.signer).unwrap();
+        Ok(Json(
+            json!({ "id_token": id_token, "token_type": "Bearer" }),
+        ))
+    }
+
+    fn make_client(idp: &MockIdp, role_map: &str) -> Client {
+        Client::new(
+            Config {
+                discovery_url: idp.url.clone(),
+                client_id: "authware".to_string(),
+                client_secret: "secret".into(),
+                redirect_url: "https://localhost/auth/oidc/callback".to_string(),
+                scopes: vec!["openid".to_string(), "profile".to_string()],
+                user_claim: "preferred_username".to_string(),
+                name_claim: "name".to_string(),
+                department_claim: "department".to_string(),
+                roles_claim: "realm_access.roles".to_string(),
+                role_map: parse_role_map(role_map).unwrap(),
+                post_login_url: "".to_string(),
+            },
+            Box::new(MagicEncryptor::new("1234567890123456").unwrap()),
+        )
+        .unwrap()
+    }
+
+    fn keycloak_claims() -> Value {
+        json!({
+            "preferred_username": "olia",
+            "name": "Olia Olialia",
+            "department": "IT",
+            "realm_access": { "roles": ["app-admin", "app-user", "offline_access"] },
+        })
+    }
+
+    #[tokio::test]
+    async fn test_login() {
+        let idp = MockIdp::start(keycloak_claims(), None).await;
+        let client = make_client(&idp, "app-admin=ADMIN;app-user=USER");
+        let start = client.start(NOW).await.unwrap();
+        let (code, state) = idp.authorize(&start.url);
+        let user = client
+            .finish(&start.cookie, &state, &code, NOW + 1000)
+            .await
+            .unwrap();
+        assert_eq!(
+            user,
+            auth::User {
+                id: "olia".to_string(),
+                name: "Olia Olialia".to_string(),
+                department: "IT".to_string(),
+                roles: vec!["ADMIN".to_string(), "USER".to_string()],
+                backend: BACKEND.to_string(),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_login_no_roles() {
+        let idp = MockIdp::start(keycloak_claims(), None).await;
+        let client = make_client(&idp, "other=ADMIN");
+        let start = client.start(NOW).await.unwrap();
+        let (code, state) = idp.authorize(&start.url);
+        let res = client.finish(&start.cookie, &state, &code, NOW).await;
+        assert_eq!(
+            res.err().unwrap().to_string(),
+            auth::Error::NoAccess().to_string()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_login_wrong_state() {
+        let idp = MockIdp::start(keycloak_claims(), None).await;
+        let client = make_client(&idp, "");
+        let start = client.start(NOW).await.unwrap();
+        let (code, _) = idp.authorize(&start.url);
+        let res = client.finish(&start.cookie, "other", &code, NOW).await;
+        assert!(matches!(res, Err(auth::Error::OtherAuth(_))));
+    }
+
+    #[tokio::test]
+    async fn test_login_expired() {
+        let idp = MockIdp::start(keycloak_claims(), None).await;
+        let client = make_client(&idp, "");
+        let start = client.start(NOW).await.unwrap();
+        let (code, state) = idp.authorize(&start.url);
+        let res = client
+            .finish(&start.cookie, &state, &code, NOW + PENDING_TTL_MS + 1)
+            .await;
+        assert!(matches!(res, Err(auth::Error::OtherAuth(_))));
+    }
+
+    #[tokio::test]
+    async fn test_login_tampered_cookie() {
+        let idp = MockIdp::start(keycloak_claims(), None).await;
+        let client = make_client(&idp, "");
+        let start = client.start(NOW).await.unwrap();
+        let (code, state) = idp.authorize(&start.url);
+        let res = client.finish("aGVsbG8=", &state, &code, NOW).await;
+        assert!(matches!(res, Err(auth::Error::OtherAuth(_))));
+    }
+
+    #[tokio::test]
+    async fn test_login_wrong_signature() {
+        let idp =
+            MockIdp::start(keycloak_claims(), Some(rcgen::KeyPair::generate().unwrap())).await;
+        let client = make_client(&idp, "");
+        let start = client.start(NOW).await.unwrap();
+        let (code, state) = idp.authorize(&start.url);
+        let res = client.finish(&start.cookie, &state, &code, NOW).await;
+        assert!(matches!(res, Err(auth::Error::OtherAuth(_))));
+    }
+
+    #[tokio::test]
+    async fn test_login_wrong_audience() {
+        let mut claims = keycloak_claims();
+        claims["aud"] = json!("other-app");
+        let idp = MockIdp::start(claims, None).await;
+        let client = make_client(&idp, "");
+        let start = client.start(NOW).await.unwrap();
+        let (code, state) = idp.authorize(&start.url);
+        let res = client.finish(&start.cookie, &state, &code, NOW).await;
+        assert!(matches!(res, Err(auth::Error::OtherAuth(_))));
+    }
+
+    #[tokio::test]
+    async fn test_login_wrong_nonce() {
+        let mut claims = keycloak_claims();
+        claims["nonce"] = json!("other");
+        let idp = MockIdp::start(claims, None).await;
+        let client = make_client(&idp, "");
+        let start = client.start(NOW).await.unwrap();
+        let (code, state) = idp.authorize(&start.url);
+        let res = client.finish(&start.cookie, &state, &code, NOW).await;
+        assert!(matches!(res, Err(auth::Error::OtherAuth(_))));
+    }
+
+    #[test_case(json!({"groups": ["a", "b"]}), "groups", &["a", "b"]; "array")]
+    #[test_case(json!({"groups": "a b,c"}), "groups", &["a", "b", "c"]; "string")]
+    #[test_case(json!({"realm_access": {"roles": ["a"]}}), "realm_access.roles", &["a"]; "nested")]
+    #[test_case(json!({"groups": ["a"]}), "roles", &[]; "missing")]
+    #[test_case(json!({"groups": ["a"]}), "", &[]; "empty path")]
+    fn test_claim_strs(claims: Value, path: &str, wanted: &[&str]) {
+        assert_eq!(claim_strs(&claims, path), wanted);
+    }
+
+    #[test_case("", &[]; "empty")]
+    #[test_case("a=ADMIN", &[("a", "ADMIN")]; "one")]
+    #[test_case("a=ADMIN; b = USER;", &[("a", "ADMIN"), ("b", "USER")]; "several")]
+    fn test_parse_role_map(input: &str, wanted: &[(&str, &str)]) {
+        let wanted: HashMap<String, String> = wanted
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        assert_eq!(parse_role_map(input).unwrap(), wanted);
+    }
+
+    #[test_case("a"; "no value")]
+    #[test_case("=ADMIN"; "no key")]
+    fn test_parse_role_map_failure(input: &str) {
+        assert!(parse_role_map(input).is_err());
+    }
+
+    #[test]
+    fn test_pkce_challenge() {
+        // RFC 7636 appendix B
+        assert_eq!(
+            pkce_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+    }
+}
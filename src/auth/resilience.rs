@@ -0,0 +1,493 @@
+use std::{
+    future::Future,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use thiserror::Error;
+use tokio::sync::Semaphore;
+
+use crate::{
+    model::auth::{self, Unavailable},
+    utils::secret_str::SecretString,
+    AuthService,
+};
+
+// Wrappers of remote auth backends, composed in `init_auth`:
+// bulkhead -> circuit breaker -> retry -> timeout -> backend.
+// Only `ServiceError`s are failures, a wrong password is a healthy answer
+
+fn unavailable(name: &str, reason: &str) -> auth::Error {
+    auth::Error::ServiceError(Unavailable(format!("{name}: {reason}")).into())
+}
+
+// Marks the `Unavailable` error of a timed out attempt, it is worth a retry
+#[derive(Debug, Error)]
+#[error("timeout")]
+struct TimedOut;
+
+fn is_failure<T>(res: &Result<T, auth::Error>) -> bool {
+    matches!(res, Err(auth::Error::ServiceError(_)))
+}
+
+// Fails the call after the deadline. Under `Retry` it bounds each attempt
+pub struct Timeout {
+    name: String,
+    inner: Box<dyn AuthService + Send + Sync>,
+    timeout: Duration,
+}
+
+impl Timeout {
+    pub fn new(name: &str, inner: Box<dyn AuthService + Send + Sync>, timeout: Duration) -> Self {
+        Timeout {
+            name: name.to_string(),
+            inner,
+            timeout,
+        }
+    }
+
+    async fn run<T, F>(&self, f: F) -> Result<T, auth::Error>
+    where
+        F: Future<Output = Result<T, auth::Error>>,
+    {
+        match tokio::time::timeout(self.timeout, f).await {
+            Ok(res) => res,
+            Err(_) => {
+                tracing::warn!(name = self.name, timeout = ?self.timeout, "auth backend timeout");
+                let reason = format!("no answer in {}", humantime::format_duration(self.timeout));
+                Err(auth::Error::ServiceError(
+                    anyhow::Error::new(TimedOut)
+                        .context(Unavailable(format!("{}: {reason}", self.name))),
+                ))
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl AuthService for Timeout {
+    async fn login(&self, user: &str, pass: &SecretString) -> Result<auth::User, auth::Error> {
+        self.run(self.inner.login(user, pass)).await
+    }
+
+    async fn lookup(&self, user: &str) -> Result<auth::User, auth::Error> {
+        self.run(self.inner.lookup(user)).await
+    }
+
+    async fn refresh_user(&self, user: &auth::User) -> Result<Option<auth::User>, auth::Error> {
+        self.run(self.inner.refresh_user(user)).await
+    }
+}
+
+// Retries transient service errors and timed out attempts with an exponential backoff.
+// Errors raised by other wrappers (open circuit, full bulkhead) are not retried
+pub struct Retry {
+    name: String,
+    inner: Box<dyn AuthService + Send + Sync>,
+    retries: u32,
+    backoff: Duration,
+}
+
+impl Retry {
+    pub fn new(
+        name: &str,
+        inner: Box<dyn AuthService + Send + Sync>,
+        retries: u32,
+        backoff: Duration,
+    ) -> Self {
+        Retry {
+            name: name.to_string(),
+            inner,
+            retries,
+            backoff,
+        }
+    }
+
+    async fn run<T, F, Fut>(&self, f: F) -> Result<T, auth::Error>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T, auth::Error>>,
+    {
+        let mut wait = self.backoff;
+        let mut attempt = 0;
+        loop {
+            let res = f().await;
+            match &res {
+                Err(auth::Error::ServiceError(err))
+                    if attempt < self.retries
+                        && (err.downcast_ref::<Unavailable>().is_none()
+                            || err.downcast_ref::<TimedOut>().is_some()) =>
+                {
+                    attempt += 1;
+                    tracing::debug!(name = self.name, attempt, error = %err, "retry");
+                    tokio::time::sleep(wait).await;
+                    wait *= 2;
+                }
+                _ => return res,
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl AuthService for Retry {
+    async fn login(&self, user: &str, pass: &SecretString) -> Result<auth::User, auth::Error> {
+        self.run(|| self.inner.login(user, pass)).await
+    }
+
+    async fn lookup(&self, user: &str) -> Result<auth::User, auth::Error> {
+        self.run(|| self.inner.lookup(user)).await
+    }
+
+    async fn refresh_user(&self, user: &auth::User) -> Result<Option<auth::User>, auth::Error> {
+        self.run(|| self.inner.refresh_user(user)).await
+    }
+}
+
+#[derive(Default)]
+struct State {
+    failures: u32,
+    open_till: Option<Instant>,
+    // a single call is let through when the open time is over
+    trial_since: Option<Instant>,
+}
+
+// Stops calling the backend after `failures` service errors in a row for `open_for`
+pub struct CircuitBreaker {
+    name: String,
+    inner: Box<dyn AuthService + Send + Sync>,
+    failures: u32,
+    open_for: Duration,
+    state: Mutex<State>,
+}
+
+impl CircuitBreaker {
+    pub fn new(
+        name: &str,
+        inner: Box<dyn AuthService + Send + Sync>,
+        failures: u32,
+        open_for: Duration,
+    ) -> anyhow::Result<Self> {
+        if failures == 0 {
+            return Err(anyhow::anyhow!("circuit breaker needs failures > 0"));
+        }
+        Ok(CircuitBreaker {
+            name: name.to_string(),
+            inner,
+            failures,
+            open_for,
+            state: Mutex::new(State::default()),
+        })
+    }
+
+    fn before(&self, now: Instant) -> Result<(), auth::Error> {
+        let mut state = self.state.lock().unwrap();
+        let open_till = match state.open_till {
+            Some(open_till) => open_till,
+            None => return Ok(()),
+        };
+        if now < open_till {
+            return Err(unavailable(&self.name, "circuit is open"));
+        }
+        // a lost trial (cancelled call) is replaced after the open time
+        if state
+            .trial_since
+            .is_some_and(|since| now < since + self.open_for)
+        {
+            return Err(unavailable(&self.name, "circuit is open"));
+        }
+        state.trial_since = Some(now);
+        Ok(())
+    }
+
+    fn after(&self, failed: bool, now: Instant) {
+        let mut state = self.state.lock().unwrap();
+        if !failed {
+            if state.open_till.is_some() {
+                tracing::info!(name = self.name, "circuit closed");
+            }
+            *state = State::default();
+            return;
+        }
+        state.failures += 1;
+        if state.trial_since.is_some() || state.failures >= self.failures {
+            tracing::warn!(name = self.name, open_for = ?self.open_for, "circuit opened");
+            *state = State {
+                failures: 0,
+                open_till: Some(now + self.open_for),
+                trial_since: None,
+            };
+        }
+    }
+
+    async fn run<T, F>(&self, f: F) -> Result<T, auth::Error>
+    where
+        F: Future<Output = Result<T, auth::Error>>,
+    {
+        self.before(Instant::now())?;
+        let res = f.await;
+        self.after(is_failure(&res), Instant::now());
+        res
+    }
+}
+
+#[async_trait]
+impl AuthService for CircuitBreaker {
+    async fn login(&self, user: &str, pass: &SecretString) -> Result<auth::User, auth::Error> {
+        self.run(self.inner.login(user, pass)).await
+    }
+
+    async fn lookup(&self, user: &str) -> Result<auth::User, auth::Error> {
+        self.run(self.inner.lookup(user)).await
+    }
+
+    async fn refresh_user(&self, user: &auth::User) -> Result<Option<auth::User>, auth::Error> {
+        self.run(self.inner.refresh_user(user)).await
+    }
+}
+
+// Limits calls in flight, the ones over the limit fail at once
+pub struct Bulkhead {
+    name: String,
+    inner: Box<dyn AuthService + Send + Sync>,
+    permits: Semaphore,
+}
+
+impl Bulkhead {
+    pub fn new(
+        name: &str,
+        inner: Box<dyn AuthService + Send + Sync>,
+        max_in_flight: usize,
+    ) -> anyhow::Result<Self> {
+        if max_in_flight == 0 {
+            return Err(anyhow::anyhow!("bulkhead needs max in flight > 0"));
+        }
+        Ok(Bulkhead {
+            name: name.to_string(),
+            inner,
+            permits: Semaphore::new(max_in_flight),
+        })
+    }
+
+    async fn run<T, F>(&self, f: F) -> Result<T, auth::Error>
+    where
+        F: Future<Output = Result<T, auth::Error>>,
+    {
+        let _permit = self.permits.try_acquire().map_err(|_| {
+            tracing::warn!(name = self.name, "too many auth calls in flight");
+            unavailable(&self.name, "too many calls in flight")
+        })?;
+        f.await
+    }
+}
+
+#[async_trait]
+impl AuthService for Bulkhead {
+    async fn login(&self, user: &str, pass: &SecretString) -> Result<auth::User, auth::Error> {
+        self.run(self.inner.login(user, pass)).await
+    }
+
+    async fn lookup(&self, user: &str) -> Result<auth::User, auth::Error> {
+        self.run(self.inner.lookup(user)).await
+    }
+
+    async fn refresh_user(&self, user: &auth::User) -> Result<Option<auth::User>, auth::Error> {
+        self.run(self.inner.refresh_user(user)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use test_case::test_case;
+
+    use super::*;
+
+    #[derive(Clone, Copy, PartialEq, Debug)]
+    enum Res {
+        Ok,
+        Wrong,
+        Down,
+        Hang,
+    }
+
+    struct Mock {
+        res: Arc<Mutex<Vec<Res>>>,
+        calls: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl AuthService for Mock {
+        async fn login(&self, user: &str, _: &SecretString) -> Result<auth::User, auth::Error> {
+            let call = self.calls.fetch_add(1, Ordering::SeqCst);
+            let res = {
+                let res = self.res.lock().unwrap();
+                res[call.min(res.len() - 1)]
+            };
+            match res {
+                Res::Ok => Ok(auth::User {
+                    id: user.to_string(),
+                    name: user.to_string(),
+                    department: String::new(),
+                    roles: vec!["USER".to_string()],
+                    backend: String::new(),
+                }),
+                Res::Wrong => Err(auth::Error::WrongUserPass()),
+                Res::Down => Err(auth::Error::ServiceError(anyhow::anyhow!("down"))),
+                Res::Hang => {
+                    tokio::time::sleep(Duration::from_secs(10)).await;
+                    Err(auth::Error::ServiceError(anyhow::anyhow!("late")))
+                }
+            }
+        }
+    }
+
+    fn mock(res: &[Res]) -> (Box<dyn AuthService + Send + Sync>, Arc<AtomicUsize>) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        (
+            Box::new(Mock {
+                res: Arc::new(Mutex::new(res.to_vec())),
+                calls: calls.clone(),
+            }),
+            calls,
+        )
+    }
+
+    fn result(res: Result<auth::User, auth::Error>) -> String {
+        match res {
+            Ok(user) => format!("ok {}", user.id),
+            Err(auth::Error::ServiceError(err)) => match err.downcast_ref::<Unavailable>() {
+                Some(_) => format!("unavailable {err}"),
+                None => err.to_string(),
+            },
+            Err(err) => err.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_timeout() {
+        let (inner, _) = mock(&[Res::Hang]);
+        let auth = Timeout::new("ws", inner, Duration::from_millis(20));
+        let res = auth.login("olia", &"pass".into()).await;
+        assert_eq!(
+            result(res),
+            "unavailable Auth service unavailable: ws: no answer in 20ms"
+        );
+    }
+
+    #[test_case(&[Res::Down, Res::Down, Res::Ok], 2, "ok olia", 3; "recovers")]
+    #[test_case(&[Res::Down], 2, "down", 3; "gives up")]
+    #[test_case(&[Res::Wrong, Res::Ok], 2, "Wrong password", 1; "no retry on wrong pass")]
+    #[test_case(&[Res::Down, Res::Ok], 0, "down", 1; "disabled")]
+    #[tokio::test]
+    async fn test_retry(res: &[Res], retries: u32, expected: &str, expected_calls: usize) {
+        let (inner, calls) = mock(res);
+        let auth = Retry::new("ws", inner, retries, Duration::from_millis(1));
+        assert_eq!(result(auth.login("olia", &"pass".into()).await), expected);
+        assert_eq!(calls.load(Ordering::SeqCst), expected_calls);
+    }
+
+    #[test_case(&[Res::Hang], "unavailable Auth service unavailable: ws: no answer in 10ms", 4; "gives up")]
+    #[test_case(&[Res::Hang, Res::Hang, Res::Ok], "ok olia", 3; "recovers")]
+    #[tokio::test]
+    async fn test_retry_timeout(res: &[Res], expected: &str, expected_calls: usize) {
+        let (inner, calls) = mock(res);
+        let auth = Retry::new(
+            "ws",
+            Box::new(Timeout::new("ws", inner, Duration::from_millis(10))),
+            3,
+            Duration::from_millis(1),
+        );
+        assert_eq!(result(auth.login("olia", &"pass".into()).await), expected);
+        assert_eq!(calls.load(Ordering::SeqCst), expected_calls);
+    }
+
+    #[tokio::test]
+    async fn test_retry_skips_open_circuit() {
+        let (inner, calls) = mock(&[Res::Down]);
+        let breaker = CircuitBreaker::new("ws", inner, 1, Duration::from_secs(60)).unwrap();
+        let auth = Retry::new("ws", Box::new(breaker), 3, Duration::from_millis(1));
+        let res = auth.login("olia", &"pass".into()).await;
+        assert_eq!(
+            result(res),
+            "unavailable Auth service unavailable: ws: circuit is open"
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_circuit_breaker() {
+        let res = Arc::new(Mutex::new(vec![Res::Down]));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let inner = Box::new(Mock {
+            res: res.clone(),
+            calls: calls.clone(),
+        });
+        let auth = CircuitBreaker::new("ws", inner, 2, Duration::from_millis(50)).unwrap();
+        assert_eq!(result(auth.login("olia", &"pass".into()).await), "down");
+        assert_eq!(result(auth.login("olia", &"pass".into()).await), "down");
+        assert_eq!(
+            result(auth.login("olia", &"pass".into()).await),
+            "unavailable Auth service unavailable: ws: circuit is open"
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // failed trial opens again
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(result(auth.login("olia", &"pass".into()).await), "down");
+        assert!(result(auth.login("olia", &"pass".into()).await).starts_with("unavailable"));
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        *res.lock().unwrap() = vec![Res::Wrong];
+        assert_eq!(
+            result(auth.login("olia", &"pass".into()).await),
+            "Wrong password"
+        );
+        *res.lock().unwrap() = vec![Res::Ok];
+        assert_eq!(result(auth.login("olia", &"pass".into()).await), "ok olia");
+        assert_eq!(calls.load(Ordering::SeqCst), 5);
+    }
+
+    #[tokio::test]
+    async fn test_circuit_breaker_resets_on_success() {
+        let (inner, _) = mock(&[Res::Down, Res::Ok, Res::Down, Res::Ok]);
+        let auth = CircuitBreaker::new("ws", inner, 2, Duration::from_secs(60)).unwrap();
+        for _ in 0..4 {
+            let _ = auth.login("olia", &"pass".into()).await;
+        }
+        assert_eq!(auth.state.lock().unwrap().open_till, None);
+    }
+
+    #[tokio::test]
+    async fn test_bulkhead() {
+        let (inner, calls) = mock(&[Res::Hang]);
+        let auth = Arc::new(Bulkhead::new("ws", inner, 1).unwrap());
+        let first = {
+            let auth = auth.clone();
+            tokio::spawn(async move { auth.login("olia", &"pass".into()).await })
+        };
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(
+            result(auth.login("olia", &"pass".into()).await),
+            "unavailable Auth service unavailable: ws: too many calls in flight"
+        );
+        first.abort();
+        let _ = first.await;
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(auth.permits.available_permits(), 1);
+    }
+
+    #[test]
+    fn test_new_fails() {
+        let (inner, _) = mock(&[Res::Ok]);
+        assert!(CircuitBreaker::new("ws", inner, 0, Duration::from_secs(1)).is_err());
+        let (inner, _) = mock(&[Res::Ok]);
+        assert!(Bulkhead::new("ws", inner, 0).is_err());
+    }
+}
@@ -0,0 +1,742 @@
+use std::{collections::HashMap, time::Duration};
+
+use async_trait::async_trait;
+use reqwest::{Client, Method};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{model::auth, utils::secret_str::SecretString, AuthService};
+
+const USER: &str = "{user}";
+const PASS: &str = "{pass}";
+
+// Generic http auth backend configured by a json file, e.g.:
+//
+//  {
+//    "login": {"url": "https://idp/api/login", "method": "POST",
+//              "body": "{\"login\": \"{user}\", \"password\": \"{pass}\"}"},
+//    "roles_request": {"url": "https://idp/api/users/{user}/roles", "method": "GET"},
+//    "name": ["/firstName", "/lastName"], "department": "/unit/name",
+//    "roles": "/roles", "role_field": "/name",
+//    "errors": [{"status": 401, "error": "wrong_user_pass"},
+//               {"pointer": "/code", "equals": "EXPIRED", "error": "expired_pass"}]
+//  }
+//
+// `{user}` and `{pass}` are replaced in the body and headers, escaped for the content type,
+// `{user}` also in the url. Values are extracted by json pointers, xml responses are converted
+// to json first: elements become objects, repeated elements arrays, attributes `@name` keys,
+// so `/user/firstName` works for `<user><firstName>..`
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    pub login: RequestConfig,
+    // optional second call for roles, only `{user}` is available
+    #[serde(default)]
+    pub roles_request: Option<RequestConfig>,
+    #[serde(default)]
+    pub basic_auth_user: String,
+    #[serde(default)]
+    pub basic_auth_pass: String,
+    #[serde(default = "default_timeout", deserialize_with = "duration")]
+    pub timeout: Duration,
+    // pointer to the user id, the login name is used if empty
+    #[serde(default)]
+    pub id: String,
+    // pointers joined by space
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub name: Vec<String>,
+    #[serde(default)]
+    pub department: String,
+    pub roles: String,
+    // pointer inside each role item, if roles are objects
+    #[serde(default)]
+    pub role_field: String,
+    // checked in order before extraction
+    #[serde(default)]
+    pub errors: Vec<ErrorRule>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RequestConfig {
+    pub url: String,
+    #[serde(default = "default_method")]
+    pub method: String,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    #[serde(default)]
+    pub body: String,
+    #[serde(default = "default_content_type")]
+    pub content_type: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ErrorRule {
+    #[serde(default)]
+    pub status: Option<u16>,
+    // pointer to a value in the body, the whole body is compared if empty
+    #[serde(default)]
+    pub pointer: String,
+    #[serde(default)]
+    pub equals: Option<String>,
+    pub error: ErrorKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    WrongUserPass,
+    ExpiredPass,
+    NoAccess,
+    Other,
+    Service,
+}
+
+// below the default AUTH_CALL_TIMEOUT
+fn default_timeout() -> Duration {
+    Duration::from_secs(3)
+}
+
+fn default_method() -> String {
+    "POST".to_string()
+}
+
+fn default_content_type() -> String {
+    "application/json".to_string()
+}
+
+fn duration<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let value = String::deserialize(deserializer)?;
+    humantime::parse_duration(&value).map_err(serde::de::Error::custom)
+}
+
+fn one_or_many<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(s) => vec![s],
+        OneOrMany::Many(v) => v,
+    })
+}
+
+pub struct Auth {
+    config: Config,
+    basic_auth_pass: SecretString,
+    client: Client,
+}
+
+struct Response {
+    status: u16,
+    body: String,
+    value: Option<Value>,
+}
+
+impl Auth {
+    pub fn new(path: &str) -> anyhow::Result<Self> {
+        let content =
+            std::fs::read_to_string(path).map_err(|e| anyhow::anyhow!("can't read {path}: {e}"))?;
+        let config: Config = serde_json::from_str(&content)
+            .map_err(|e| anyhow::anyhow!("can't parse {path}: {e}"))?;
+        Self::from_config(config)
+    }
+
+    pub fn from_config(config: Config) -> anyhow::Result<Self> {
+        tracing::debug!(
+            url = config.login.url,
+            method = config.login.method,
+            "init rest auth"
+        );
+        for request in std::iter::once(&config.login).chain(config.roles_request.as_ref()) {
+            if request.url.is_empty() {
+                return Err(anyhow::anyhow!("empty rest auth url"));
+            }
+            // urls end up in logs
+            if request.url.contains(PASS) {
+                return Err(anyhow::anyhow!("{PASS} is not allowed in the url"));
+            }
+            Method::from_bytes(request.method.as_bytes())
+                .map_err(|e| anyhow::anyhow!("wrong method {}: {e}", request.method))?;
+        }
+        if config
+            .roles_request
+            .as_ref()
+            .is_some_and(|r| r.body.contains(PASS) || r.headers.values().any(|h| h.contains(PASS)))
+        {
+            return Err(anyhow::anyhow!(
+                "{PASS} is not allowed in the roles request"
+            ));
+        }
+        if config.roles.is_empty() {
+            return Err(anyhow::anyhow!("no roles pointer"));
+        }
+        Ok(Auth {
+            basic_auth_pass: config.basic_auth_pass.as_str().into(),
+            client: Client::builder().timeout(config.timeout).build()?,
+            config,
+        })
+    }
+
+    async fn call(
+        &self,
+        request: &RequestConfig,
+        user: &str,
+        pass: &SecretString,
+    ) -> Result<Response, auth::Error> {
+        let url = request.url.replace(USER, &urlencoding::encode(user));
+        let method = Method::from_bytes(request.method.as_bytes())
+            .map_err(|e| anyhow::anyhow!("wrong method: {e}"))?;
+        tracing::trace!(url, method = request.method, "call");
+        let mut builder = self.client.request(method, &url);
+        if !self.config.basic_auth_user.is_empty() {
+            builder = builder.basic_auth(
+                &self.config.basic_auth_user,
+                Some(self.basic_auth_pass.reveal_secret()),
+            );
+        }
+        for (name, value) in &request.headers {
+            builder = builder.header(name, fill(value, user, pass, Escape::None));
+        }
+        if !request.body.is_empty() {
+            let escape = Escape::for_content_type(&request.content_type);
+            builder = builder
+                .header(reqwest::header::CONTENT_TYPE, &request.content_type)
+                .body(fill(&request.body, user, pass, escape));
+        }
+        let response = builder
+            .send()
+            .await
+            .map_err(|e| anyhow::anyhow!("call {url}: {}", e.without_url()))?;
+        let status = response.status().as_u16();
+        let body = response
+            .text()
+            .await
+            .map_err(|e| anyhow::anyhow!("read body from {url}: {}", e.without_url()))?;
+        tracing::trace!(status, "got response");
+        Ok(Response {
+            status,
+            value: parse_body(&body),
+            body,
+        })
+    }
+
+    fn check_errors(&self, response: &Response) -> Result<(), auth::Error> {
+        for rule in &self.config.errors {
+            if rule.status.is_some_and(|s| s != response.status) {
+                continue;
+            }
+            if let Some(equals) = &rule.equals {
+                let actual = if rule.pointer.is_empty() {
+                    Some(response.body.trim().to_string())
+                } else {
+                    response
+                        .value
+                        .as_ref()
+                        .and_then(|v| v.pointer(&rule.pointer))
+                        .and_then(to_string)
+                };
+                if actual.as_deref() != Some(equals.as_str()) {
+                    continue;
+                }
+            } else if rule.status.is_none() {
+                continue;
+            }
+            tracing::debug!(status = response.status, error = ?rule.error, "matched error rule");
+            return Err(to_error(rule.error, response));
+        }
+        // a refusal, not an outage to count against the backend
+        if response.status == 401 || response.status == 403 {
+            tracing::debug!(status = response.status, "refused with no error rule");
+            return Err(auth::Error::WrongUserPass());
+        }
+        if !(200..300).contains(&response.status) {
+            return Err(auth::Error::ServiceError(anyhow::anyhow!(
+                "auth service status {}",
+                response.status
+            )));
+        }
+        Ok(())
+    }
+
+    fn extract(
+        &self,
+        user: &str,
+        value: &Value,
+        roles_value: &Value,
+    ) -> Result<auth::User, auth::Error> {
+        let get = |pointer: &str| {
+            value
+                .pointer(pointer)
+                .and_then(to_string)
+                .unwrap_or_default()
+        };
+        let id = if self.config.id.is_empty() {
+            user.to_string()
+        } else {
+            get(&self.config.id)
+        };
+        if id.is_empty() {
+            return Err(auth::Error::ServiceError(anyhow::anyhow!(
+                "no user id in the response"
+            )));
+        }
+        let name = self
+            .config
+            .name
+            .iter()
+            .map(|p| get(p))
+            .filter(|s| !s.is_empty())
+            .collect::<Vec<_>>()
+            .join(" ");
+        let roles: Vec<String> = match roles_value.pointer(&self.config.roles) {
+            Some(Value::Array(items)) => items.iter().collect(),
+            Some(Value::Null) | None => vec![],
+            // single xml element
+            Some(item) => vec![item],
+        }
+        .into_iter()
+        .filter_map(|item| {
+            if self.config.role_field.is_empty() {
+                to_string(item)
+            } else {
+                item.pointer(&self.config.role_field).and_then(to_string)
+            }
+        })
+        .filter(|r| !r.is_empty())
+        .collect();
+        if roles.is_empty() {
+            return Err(auth::Error::NoAccess());
+        }
+        Ok(auth::User {
+            name: if name.is_empty() { id.clone() } else { name },
+            id,
+            department: if self.config.department.is_empty() {
+                String::new()
+            } else {
+                get(&self.config.department)
+            },
+            roles,
+            backend: String::new(),
+        })
+    }
+}
+
+#[async_trait]
+impl AuthService for Auth {
+    async fn login(&self, user: &str, pass: &SecretString) -> Result<auth::User, auth::Error> {
+        if user.is_empty() || pass.reveal_secret().is_empty() {
+            return Err(auth::Error::WrongUserPass());
+        }
+        let response = self.call(&self.config.login, user, pass).await?;
+        self.check_errors(&response)?;
+        let value = response.value.ok_or_else(|| {
+            auth::Error::ServiceError(anyhow::anyhow!("can't parse the login response"))
+        })?;
+        let roles_value = match &self.config.roles_request {
+            Some(request) => {
+                let response = self.call(request, user, &"".into()).await?;
+                self.check_errors(&response)?;
+                response.value.ok_or_else(|| {
+                    auth::Error::ServiceError(anyhow::anyhow!("can't parse the roles response"))
+                })?
+            }
+            None => value.clone(),
+        };
+        self.extract(user, &value, &roles_value)
+    }
+}
+
+fn to_error(kind: ErrorKind, response: &Response) -> auth::Error {
+    match kind {
+        ErrorKind::WrongUserPass => auth::Error::WrongUserPass(),
+        ErrorKind::ExpiredPass => auth::Error::ExpiredPass(),
+        ErrorKind::NoAccess => auth::Error::NoAccess(),
+        ErrorKind::Other => auth::Error::OtherAuth(format!("status {}", response.status)),
+        ErrorKind::Service => auth::Error::ServiceError(anyhow::anyhow!(
+            "auth service error, status {}",
+            response.status
+        )),
+    }
+}
+
+fn to_string(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        // xml element with attributes and text
+        Value::Object(o) => o.get("#text").and_then(to_string),
+        _ => None,
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Escape {
+    None,
+    Json,
+    Form,
+    Xml,
+}
+
+impl Escape {
+    fn for_content_type(content_type: &str) -> Self {
+        let content_type = content_type.to_lowercase();
+        if content_type.contains("json") {
+            Escape::Json
+        } else if content_type.contains("x-www-form-urlencoded") {
+            Escape::Form
+        } else if content_type.contains("xml") {
+            Escape::Xml
+        } else {
+            Escape::None
+        }
+    }
+
+    fn apply(self, value: &str) -> String {
+        match self {
+            Escape::None => value.to_string(),
+            Escape::Json => {
+                let quoted = Value::String(value.to_string()).to_string();
+                quoted[1..quoted.len() - 1].to_string()
+            }
+            Escape::Form => urlencoding::encode(value).to_string(),
+            Escape::Xml => value
+                .replace('&', "&amp;")
+                .replace('<', "&lt;")
+                .replace('>', "&gt;")
+                .replace('"', "&quot;")
+                .replace('\'', "&apos;"),
+        }
+    }
+}
+
+// Replaces placeholders in one pass, so a user name containing `{pass}` is not expanded
+fn fill(template: &str, user: &str, pass: &SecretString, escape: Escape) -> String {
+    let mut res = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(pos) = rest.find('{') {
+        res.push_str(&rest[..pos]);
+        rest = &rest[pos..];
+        if let Some(r) = rest.strip_prefix(USER) {
+            res.push_str(&escape.apply(user));
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix(PASS) {
+            res.push_str(&escape.apply(pass.reveal_secret()));
+            rest = r;
+        } else {
+            res.push('{');
+            rest = &rest[1..];
+        }
+    }
+    res.push_str(rest);
+    res
+}
+
+fn parse_body(body: &str) -> Option<Value> {
+    let trimmed = body.trim_start();
+    if trimmed.starts_with('<') {
+        let doc = roxmltree::Document::parse(trimmed).ok()?;
+        let root = doc.root_element();
+        let mut res = serde_json::Map::new();
+        res.insert(root.tag_name().name().to_string(), xml_to_json(root));
+        return Some(Value::Object(res));
+    }
+    serde_json::from_str(body).ok()
+}
+
+fn xml_to_json(node: roxmltree::Node) -> Value {
+    let mut res = serde_json::Map::new();
+    for attr in node.attributes() {
+        res.insert(
+            format!("@{}", attr.name()),
+            Value::String(attr.value().to_string()),
+        );
+    }
+    let mut text = String::new();
+    for child in node.children() {
+        if child.is_text() {
+            text.push_str(child.text().unwrap_or_default());
+            continue;
+        }
+        if !child.is_element() {
+            continue;
+        }
+        let name = child.tag_name().name().to_string();
+        let value = xml_to_json(child);
+        match res.get_mut(&name) {
+            Some(Value::Array(items)) => items.push(value),
+            Some(existing) => {
+                let first = existing.take();
+                *existing = Value::Array(vec![first, value]);
+            }
+            None => {
+                res.insert(name, value);
+            }
+        }
+    }
+    let text = text.trim();
+    if res.is_empty() {
+        return Value::String(text.to_string());
+    }
+    if !text.is_empty() {
+        res.insert("#text".to_string(), Value::String(text.to_string()));
+    }
+    Value::Object(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use axum::{
+        body::Bytes,
+        extract::{Path, State},
+        http::{HeaderMap, StatusCode},
+        routing::{get, post},
+        Router,
+    };
+    use serde_json::json;
+    use test_case::test_case;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct Calls {
+        bodies: Vec<String>,
+        headers: Vec<HeaderMap>,
+    }
+
+    // local mock identity api
+    async fn start_server() -> (String, Arc<Mutex<Calls>>) {
+        let calls = Arc::new(Mutex::new(Calls::default()));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let app = Router::new()
+            .route("/json/login", post(json_login))
+            .route("/xml/login/:user", get(xml_login))
+            .route("/xml/roles/:user", get(xml_roles))
+            .with_state(calls.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (url, calls)
+    }
+
+    async fn json_login(
+        State(calls): State<Arc<Mutex<Calls>>>,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> (StatusCode, String) {
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        {
+            let mut calls = calls.lock().unwrap();
+            calls.bodies.push(body.clone());
+            calls.headers.push(headers);
+        }
+        let req: Value = match serde_json::from_str(&body) {
+            Ok(v) => v,
+            Err(_) => return (StatusCode::BAD_REQUEST, "".to_string()),
+        };
+        let res = match (req["login"].as_str(), req["password"].as_str()) {
+            (Some("olia"), Some("pass\"\\{user}")) | (Some("o\"lia"), Some("pass")) => json!({
+                "user": {"login": req["login"], "first": "Olia", "last": "Olialia",
+                         "unit": {"name": "IT"}},
+                "roles": [{"name": "USER"}, {"name": "ADMIN"}]
+            }),
+            (Some("noroles"), _) => json!({"user": {"login": "noroles"}, "roles": []}),
+            (Some("expired"), _) => {
+                return (
+                    StatusCode::FORBIDDEN,
+                    json!({"code": "PASSWORD_EXPIRED"}).to_string(),
+                )
+            }
+            (Some("locked"), _) => {
+                return (StatusCode::FORBIDDEN, json!({"code": "LOCKED"}).to_string())
+            }
+            (Some("broken"), _) => return (StatusCode::BAD_GATEWAY, "".to_string()),
+            _ => return (StatusCode::UNAUTHORIZED, "".to_string()),
+        };
+        (StatusCode::OK, res.to_string())
+    }
+
+    async fn xml_login(Path(user): Path<String>, headers: HeaderMap) -> String {
+        if headers.get("x-pass").and_then(|h| h.to_str().ok()) != Some("pass") {
+            return "1".to_string();
+        }
+        format!(
+            r#"<?xml version="1.0"?><user id="{user}"><firstName>Olia</firstName><lastName>Olialia</lastName>
+            <organizationUnit><name>IT</name></organizationUnit></user>"#
+        )
+    }
+
+    async fn xml_roles(Path(user): Path<String>) -> String {
+        if user == "one" {
+            return r#"<roles><role><name>R1</name></role></roles>"#.to_string();
+        }
+        r#"<roles user="olia"><role><name>R1</name></role><role><name>R2</name></role></roles>"#
+            .to_string()
+    }
+
+    fn json_config(url: &str) -> Config {
+        serde_json::from_value(json!({
+            "login": {
+                "url": format!("{url}/json/login"),
+                "headers": {"X-App": "authware"},
+                "body": r#"{"login": "{user}", "password": "{pass}"}"#
+            },
+            "basic_auth_user": "svc",
+            "basic_auth_pass": "svc-pass",
+            "id": "/user/login",
+            "name": ["/user/first", "/user/last"],
+            "department": "/user/unit/name",
+            "roles": "/roles",
+            "role_field": "/name",
+            "errors": [
+                {"status": 401, "error": "wrong_user_pass"},
+                {"pointer": "/code", "equals": "PASSWORD_EXPIRED", "error": "expired_pass"}
+            ]
+        }))
+        .unwrap()
+    }
+
+    fn xml_config(url: &str) -> Config {
+        serde_json::from_value(json!({
+            "login": {
+                "url": format!("{url}/xml/login/{{user}}"),
+                "method": "GET",
+                "headers": {"X-Pass": "{pass}"}
+            },
+            "roles_request": {"url": format!("{url}/xml/roles/{{user}}"), "method": "GET"},
+            "id": "/user/@id",
+            "name": "/user/firstName",
+            "department": "/user/organizationUnit/name",
+            "roles": "/roles/role",
+            "role_field": "/name",
+            "errors": [{"equals": "1", "error": "wrong_user_pass"}]
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_json_login() {
+        let (url, calls) = start_server().await;
+        let auth = Auth::from_config(json_config(&url)).unwrap();
+        let res = auth.login("olia", &"pass\"\\{user}".into()).await.unwrap();
+        assert_eq!(
+            res,
+            auth::User {
+                id: "olia".to_string(),
+                name: "Olia Olialia".to_string(),
+                department: "IT".to_string(),
+                roles: vec!["USER".to_string(), "ADMIN".to_string()],
+                backend: String::new(),
+            }
+        );
+        let res = auth.login("o\"lia", &"pass".into()).await.unwrap();
+        assert_eq!(res.id, "o\"lia");
+        let calls = calls.lock().unwrap();
+        assert_eq!(calls.headers[0]["x-app"], "authware");
+        assert!(calls.headers[0]["authorization"]
+            .to_str()
+            .unwrap()
+            .starts_with("Basic "));
+        assert_eq!(calls.headers[0]["content-type"], "application/json");
+    }
+
+    #[test_case("olia", "wrong", auth::Error::WrongUserPass(); "wrong pass")]
+    #[test_case("expired", "pass", auth::Error::ExpiredPass(); "expired")]
+    #[test_case("noroles", "pass", auth::Error::NoAccess(); "no roles")]
+    #[test_case("locked", "pass", auth::Error::WrongUserPass(); "forbidden with no rule")]
+    #[test_case("broken", "pass", auth::Error::ServiceError(anyhow::anyhow!("auth service status 502")); "status")]
+    #[test_case("olia", "", auth::Error::WrongUserPass(); "empty pass")]
+    #[tokio::test]
+    async fn test_json_login_fail(user: &str, pass: &str, wanted: auth::Error) {
+        let (url, _) = start_server().await;
+        let auth = Auth::from_config(json_config(&url)).unwrap();
+        let res = auth.login(user, &pass.into()).await;
+        assert_eq!(res.err().unwrap().to_string(), wanted.to_string());
+    }
+
+    #[tokio::test]
+    async fn test_xml_login() {
+        let (url, _) = start_server().await;
+        let auth = Auth::from_config(xml_config(&url)).unwrap();
+        let res = auth.login("olia", &"pass".into()).await.unwrap();
+        assert_eq!(
+            res,
+            auth::User {
+                id: "olia".to_string(),
+                name: "Olia".to_string(),
+                department: "IT".to_string(),
+                roles: vec!["R1".to_string(), "R2".to_string()],
+                backend: String::new(),
+            }
+        );
+        let res = auth.login("one", &"pass".into()).await.unwrap();
+        assert_eq!(res.roles, vec!["R1".to_string()]);
+        let res = auth.login("olia", &"wrong".into()).await;
+        assert!(matches!(res, Err(auth::Error::WrongUserPass())));
+    }
+
+    #[tokio::test]
+    async fn test_refused_without_rules() {
+        let (url, _) = start_server().await;
+        let mut config = json_config(&url);
+        config.errors.clear();
+        let auth = Auth::from_config(config).unwrap();
+        let res = auth.login("olia", &"wrong".into()).await;
+        assert!(matches!(res, Err(auth::Error::WrongUserPass())));
+        let res = auth.login("broken", &"pass".into()).await;
+        assert!(matches!(res, Err(auth::Error::ServiceError(_))));
+    }
+
+    #[tokio::test]
+    async fn test_service_down() {
+        let auth = Auth::from_config(json_config("http://127.0.0.1:1")).unwrap();
+        let res = auth.login("olia", &"secret-pass".into()).await;
+        match res {
+            Err(auth::Error::ServiceError(err)) => {
+                assert!(!format!("{err:?}").contains("secret-pass"))
+            }
+            _ => panic!("expected service error"),
+        }
+    }
+
+    #[test_case(r#"{"login": {"url": "http://a/{pass}"}, "roles": "/r"}"#; "pass in url")]
+    #[test_case(r#"{"login": {"url": ""}, "roles": "/r"}"#; "empty url")]
+    #[test_case(r#"{"login": {"url": "http://a", "method": "G T"}, "roles": "/r"}"#; "method")]
+    #[test_case(r#"{"login": {"url": "http://a"}, "roles": ""}"#; "no roles")]
+    #[test_case(r#"{"login": {"url": "http://a"}, "roles_request": {"url": "http://a", "body": "{pass}"}, "roles": "/r"}"#; "pass in roles")]
+    fn test_config_fails(input: &str) {
+        let config: Config = serde_json::from_str(input).unwrap();
+        assert!(Auth::from_config(config).is_err());
+    }
+
+    #[test_case("{user}:{pass}", "ol\"ia", "p{user}", Escape::None, "ol\"ia:p{user}"; "none")]
+    #[test_case(r#"{"u": "{user}", "p": "{pass}"}"#, "ol\"ia", "p\\", Escape::Json, r#"{"u": "ol\"ia", "p": "p\\"}"#; "json")]
+    #[test_case("u={user}&p={pass}", "o&a", "p=1", Escape::Form, "u=o%26a&p=p%3D1"; "form")]
+    #[test_case("<u>{user}</u>", "<a>", "", Escape::Xml, "<u>&lt;a&gt;</u>"; "xml")]
+    #[test_case("{a}{user", "u", "p", Escape::None, "{a}{user"; "no placeholder")]
+    fn test_fill(template: &str, user: &str, pass: &str, escape: Escape, expected: &str) {
+        assert_eq!(fill(template, user, &pass.into(), escape), expected);
+    }
+
+    #[test]
+    fn test_parse_xml() {
+        let value = parse_body(
+            r#"<?xml version="1.0"?><roles user="dev"><role><name>R1</name></role><role><name>R2</name></role><x a="1">t</x></roles>"#,
+        )
+        .unwrap();
+        assert_eq!(
+            value,
+            json!({"roles": {"@user": "dev", "role": [{"name": "R1"}, {"name": "R2"}],
+                   "x": {"@a": "1", "#text": "t"}}})
+        );
+        assert_eq!(to_string(value.pointer("/roles/x").unwrap()).unwrap(), "t");
+    }
+}
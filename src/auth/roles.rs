@@ -0,0 +1,277 @@
+use std::collections::HashMap;
+
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::model::auth;
+
+// Normalizes backend roles, configured by a json file, e.g.:
+//
+//  {
+//    "rename": {"app_admin": "ADMIN", "app_user": ["USER", "VIEWER"]},
+//    "allow": ["^[A-Z_]+$"], "deny": ["^TMP_"],
+//    "inherit": {"ADMIN": ["USER"]},
+//    "users": {"ldap:olia": ["AUDITOR"]}, "departments": {"IT": ["USER"]}
+//  }
+//
+// Steps go in order: rename, allow/deny regex filters, static grants, inheritance.
+// Roles not listed in `rename` are kept as is, an empty result rejects the login.
+// User grants are keyed by `backend:id`, the same id of another backend, api key or
+// certificate is another user
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub rename: HashMap<String, OneOrMany>,
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+    #[serde(default)]
+    pub inherit: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    pub users: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    pub departments: HashMap<String, Vec<String>>,
+    #[serde(default = "default_reject_empty")]
+    pub reject_empty: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+fn default_reject_empty() -> bool {
+    true
+}
+
+// the same as an empty json config
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            rename: HashMap::new(),
+            allow: Vec::new(),
+            deny: Vec::new(),
+            inherit: HashMap::new(),
+            users: HashMap::new(),
+            departments: HashMap::new(),
+            reject_empty: default_reject_empty(),
+        }
+    }
+}
+
+pub struct RoleMapper {
+    rename: HashMap<String, Vec<String>>,
+    allow: Vec<Regex>,
+    deny: Vec<Regex>,
+    inherit: HashMap<String, Vec<String>>,
+    users: HashMap<String, Vec<String>>,
+    departments: HashMap<String, Vec<String>>,
+    reject_empty: bool,
+}
+
+impl RoleMapper {
+    pub fn new(path: &str) -> anyhow::Result<Self> {
+        let content =
+            std::fs::read_to_string(path).map_err(|e| anyhow::anyhow!("can't read {path}: {e}"))?;
+        let config: Config = serde_json::from_str(&content)
+            .map_err(|e| anyhow::anyhow!("can't parse {path}: {e}"))?;
+        Self::from_config(config)
+    }
+
+    pub fn from_config(config: Config) -> anyhow::Result<Self> {
+        if let Some(key) = config.users.keys().find(|k| !is_user_key(k)) {
+            return Err(anyhow::anyhow!(
+                "wrong user grant key {key}, expected backend:id"
+            ));
+        }
+        let compile = |patterns: Vec<String>| {
+            patterns
+                .into_iter()
+                .map(|p| Regex::new(&p).map_err(|e| anyhow::anyhow!("wrong role regex {p}: {e}")))
+                .collect::<anyhow::Result<Vec<_>>>()
+        };
+        let res = RoleMapper {
+            rename: config
+                .rename
+                .into_iter()
+                .map(|(k, v)| {
+                    let v = match v {
+                        OneOrMany::One(s) => vec![s],
+                        OneOrMany::Many(v) => v,
+                    };
+                    (k, v)
+                })
+                .collect(),
+            allow: compile(config.allow)?,
+            deny: compile(config.deny)?,
+            inherit: config.inherit,
+            users: config.users,
+            departments: config.departments,
+            reject_empty: config.reject_empty,
+        };
+        for role in res.inherit.keys() {
+            res.check_cycle(role, &mut vec![])?;
+        }
+        tracing::debug!(
+            rename = res.rename.len(),
+            allow = res.allow.len(),
+            deny = res.deny.len(),
+            inherit = res.inherit.len(),
+            "init role mapper"
+        );
+        Ok(res)
+    }
+
+    fn check_cycle<'a>(&'a self, role: &'a str, path: &mut Vec<&'a str>) -> anyhow::Result<()> {
+        if path.contains(&role) {
+            return Err(anyhow::anyhow!(
+                "role inheritance cycle: {} -> {role}",
+                path.join(" -> ")
+            ));
+        }
+        path.push(role);
+        for child in self.inherit.get(role).into_iter().flatten() {
+            self.check_cycle(child, path)?;
+        }
+        path.pop();
+        Ok(())
+    }
+
+    pub fn map(&self, user: auth::User) -> Result<auth::User, auth::Error> {
+        let mut roles: Vec<String> = Vec::new();
+        for role in &user.roles {
+            match self.rename.get(role) {
+                Some(names) => names.iter().for_each(|r| push(&mut roles, r)),
+                None => push(&mut roles, role),
+            }
+        }
+        roles.retain(|r| {
+            (self.allow.is_empty() || self.allow.iter().any(|re| re.is_match(r)))
+                && !self.deny.iter().any(|re| re.is_match(r))
+        });
+        for role in self
+            .users
+            .get(&format!("{}:{}", user.backend, user.id))
+            .into_iter()
+            .chain(self.departments.get(&user.department))
+            .flatten()
+        {
+            push(&mut roles, role);
+        }
+        let mut i = 0;
+        while i < roles.len() {
+            if let Some(implied) = self.inherit.get(&roles[i]) {
+                for role in implied.clone() {
+                    push(&mut roles, &role);
+                }
+            }
+            i += 1;
+        }
+        tracing::trace!(user = user.id, from = ?user.roles, to = ?roles, "mapped roles");
+        if roles.is_empty() && self.reject_empty {
+            tracing::warn!(user = user.id, "no roles after mapping");
+            return Err(auth::Error::NoAccess());
+        }
+        Ok(auth::User { roles, ..user })
+    }
+}
+
+fn is_user_key(key: &str) -> bool {
+    key.split_once(':')
+        .is_some_and(|(backend, id)| !backend.is_empty() && !id.is_empty())
+}
+
+fn push(roles: &mut Vec<String>, role: &str) {
+    if !roles.iter().any(|r| r == role) {
+        roles.push(role.to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use test_case::test_case;
+
+    use super::*;
+
+    fn mapper() -> RoleMapper {
+        RoleMapper::from_config(
+            serde_json::from_value(json!({
+                "rename": {"app_admin": "ADMIN", "app_user": ["USER", "VIEWER"], "old": "USER"},
+                "allow": ["^[A-Z_]+$"],
+                "deny": ["^TMP_"],
+                "inherit": {"ADMIN": ["OPERATOR"], "OPERATOR": ["USER"]},
+                "users": {"sample:olia": ["AUDITOR"], "ldap:jonas": ["AUDITOR"]},
+                "departments": {"IT": ["IT_USER"]}
+            }))
+            .unwrap(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_default_config() {
+        let config = Config::default();
+        let json: Config = serde_json::from_value(json!({})).unwrap();
+        assert!(config.reject_empty);
+        assert_eq!(config.reject_empty, json.reject_empty);
+    }
+
+    fn user(id: &str, department: &str, roles: &[&str]) -> auth::User {
+        auth::User {
+            id: id.to_string(),
+            name: id.to_string(),
+            department: department.to_string(),
+            roles: roles.iter().map(|r| r.to_string()).collect(),
+            backend: "sample".to_string(),
+        }
+    }
+
+    #[test_case("u", "", &["app_user"], &["USER", "VIEWER"]; "alias")]
+    #[test_case("u", "", &["app_user", "old"], &["USER", "VIEWER"]; "dedup")]
+    #[test_case("u", "", &["app_admin"], &["ADMIN", "OPERATOR", "USER"]; "inherit transitive")]
+    #[test_case("u", "", &["KEEP", "lower", "TMP_X"], &["KEEP"]; "filters")]
+    #[test_case("olia", "", &["lower"], &["AUDITOR"]; "user grant")]
+    #[test_case("jonas", "", &["KEEP"], &["KEEP"]; "user grant of another backend")]
+    #[test_case("u", "IT", &[], &["IT_USER"]; "department grant")]
+    fn test_map(id: &str, department: &str, roles: &[&str], expected: &[&str]) {
+        let res = mapper().map(user(id, department, roles)).unwrap();
+        assert_eq!(res.roles, expected);
+        assert_eq!(res.backend, "sample");
+    }
+
+    #[test_case(&["lower"]; "filtered")]
+    #[test_case(&["TMP_A"]; "denied")]
+    #[test_case(&[]; "none")]
+    fn test_map_reject(roles: &[&str]) {
+        let res = mapper().map(user("u", "", roles));
+        assert!(matches!(res, Err(auth::Error::NoAccess())));
+    }
+
+    #[test]
+    fn test_map_allow_empty() {
+        let mapper = RoleMapper::from_config(Config {
+            reject_empty: false,
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(mapper.map(user("u", "", &[])).unwrap().roles.is_empty());
+    }
+
+    #[test_case(json!({"allow": ["("]}); "regex")]
+    #[test_case(json!({"inherit": {"A": ["B"], "B": ["A"]}}); "cycle")]
+    #[test_case(json!({"inherit": {"A": ["A"]}}); "self cycle")]
+    #[test_case(json!({"renames": {}}); "unknown field")]
+    #[test_case(json!({"users": {"olia": ["A"]}}); "user without backend")]
+    #[test_case(json!({"users": {":olia": ["A"]}}); "empty backend")]
+    fn test_config_fails(input: serde_json::Value) {
+        let res = serde_json::from_value::<Config>(input)
+            .map_err(anyhow::Error::from)
+            .and_then(RoleMapper::from_config);
+        assert!(res.is_err());
+    }
+}
@@ -33,6 +33,7 @@ impl ParsedUser {
             name: self.user.clone(),
             department: self.department.clone(),
             roles: self.roles.clone(),
+            backend: String::new(),
         }
     }
 }
@@ -111,6 +112,13 @@ impl AuthService for Sample {
             _ => Err(auth::Error::WrongUserPass()),
         }
     }
+
+    async fn lookup(&self, user: &str) -> Result<auth::User, auth::Error> {
+        self.users
+            .get(user)
+            .cloned()
+            .ok_or(auth::Error::WrongUserPass())
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,607 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use argon2::{
+    password_hash::{rand_core::OsRng, SaltString},
+    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
+};
+use async_trait::async_trait;
+use chrono::Utc;
+use rusqlite::{params, Connection, ErrorCode, OptionalExtension};
+
+use crate::{
+    model::{
+        auth,
+        user::{self, NewUser, UpdateUser, UserInfo},
+    },
+    utils::secret_str::SecretString,
+    AuthService, UserAdmin,
+};
+
+// backend name of the db users, their sessions are revoked on admin changes
+pub const BACKEND: &str = "sqlite";
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS users (
+    id TEXT PRIMARY KEY NOT NULL,
+    name TEXT NOT NULL,
+    department TEXT NOT NULL DEFAULT '',
+    pass_hash TEXT NOT NULL,
+    disabled INTEGER NOT NULL DEFAULT 0,
+    pass_changed INTEGER NOT NULL,
+    created INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS user_roles (
+    user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    role TEXT NOT NULL,
+    PRIMARY KEY (user_id, role)
+);
+";
+
+// Self contained user directory in an embedded sqlite db with argon2 password hashes
+#[derive(Clone)]
+pub struct Auth {
+    db: Arc<Mutex<Connection>>,
+    argon2: Argon2<'static>,
+    // hash verified for unknown users, so the timing does not reveal whether the user exists
+    dummy_hash: Arc<String>,
+    password_max_age: i64, // millis, 0 - never expires
+}
+
+struct Record {
+    info: UserInfo,
+    hash: String,
+}
+
+impl Auth {
+    pub fn new(path: &str, password_max_age: Duration) -> anyhow::Result<Self> {
+        Self::new_with_hasher(path, password_max_age, Argon2::default())
+    }
+
+    fn new_with_hasher(
+        path: &str,
+        password_max_age: Duration,
+        argon2: Argon2<'static>,
+    ) -> anyhow::Result<Self> {
+        tracing::debug!(path, password_max_age = ?password_max_age, "init sqlite auth");
+        if path.is_empty() {
+            return Err(anyhow::anyhow!("Empty user db path"));
+        }
+        let conn = Connection::open(path).map_err(|e| anyhow::anyhow!("can't open {path}: {e}"))?;
+        conn.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+        conn.pragma_update(None, "foreign_keys", "ON")?;
+        conn.busy_timeout(Duration::from_secs(5))?;
+        conn.execute_batch(SCHEMA)
+            .map_err(|e| anyhow::anyhow!("can't init schema: {e}"))?;
+        let dummy_hash = hash_pass(&argon2, SaltString::generate(&mut OsRng).as_str())?;
+        Ok(Auth {
+            db: Arc::new(Mutex::new(conn)),
+            argon2,
+            dummy_hash: Arc::new(dummy_hash),
+            password_max_age: password_max_age.as_millis() as i64,
+        })
+    }
+
+    // Creates the first admin user if the db has no users
+    pub async fn init_admin(
+        &self,
+        id: &str,
+        pass: SecretString,
+        role: &str,
+    ) -> Result<bool, user::Error> {
+        let count: i64 = self
+            .call(|conn| {
+                conn.query_row("SELECT COUNT(*) FROM users", [], |r| r.get(0))
+                    .map_err(db_err)
+            })
+            .await?;
+        if count > 0 {
+            return Ok(false);
+        }
+        tracing::warn!(user = id, "creating initial admin user");
+        self.create(NewUser {
+            id: id.to_string(),
+            name: id.to_string(),
+            department: "".to_string(),
+            pass,
+            roles: vec![role.to_string()],
+        })
+        .await?;
+        Ok(true)
+    }
+
+    async fn call<F, R>(&self, f: F) -> Result<R, user::Error>
+    where
+        F: FnOnce(&mut Connection) -> Result<R, user::Error> + Send + 'static,
+        R: Send + 'static,
+    {
+        let db = self.db.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut conn = db
+                .lock()
+                .map_err(|e| anyhow::anyhow!("db lock poisoned: {e}"))?;
+            f(&mut conn)
+        })
+        .await
+        .map_err(|e| anyhow::anyhow!("db task: {e}"))?
+    }
+
+    async fn hash(&self, pass: &SecretString) -> Result<String, user::Error> {
+        if pass.reveal_secret().is_empty() {
+            return Err(user::Error::Invalid("empty password".to_string()));
+        }
+        let argon2 = self.argon2.clone();
+        let pass = pass.clone();
+        Ok(
+            tokio::task::spawn_blocking(move || hash_pass(&argon2, pass.reveal_secret()))
+                .await
+                .map_err(|e| anyhow::anyhow!("hash task: {e}"))??,
+        )
+    }
+
+    async fn get_record(&self, id: &str) -> Result<Option<Record>, user::Error> {
+        let id = id.to_string();
+        self.call(move |conn| select_record(conn, &id)).await
+    }
+}
+
+fn hash_pass(argon2: &Argon2, pass: &str) -> anyhow::Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    argon2
+        .hash_password(pass.as_bytes(), &salt)
+        .map(|h| h.to_string())
+        .map_err(|e| anyhow::anyhow!("can't hash password: {e}"))
+}
+
+fn verify(argon2: &Argon2, hash: &str, pass: &str) -> bool {
+    PasswordHash::new(hash)
+        .and_then(|h| argon2.verify_password(pass.as_bytes(), &h))
+        .is_ok()
+}
+
+fn select_record(conn: &Connection, id: &str) -> Result<Option<Record>, user::Error> {
+    let record = conn
+        .query_row(
+            "SELECT id, name, department, pass_hash, disabled, pass_changed, created FROM users WHERE id = ?1",
+            params![id],
+            |r| {
+                Ok(Record {
+                    info: UserInfo {
+                        id: r.get(0)?,
+                        name: r.get(1)?,
+                        department: r.get(2)?,
+                        roles: Vec::new(),
+                        disabled: r.get(4)?,
+                        pass_changed: r.get(5)?,
+                        created: r.get(6)?,
+                    },
+                    hash: r.get(3)?,
+                })
+            },
+        )
+        .optional()
+        .map_err(db_err)?;
+    match record {
+        Some(mut record) => {
+            record.info.roles = select_roles(conn, id)?;
+            Ok(Some(record))
+        }
+        None => Ok(None),
+    }
+}
+
+fn select_roles(conn: &Connection, id: &str) -> Result<Vec<String>, user::Error> {
+    let mut stmt = conn
+        .prepare_cached("SELECT role FROM user_roles WHERE user_id = ?1 ORDER BY role")
+        .map_err(db_err)?;
+    let roles = stmt
+        .query_map(params![id], |r| r.get(0))
+        .and_then(|rows| rows.collect::<Result<Vec<String>, _>>())
+        .map_err(db_err)?;
+    Ok(roles)
+}
+
+fn insert_roles(conn: &Connection, id: &str, roles: &[String]) -> Result<(), user::Error> {
+    let mut stmt = conn
+        .prepare_cached("INSERT OR IGNORE INTO user_roles (user_id, role) VALUES (?1, ?2)")
+        .map_err(db_err)?;
+    for role in roles {
+        stmt.execute(params![id, role]).map_err(db_err)?;
+    }
+    Ok(())
+}
+
+fn clean_roles(roles: Vec<String>) -> Vec<String> {
+    roles
+        .into_iter()
+        .map(|r| r.trim().to_string())
+        .filter(|r| !r.is_empty())
+        .collect()
+}
+
+fn db_err(e: rusqlite::Error) -> user::Error {
+    match &e {
+        rusqlite::Error::SqliteFailure(err, _) if err.code == ErrorCode::ConstraintViolation => {
+            user::Error::Exists()
+        }
+        _ => user::Error::Other(anyhow::anyhow!("db error: {e}")),
+    }
+}
+
+#[async_trait]
+impl AuthService for Auth {
+    async fn login(&self, user: &str, pass: &SecretString) -> Result<auth::User, auth::Error> {
+        let record = self
+            .get_record(user)
+            .await
+            .map_err(|e| anyhow::anyhow!(e))?;
+        let hash = record
+            .as_ref()
+            .map_or_else(|| self.dummy_hash.to_string(), |r| r.hash.clone());
+        let argon2 = self.argon2.clone();
+        let pass = pass.clone();
+        let ok = tokio::task::spawn_blocking(move || verify(&argon2, &hash, pass.reveal_secret()))
+            .await
+            .map_err(|e| anyhow::anyhow!("verify task: {e}"))?;
+        let info = match record {
+            Some(record) if ok => record.info,
+            _ => return Err(auth::Error::WrongUserPass()),
+        };
+        if self.password_max_age > 0
+            && !info.disabled
+            && info.pass_changed + self.password_max_age < Utc::now().timestamp_millis()
+        {
+            return Err(auth::Error::ExpiredPass());
+        }
+        to_auth_user(info)
+    }
+
+    async fn lookup(&self, user: &str) -> Result<auth::User, auth::Error> {
+        let record = self
+            .get_record(user)
+            .await
+            .map_err(|e| anyhow::anyhow!(e))?
+            .ok_or(auth::Error::WrongUserPass())?;
+        to_auth_user(record.info)
+    }
+
+    async fn refresh_user(&self, user: &auth::User) -> Result<Option<auth::User>, auth::Error> {
+        self.lookup(&user.id).await.map(Some)
+    }
+}
+
+fn to_auth_user(info: UserInfo) -> Result<auth::User, auth::Error> {
+    if info.disabled {
+        tracing::debug!(user = info.id, "user disabled");
+        return Err(auth::Error::NoAccess());
+    }
+    if info.roles.is_empty() {
+        return Err(auth::Error::NoAccess());
+    }
+    Ok(auth::User {
+        id: info.id,
+        name: info.name,
+        department: info.department,
+        roles: info.roles,
+        backend: String::new(),
+    })
+}
+
+#[async_trait]
+impl UserAdmin for Auth {
+    async fn list(&self) -> Result<Vec<UserInfo>, user::Error> {
+        self.call(|conn| {
+            let ids: Vec<String> = conn
+                .prepare("SELECT id FROM users ORDER BY id")
+                .and_then(|mut stmt| {
+                    stmt.query_map([], |r| r.get(0))
+                        .and_then(|rows| rows.collect())
+                })
+                .map_err(db_err)?;
+            let mut res = Vec::with_capacity(ids.len());
+            for id in ids {
+                if let Some(record) = select_record(conn, &id)? {
+                    res.push(record.info);
+                }
+            }
+            Ok(res)
+        })
+        .await
+    }
+
+    async fn get(&self, id: &str) -> Result<UserInfo, user::Error> {
+        self.get_record(id)
+            .await?
+            .map(|r| r.info)
+            .ok_or(user::Error::NoUser())
+    }
+
+    async fn create(&self, user: NewUser) -> Result<(), user::Error> {
+        if user.id.trim().is_empty() {
+            return Err(user::Error::Invalid("empty id".to_string()));
+        }
+        let hash = self.hash(&user.pass).await?;
+        let now = Utc::now().timestamp_millis();
+        self.call(move |conn| {
+            let tx = conn.transaction().map_err(db_err)?;
+            tx.execute(
+                "INSERT INTO users (id, name, department, pass_hash, disabled, pass_changed, created) VALUES (?1, ?2, ?3, ?4, 0, ?5, ?5)",
+                params![user.id, user.name, user.department, hash, now],
+            )
+            .map_err(db_err)?;
+            insert_roles(&tx, &user.id, &clean_roles(user.roles))?;
+            tx.commit().map_err(db_err)
+        })
+        .await
+    }
+
+    async fn update(&self, id: &str, update: UpdateUser) -> Result<(), user::Error> {
+        let hash = match &update.pass {
+            Some(pass) => Some(self.hash(pass).await?),
+            None => None,
+        };
+        let id = id.to_string();
+        let now = Utc::now().timestamp_millis();
+        self.call(move |conn| {
+            let tx = conn.transaction().map_err(db_err)?;
+            if select_record(&tx, &id)?.is_none() {
+                return Err(user::Error::NoUser());
+            }
+            if let Some(name) = update.name {
+                tx.execute(
+                    "UPDATE users SET name = ?2 WHERE id = ?1",
+                    params![id, name],
+                )
+                .map_err(db_err)?;
+            }
+            if let Some(department) = update.department {
+                tx.execute(
+                    "UPDATE users SET department = ?2 WHERE id = ?1",
+                    params![id, department],
+                )
+                .map_err(db_err)?;
+            }
+            if let Some(hash) = hash {
+                tx.execute(
+                    "UPDATE users SET pass_hash = ?2, pass_changed = ?3 WHERE id = ?1",
+                    params![id, hash, now],
+                )
+                .map_err(db_err)?;
+            }
+            if let Some(disabled) = update.disabled {
+                tx.execute(
+                    "UPDATE users SET disabled = ?2 WHERE id = ?1",
+                    params![id, disabled],
+                )
+                .map_err(db_err)?;
+            }
+            tx.commit().map_err(db_err)
+        })
+        .await
+    }
+
+    async fn delete(&self, id: &str) -> Result<(), user::Error> {
+        let id = id.to_string();
+        self.call(move |conn| {
+            match conn
+                .execute("DELETE FROM users WHERE id = ?1", params![id])
+                .map_err(db_err)?
+            {
+                0 => Err(user::Error::NoUser()),
+                _ => Ok(()),
+            }
+        })
+        .await
+    }
+
+    async fn set_roles(&self, id: &str, roles: Vec<String>) -> Result<(), user::Error> {
+        let id = id.to_string();
+        self.call(move |conn| {
+            let tx = conn.transaction().map_err(db_err)?;
+            if select_record(&tx, &id)?.is_none() {
+                return Err(user::Error::NoUser());
+            }
+            tx.execute("DELETE FROM user_roles WHERE user_id = ?1", params![id])
+                .map_err(db_err)?;
+            insert_roles(&tx, &id, &clean_roles(roles))?;
+            tx.commit().map_err(db_err)
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use argon2::{Algorithm, Params, Version};
+    use test_case::test_case;
+
+    use super::*;
+
+    fn make_auth(password_max_age: Duration) -> Auth {
+        let argon2 = Argon2::new(
+            Algorithm::Argon2id,
+            Version::V0x13,
+            Params::new(1024, 1, 1, None).unwrap(),
+        );
+        Auth::new_with_hasher(":memory:", password_max_age, argon2).unwrap()
+    }
+
+    fn new_user(id: &str, roles: &[&str]) -> NewUser {
+        NewUser {
+            id: id.to_string(),
+            name: format!("{id} name"),
+            department: "IT".to_string(),
+            pass: format!("{id}-pass").into(),
+            roles: roles.iter().map(|r| r.to_string()).collect(),
+        }
+    }
+
+    fn err_str<T>(res: Result<T, auth::Error>) -> String {
+        res.err().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn test_login() {
+        let auth = make_auth(Duration::ZERO);
+        auth.create(new_user("olia", &["USER", " ADMIN ", ""]))
+            .await
+            .unwrap();
+        let res = auth.login("olia", &"olia-pass".into()).await.unwrap();
+        assert_eq!(
+            res,
+            auth::User {
+                id: "olia".to_string(),
+                name: "olia name".to_string(),
+                department: "IT".to_string(),
+                roles: vec!["ADMIN".to_string(), "USER".to_string()],
+                backend: String::new(),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_lookup() {
+        let auth = make_auth(Duration::from_millis(1));
+        auth.create(new_user("olia", &["USER"])).await.unwrap();
+        auth.create(new_user("jonas", &[])).await.unwrap();
+        // password age does not matter without a password
+        assert_eq!(auth.lookup("olia").await.unwrap().name, "olia name");
+        assert_eq!(
+            err_str(auth.lookup("jonas").await),
+            auth::Error::NoAccess().to_string()
+        );
+        assert_eq!(
+            err_str(auth.lookup("nobody").await),
+            auth::Error::WrongUserPass().to_string()
+        );
+    }
+
+    #[test_case("olia", "wrong"; "wrong pass")]
+    #[test_case("olia", ""; "empty pass")]
+    #[test_case("nobody", "olia-pass"; "no user")]
+    #[tokio::test]
+    async fn test_login_fail(user: &str, pass: &str) {
+        let auth = make_auth(Duration::ZERO);
+        auth.create(new_user("olia", &["USER"])).await.unwrap();
+        let res = auth.login(user, &pass.into()).await;
+        assert_eq!(err_str(res), auth::Error::WrongUserPass().to_string());
+    }
+
+    #[tokio::test]
+    async fn test_login_disabled() {
+        let auth = make_auth(Duration::ZERO);
+        auth.create(new_user("olia", &["USER"])).await.unwrap();
+        let update = UpdateUser {
+            disabled: Some(true),
+            ..Default::default()
+        };
+        auth.update("olia", update).await.unwrap();
+        let res = auth.login("olia", &"olia-pass".into()).await;
+        assert_eq!(err_str(res), auth::Error::NoAccess().to_string());
+        // wrong password does not reveal the disabled state
+        let res = auth.login("olia", &"wrong".into()).await;
+        assert_eq!(err_str(res), auth::Error::WrongUserPass().to_string());
+    }
+
+    #[tokio::test]
+    async fn test_login_no_roles() {
+        let auth = make_auth(Duration::ZERO);
+        auth.create(new_user("olia", &[])).await.unwrap();
+        let res = auth.login("olia", &"olia-pass".into()).await;
+        assert_eq!(err_str(res), auth::Error::NoAccess().to_string());
+        auth.set_roles("olia", vec!["USER".to_string()])
+            .await
+            .unwrap();
+        assert!(auth.login("olia", &"olia-pass".into()).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_login_expired() {
+        let auth = make_auth(Duration::from_millis(50));
+        auth.create(new_user("olia", &["USER"])).await.unwrap();
+        assert!(auth.login("olia", &"olia-pass".into()).await.is_ok());
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        let res = auth.login("olia", &"olia-pass".into()).await;
+        assert_eq!(err_str(res), auth::Error::ExpiredPass().to_string());
+
+        let update = UpdateUser {
+            pass: Some("new-pass".into()),
+            ..Default::default()
+        };
+        auth.update("olia", update).await.unwrap();
+        assert!(auth.login("olia", &"new-pass".into()).await.is_ok());
+        assert!(auth.login("olia", &"olia-pass".into()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_admin() {
+        let auth = make_auth(Duration::ZERO);
+        auth.create(new_user("vv", &["USER"])).await.unwrap();
+        auth.create(new_user("olia", &["USER"])).await.unwrap();
+        assert!(matches!(
+            auth.create(new_user("olia", &["USER"])).await,
+            Err(user::Error::Exists())
+        ));
+        assert!(matches!(
+            auth.create(new_user(" ", &["USER"])).await,
+            Err(user::Error::Invalid(_))
+        ));
+
+        let update = UpdateUser {
+            name: Some("Olia".to_string()),
+            department: Some("HR".to_string()),
+            ..Default::default()
+        };
+        auth.update("olia", update).await.unwrap();
+        auth.set_roles("olia", vec!["B".to_string(), "A".to_string()])
+            .await
+            .unwrap();
+        let res = auth.get("olia").await.unwrap();
+        assert_eq!(res.name, "Olia");
+        assert_eq!(res.department, "HR");
+        assert_eq!(res.roles, vec!["A".to_string(), "B".to_string()]);
+        assert!(!res.disabled);
+
+        let ids: Vec<String> = auth
+            .list()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|u| u.id)
+            .collect();
+        assert_eq!(ids, vec!["olia".to_string(), "vv".to_string()]);
+
+        auth.delete("olia").await.unwrap();
+        assert!(matches!(auth.get("olia").await, Err(user::Error::NoUser())));
+        assert!(matches!(
+            auth.delete("olia").await,
+            Err(user::Error::NoUser())
+        ));
+        assert!(matches!(
+            auth.set_roles("olia", vec![]).await,
+            Err(user::Error::NoUser())
+        ));
+        assert!(matches!(
+            auth.update("olia", UpdateUser::default()).await,
+            Err(user::Error::NoUser())
+        ));
+        assert_eq!(auth.list().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_init_admin() {
+        let auth = make_auth(Duration::ZERO);
+        assert!(auth
+            .init_admin("admin", "pass".into(), "ADM")
+            .await
+            .unwrap());
+        assert!(!auth
+            .init_admin("admin2", "pass".into(), "ADM")
+            .await
+            .unwrap());
+        let res = auth.login("admin", &"pass".into()).await.unwrap();
+        assert_eq!(res.roles, vec!["ADM".to_string()]);
+    }
+}
@@ -0,0 +1,745 @@
+use std::time::Duration;
+
+use base32::Alphabet;
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    model::{auth, mfa::Error},
+    utils::secret_str::ct_eq,
+    Encryptor, KeyValueStore,
+};
+
+const DIGITS: u32 = 6;
+const PERIOD_SECS: i64 = 30;
+// accepted clock drift in periods
+const SKEW: i64 = 1;
+const SECRET_LEN: usize = 20;
+const RECOVERY_CODES: usize = 10;
+const MAX_FAILURES: u32 = 5;
+const LOCK_MS: i64 = 5 * 60 * 1000;
+// compare and set attempts of a state update under contention
+const MAX_UPDATE_TRIES: usize = 10;
+const CHALLENGE_TTL: Duration = Duration::from_secs(5 * 60);
+const STATE_PREFIX: &str = "mfa:";
+const CHALLENGE_PREFIX: &str = "mfa-challenge:";
+
+// Per user MFA state, stored encrypted
+#[derive(Debug, Serialize, Deserialize, Default)]
+struct State {
+    secret: String,
+    confirmed: bool,
+    // sha256 of unused recovery codes
+    recovery: Vec<String>,
+    // last accepted time step, a code can't be used twice
+    last_step: i64,
+    failures: u32,
+    locked_till: i64,
+}
+
+// Login waiting for the second factor
+#[derive(Debug, Serialize, Deserialize)]
+struct Challenge {
+    user: auth::User,
+    ip: String,
+    attempts: u32,
+}
+
+pub struct Enrollment {
+    pub secret: String,
+    pub uri: String,
+}
+
+// RFC 6238 TOTP second factor with single use recovery codes
+pub struct Mfa {
+    store: Box<dyn KeyValueStore + Send + Sync>,
+    encryptor: Box<dyn Encryptor + Send + Sync>,
+    issuer: String,
+}
+
+impl Mfa {
+    pub fn new(
+        store: Box<dyn KeyValueStore + Send + Sync>,
+        encryptor: Box<dyn Encryptor + Send + Sync>,
+        issuer: &str,
+    ) -> Self {
+        tracing::debug!(issuer, "init totp mfa");
+        Mfa {
+            store,
+            encryptor,
+            issuer: issuer.to_string(),
+        }
+    }
+
+    pub async fn is_enrolled(&self, user_id: &str) -> Result<bool, Error> {
+        Ok(self.load(user_id).await?.is_some_and(|s| s.confirmed))
+    }
+
+    // Stores the authenticated user until the second factor is verified, returns the challenge token
+    pub async fn challenge(&self, user: &auth::User, ip: &str) -> Result<String, Error> {
+        let token = random_token();
+        let challenge = Challenge {
+            user: user.clone(),
+            ip: ip.to_string(),
+            attempts: 0,
+        };
+        self.set_encrypted(&self.challenge_key(&token), &challenge, Some(CHALLENGE_TTL))
+            .await?;
+        Ok(token)
+    }
+
+    // Verifies a TOTP code or a recovery code for the challenge and returns the user
+    pub async fn verify_challenge(
+        &self,
+        token: &str,
+        ip: &str,
+        code: Option<&str>,
+        recovery_code: Option<&str>,
+        now: i64,
+    ) -> Result<auth::User, Error> {
+        let challenge = self.load_challenge(token, ip).await?;
+        match self
+            .check_code(&challenge.user.id, code, recovery_code, now)
+            .await
+        {
+            Ok(()) => self.take_challenge(token, ip).await,
+            Err(Error::WrongCode()) => {
+                self.update(
+                    &self.challenge_key(token),
+                    Some(CHALLENGE_TTL),
+                    |challenge: Option<Challenge>| {
+                        let mut challenge = challenge.ok_or(Error::NoChallenge())?;
+                        challenge.attempts += 1;
+                        if challenge.attempts >= MAX_FAILURES {
+                            return Ok((None, ()));
+                        }
+                        Ok((Some(challenge), ()))
+                    },
+                )
+                .await?;
+                Err(Error::WrongCode())
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    // Returns the user waiting for the second factor
+    pub async fn peek_challenge(&self, token: &str, ip: &str) -> Result<auth::User, Error> {
+        Ok(self.load_challenge(token, ip).await?.user)
+    }
+
+    // Completes the challenge after the user passed another second factor
+    pub async fn take_challenge(&self, token: &str, ip: &str) -> Result<auth::User, Error> {
+        self.load_challenge(token, ip).await?;
+        // only one of concurrent requests gets the user
+        self.update(
+            &self.challenge_key(token),
+            None,
+            |challenge: Option<Challenge>| {
+                let challenge = challenge.ok_or(Error::NoChallenge())?;
+                Ok((None, challenge.user))
+            },
+        )
+        .await
+    }
+
+    async fn load_challenge(&self, token: &str, ip: &str) -> Result<Challenge, Error> {
+        let challenge: Challenge = match self.get_decrypted(&self.challenge_key(token)).await? {
+            Some(challenge) => challenge,
+            None => return Err(Error::NoChallenge()),
+        };
+        if challenge.ip != ip {
+            tracing::warn!(user = challenge.user.id, ip, "mfa challenge ip mismatch");
+            return Err(Error::NoChallenge());
+        }
+        Ok(challenge)
+    }
+
+    // Generates a new secret, it is activated after `confirm`
+    pub async fn enroll(&self, user_id: &str) -> Result<Enrollment, Error> {
+        let secret = self
+            .update(&self.state_key(user_id), None, |state: Option<State>| {
+                if state.is_some_and(|s| s.confirmed) {
+                    return Err(Error::AlreadyEnrolled());
+                }
+                let mut secret = [0u8; SECRET_LEN];
+                OsRng.fill_bytes(&mut secret);
+                let secret = base32::encode(Alphabet::Rfc4648 { padding: false }, &secret);
+                let state = State {
+                    secret: secret.clone(),
+                    ..Default::default()
+                };
+                Ok((Some(state), secret))
+            })
+            .await?;
+        Ok(Enrollment {
+            uri: make_uri(&self.issuer, user_id, &secret),
+            secret,
+        })
+    }
+
+    // Activates TOTP after the user proves the authenticator works, returns recovery codes
+    pub async fn confirm(&self, user_id: &str, code: &str, now: i64) -> Result<Vec<String>, Error> {
+        let codes = self
+            .update(&self.state_key(user_id), None, |state: Option<State>| {
+                let mut state = match state {
+                    Some(state) if state.confirmed => return Err(Error::AlreadyEnrolled()),
+                    Some(state) => state,
+                    None => return Err(Error::NotEnrolled()),
+                };
+                let step = verify_totp(&state.secret, code, now, state.last_step)?
+                    .ok_or(Error::WrongCode())?;
+                let codes: Vec<String> = (0..RECOVERY_CODES).map(|_| recovery_code()).collect();
+                state.recovery = codes.iter().map(|c| hash_recovery(c)).collect();
+                state.confirmed = true;
+                state.last_step = step;
+                Ok((Some(state), codes))
+            })
+            .await?;
+        tracing::info!(user = user_id, "mfa enrolled");
+        Ok(codes)
+    }
+
+    pub async fn disable(&self, user_id: &str, code: &str, now: i64) -> Result<(), Error> {
+        let ok = self
+            .update(&self.state_key(user_id), None, |state: Option<State>| {
+                let mut state = enrolled_state(state)?;
+                let ok = check(&mut state, user_id, Some(code), None, now)?;
+                // the failures are kept, the state goes only with a valid code
+                Ok((Some(state).filter(|_| !ok), ok))
+            })
+            .await?;
+        if !ok {
+            return Err(Error::WrongCode());
+        }
+        tracing::info!(user = user_id, "mfa disabled");
+        Ok(())
+    }
+
+    async fn check_code(
+        &self,
+        user_id: &str,
+        code: Option<&str>,
+        recovery_code: Option<&str>,
+        now: i64,
+    ) -> Result<(), Error> {
+        let ok = self
+            .update(&self.state_key(user_id), None, |state: Option<State>| {
+                let mut state = enrolled_state(state)?;
+                let ok = check(&mut state, user_id, code, recovery_code, now)?;
+                Ok((Some(state), ok))
+            })
+            .await?;
+        if ok {
+            Ok(())
+        } else {
+            Err(Error::WrongCode())
+        }
+    }
+
+    fn state_key(&self, user_id: &str) -> String {
+        format!("{STATE_PREFIX}{}", self.encryptor.encrypt(user_id))
+    }
+
+    fn challenge_key(&self, token: &str) -> String {
+        format!("{CHALLENGE_PREFIX}{}", self.encryptor.encrypt(token))
+    }
+
+    async fn load(&self, user_id: &str) -> Result<Option<State>, Error> {
+        self.get_decrypted(&self.state_key(user_id)).await
+    }
+
+    // Read-check-write of a value: `f` gets the stored value and returns the new one (None -
+    // remove) with the result, an error leaves the value as is. The write is compare and set,
+    // so `f` runs again if a concurrent request changed the value
+    async fn update<T, R>(
+        &self,
+        key: &str,
+        ttl: Option<Duration>,
+        f: impl Fn(Option<T>) -> Result<(Option<T>, R), Error>,
+    ) -> Result<R, Error>
+    where
+        T: Serialize + for<'de> Deserialize<'de>,
+    {
+        for _ in 0..MAX_UPDATE_TRIES {
+            let current = self
+                .store
+                .get(key)
+                .await
+                .map_err(|e| anyhow::anyhow!("mfa store get: {e}"))?;
+            let value = current.as_deref().map(|v| self.decode(v)).transpose()?;
+            let (value, res) = f(value)?;
+            let value = value.map(|v| self.encode(&v)).transpose()?;
+            if self
+                .store
+                .compare_and_set(key, current.as_deref(), value.as_deref(), ttl)
+                .await
+                .map_err(|e| anyhow::anyhow!("mfa store compare and set: {e}"))?
+            {
+                return Ok(res);
+            }
+            tracing::debug!("mfa value changed concurrently, retrying");
+        }
+        Err(anyhow::anyhow!("mfa value keeps changing concurrently").into())
+    }
+
+    async fn get_decrypted<T: for<'de> Deserialize<'de>>(
+        &self,
+        key: &str,
+    ) -> Result<Option<T>, Error> {
+        let value = self
+            .store
+            .get(key)
+            .await
+            .map_err(|e| anyhow::anyhow!("mfa store get: {e}"))?;
+        value.map(|value| self.decode(&value)).transpose()
+    }
+
+    async fn set_encrypted<T: Serialize>(
+        &self,
+        key: &str,
+        value: &T,
+        ttl: Option<Duration>,
+    ) -> Result<(), Error> {
+        self.store
+            .set(key, &self.encode(value)?, ttl)
+            .await
+            .map_err(|e| anyhow::anyhow!("mfa store set: {e}"))?;
+        Ok(())
+    }
+
+    fn decode<T: for<'de> Deserialize<'de>>(&self, value: &str) -> Result<T, Error> {
+        let value = self.encryptor.decrypt(value)?;
+        Ok(serde_json::from_str(&value)
+            .map_err(|e| anyhow::anyhow!("Deserialization error: {:?}", e))?)
+    }
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<String, Error> {
+        let value = serde_json::to_string(value)
+            .map_err(|e| anyhow::anyhow!("Serialization error: {:?}", e))?;
+        Ok(self.encryptor.encrypt(&value))
+    }
+}
+
+fn enrolled_state(state: Option<State>) -> Result<State, Error> {
+    match state {
+        Some(state) if state.confirmed => Ok(state),
+        _ => Err(Error::NotEnrolled()),
+    }
+}
+
+// Checks a code against the state and records the outcome in it, false - a wrong code
+fn check(
+    state: &mut State,
+    user_id: &str,
+    code: Option<&str>,
+    recovery_code: Option<&str>,
+    now: i64,
+) -> Result<bool, Error> {
+    if state.locked_till > now {
+        return Err(Error::TooManyAttempts());
+    }
+    let ok = match (code, recovery_code) {
+        (Some(code), _) => match verify_totp(&state.secret, code, now, state.last_step)? {
+            Some(step) => {
+                state.last_step = step;
+                true
+            }
+            None => false,
+        },
+        (None, Some(recovery_code)) => {
+            let hash = hash_recovery(recovery_code);
+            let len = state.recovery.len();
+            state
+                .recovery
+                .retain(|h| !ct_eq(h.as_bytes(), hash.as_bytes()));
+            if state.recovery.len() < len {
+                tracing::info!(
+                    user = user_id,
+                    left = state.recovery.len(),
+                    "recovery code used"
+                );
+            }
+            state.recovery.len() < len
+        }
+        (None, None) => false,
+    };
+    if ok {
+        state.failures = 0;
+    } else {
+        state.failures += 1;
+        if state.failures >= MAX_FAILURES {
+            tracing::warn!(user = user_id, "too many mfa failures, locking");
+            state.failures = 0;
+            state.locked_till = now + LOCK_MS;
+        }
+    }
+    Ok(ok)
+}
+
+// RFC 4226 HOTP value
+fn hotp(secret: &[u8], counter: u64, digits: u32) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("hmac accepts any key size");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let value = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    value % 10u32.pow(digits)
+}
+
+// Returns the matched time step, only steps after `last_step` are accepted
+fn verify_totp(secret: &str, code: &str, now: i64, last_step: i64) -> Result<Option<i64>, Error> {
+    let code = code.trim();
+    if code.len() != DIGITS as usize || !code.chars().all(|c| c.is_ascii_digit()) {
+        return Ok(None);
+    }
+    let secret = base32::decode(Alphabet::Rfc4648 { padding: false }, secret)
+        .ok_or_else(|| anyhow::anyhow!("invalid totp secret"))?;
+    let current = now / 1000 / PERIOD_SECS;
+    let mut res = None;
+    for step in (current - SKEW)..=(current + SKEW) {
+        let expected = format!(
+            "{:0width$}",
+            hotp(&secret, step as u64, DIGITS),
+            width = DIGITS as usize
+        );
+        if ct_eq(expected.as_bytes(), code.as_bytes()) && step > last_step {
+            res = Some(step);
+        }
+    }
+    Ok(res)
+}
+
+fn make_uri(issuer: &str, user_id: &str, secret: &str) -> String {
+    format!(
+        "otpauth://totp/{}:{}?secret={secret}&issuer={}&algorithm=SHA1&digits={DIGITS}&period={PERIOD_SECS}",
+        urlencoding::encode(issuer),
+        urlencoding::encode(user_id),
+        urlencoding::encode(issuer),
+    )
+}
+
+fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    BASE64_URL_SAFE_NO_PAD.encode(bytes)
+}
+
+fn recovery_code() -> String {
+    let mut bytes = [0u8; 8];
+    OsRng.fill_bytes(&mut bytes);
+    let code = base32::encode(Alphabet::Rfc4648Lower { padding: false }, &bytes);
+    format!("{}-{}", &code[..5], &code[5..10])
+}
+
+fn hash_recovery(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    format!("{:x}", Sha256::digest(normalized.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{
+        encryptor::MagicEncryptor,
+        memory::{InMemoryKeyValueStore, YieldingKeyValueStore},
+    };
+    use test_case::test_case;
+
+    const NOW: i64 = 1_700_000_000_000;
+
+    fn make_mfa() -> Mfa {
+        Mfa::new(
+            Box::new(InMemoryKeyValueStore::new()),
+            Box::new(MagicEncryptor::new("1234567890123456").unwrap()),
+            "authware test",
+        )
+    }
+
+    fn code(secret: &str, at: i64) -> String {
+        let secret = base32::decode(Alphabet::Rfc4648 { padding: false }, secret).unwrap();
+        format!(
+            "{:06}",
+            hotp(&secret, (at / 1000 / PERIOD_SECS) as u64, DIGITS)
+        )
+    }
+
+    fn user() -> auth::User {
+        auth::User {
+            id: "olia".to_string(),
+            name: "Olia".to_string(),
+            department: "IT".to_string(),
+            roles: vec!["USER".to_string()],
+            backend: String::new(),
+        }
+    }
+
+    async fn enrolled(mfa: &Mfa) -> (String, Vec<String>) {
+        let enrollment = mfa.enroll("olia").await.unwrap();
+        let codes = mfa
+            .confirm("olia", &code(&enrollment.secret, NOW), NOW)
+            .await
+            .unwrap();
+        (enrollment.secret, codes)
+    }
+
+    // RFC 6238 appendix B, SHA1
+    #[test_case(59, 94287082)]
+    #[test_case(1111111109, 7081804)]
+    #[test_case(1234567890, 89005924)]
+    #[test_case(20000000000, 65353130)]
+    fn test_hotp(time: u64, expected: u32) {
+        assert_eq!(hotp(b"12345678901234567890", time / 30, 8), expected);
+    }
+
+    #[test]
+    fn test_verify_totp() {
+        let secret = base32::encode(
+            Alphabet::Rfc4648 { padding: false },
+            b"12345678901234567890",
+        );
+        let now = 59_000;
+        assert_eq!(verify_totp(&secret, "287082", now, 0).unwrap(), Some(1));
+        assert_eq!(verify_totp(&secret, " 287082 ", now, 0).unwrap(), Some(1));
+        // next period still accepts the previous code
+        assert_eq!(
+            verify_totp(&secret, "287082", now + 30_000, 0).unwrap(),
+            Some(1)
+        );
+        assert_eq!(
+            verify_totp(&secret, "287082", now + 60_000, 0).unwrap(),
+            None
+        );
+        // replay
+        assert_eq!(verify_totp(&secret, "287082", now, 1).unwrap(), None);
+        assert_eq!(verify_totp(&secret, "287083", now, 0).unwrap(), None);
+        assert_eq!(verify_totp(&secret, "28708", now, 0).unwrap(), None);
+        assert_eq!(verify_totp(&secret, "", now, 0).unwrap(), None);
+    }
+
+    #[test]
+    fn test_uri() {
+        assert_eq!(
+            make_uri("authware test", "olia@corp", "ABC"),
+            "otpauth://totp/authware%20test:olia%40corp?secret=ABC&issuer=authware%20test&algorithm=SHA1&digits=6&period=30"
+        );
+    }
+
+    #[test_case("abcde-fghij", "ABCDEFGHIJ"; "upper")]
+    #[test_case("abcde-fghij", " abcde fghij "; "spaces")]
+    fn test_hash_recovery(code: &str, input: &str) {
+        assert_eq!(hash_recovery(code), hash_recovery(input));
+    }
+
+    #[tokio::test]
+    async fn test_enroll() {
+        let mfa = make_mfa();
+        assert!(!mfa.is_enrolled("olia").await.unwrap());
+        let enrollment = mfa.enroll("olia").await.unwrap();
+        assert!(enrollment.uri.contains(&enrollment.secret));
+        assert!(!mfa.is_enrolled("olia").await.unwrap());
+        assert!(matches!(
+            mfa.confirm("olia", "000000", NOW).await,
+            Err(Error::WrongCode())
+        ));
+        let codes = mfa
+            .confirm("olia", &code(&enrollment.secret, NOW), NOW)
+            .await
+            .unwrap();
+        assert_eq!(codes.len(), RECOVERY_CODES);
+        assert!(mfa.is_enrolled("olia").await.unwrap());
+        assert!(matches!(
+            mfa.enroll("olia").await,
+            Err(Error::AlreadyEnrolled())
+        ));
+        assert!(matches!(
+            mfa.confirm("other", "000000", NOW).await,
+            Err(Error::NotEnrolled())
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_challenge() {
+        let mfa = make_mfa();
+        let (secret, _) = enrolled(&mfa).await;
+        let now = NOW + 60_000;
+        let token = mfa.challenge(&user(), "1.1.1.1").await.unwrap();
+        assert!(matches!(
+            mfa.verify_challenge(&token, "2.2.2.2", Some(&code(&secret, now)), None, now)
+                .await,
+            Err(Error::NoChallenge())
+        ));
+        assert!(matches!(
+            mfa.verify_challenge(&token, "1.1.1.1", Some("000000"), None, now)
+                .await,
+            Err(Error::WrongCode())
+        ));
+        let res = mfa
+            .verify_challenge(&token, "1.1.1.1", Some(&code(&secret, now)), None, now)
+            .await
+            .unwrap();
+        assert_eq!(res, user());
+        // challenge is single use
+        assert!(matches!(
+            mfa.verify_challenge(&token, "1.1.1.1", Some(&code(&secret, now)), None, now)
+                .await,
+            Err(Error::NoChallenge())
+        ));
+        // code is single use
+        let token = mfa.challenge(&user(), "1.1.1.1").await.unwrap();
+        assert!(matches!(
+            mfa.verify_challenge(&token, "1.1.1.1", Some(&code(&secret, now)), None, now)
+                .await,
+            Err(Error::WrongCode())
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_take_challenge() {
+        let mfa = make_mfa();
+        let token = mfa.challenge(&user(), "1.1.1.1").await.unwrap();
+        assert!(matches!(
+            mfa.peek_challenge(&token, "2.2.2.2").await,
+            Err(Error::NoChallenge())
+        ));
+        assert_eq!(mfa.peek_challenge(&token, "1.1.1.1").await.unwrap(), user());
+        assert_eq!(mfa.take_challenge(&token, "1.1.1.1").await.unwrap(), user());
+        assert!(matches!(
+            mfa.take_challenge(&token, "1.1.1.1").await,
+            Err(Error::NoChallenge())
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_recovery_code() {
+        let mfa = make_mfa();
+        let (_, codes) = enrolled(&mfa).await;
+        let token = mfa.challenge(&user(), "").await.unwrap();
+        assert!(mfa
+            .verify_challenge(&token, "", None, Some(&codes[3].to_uppercase()), NOW)
+            .await
+            .is_ok());
+        let token = mfa.challenge(&user(), "").await.unwrap();
+        assert!(matches!(
+            mfa.verify_challenge(&token, "", None, Some(&codes[3]), NOW)
+                .await,
+            Err(Error::WrongCode())
+        ));
+        assert!(mfa
+            .verify_challenge(&token, "", None, Some(&codes[4]), NOW)
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_lock() {
+        let mfa = make_mfa();
+        let (secret, _) = enrolled(&mfa).await;
+        let now = NOW + 60_000;
+        for _ in 0..MAX_FAILURES {
+            let token = mfa.challenge(&user(), "").await.unwrap();
+            let res = mfa
+                .verify_challenge(&token, "", Some("000000"), None, now)
+                .await;
+            assert!(matches!(res, Err(Error::WrongCode())));
+        }
+        let token = mfa.challenge(&user(), "").await.unwrap();
+        assert!(matches!(
+            mfa.verify_challenge(&token, "", Some(&code(&secret, now)), None, now)
+                .await,
+            Err(Error::TooManyAttempts())
+        ));
+        let now = now + LOCK_MS + 1;
+        let res = mfa
+            .verify_challenge(&token, "", Some(&code(&secret, now)), None, now)
+            .await;
+        assert!(res.is_ok(), "{:?}", res.err());
+    }
+
+    #[tokio::test]
+    async fn test_challenge_attempts() {
+        let mfa = make_mfa();
+        enrolled(&mfa).await;
+        let token = mfa.challenge(&user(), "").await.unwrap();
+        for _ in 0..MAX_FAILURES {
+            let res = mfa
+                .verify_challenge(&token, "", None, Some("wrong"), NOW)
+                .await;
+            assert!(matches!(res, Err(Error::WrongCode())));
+        }
+        assert!(matches!(
+            mfa.verify_challenge(&token, "", None, Some("wrong"), NOW)
+                .await,
+            Err(Error::NoChallenge())
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_disable() {
+        let mfa = make_mfa();
+        let (secret, _) = enrolled(&mfa).await;
+        let now = NOW + 60_000;
+        assert!(mfa.disable("olia", "000000", now).await.is_err());
+        mfa.disable("olia", &code(&secret, now), now).await.unwrap();
+        assert!(!mfa.is_enrolled("olia").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_code_used_once() {
+        let mfa = Mfa::new(
+            Box::new(YieldingKeyValueStore::new()),
+            Box::new(MagicEncryptor::new("1234567890123456").unwrap()),
+            "authware test",
+        );
+        let (secret, codes) = enrolled(&mfa).await;
+        let now = NOW + 60_000;
+        let code = code(&secret, now);
+        let mut tokens = vec![];
+        for _ in 0..3 {
+            tokens.push(mfa.challenge(&user(), "").await.unwrap());
+        }
+        let res = futures::future::join_all(
+            tokens
+                .iter()
+                .map(|token| mfa.verify_challenge(token, "", Some(&code), None, now)),
+        )
+        .await;
+        assert_eq!(res.iter().filter(|res| res.is_ok()).count(), 1);
+
+        let res = futures::future::join_all(
+            tokens
+                .iter()
+                .map(|token| mfa.verify_challenge(token, "", None, Some(&codes[0]), now)),
+        )
+        .await;
+        assert_eq!(res.iter().filter(|res| res.is_ok()).count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_take_challenge() {
+        let mfa = Mfa::new(
+            Box::new(YieldingKeyValueStore::new()),
+            Box::new(MagicEncryptor::new("1234567890123456").unwrap()),
+            "authware test",
+        );
+        let token = mfa.challenge(&user(), "").await.unwrap();
+        let (a, b) = tokio::join!(
+            mfa.take_challenge(&token, ""),
+            mfa.take_challenge(&token, "")
+        );
+        assert!(a.is_ok() != b.is_ok());
+    }
+}
@@ -0,0 +1,816 @@
+use std::time::Duration;
+
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+use ciborium::Value;
+use p256::ecdsa::{signature::Verifier, Signature, VerifyingKey};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    model::{
+        auth,
+        webauthn::{Credential, Error},
+    },
+    CredentialStore, Encryptor, KeyValueStore,
+};
+
+const CHALLENGE_PREFIX: &str = "webauthn-challenge:";
+// ES256 in COSE
+const ALG_ES256: i64 = -7;
+const FLAG_UP: u8 = 0x01;
+const FLAG_UV: u8 = 0x04;
+const FLAG_AT: u8 = 0x40;
+
+pub struct Config {
+    pub rp_id: String,
+    pub rp_name: String,
+    // allowed origins of the pages running the ceremonies
+    pub origins: Vec<String>,
+    pub timeout: Duration,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Ceremony {
+    Register {
+        user: auth::User,
+    },
+    Login {
+        user_id: Option<String>,
+        // set when the passkey is used as a second factor
+        mfa_token: Option<String>,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Challenge {
+    ceremony: Ceremony,
+    ip: String,
+}
+
+// Browser's PublicKeyCredential with binary fields encoded as base64 url-safe
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PublicKeyCredential {
+    pub id: String,
+    pub response: AuthenticatorResponse,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthenticatorResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    pub attestation_object: Option<String>,
+    pub authenticator_data: Option<String>,
+    pub signature: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    kind: String,
+    challenge: String,
+    origin: String,
+}
+
+pub struct LoginResult {
+    pub credential: Credential,
+    pub mfa_token: Option<String>,
+}
+
+struct AuthData {
+    rp_id_hash: Vec<u8>,
+    flags: u8,
+    sign_count: u32,
+    // credential id and SEC1 public key, present on registration
+    attested: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+// WebAuthn registration and assertion ceremonies for ES256 passkeys
+pub struct WebAuthn {
+    config: Config,
+    rp_id_hash: Vec<u8>,
+    credentials: Box<dyn CredentialStore + Send + Sync>,
+    store: Box<dyn KeyValueStore + Send + Sync>,
+    encryptor: Box<dyn Encryptor + Send + Sync>,
+}
+
+impl WebAuthn {
+    pub fn new(
+        config: Config,
+        credentials: Box<dyn CredentialStore + Send + Sync>,
+        store: Box<dyn KeyValueStore + Send + Sync>,
+        encryptor: Box<dyn Encryptor + Send + Sync>,
+    ) -> anyhow::Result<Self> {
+        if config.rp_id.is_empty() {
+            return Err(anyhow::anyhow!("no webauthn rp id"));
+        }
+        if config.origins.is_empty() {
+            return Err(anyhow::anyhow!("no webauthn origins"));
+        }
+        tracing::debug!(rp_id = config.rp_id, origins = ?config.origins, "init webauthn");
+        Ok(WebAuthn {
+            rp_id_hash: Sha256::digest(config.rp_id.as_bytes()).to_vec(),
+            config,
+            credentials,
+            store,
+            encryptor,
+        })
+    }
+
+    pub async fn has_credentials(&self, user_id: &str) -> Result<bool, Error> {
+        Ok(!self.credentials.list(user_id).await?.is_empty())
+    }
+
+    pub async fn list_credentials(&self, user_id: &str) -> Result<Vec<Credential>, Error> {
+        self.credentials.list(user_id).await
+    }
+
+    // Removes the user's credential, other users' credentials are unknown
+    pub async fn remove_credential(&self, user_id: &str, id: &str) -> Result<(), Error> {
+        match self.credentials.get(id).await? {
+            Some(credential) if credential.user_id == user_id => {
+                self.credentials.remove(id).await?;
+                tracing::info!(user = user_id, "webauthn credential removed");
+                Ok(())
+            }
+            _ => Err(Error::NoCredential()),
+        }
+    }
+
+    // Returns PublicKeyCredentialCreationOptions for navigator.credentials.create
+    pub async fn start_register(
+        &self,
+        user: &auth::User,
+        ip: &str,
+    ) -> Result<serde_json::Value, Error> {
+        let exclude: Vec<_> = self
+            .credentials
+            .list(&user.id)
+            .await?
+            .into_iter()
+            .map(|c| json!({"type": "public-key", "id": c.id}))
+            .collect();
+        let challenge = self
+            .save_challenge(Ceremony::Register { user: user.clone() }, ip)
+            .await?;
+        Ok(json!({
+            "publicKey": {
+                "challenge": challenge,
+                "rp": {"id": self.config.rp_id, "name": self.config.rp_name},
+                "user": {
+                    "id": BASE64_URL_SAFE_NO_PAD.encode(user.id.as_bytes()),
+                    "name": user.id,
+                    "displayName": user.name,
+                },
+                "pubKeyCredParams": [{"type": "public-key", "alg": ALG_ES256}],
+                "timeout": self.config.timeout.as_millis() as u64,
+                "attestation": "none",
+                "excludeCredentials": exclude,
+                "authenticatorSelection": {
+                    "residentKey": "preferred",
+                    "userVerification": "preferred",
+                },
+            }
+        }))
+    }
+
+    pub async fn finish_register(
+        &self,
+        user_id: &str,
+        ip: &str,
+        credential: &PublicKeyCredential,
+        now: i64,
+    ) -> Result<Credential, Error> {
+        let client_data_json = decode(&credential.response.client_data_json)?;
+        let challenge = self.check_client_data(&client_data_json, "webauthn.create", ip)?;
+        let user = match self.take_challenge(&challenge, ip).await? {
+            Ceremony::Register { user } if user.id == user_id => user,
+            _ => return Err(Error::NoChallenge()),
+        };
+        let attestation = decode(
+            credential
+                .response
+                .attestation_object
+                .as_deref()
+                .ok_or_else(|| Error::Invalid("no attestation object".to_string()))?,
+        )?;
+        // attestation statement is not verified, authenticators are not restricted by vendor
+        let auth_data = parse_attestation(&attestation)?;
+        let auth_data = self.check_auth_data(&auth_data, false)?;
+        let (id, public_key) = auth_data
+            .attested
+            .ok_or_else(|| Error::Invalid("no attested credential".to_string()))?;
+        let res = Credential {
+            id: BASE64_URL_SAFE_NO_PAD.encode(id),
+            user_id: user.id,
+            public_key,
+            sign_count: auth_data.sign_count,
+            created: now,
+        };
+        self.credentials.add(res.clone()).await?;
+        tracing::info!(user = user_id, "webauthn credential registered");
+        Ok(res)
+    }
+
+    // Returns PublicKeyCredentialRequestOptions for navigator.credentials.get
+    pub async fn start_login(
+        &self,
+        user_id: Option<&str>,
+        mfa_token: Option<String>,
+        ip: &str,
+    ) -> Result<serde_json::Value, Error> {
+        let allow: Vec<_> = match user_id {
+            Some(user_id) => self
+                .credentials
+                .list(user_id)
+                .await?
+                .into_iter()
+                .map(|c| json!({"type": "public-key", "id": c.id}))
+                .collect(),
+            None => vec![],
+        };
+        let user_verification = if mfa_token.is_some() {
+            "discouraged"
+        } else {
+            "required"
+        };
+        let challenge = self
+            .save_challenge(
+                Ceremony::Login {
+                    user_id: user_id.map(|s| s.to_string()),
+                    mfa_token,
+                },
+                ip,
+            )
+            .await?;
+        Ok(json!({
+            "publicKey": {
+                "challenge": challenge,
+                "rpId": self.config.rp_id,
+                "timeout": self.config.timeout.as_millis() as u64,
+                "allowCredentials": allow,
+                "userVerification": user_verification,
+            }
+        }))
+    }
+
+    pub async fn finish_login(
+        &self,
+        ip: &str,
+        credential: &PublicKeyCredential,
+    ) -> Result<LoginResult, Error> {
+        let client_data_json = decode(&credential.response.client_data_json)?;
+        let challenge = self.check_client_data(&client_data_json, "webauthn.get", ip)?;
+        let (user_id, mfa_token) = match self.take_challenge(&challenge, ip).await? {
+            Ceremony::Login { user_id, mfa_token } => (user_id, mfa_token),
+            _ => return Err(Error::NoChallenge()),
+        };
+        let mut stored = self
+            .credentials
+            .get(&credential.id)
+            .await?
+            .ok_or(Error::NoCredential())?;
+        if user_id.is_some_and(|id| id != stored.user_id) {
+            return Err(Error::Invalid("credential of other user".to_string()));
+        }
+        let raw_auth_data = decode(
+            credential
+                .response
+                .authenticator_data
+                .as_deref()
+                .ok_or_else(|| Error::Invalid("no authenticator data".to_string()))?,
+        )?;
+        let signature = decode(
+            credential
+                .response
+                .signature
+                .as_deref()
+                .ok_or_else(|| Error::Invalid("no signature".to_string()))?,
+        )?;
+        // passwordless login must prove the user, not only the presence
+        let auth_data = self.check_auth_data(&raw_auth_data, mfa_token.is_none())?;
+        verify_signature(
+            &stored.public_key,
+            &raw_auth_data,
+            &client_data_json,
+            &signature,
+        )?;
+        if !stored.sign_count_grows(auth_data.sign_count) {
+            tracing::warn!(user = stored.user_id, "webauthn sign count did not grow");
+            return Err(Error::Invalid("sign count".to_string()));
+        }
+        stored.sign_count = auth_data.sign_count;
+        self.credentials.update(stored.clone()).await?;
+        tracing::debug!(user = stored.user_id, "webauthn login");
+        Ok(LoginResult {
+            credential: stored,
+            mfa_token,
+        })
+    }
+
+    // Returns the challenge from the client data
+    fn check_client_data(&self, data: &[u8], kind: &str, ip: &str) -> Result<String, Error> {
+        let client_data: ClientData = serde_json::from_slice(data)
+            .map_err(|e| Error::Invalid(format!("client data: {e}")))?;
+        if client_data.kind != kind {
+            return Err(Error::Invalid(format!("type {}", client_data.kind)));
+        }
+        if !self.config.origins.contains(&client_data.origin) {
+            tracing::warn!(origin = client_data.origin, ip, "wrong webauthn origin");
+            return Err(Error::Invalid(format!("origin {}", client_data.origin)));
+        }
+        Ok(client_data.challenge)
+    }
+
+    fn check_auth_data(&self, data: &[u8], require_uv: bool) -> Result<AuthData, Error> {
+        let res = parse_auth_data(data)?;
+        if res.rp_id_hash != self.rp_id_hash {
+            return Err(Error::Invalid("rp id".to_string()));
+        }
+        if res.flags & FLAG_UP == 0 {
+            return Err(Error::Invalid("user not present".to_string()));
+        }
+        if require_uv && res.flags & FLAG_UV == 0 {
+            return Err(Error::Invalid("user not verified".to_string()));
+        }
+        Ok(res)
+    }
+
+    async fn save_challenge(&self, ceremony: Ceremony, ip: &str) -> Result<String, Error> {
+        let mut bytes = [0u8; 32];
+        OsRng.fill_bytes(&mut bytes);
+        let challenge = BASE64_URL_SAFE_NO_PAD.encode(bytes);
+        let value = serde_json::to_string(&Challenge {
+            ceremony,
+            ip: ip.to_string(),
+        })
+        .map_err(|e| anyhow::anyhow!("Serialization error: {:?}", e))?;
+        self.store
+            .set(
+                &self.challenge_key(&challenge),
+                &self.encryptor.encrypt(&value),
+                Some(self.config.timeout),
+            )
+            .await
+            .map_err(|e| anyhow::anyhow!("webauthn store set: {e}"))?;
+        Ok(challenge)
+    }
+
+    // Challenges are single use, it is removed even if the ceremony fails later
+    async fn take_challenge(&self, challenge: &str, ip: &str) -> Result<Ceremony, Error> {
+        let key = self.challenge_key(challenge);
+        let value = self
+            .store
+            .get(&key)
+            .await
+            .map_err(|e| anyhow::anyhow!("webauthn store get: {e}"))?
+            .ok_or(Error::NoChallenge())?;
+        self.store
+            .remove(&key)
+            .await
+            .map_err(|e| anyhow::anyhow!("webauthn store remove: {e}"))?;
+        let value = self.encryptor.decrypt(&value)?;
+        let res: Challenge = serde_json::from_str(&value)
+            .map_err(|e| anyhow::anyhow!("Deserialization error: {:?}", e))?;
+        if res.ip != ip {
+            tracing::warn!(ip, "webauthn challenge ip mismatch");
+            return Err(Error::NoChallenge());
+        }
+        Ok(res.ceremony)
+    }
+
+    fn challenge_key(&self, challenge: &str) -> String {
+        format!("{CHALLENGE_PREFIX}{}", self.encryptor.encrypt(challenge))
+    }
+}
+
+fn decode(value: &str) -> Result<Vec<u8>, Error> {
+    BASE64_URL_SAFE_NO_PAD
+        .decode(value.trim_end_matches('='))
+        .map_err(|e| Error::Invalid(format!("base64: {e}")))
+}
+
+fn parse_attestation(data: &[u8]) -> Result<Vec<u8>, Error> {
+    let value: Value =
+        ciborium::from_reader(data).map_err(|e| Error::Invalid(format!("attestation: {e}")))?;
+    value
+        .as_map()
+        .and_then(|m| {
+            m.iter()
+                .find(|(k, _)| k.as_text() == Some("authData"))
+                .and_then(|(_, v)| v.as_bytes())
+        })
+        .cloned()
+        .ok_or_else(|| Error::Invalid("no authData".to_string()))
+}
+
+fn parse_auth_data(data: &[u8]) -> Result<AuthData, Error> {
+    if data.len() < 37 {
+        return Err(Error::Invalid("short authenticator data".to_string()));
+    }
+    let flags = data[32];
+    let sign_count = u32::from_be_bytes([data[33], data[34], data[35], data[36]]);
+    let attested = if flags & FLAG_AT != 0 {
+        // aaguid(16) + id length(2)
+        let rest = &data[37..];
+        if rest.len() < 18 {
+            return Err(Error::Invalid("short attested data".to_string()));
+        }
+        let id_len = u16::from_be_bytes([rest[16], rest[17]]) as usize;
+        let rest = &rest[18..];
+        if rest.len() < id_len {
+            return Err(Error::Invalid("short credential id".to_string()));
+        }
+        let (id, mut key) = rest.split_at(id_len);
+        let key: Value = ciborium::from_reader(&mut key)
+            .map_err(|e| Error::Invalid(format!("public key: {e}")))?;
+        Some((id.to_vec(), parse_cose_key(&key)?))
+    } else {
+        None
+    };
+    Ok(AuthData {
+        rp_id_hash: data[..32].to_vec(),
+        flags,
+        sign_count,
+        attested,
+    })
+}
+
+// Converts a COSE EC2 P-256 key to SEC1 encoding
+fn parse_cose_key(key: &Value) -> Result<Vec<u8>, Error> {
+    let map = key
+        .as_map()
+        .ok_or_else(|| Error::Invalid("public key is not a map".to_string()))?;
+    let get = |label: i64| {
+        map.iter()
+            .find(|(k, _)| {
+                k.as_integer()
+                    .is_some_and(|k| i128::from(k) == i128::from(label))
+            })
+            .map(|(_, v)| v)
+    };
+    let int = |label: i64| get(label).and_then(|v| v.as_integer()).map(i128::from);
+    // kty EC2, alg ES256, crv P-256
+    if int(1) != Some(2) || int(3) != Some(ALG_ES256 as i128) || int(-1) != Some(1) {
+        return Err(Error::Invalid("only ES256 keys are supported".to_string()));
+    }
+    let coord = |label: i64| {
+        get(label)
+            .and_then(|v| v.as_bytes())
+            .filter(|v| v.len() == 32)
+            .ok_or_else(|| Error::Invalid("wrong key coordinate".to_string()))
+    };
+    let mut res = vec![4u8];
+    res.extend(coord(-2)?);
+    res.extend(coord(-3)?);
+    VerifyingKey::from_sec1_bytes(&res).map_err(|e| Error::Invalid(format!("public key: {e}")))?;
+    Ok(res)
+}
+
+fn verify_signature(
+    public_key: &[u8],
+    auth_data: &[u8],
+    client_data_json: &[u8],
+    signature: &[u8],
+) -> Result<(), Error> {
+    let key = VerifyingKey::from_sec1_bytes(public_key)
+        .map_err(|e| anyhow::anyhow!("stored public key: {e}"))?;
+    let signature =
+        Signature::from_der(signature).map_err(|e| Error::Invalid(format!("signature: {e}")))?;
+    let mut message = auth_data.to_vec();
+    message.extend(Sha256::digest(client_data_json));
+    key.verify(&message, &signature)
+        .map_err(|_| Error::Invalid("wrong signature".to_string()))
+}
+
+// the software authenticator is shared with the handler tests
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::store::{
+        credential::KeyValueCredentialStore, encryptor::MagicEncryptor,
+        memory::InMemoryKeyValueStore,
+    };
+    use p256::ecdsa::{signature::Signer, SigningKey};
+
+    const ORIGIN: &str = "https://auth.example.com";
+    const NOW: i64 = 1_700_000_000_000;
+    pub(crate) const USER_VERIFIED: u8 = FLAG_UP | FLAG_UV;
+
+    // Software authenticator producing ES256 credentials
+    pub(crate) struct Authenticator {
+        key: SigningKey,
+        id: Vec<u8>,
+        rp_id: String,
+        counter: u32,
+    }
+
+    impl Authenticator {
+        fn new(rp_id: &str) -> Self {
+            let mut id = vec![0u8; 16];
+            OsRng.fill_bytes(&mut id);
+            Authenticator {
+                key: SigningKey::random(&mut OsRng),
+                id,
+                rp_id: rp_id.to_string(),
+                counter: 0,
+            }
+        }
+
+        fn id(&self) -> String {
+            BASE64_URL_SAFE_NO_PAD.encode(&self.id)
+        }
+
+        fn auth_data(&self, flags: u8) -> Vec<u8> {
+            let mut res = Sha256::digest(self.rp_id.as_bytes()).to_vec();
+            res.push(flags);
+            res.extend(self.counter.to_be_bytes());
+            res
+        }
+
+        fn client_data(kind: &str, options: &serde_json::Value, origin: &str) -> String {
+            json!({
+                "type": kind,
+                "challenge": options["publicKey"]["challenge"],
+                "origin": origin,
+                "crossOrigin": false,
+            })
+            .to_string()
+        }
+
+        fn create(&mut self, options: &serde_json::Value, origin: &str) -> PublicKeyCredential {
+            self.counter += 1;
+            let point = self.key.verifying_key().to_encoded_point(false);
+            let cose = Value::Map(vec![
+                (Value::from(1), Value::from(2)),
+                (Value::from(3), Value::from(ALG_ES256)),
+                (Value::from(-1), Value::from(1)),
+                (Value::from(-2), Value::Bytes(point.x().unwrap().to_vec())),
+                (Value::from(-3), Value::Bytes(point.y().unwrap().to_vec())),
+            ]);
+            let mut auth_data = self.auth_data(FLAG_UP | FLAG_UV | FLAG_AT);
+            auth_data.extend([0u8; 16]);
+            auth_data.extend((self.id.len() as u16).to_be_bytes());
+            auth_data.extend(&self.id);
+            ciborium::into_writer(&cose, &mut auth_data).unwrap();
+            let attestation = Value::Map(vec![
+                (Value::from("fmt"), Value::from("none")),
+                (Value::from("attStmt"), Value::Map(vec![])),
+                (Value::from("authData"), Value::Bytes(auth_data)),
+            ]);
+            let mut attestation_object = vec![];
+            ciborium::into_writer(&attestation, &mut attestation_object).unwrap();
+            PublicKeyCredential {
+                id: self.id(),
+                response: AuthenticatorResponse {
+                    client_data_json: BASE64_URL_SAFE_NO_PAD.encode(Self::client_data(
+                        "webauthn.create",
+                        options,
+                        origin,
+                    )),
+                    attestation_object: Some(BASE64_URL_SAFE_NO_PAD.encode(attestation_object)),
+                    authenticator_data: None,
+                    signature: None,
+                },
+            }
+        }
+
+        pub(crate) fn get(
+            &mut self,
+            options: &serde_json::Value,
+            flags: u8,
+        ) -> PublicKeyCredential {
+            self.counter += 1;
+            let auth_data = self.auth_data(flags);
+            let client_data = Self::client_data("webauthn.get", options, ORIGIN);
+            let mut message = auth_data.clone();
+            message.extend(Sha256::digest(client_data.as_bytes()));
+            let signature: Signature = self.key.sign(&message);
+            PublicKeyCredential {
+                id: self.id(),
+                response: AuthenticatorResponse {
+                    client_data_json: BASE64_URL_SAFE_NO_PAD.encode(client_data),
+                    attestation_object: None,
+                    authenticator_data: Some(BASE64_URL_SAFE_NO_PAD.encode(auth_data)),
+                    signature: Some(BASE64_URL_SAFE_NO_PAD.encode(signature.to_der())),
+                },
+            }
+        }
+    }
+
+    pub(crate) fn make_webauthn() -> WebAuthn {
+        let encryptor = || Box::new(MagicEncryptor::new("1234567890123456").unwrap());
+        WebAuthn::new(
+            Config {
+                rp_id: "auth.example.com".to_string(),
+                rp_name: "authware".to_string(),
+                origins: vec![ORIGIN.to_string()],
+                timeout: Duration::from_secs(60),
+            },
+            Box::new(KeyValueCredentialStore::new(
+                Box::new(InMemoryKeyValueStore::new()),
+                encryptor(),
+            )),
+            Box::new(InMemoryKeyValueStore::new()),
+            encryptor(),
+        )
+        .unwrap()
+    }
+
+    fn user(id: &str) -> auth::User {
+        auth::User {
+            id: id.to_string(),
+            name: "Olia".to_string(),
+            department: "IT".to_string(),
+            roles: vec!["USER".to_string()],
+            backend: String::new(),
+        }
+    }
+
+    pub(crate) async fn registered(webauthn: &WebAuthn, user_id: &str) -> Authenticator {
+        let mut authenticator = Authenticator::new("auth.example.com");
+        let options = webauthn.start_register(&user(user_id), "").await.unwrap();
+        let credential = authenticator.create(&options, ORIGIN);
+        webauthn
+            .finish_register(user_id, "", &credential, NOW)
+            .await
+            .unwrap();
+        authenticator
+    }
+
+    #[tokio::test]
+    async fn test_register() {
+        let webauthn = make_webauthn();
+        assert!(!webauthn.has_credentials("olia").await.unwrap());
+        let authenticator = registered(&webauthn, "olia").await;
+        assert!(webauthn.has_credentials("olia").await.unwrap());
+        let options = webauthn.start_register(&user("olia"), "").await.unwrap();
+        assert_eq!(
+            options["publicKey"]["excludeCredentials"][0]["id"],
+            authenticator.id()
+        );
+        assert_eq!(options["publicKey"]["rp"]["id"], "auth.example.com");
+    }
+
+    #[tokio::test]
+    async fn test_register_fails() {
+        let webauthn = make_webauthn();
+        let mut authenticator = Authenticator::new("auth.example.com");
+        let options = webauthn.start_register(&user("olia"), "").await.unwrap();
+        let credential = authenticator.create(&options, "https://evil.com");
+        assert!(matches!(
+            webauthn.finish_register("olia", "", &credential, NOW).await,
+            Err(Error::Invalid(_))
+        ));
+
+        let options = webauthn.start_register(&user("olia"), "").await.unwrap();
+        let credential = authenticator.create(&options, ORIGIN);
+        assert!(matches!(
+            webauthn
+                .finish_register("jonas", "", &credential, NOW)
+                .await,
+            Err(Error::NoChallenge())
+        ));
+        // challenge is single use
+        assert!(matches!(
+            webauthn.finish_register("olia", "", &credential, NOW).await,
+            Err(Error::NoChallenge())
+        ));
+
+        let mut authenticator = Authenticator::new("evil.com");
+        let options = webauthn.start_register(&user("olia"), "").await.unwrap();
+        let credential = authenticator.create(&options, ORIGIN);
+        assert!(matches!(
+            webauthn.finish_register("olia", "", &credential, NOW).await,
+            Err(Error::Invalid(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_passwordless() {
+        let webauthn = make_webauthn();
+        let mut authenticator = registered(&webauthn, "olia").await;
+        let options = webauthn.start_login(None, None, "1.1.1.1").await.unwrap();
+        assert_eq!(options["publicKey"]["userVerification"], "required");
+        let credential = authenticator.get(&options, FLAG_UP | FLAG_UV);
+        let res = webauthn.finish_login("1.1.1.1", &credential).await.unwrap();
+        assert_eq!(res.credential.user_id, "olia");
+        assert_eq!(res.mfa_token, None);
+        // replay
+        assert!(matches!(
+            webauthn.finish_login("1.1.1.1", &credential).await,
+            Err(Error::NoChallenge())
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_second_factor() {
+        let webauthn = make_webauthn();
+        let mut authenticator = registered(&webauthn, "olia").await;
+        let options = webauthn
+            .start_login(Some("olia"), Some("token".to_string()), "")
+            .await
+            .unwrap();
+        assert_eq!(
+            options["publicKey"]["allowCredentials"][0]["id"],
+            authenticator.id()
+        );
+        let credential = authenticator.get(&options, FLAG_UP);
+        let res = webauthn.finish_login("", &credential).await.unwrap();
+        assert_eq!(res.mfa_token.as_deref(), Some("token"));
+    }
+
+    #[tokio::test]
+    async fn test_remove_credential() {
+        let webauthn = make_webauthn();
+        let authenticator = registered(&webauthn, "olia").await;
+        registered(&webauthn, "jonas").await;
+        assert!(matches!(
+            webauthn
+                .remove_credential("jonas", &authenticator.id())
+                .await,
+            Err(Error::NoCredential())
+        ));
+        webauthn
+            .remove_credential("olia", &authenticator.id())
+            .await
+            .unwrap();
+        assert!(!webauthn.has_credentials("olia").await.unwrap());
+        assert!(webauthn.has_credentials("jonas").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_login_fails() {
+        let webauthn = make_webauthn();
+        let mut authenticator = registered(&webauthn, "olia").await;
+        registered(&webauthn, "jonas").await;
+
+        let options = webauthn.start_login(None, None, "").await.unwrap();
+        let credential = authenticator.get(&options, FLAG_UP);
+        assert!(matches!(
+            webauthn.finish_login("", &credential).await,
+            Err(Error::Invalid(_))
+        ));
+
+        let options = webauthn.start_login(Some("jonas"), None, "").await.unwrap();
+        let credential = authenticator.get(&options, FLAG_UP | FLAG_UV);
+        assert!(matches!(
+            webauthn.finish_login("", &credential).await,
+            Err(Error::Invalid(_))
+        ));
+
+        let options = webauthn.start_login(None, None, "1.1.1.1").await.unwrap();
+        let credential = authenticator.get(&options, FLAG_UP | FLAG_UV);
+        assert!(matches!(
+            webauthn.finish_login("2.2.2.2", &credential).await,
+            Err(Error::NoChallenge())
+        ));
+
+        let options = webauthn.start_login(None, None, "").await.unwrap();
+        let mut credential = authenticator.get(&options, FLAG_UP | FLAG_UV);
+        credential.response.authenticator_data =
+            Some(BASE64_URL_SAFE_NO_PAD.encode(authenticator.auth_data(FLAG_UP | FLAG_UV | 0x08)));
+        assert!(matches!(
+            webauthn.finish_login("", &credential).await,
+            Err(Error::Invalid(_))
+        ));
+
+        let mut other = Authenticator::new("auth.example.com");
+        let options = webauthn.start_login(None, None, "").await.unwrap();
+        assert!(matches!(
+            webauthn
+                .finish_login("", &other.get(&options, FLAG_UP | FLAG_UV))
+                .await,
+            Err(Error::NoCredential())
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_sign_count() {
+        let webauthn = make_webauthn();
+        let mut authenticator = registered(&webauthn, "olia").await;
+        let options = webauthn.start_login(None, None, "").await.unwrap();
+        let credential = authenticator.get(&options, FLAG_UP | FLAG_UV);
+        assert!(webauthn.finish_login("", &credential).await.is_ok());
+        // cloned authenticator
+        authenticator.counter -= 1;
+        let options = webauthn.start_login(None, None, "").await.unwrap();
+        let credential = authenticator.get(&options, FLAG_UP | FLAG_UV);
+        assert!(matches!(
+            webauthn.finish_login("", &credential).await,
+            Err(Error::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_cose_key_rejects_other_alg() {
+        let key = Value::Map(vec![
+            (Value::from(1), Value::from(2)),
+            (Value::from(3), Value::from(-257)),
+            (Value::from(-1), Value::from(1)),
+        ]);
+        assert!(matches!(parse_cose_key(&key), Err(Error::Invalid(_))));
+    }
+}
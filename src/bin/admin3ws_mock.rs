@@ -0,0 +1,30 @@
+use authware::{shutdown_signal, testing::admin3ws::Mock};
+use clap::Parser;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+
+/// Scripted admin3ws web service for integration tests
+#[derive(Parser, Debug)]
+#[command(version = env!("CARGO_APP_VERSION"), name = "admin3ws-mock", about, long_about = None)]
+struct Args {
+    /// json file with users, roles, error codes and injected failures
+    #[arg(long, env = "MOCK_CONFIG")]
+    config: String,
+    /// listen address
+    #[arg(long, env = "MOCK_ADDR", default_value = "0.0.0.0:8080")]
+    addr: String,
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::EnvFilter::from_default_env())
+        .with(tracing_subscriber::fmt::Layer::default().compact())
+        .init();
+    let args = Args::parse();
+    let mock = Mock::from_file(&args.config)?;
+    let addr = mock.start(&args.addr).await?;
+    tracing::info!(addr = addr.to_string(), "admin3ws mock listening");
+    shutdown_signal().await;
+    tracing::info!(calls = mock.calls(), "Bye");
+    Ok(())
+}
@@ -0,0 +1,50 @@
+use axum::http::HeaderMap;
+use axum_extra::{
+    headers::{authorization::Bearer, Authorization},
+    TypedHeader,
+};
+use chrono::Utc;
+
+use crate::model::{data::SessionData, service};
+
+use super::error::ApiError;
+
+// Validates the caller's session and checks it holds the configured admin role
+pub(crate) async fn check_admin(
+    data: &service::Data,
+    headers: &HeaderMap,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+) -> Result<SessionData, ApiError> {
+    check_role(data, headers, bearer, &data.admin_role).await
+}
+
+// Validates the caller's session and checks it holds the role
+pub(crate) async fn check_role(
+    data: &service::Data,
+    headers: &HeaderMap,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    role: &str,
+) -> Result<SessionData, ApiError> {
+    let res = check_session(data, headers, bearer).await?;
+    if !res.user.roles.iter().any(|r| r == role) {
+        tracing::warn!(user = res.user.id, role, "no required role");
+        return Err(ApiError::Forbidden());
+    }
+    Ok(res)
+}
+
+// Validates the caller's session
+pub(crate) async fn check_session(
+    data: &service::Data,
+    headers: &HeaderMap,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+) -> Result<SessionData, ApiError> {
+    let bearer = bearer.ok_or(ApiError::NoSession())?;
+    let ip = data.ip_extractor.get(headers);
+    let res = data.store.get(bearer.token()).await?;
+    res.check_ip(&ip)?;
+    let now = Utc::now().timestamp_millis();
+    res.check_expired(now)?;
+    res.check_inactivity(now, data.config.inactivity)?;
+    Ok(res)
+}
@@ -15,9 +15,9 @@ use axum_extra::{
 use reqwest::StatusCode;
 use urlencoding::decode;
 
-use crate::model::service;
+use crate::model::{self, data::SessionData, service};
 
-use super::error::ApiError;
+use super::{data::User, error::ApiError};
 
 const OK_RESPONSE: &str = "OK";
 
@@ -38,6 +38,24 @@ pub async fn handler(
 
     let ip = data.ip_extractor.get(&headers);
     tracing::info!(url = forwarded_uri, ip = ip.as_ref(), "auth");
+    if let Some(api_keys) = &data.api_keys {
+        if let Some(key) = api_keys.extract(&headers) {
+            // keys are not sessions, no inactivity tracking
+            let user = map_roles(&data, api_keys.check(key, &ip)?)?;
+            tracing::debug!(user = user.id, "api key");
+            return make_response(&data, &user, None);
+        }
+    }
+    if let Some(client_cert) = &data.client_cert {
+        if let Some(res) = client_cert
+            .authenticate(&headers, data.auth_service.as_ref())
+            .await
+        {
+            let user = map_roles(&data, res?)?;
+            tracing::debug!(user = user.id, "client cert");
+            return make_response(&data, &user, None);
+        }
+    }
     let session_id = match bearer.as_ref() {
         None => match forwarded_uri {
             Some(token) => parse_token_from_url(token).unwrap_or(Cow::Borrowed("")),
@@ -61,6 +79,11 @@ pub async fn handler(
     res.check_expired(now)?;
     let config = &data.config;
     res.check_inactivity(now, config.inactivity)?;
+    let res = if res.needs_refresh(now, config.refresh) {
+        refresh(&data, &session_id, res, now).await?
+    } else {
+        res
+    };
 
     if !skip_alive {
         store.mark_last_used(session_id.as_ref(), now).await?;
@@ -71,7 +94,70 @@ pub async fn handler(
         );
     }
 
-    let header = serde_json::to_string(&res.user)
+    make_response(&data, &res.user, Some(res.last_access))
+}
+
+// Reloads the session user from the auth backend, revokes the session if the user lost access
+async fn refresh(
+    data: &service::Data,
+    session_id: &str,
+    mut res: SessionData,
+    now: i64,
+) -> Result<SessionData, ApiError> {
+    // claims the refresh first, so concurrent calls of the session ask the backend once.
+    // A failed refresh is retried after the refresh period too
+    let current = res.refreshed;
+    res.refreshed = now;
+    if !data
+        .store
+        .update_user(session_id, res.clone(), current)
+        .await?
+    {
+        tracing::debug!(user = res.user.id, "refreshed by another request");
+        res.refreshed = current;
+        return Ok(res);
+    }
+    let user = match data.auth_service.refresh_user(&res.user).await {
+        Ok(Some(user)) => map_roles(data, user),
+        Ok(None) => return Ok(res),
+        Err(model::auth::Error::ServiceError(err)) => {
+            tracing::warn!(user = res.user.id, err = %err, "can't refresh user, keep the old one");
+            return Ok(res);
+        }
+        Err(err) => Err(err.into()),
+    };
+    match user {
+        Ok(user) => {
+            tracing::debug!(user = user.id, roles = ?user.roles, "refreshed user");
+            res.user = user;
+            // only an existing session is updated, a logout meanwhile wins
+            if !data.store.update_user(session_id, res.clone(), now).await? {
+                tracing::debug!(user = res.user.id, "refreshed again meanwhile");
+            }
+            Ok(res)
+        }
+        Err(err) => {
+            tracing::warn!(user = res.user.id, "user lost access, revoke session");
+            data.store.remove(session_id).await?;
+            Err(err)
+        }
+    }
+}
+
+fn map_roles(data: &service::Data, user: model::auth::User) -> Result<model::auth::User, ApiError> {
+    match &data.role_mapper {
+        Some(mapper) => Ok(mapper.map(user)?),
+        None => Ok(user),
+    }
+}
+
+fn make_response(
+    data: &service::Data,
+    user: &model::auth::User,
+    last_access: Option<i64>,
+) -> Result<Response<String>, ApiError> {
+    // the downstream contract, internal fields such as the backend stay out
+    let header = serde_json::to_string(&User::from(user.clone()))
         .map_err(|e| ApiError::Server(format!("serialize session data: {e}")))?;
     let encoded_header = base64::prelude::BASE64_STANDARD.encode(header.as_bytes());
 
@@ -81,7 +167,9 @@ pub async fn handler(
             .map_err(|e| ApiError::Server(format!("build response: {e}")))?,
     );
     if data.is_test_mode {
-        partial_response = partial_response.header("test-last-access", res.last_access.to_string());
+        if let Some(last_access) = last_access {
+            partial_response = partial_response.header("test-last-access", last_access.to_string());
+        }
     }
     let response = partial_response
         .body(OK_RESPONSE.to_string())
@@ -106,9 +194,108 @@ fn parse_token_from_url(url: &str) -> Option<Cow<'_, str>> {
 
 #[cfg(test)]
 mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
     use super::*;
+    use crate::{
+        auth::sample::Sample, store::memory::InMemorySessionStore, utils::secret_str::SecretString,
+        AuthService, SessionStore,
+    };
+    use async_trait::async_trait;
     use test_case::test_case;
 
+    // Refreshes the user, logs the session out during the call if asked
+    struct Refreshing {
+        calls: Arc<AtomicUsize>,
+        logout: Option<InMemorySessionStore>,
+    }
+
+    #[async_trait]
+    impl AuthService for Refreshing {
+        async fn login(
+            &self,
+            _user: &str,
+            _pass: &SecretString,
+        ) -> Result<model::auth::User, model::auth::Error> {
+            Err(model::auth::Error::WrongUserPass())
+        }
+
+        async fn refresh_user(
+            &self,
+            user: &model::auth::User,
+        ) -> Result<Option<model::auth::User>, model::auth::Error> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            tokio::task::yield_now().await;
+            if let Some(store) = &self.logout {
+                store.remove("s1").await.unwrap();
+            }
+            Ok(Some(model::auth::User {
+                roles: vec!["NEW".to_string()],
+                ..user.clone()
+            }))
+        }
+    }
+
+    async fn make_data(logout: bool) -> (service::Data, SessionData, Arc<AtomicUsize>) {
+        let store = InMemorySessionStore::new();
+        let session = SessionData {
+            user: model::auth::User {
+                id: "olia".to_string(),
+                name: "Olia".to_string(),
+                department: "IT".to_string(),
+                roles: vec!["OLD".to_string()],
+                backend: String::new(),
+            },
+            ip: "1.1.1.1".to_string(),
+            valid_till: Utc::now().timestamp_millis() + 60_000,
+            last_access: 10,
+            refreshed: 10,
+        };
+        store.add("s1", session.clone()).await.unwrap();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let auth = Refreshing {
+            calls: calls.clone(),
+            logout: logout.then(|| store.clone()),
+        };
+        let data = service::Data::for_tests(Box::new(store), Box::new(auth));
+        (data, session, calls)
+    }
+
+    #[tokio::test]
+    async fn test_refresh() {
+        let (data, session, calls) = make_data(false).await;
+        let res = refresh(&data, "s1", session, 20).await.unwrap();
+        assert_eq!(res.user.roles, vec!["NEW"]);
+        let stored = data.store.get("s1").await.unwrap();
+        assert_eq!(
+            (stored.user.roles, stored.refreshed),
+            (vec!["NEW".to_string()], 20)
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_refresh_logout_race() {
+        let (data, session, _) = make_data(true).await;
+        assert!(matches!(
+            refresh(&data, "s1", session, 20).await,
+            Err(ApiError::NoSession())
+        ));
+        assert!(data.store.get("s1").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_refresh_calls_backend_once() {
+        let (data, session, calls) = make_data(false).await;
+        let (a, b) = tokio::join!(
+            refresh(&data, "s1", session.clone(), 20),
+            refresh(&data, "s1", session, 20)
+        );
+        assert!(a.is_ok() && b.is_ok());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(data.store.get("s1").await.unwrap().user.roles, vec!["NEW"]);
+    }
+
     #[test_case("",  None; "empty")]
     #[test_case("/olia", None; "no token")]
     #[test_case("/olia?token=aaaaa", Some(Cow::Borrowed("aaaaa")); "parsed")]
@@ -119,4 +306,26 @@ mod tests {
         let actual = parse_token_from_url(input);
         assert_eq!(expected, actual);
     }
+
+    #[test]
+    fn test_user_info_header() {
+        let data = service::Data::for_tests(
+            Box::new(InMemorySessionStore::new()),
+            Box::new(Sample::new("olia:pass:IT:USER").unwrap()),
+        );
+        let user = model::auth::User {
+            id: "olia".to_string(),
+            name: "Olia".to_string(),
+            department: "IT".to_string(),
+            roles: vec!["USER".to_string()],
+            backend: "ldap".to_string(),
+        };
+        let res = make_response(&data, &user, None).unwrap();
+        let header = res.headers().get("User-Info").unwrap().to_str().unwrap();
+        let header = base64::prelude::BASE64_STANDARD.decode(header).unwrap();
+        assert_eq!(
+            String::from_utf8(header).unwrap(),
+            r#"{"id":"olia","name":"Olia","department":"IT","roles":["USER"]}"#
+        );
+    }
 }
@@ -4,7 +4,7 @@ use axum::response::IntoResponse;
 use reqwest::StatusCode;
 use thiserror::Error;
 
-use crate::model::{auth, store};
+use crate::model::{auth, mfa, store, user, webauthn};
 
 #[derive(Debug, Error)]
 pub enum ApiError {
@@ -24,6 +24,18 @@ pub enum ApiError {
     NoAccess(),
     #[error("other auth error: {0}")]
     OtherAuth(String),
+    #[error("Forbidden")]
+    Forbidden(),
+    #[error("Not found: {0}")]
+    NotFound(String),
+    #[error("Conflict: {0}")]
+    Conflict(String),
+    #[error("Wrong code")]
+    WrongCode(),
+    #[error("Too many attempts")]
+    TooManyAttempts(),
+    #[error("Service unavailable: {0}")]
+    Unavailable(String),
     #[error(transparent)]
     Other(#[from] anyhow::Error),
 }
@@ -76,6 +88,36 @@ impl IntoResponse for ApiError {
                 tracing::warn!("{}", error);
                 (StatusCode::UNAUTHORIZED, Cow::Borrowed("No access"))
             }
+            ApiError::Forbidden() => {
+                tracing::warn!("Forbidden");
+                (StatusCode::FORBIDDEN, Cow::Borrowed("Forbidden"))
+            }
+            ApiError::NotFound(msg) => {
+                tracing::warn!("Not found: {}", msg);
+                (StatusCode::NOT_FOUND, Cow::Owned(msg))
+            }
+            ApiError::Conflict(msg) => {
+                tracing::warn!("Conflict: {}", msg);
+                (StatusCode::CONFLICT, Cow::Owned(msg))
+            }
+            ApiError::WrongCode() => {
+                tracing::warn!("Wrong code");
+                (StatusCode::UNAUTHORIZED, Cow::Borrowed("Wrong code"))
+            }
+            ApiError::TooManyAttempts() => {
+                tracing::warn!("Too many attempts");
+                (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    Cow::Borrowed("Too many attempts"),
+                )
+            }
+            ApiError::Unavailable(msg) => {
+                tracing::warn!("Service unavailable: {}", msg);
+                (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    Cow::Borrowed("Auth service unavailable"),
+                )
+            }
         };
 
         (status, message).into_response()
@@ -87,8 +129,13 @@ impl From<auth::Error> for ApiError {
         match error {
             auth::Error::WrongUserPass() => ApiError::WrongUserPass(),
             auth::Error::ExpiredPass() => ApiError::ExpiredPass(),
-            auth::Error::ServiceError(error) => ApiError::Other(error),
-            auth::Error::OtherAuth(error) => ApiError::OtherAuth(error),
+            auth::Error::ServiceError(error) => match error.downcast_ref::<auth::Unavailable>() {
+                Some(unavailable) => ApiError::Unavailable(unavailable.0.clone()),
+                None => ApiError::Other(error),
+            },
+            auth::Error::OtherAuth(error) | auth::Error::Unsupported(error) => {
+                ApiError::OtherAuth(error)
+            }
             auth::Error::NoAccess() => ApiError::NoAccess(),
         }
     }
@@ -98,7 +145,49 @@ impl From<store::Error> for ApiError {
     fn from(error: store::Error) -> Self {
         match error {
             store::Error::NoSession() => ApiError::NoSession(),
+            store::Error::TooManySessions() => ApiError::Conflict("Too many sessions".to_string()),
+            store::Error::StoreFull() => ApiError::Unavailable("Session store is full".to_string()),
             store::Error::Other(error) => ApiError::Other(error),
         }
     }
 }
+
+impl From<user::Error> for ApiError {
+    fn from(error: user::Error) -> Self {
+        match error {
+            user::Error::NoUser() => ApiError::NotFound("No user".to_string()),
+            user::Error::Exists() => ApiError::Conflict("User exists".to_string()),
+            user::Error::Invalid(msg) => ApiError::BadRequest(msg, "user data".to_string()),
+            user::Error::Other(error) => ApiError::Other(error),
+        }
+    }
+}
+
+impl From<mfa::Error> for ApiError {
+    fn from(error: mfa::Error) -> Self {
+        match error {
+            mfa::Error::NotEnrolled() => ApiError::NotFound("MFA is not enrolled".to_string()),
+            mfa::Error::AlreadyEnrolled() => {
+                ApiError::Conflict("MFA is already enrolled".to_string())
+            }
+            mfa::Error::WrongCode() => ApiError::WrongCode(),
+            mfa::Error::TooManyAttempts() => ApiError::TooManyAttempts(),
+            mfa::Error::NoChallenge() => ApiError::NoSession(),
+            mfa::Error::Other(error) => ApiError::Other(error),
+        }
+    }
+}
+
+impl From<webauthn::Error> for ApiError {
+    fn from(error: webauthn::Error) -> Self {
+        match error {
+            webauthn::Error::NoChallenge() => ApiError::NoSession(),
+            webauthn::Error::NoCredential() => {
+                ApiError::OtherAuth("unknown credential".to_string())
+            }
+            webauthn::Error::Exists() => ApiError::Conflict("Credential exists".to_string()),
+            webauthn::Error::Invalid(msg) => ApiError::OtherAuth(format!("webauthn: {msg}")),
+            webauthn::Error::Other(error) => ApiError::Other(error),
+        }
+    }
+}
@@ -1,18 +1,55 @@
-use axum::{extract, Json};
+use std::sync::Arc;
+
+use axum::{
+    extract::{self, State},
+    Json,
+};
 use serde::Serialize;
 
+use crate::{model::service, store::memory::MemoryStoreMetrics};
+
 use super::error::ApiError;
 
 #[derive(Serialize, Clone)]
 pub struct LiveResult {
     success: bool,
     version: String,
+    // in-memory store counters
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sessions: Option<MemoryStoreMetrics>,
 }
 
-pub async fn handler() -> Result<extract::Json<LiveResult>, ApiError> {
+pub async fn handler(
+    State(data): State<Arc<service::Data>>,
+) -> Result<extract::Json<LiveResult>, ApiError> {
     let res = LiveResult {
         success: true,
         version: env!("CARGO_APP_VERSION").to_string(),
+        sessions: data.memory_store.as_ref().map(|store| store.metrics()),
     };
     Ok(Json(res))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{auth::sample::Sample, store::memory::InMemorySessionStore};
+
+    #[tokio::test]
+    async fn test_live() {
+        let data = service::Data::for_tests(
+            Box::new(InMemorySessionStore::new()),
+            Box::new(Sample::new("").unwrap()),
+        );
+        let res = handler(State(Arc::new(data))).await.unwrap();
+        assert!(res.sessions.is_none());
+        let store = InMemorySessionStore::new();
+        let mut data =
+            service::Data::for_tests(Box::new(store.clone()), Box::new(Sample::new("").unwrap()));
+        data.memory_store = Some(store);
+        let res = handler(State(Arc::new(data))).await.unwrap();
+        assert_eq!(res.sessions, Some(MemoryStoreMetrics::default()));
+        let json = serde_json::to_value(&res.0).unwrap();
+        assert_eq!(json["sessions"]["rejected"], 0);
+    }
+}
@@ -13,7 +13,7 @@ use serde::{Deserialize, Serialize};
 
 use crate::{
     handler::data::User,
-    model::{data::SessionData, service},
+    model::{self, data::SessionData, service},
 };
 
 use super::error::ApiError;
@@ -22,57 +22,148 @@ use super::error::ApiError;
 pub struct Request {
     user: Option<String>,
     pass: Option<String>,
+    // auth backend name, if the client picks it
+    backend: Option<String>,
 }
 
 #[derive(Serialize)]
 pub struct Response {
-    session_id: String,
+    pub(crate) session_id: String,
     user: User,
 }
 
+// Returned instead of a session when the user must pass the second factor
+#[derive(Serialize)]
+pub struct MfaResponse {
+    mfa_required: bool,
+    pub(crate) mfa_token: String,
+    pub(crate) mfa_methods: Vec<&'static str>,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum LoginResponse {
+    Session(Response),
+    Mfa(MfaResponse),
+}
+
 #[debug_handler]
 pub async fn handler(
     State(data): State<Arc<service::Data>>,
     headers: HeaderMap,
     Json(payload): Json<Request>,
-) -> Result<extract::Json<Response>, ApiError> {
+) -> Result<extract::Json<LoginResponse>, ApiError> {
     let user = payload.user.as_deref().unwrap_or("");
     tracing::debug!(user = user, "starting login");
     if payload.user.is_none() || payload.pass.is_none() {
         return Err(ApiError::WrongUserPass());
     }
     let ip = data.ip_extractor.get(&headers);
-
-    let now = Utc::now();
-    let cfg = &data.config;
-    let store = &data.store;
     let auth = &data.auth_service;
 
     let pass = payload.pass.as_deref().unwrap_or("");
 
     tracing::debug!(user = user, ip = ip.as_ref(), "call auth service login");
-    let res = auth.login(user, &pass.into()).await?;
-    tracing::trace!(user = user, "got result");
-    tracing::trace!(user = user, "creating session");
+    let res = match payload.backend.as_deref() {
+        Some(backend) => auth.login_backend(backend, user, &pass.into()).await?,
+        None => auth.login(user, &pass.into()).await?,
+    };
+    tracing::trace!(user = user, backend = res.backend, "got result");
+    if let Some(res) = start_mfa(&data, &res, &ip).await? {
+        return Ok(Json(LoginResponse::Mfa(res)));
+    }
+    let response = create_session(&data, res, &ip).await?;
+    Ok(Json(LoginResponse::Session(response)))
+}
+
+// Starts the second factor of a user enrolled in one, None - the session can be created
+pub(crate) async fn start_mfa(
+    data: &service::Data,
+    user: &model::auth::User,
+    ip: &str,
+) -> Result<Option<MfaResponse>, ApiError> {
+    let mfa_methods = mfa_methods(data, &user.id).await?;
+    if mfa_methods.is_empty() {
+        return Ok(None);
+    }
+    // the challenge store comes with the totp mfa, never skip an enrolled factor
+    let mfa = data
+        .mfa
+        .as_ref()
+        .ok_or_else(|| ApiError::Server("second factor needs mfa to be enabled".to_string()))?;
+    if let Some(mapper) = &data.role_mapper {
+        // reject before the second factor, the session maps the roles again
+        mapper.map(user.clone())?;
+    }
+    tracing::debug!(user = user.id, methods = ?mfa_methods, "mfa required");
+    let mfa_token = mfa.challenge(user, ip).await?;
+    Ok(Some(MfaResponse {
+        mfa_required: true,
+        mfa_token,
+        mfa_methods,
+    }))
+}
+
+// Second factors the user is enrolled in
+async fn mfa_methods(data: &service::Data, user_id: &str) -> Result<Vec<&'static str>, ApiError> {
+    let mut res = vec![];
+    if let Some(mfa) = &data.mfa {
+        if mfa.is_enrolled(user_id).await? {
+            res.push("totp");
+        }
+    }
+    if let Some(webauthn) = &data.webauthn {
+        if webauthn.has_credentials(user_id).await? {
+            res.push("webauthn");
+        }
+    }
+    Ok(res)
+}
+
+// Creates and stores a new session for an authenticated user
+pub(crate) async fn create_session(
+    data: &service::Data,
+    user: model::auth::User,
+    ip: &str,
+) -> Result<Response, ApiError> {
+    let now = Utc::now();
+    let cfg = &data.config;
+    let user = match &data.role_mapper {
+        Some(mapper) => mapper.map(user)?,
+        None => user,
+    };
+    tracing::debug!(user = user.id, backend = user.backend, "creating session");
     let session_id = generate_session();
-    tracing::trace!(user = user, "saving");
-    store
-        .add(
+    tracing::trace!(user = user.id, "saving");
+    let max = cfg.limits.limit_for(&user);
+    let evicted = data
+        .store
+        .add_limited(
             &session_id,
             SessionData {
-                user: res.clone(),
+                user: user.clone(),
                 ip: ip.to_string(),
                 valid_till: now.timestamp_millis() + cfg.session_timeout,
                 last_access: now.timestamp_millis(),
+                refreshed: now.timestamp_millis(),
             },
+            max,
+            cfg.limits.policy,
         )
-        .await?;
-    tracing::trace!(user = user, "saved");
-    let response = Response {
+        .await
+        .inspect_err(|err| {
+            if matches!(err, model::store::Error::TooManySessions()) {
+                tracing::warn!(user = user.id, max, "session limit reached");
+            }
+        })?;
+    if evicted > 0 {
+        tracing::info!(user = user.id, evicted, max, "evicted sessions");
+    }
+    tracing::trace!(user = user.id, "saved");
+    Ok(Response {
         session_id,
-        user: res.into(),
-    };
-    Ok(Json(response))
+        user: user.into(),
+    })
 }
 
 fn generate_session() -> String {
@@ -81,3 +172,79 @@ fn generate_session() -> String {
     rng.fill_bytes(&mut session_id_bytes);
     BASE64_URL_SAFE.encode(session_id_bytes)
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+    use crate::{
+        auth::{
+            sample::Sample,
+            totp::Mfa,
+            webauthn::tests::{make_webauthn, registered},
+        },
+        store::{
+            encryptor::MagicEncryptor,
+            memory::{InMemoryKeyValueStore, InMemorySessionStore},
+        },
+    };
+
+    async fn make_data(mfa: bool) -> Arc<service::Data> {
+        let mut res = service::Data::for_tests(
+            Box::new(InMemorySessionStore::new()),
+            Box::new(Sample::new("olia:pass:IT:USER").unwrap()),
+        );
+        let webauthn = make_webauthn();
+        registered(&webauthn, "olia").await;
+        res.webauthn = Some(webauthn);
+        if mfa {
+            res.mfa = Some(Mfa::new(
+                Box::new(InMemoryKeyValueStore::new()),
+                Box::new(MagicEncryptor::new("1234567890123456").unwrap()),
+                "authware",
+            ));
+        }
+        Arc::new(res)
+    }
+
+    async fn login(data: Arc<service::Data>) -> Result<LoginResponse, ApiError> {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", HeaderValue::from_static("1.1.1.1"));
+        let request = Request {
+            user: Some("olia".to_string()),
+            pass: Some("pass".to_string()),
+            backend: None,
+        };
+        handler(State(data), headers, Json(request))
+            .await
+            .map(|res| res.0)
+    }
+
+    #[tokio::test]
+    async fn test_passkey_second_factor() {
+        let res = login(make_data(true).await).await;
+        match res {
+            Ok(LoginResponse::Mfa(res)) => assert_eq!(res.mfa_methods, vec!["webauthn"]),
+            _ => panic!("expected mfa"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_passkey_not_skipped_without_mfa() {
+        let data = make_data(false).await;
+        assert!(login(data.clone()).await.is_err());
+        assert!(data.store.list_by_user("olia").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_start_mfa() {
+        // the same check guards the oidc callback, which has no password step
+        let data = make_data(true).await;
+        let mut user = data.auth_service.lookup("olia").await.unwrap();
+        let res = start_mfa(&data, &user, "1.1.1.1").await.unwrap();
+        assert_eq!(res.map(|res| res.mfa_methods), Some(vec!["webauthn"]));
+        user.id = "jonas".to_string();
+        assert!(start_mfa(&data, &user, "1.1.1.1").await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,129 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{self, State},
+    http::HeaderMap,
+    Json,
+};
+use axum_extra::{
+    headers::{authorization::Bearer, Authorization},
+    TypedHeader,
+};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use crate::{auth::totp, model::service};
+
+use super::{
+    admin::check_session,
+    error::ApiError,
+    login::{create_session, Response},
+};
+
+#[derive(Deserialize)]
+pub struct LoginRequest {
+    mfa_token: Option<String>,
+    code: Option<String>,
+    recovery_code: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct CodeRequest {
+    code: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct EnrollResponse {
+    secret: String,
+    uri: String,
+}
+
+#[derive(Serialize)]
+pub struct ConfirmResponse {
+    recovery_codes: Vec<String>,
+}
+
+// Second login step, exchanges the mfa token and a code for a session
+pub async fn login(
+    State(data): State<Arc<service::Data>>,
+    headers: HeaderMap,
+    Json(payload): Json<LoginRequest>,
+) -> Result<extract::Json<Response>, ApiError> {
+    let mfa = get_mfa(&data)?;
+    let token = payload.mfa_token.ok_or(ApiError::NoSession())?;
+    if payload.code.is_none() && payload.recovery_code.is_none() {
+        return Err(ApiError::WrongCode());
+    }
+    let ip = data.ip_extractor.get(&headers);
+    tracing::debug!(ip = ip.as_ref(), "mfa login");
+    let user = mfa
+        .verify_challenge(
+            &token,
+            &ip,
+            payload.code.as_deref(),
+            payload.recovery_code.as_deref(),
+            Utc::now().timestamp_millis(),
+        )
+        .await?;
+    tracing::debug!(user = user.id, "mfa passed");
+    let response = create_session(&data, user, &ip).await?;
+    Ok(Json(response))
+}
+
+pub async fn enroll(
+    State(data): State<Arc<service::Data>>,
+    headers: HeaderMap,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+) -> Result<extract::Json<EnrollResponse>, ApiError> {
+    let mfa = get_mfa(&data)?;
+    let session = check_session(&data, &headers, bearer).await?;
+    tracing::debug!(user = session.user.id, "mfa enroll");
+    let res = mfa.enroll(&session.user.id).await?;
+    Ok(Json(EnrollResponse {
+        secret: res.secret,
+        uri: res.uri,
+    }))
+}
+
+pub async fn confirm(
+    State(data): State<Arc<service::Data>>,
+    headers: HeaderMap,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    Json(payload): Json<CodeRequest>,
+) -> Result<extract::Json<ConfirmResponse>, ApiError> {
+    let mfa = get_mfa(&data)?;
+    let session = check_session(&data, &headers, bearer).await?;
+    tracing::debug!(user = session.user.id, "mfa confirm");
+    let recovery_codes = mfa
+        .confirm(
+            &session.user.id,
+            payload.code.as_deref().unwrap_or_default(),
+            Utc::now().timestamp_millis(),
+        )
+        .await?;
+    Ok(Json(ConfirmResponse { recovery_codes }))
+}
+
+pub async fn disable(
+    State(data): State<Arc<service::Data>>,
+    headers: HeaderMap,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    Json(payload): Json<CodeRequest>,
+) -> Result<(), ApiError> {
+    let mfa = get_mfa(&data)?;
+    let session = check_session(&data, &headers, bearer).await?;
+    tracing::debug!(user = session.user.id, "mfa disable");
+    mfa.disable(
+        &session.user.id,
+        payload.code.as_deref().unwrap_or_default(),
+        Utc::now().timestamp_millis(),
+    )
+    .await?;
+    Ok(())
+}
+
+fn get_mfa(data: &service::Data) -> Result<&totp::Mfa, ApiError> {
+    data.mfa
+        .as_ref()
+        .ok_or_else(|| ApiError::Server("mfa is not configured".to_string()))
+}
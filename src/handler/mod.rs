@@ -1,3 +1,4 @@
+pub mod admin;
 pub mod auth;
 pub mod data;
 pub mod error;
@@ -5,4 +6,9 @@ pub mod keep_alive;
 pub mod live;
 pub mod login;
 pub mod logout;
+pub mod mfa;
+pub mod oidc;
+pub mod sessions;
+pub mod users;
 pub mod validate;
+pub mod webauthn;
@@ -0,0 +1,160 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Query, State},
+    http::{header, HeaderMap, HeaderValue},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::Utc;
+use reqwest::StatusCode;
+use serde::Deserialize;
+
+use crate::{
+    auth::oidc::{self, COOKIE_NAME},
+    model::service,
+};
+
+use super::{
+    error::ApiError,
+    login::{create_session, start_mfa},
+};
+
+const COOKIE_PATH: &str = "/auth/oidc";
+const COOKIE_MAX_AGE_SECS: i64 = 600;
+
+#[derive(Deserialize)]
+pub struct CallbackParams {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+// Redirects the browser to the IdP authorization endpoint
+pub async fn start(State(data): State<Arc<service::Data>>) -> Result<Response, ApiError> {
+    tracing::debug!("start oidc login");
+    let client = get_client(&data)?;
+    let start = client.start(Utc::now().timestamp_millis()).await?;
+    let cookie = make_cookie(&start.cookie, COOKIE_MAX_AGE_SECS);
+    Ok((
+        StatusCode::FOUND,
+        [
+            (header::LOCATION, to_header_value(&start.url)?),
+            (header::SET_COOKIE, to_header_value(&cookie)?),
+            (header::CACHE_CONTROL, HeaderValue::from_static("no-store")),
+        ],
+    )
+        .into_response())
+}
+
+// Completes the login after the IdP redirects back with the authorization code
+pub async fn callback(
+    State(data): State<Arc<service::Data>>,
+    headers: HeaderMap,
+    Query(params): Query<CallbackParams>,
+) -> Result<Response, ApiError> {
+    let client = get_client(&data)?;
+    if let Some(error) = params.error {
+        return Err(ApiError::OtherAuth(format!(
+            "oidc error: {error}, {}",
+            params.error_description.unwrap_or_default()
+        )));
+    }
+    let (code, state) = match (params.code, params.state) {
+        (Some(code), Some(state)) => (code, state),
+        _ => {
+            return Err(ApiError::BadRequest(
+                "no code or state".to_string(),
+                "oidc callback".to_string(),
+            ))
+        }
+    };
+    let cookie = get_cookie(&headers, COOKIE_NAME).ok_or_else(|| {
+        ApiError::BadRequest("no login cookie".to_string(), "oidc callback".to_string())
+    })?;
+    let ip = data.ip_extractor.get(&headers);
+    tracing::debug!(ip = ip.as_ref(), "oidc callback");
+
+    let user = client
+        .finish(&cookie, &state, &code, Utc::now().timestamp_millis())
+        .await?;
+    tracing::debug!(user = user.id, "oidc login");
+    let clear_cookie = (header::SET_COOKIE, to_header_value(&make_cookie("", 0))?);
+    // the IdP login is only the first factor, enrolled users finish it at /auth/login/mfa
+    let fragment = match start_mfa(&data, &user, &ip).await? {
+        Some(res) if client.post_login_url().is_empty() => {
+            return Ok(([clear_cookie], Json(res)).into_response());
+        }
+        Some(res) => format!(
+            "mfa_token={}&mfa_methods={}",
+            urlencoding::encode(&res.mfa_token),
+            res.mfa_methods.join(",")
+        ),
+        None => {
+            let res = create_session(&data, user, &ip).await?;
+            if client.post_login_url().is_empty() {
+                return Ok(([clear_cookie], Json(res)).into_response());
+            }
+            format!("session_id={}", urlencoding::encode(&res.session_id))
+        }
+    };
+    // fragment is not sent to servers, so the session id does not end up in access logs
+    let location = format!("{}#{fragment}", client.post_login_url());
+    Ok((
+        StatusCode::FOUND,
+        [
+            (header::LOCATION, to_header_value(&location)?),
+            clear_cookie,
+        ],
+    )
+        .into_response())
+}
+
+fn get_client(data: &service::Data) -> Result<&oidc::Client, ApiError> {
+    data.oidc
+        .as_ref()
+        .ok_or_else(|| ApiError::Server("oidc is not configured".to_string()))
+}
+
+fn make_cookie(value: &str, max_age: i64) -> String {
+    format!(
+        "{COOKIE_NAME}={value}; Path={COOKIE_PATH}; Max-Age={max_age}; HttpOnly; Secure; SameSite=Lax"
+    )
+}
+
+fn get_cookie(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(';'))
+        .filter_map(|c| c.trim().split_once('='))
+        .find(|(k, _)| *k == name)
+        .map(|(_, v)| v.to_string())
+        .filter(|v| !v.is_empty())
+}
+
+fn to_header_value(value: &str) -> Result<HeaderValue, ApiError> {
+    HeaderValue::from_str(value).map_err(|e| ApiError::Server(format!("build response: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case(&[], None; "none")]
+    #[test_case(&["a=1"], None; "other")]
+    #[test_case(&["authware_oidc=abc=="], Some("abc=="); "found")]
+    #[test_case(&["a=1; authware_oidc=abc; b=2"], Some("abc"); "several")]
+    #[test_case(&["a=1", "authware_oidc=abc"], Some("abc"); "several headers")]
+    #[test_case(&["authware_oidc="], None; "empty")]
+    fn test_get_cookie(input: &[&str], expected: Option<&str>) {
+        let mut headers = HeaderMap::new();
+        for h in input {
+            headers.append(header::COOKIE, HeaderValue::from_str(h).unwrap());
+        }
+        assert_eq!(get_cookie(&headers, COOKIE_NAME).as_deref(), expected);
+    }
+}
@@ -0,0 +1,66 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, State},
+    http::HeaderMap,
+    Json,
+};
+use axum_extra::{
+    headers::{authorization::Bearer, Authorization},
+    TypedHeader,
+};
+use serde::Serialize;
+
+use crate::model::service;
+
+use super::{admin::check_role, error::ApiError};
+
+// Session ids are bearer tokens, so they are not returned
+#[derive(Serialize)]
+pub struct SessionInfo {
+    ip: String,
+    valid_till: i64,
+    last_access: i64,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    backend: String,
+}
+
+#[derive(Serialize)]
+pub struct RevokeResponse {
+    removed: usize,
+}
+
+pub async fn list(
+    State(data): State<Arc<service::Data>>,
+    Path(user): Path<String>,
+    headers: HeaderMap,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+) -> Result<Json<Vec<SessionInfo>>, ApiError> {
+    check_role(&data, &headers, bearer, &data.sessions_admin_role).await?;
+    let mut res: Vec<SessionInfo> = data
+        .store
+        .list_by_user(&user)
+        .await?
+        .into_iter()
+        .map(|(_, s)| SessionInfo {
+            ip: s.ip,
+            valid_till: s.valid_till,
+            last_access: s.last_access,
+            backend: s.user.backend,
+        })
+        .collect();
+    res.sort_by_key(|s| std::cmp::Reverse(s.last_access));
+    Ok(Json(res))
+}
+
+pub async fn revoke(
+    State(data): State<Arc<service::Data>>,
+    Path(user): Path<String>,
+    headers: HeaderMap,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+) -> Result<Json<RevokeResponse>, ApiError> {
+    let admin = check_role(&data, &headers, bearer, &data.sessions_admin_role).await?;
+    let removed = data.store.remove_all_for_user(&user).await?;
+    tracing::info!(admin = admin.user.id, user, removed, "revoke sessions");
+    Ok(Json(RevokeResponse { removed }))
+}
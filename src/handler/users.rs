@@ -0,0 +1,331 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, State},
+    http::HeaderMap,
+    Json,
+};
+use axum_extra::{
+    headers::{authorization::Bearer, Authorization},
+    TypedHeader,
+};
+use reqwest::StatusCode;
+use serde::Deserialize;
+
+use crate::{
+    auth::sqlite,
+    model::{
+        service, store,
+        user::{NewUser, UpdateUser, UserInfo},
+    },
+    UserAdmin,
+};
+
+use super::{admin::check_admin, error::ApiError};
+
+#[derive(Deserialize)]
+pub struct CreateRequest {
+    id: String,
+    name: Option<String>,
+    #[serde(default)]
+    department: String,
+    pass: String,
+    #[serde(default)]
+    roles: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct UpdateRequest {
+    name: Option<String>,
+    department: Option<String>,
+    pass: Option<String>,
+    disabled: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub struct RolesRequest {
+    roles: Vec<String>,
+}
+
+pub async fn list(
+    State(data): State<Arc<service::Data>>,
+    headers: HeaderMap,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+) -> Result<Json<Vec<UserInfo>>, ApiError> {
+    check_admin(&data, &headers, bearer).await?;
+    Ok(Json(get_admin(&data)?.list().await?))
+}
+
+pub async fn get(
+    State(data): State<Arc<service::Data>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+) -> Result<Json<UserInfo>, ApiError> {
+    check_admin(&data, &headers, bearer).await?;
+    Ok(Json(get_admin(&data)?.get(&id).await?))
+}
+
+pub async fn create(
+    State(data): State<Arc<service::Data>>,
+    headers: HeaderMap,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    Json(payload): Json<CreateRequest>,
+) -> Result<StatusCode, ApiError> {
+    let admin = check_admin(&data, &headers, bearer).await?;
+    tracing::info!(admin = admin.user.id, user = payload.id, "create user");
+    let user = NewUser {
+        name: payload.name.unwrap_or_else(|| payload.id.clone()),
+        id: payload.id,
+        department: payload.department,
+        pass: payload.pass.into(),
+        roles: payload.roles,
+    };
+    get_admin(&data)?.create(user).await?;
+    Ok(StatusCode::CREATED)
+}
+
+pub async fn update(
+    State(data): State<Arc<service::Data>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    Json(payload): Json<UpdateRequest>,
+) -> Result<(), ApiError> {
+    let admin = check_admin(&data, &headers, bearer).await?;
+    tracing::info!(
+        admin = admin.user.id,
+        user = id,
+        pass = payload.pass.is_some(),
+        disabled = payload.disabled,
+        "update user"
+    );
+    // a new password or a disabled user ends the live sessions
+    let revoke = payload.disabled == Some(true) || payload.pass.is_some();
+    let update = UpdateUser {
+        name: payload.name,
+        department: payload.department,
+        pass: payload.pass.map(|p| p.into()),
+        disabled: payload.disabled,
+    };
+    get_admin(&data)?.update(&id, update).await?;
+    if revoke {
+        revoke_sessions(&data, &id).await?;
+    }
+    Ok(())
+}
+
+pub async fn set_roles(
+    State(data): State<Arc<service::Data>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    Json(payload): Json<RolesRequest>,
+) -> Result<(), ApiError> {
+    let admin = check_admin(&data, &headers, bearer).await?;
+    tracing::info!(admin = admin.user.id, user = id, roles = ?payload.roles, "set roles");
+    get_admin(&data)?.set_roles(&id, payload.roles).await?;
+    Ok(())
+}
+
+pub async fn delete(
+    State(data): State<Arc<service::Data>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+) -> Result<(), ApiError> {
+    let admin = check_admin(&data, &headers, bearer).await?;
+    tracing::info!(admin = admin.user.id, user = id, "delete user");
+    get_admin(&data)?.delete(&id).await?;
+    revoke_sessions(&data, &id).await?;
+    Ok(())
+}
+
+// Live sessions of a changed or deleted user are not valid anymore. Only the sessions
+// of this backend go, the same id may be another user of ldap or an api key
+async fn revoke_sessions(data: &service::Data, id: &str) -> Result<(), ApiError> {
+    let mut removed = 0;
+    for (session_id, session) in data.store.list_by_user(id).await? {
+        if session.user.backend != sqlite::BACKEND {
+            continue;
+        }
+        match data.store.remove(&session_id).await {
+            Ok(()) => removed += 1,
+            // logged out meanwhile
+            Err(store::Error::NoSession()) => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+    tracing::info!(user = id, removed, "revoke sessions");
+    Ok(())
+}
+
+fn get_admin(data: &service::Data) -> Result<&(dyn UserAdmin + Send + Sync), ApiError> {
+    data.user_admin
+        .as_deref()
+        .ok_or_else(|| ApiError::Server("user admin is not configured".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+    use axum::http::HeaderValue;
+    use chrono::Utc;
+    use test_case::test_case;
+
+    use super::*;
+    use crate::{
+        auth::sample::Sample,
+        model::{auth::User, data::SessionData, user},
+        store::memory::InMemorySessionStore,
+        SessionStore,
+    };
+
+    // accepts every change
+    struct Admin;
+
+    #[async_trait]
+    impl UserAdmin for Admin {
+        async fn list(&self) -> Result<Vec<UserInfo>, user::Error> {
+            Ok(vec![])
+        }
+        async fn get(&self, _id: &str) -> Result<UserInfo, user::Error> {
+            Err(user::Error::NoUser())
+        }
+        async fn create(&self, _user: NewUser) -> Result<(), user::Error> {
+            Ok(())
+        }
+        async fn update(&self, _id: &str, _update: UpdateUser) -> Result<(), user::Error> {
+            Ok(())
+        }
+        async fn delete(&self, _id: &str) -> Result<(), user::Error> {
+            Ok(())
+        }
+        async fn set_roles(&self, _id: &str, _roles: Vec<String>) -> Result<(), user::Error> {
+            Ok(())
+        }
+    }
+
+    fn session(user: &str, role: &str, backend: &str) -> SessionData {
+        let now = Utc::now().timestamp_millis();
+        SessionData {
+            user: User {
+                id: user.to_string(),
+                name: user.to_string(),
+                department: String::new(),
+                roles: vec![role.to_string()],
+                backend: backend.to_string(),
+            },
+            ip: "1.1.1.1".to_string(),
+            valid_till: now + 60_000,
+            last_access: now,
+            refreshed: now,
+        }
+    }
+
+    async fn make_data() -> Arc<service::Data> {
+        let store = InMemorySessionStore::new();
+        store
+            .add("admin", session("admin", "ADMIN", sqlite::BACKEND))
+            .await
+            .unwrap();
+        store
+            .add("s1", session("olia", "USER", sqlite::BACKEND))
+            .await
+            .unwrap();
+        store
+            .add("s2", session("olia", "USER", sqlite::BACKEND))
+            .await
+            .unwrap();
+        // the same id from another backend
+        store
+            .add("s3", session("olia", "USER", "ldap"))
+            .await
+            .unwrap();
+        let mut res = service::Data::for_tests(Box::new(store), Box::new(Sample::new("").unwrap()));
+        res.user_admin = Some(Box::new(Admin));
+        Arc::new(res)
+    }
+
+    fn headers() -> HeaderMap {
+        let mut res = HeaderMap::new();
+        res.insert("x-forwarded-for", HeaderValue::from_static("1.1.1.1"));
+        res
+    }
+
+    fn bearer() -> Option<TypedHeader<Authorization<Bearer>>> {
+        Some(TypedHeader(Authorization::bearer("admin").unwrap()))
+    }
+
+    fn update_request(disabled: Option<bool>, pass: Option<&str>) -> Json<UpdateRequest> {
+        Json(UpdateRequest {
+            name: Some("Olia".to_string()),
+            department: None,
+            pass: pass.map(str::to_string),
+            disabled,
+        })
+    }
+
+    async fn sessions(data: &service::Data) -> Vec<String> {
+        let mut res: Vec<String> = data
+            .store
+            .list_by_user("olia")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        res.sort();
+        res
+    }
+
+    #[tokio::test]
+    async fn test_update_keeps_sessions() {
+        let data = make_data().await;
+        for disabled in [None, Some(false)] {
+            update(
+                State(data.clone()),
+                Path("olia".to_string()),
+                headers(),
+                bearer(),
+                update_request(disabled, None),
+            )
+            .await
+            .unwrap();
+            assert_eq!(sessions(&data).await, vec!["s1", "s2", "s3"]);
+        }
+    }
+
+    #[test_case(Some(true), None; "disable")]
+    #[test_case(None, Some("new-pass"); "password")]
+    #[tokio::test]
+    async fn test_update_revokes_sessions(disabled: Option<bool>, pass: Option<&str>) {
+        let data = make_data().await;
+        update(
+            State(data.clone()),
+            Path("olia".to_string()),
+            headers(),
+            bearer(),
+            update_request(disabled, pass),
+        )
+        .await
+        .unwrap();
+        assert_eq!(sessions(&data).await, vec!["s3"]);
+        assert!(data.store.get("admin").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_delete_revokes_sessions() {
+        let data = make_data().await;
+        delete(
+            State(data.clone()),
+            Path("olia".to_string()),
+            headers(),
+            bearer(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(sessions(&data).await, vec!["s3"]);
+        assert!(data.store.get("admin").await.is_ok());
+    }
+}
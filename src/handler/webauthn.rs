@@ -0,0 +1,226 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{self, Path, State},
+    http::HeaderMap,
+    Json,
+};
+use axum_extra::{
+    headers::{authorization::Bearer, Authorization},
+    TypedHeader,
+};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth::webauthn::{self, PublicKeyCredential},
+    model::service,
+};
+
+use super::{
+    admin::check_session,
+    error::ApiError,
+    login::{create_session, Response},
+};
+
+#[derive(Deserialize)]
+pub struct LoginStartRequest {
+    user: Option<String>,
+    // token from /auth/login when the passkey is used as a second factor
+    mfa_token: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct RegisterResponse {
+    id: String,
+}
+
+#[derive(Serialize)]
+pub struct CredentialInfo {
+    id: String,
+    created: i64,
+}
+
+pub async fn register_start(
+    State(data): State<Arc<service::Data>>,
+    headers: HeaderMap,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+) -> Result<extract::Json<serde_json::Value>, ApiError> {
+    let webauthn = get_webauthn(&data)?;
+    let session = check_session(&data, &headers, bearer).await?;
+    let ip = data.ip_extractor.get(&headers);
+    tracing::debug!(user = session.user.id, "webauthn register start");
+    Ok(Json(webauthn.start_register(&session.user, &ip).await?))
+}
+
+pub async fn register_finish(
+    State(data): State<Arc<service::Data>>,
+    headers: HeaderMap,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    Json(payload): Json<PublicKeyCredential>,
+) -> Result<extract::Json<RegisterResponse>, ApiError> {
+    let webauthn = get_webauthn(&data)?;
+    let session = check_session(&data, &headers, bearer).await?;
+    let ip = data.ip_extractor.get(&headers);
+    tracing::debug!(user = session.user.id, "webauthn register finish");
+    let res = webauthn
+        .finish_register(
+            &session.user.id,
+            &ip,
+            &payload,
+            Utc::now().timestamp_millis(),
+        )
+        .await?;
+    Ok(Json(RegisterResponse { id: res.id }))
+}
+
+pub async fn login_start(
+    State(data): State<Arc<service::Data>>,
+    headers: HeaderMap,
+    Json(payload): Json<LoginStartRequest>,
+) -> Result<extract::Json<serde_json::Value>, ApiError> {
+    let webauthn = get_webauthn(&data)?;
+    let ip = data.ip_extractor.get(&headers);
+    tracing::debug!(ip = ip.as_ref(), "webauthn login start");
+    let res = match payload.mfa_token {
+        Some(mfa_token) => {
+            let mfa = data.mfa.as_ref().ok_or(ApiError::NoSession())?;
+            let user = mfa.peek_challenge(&mfa_token, &ip).await?;
+            webauthn
+                .start_login(Some(&user.id), Some(mfa_token), &ip)
+                .await?
+        }
+        None => {
+            webauthn
+                .start_login(payload.user.as_deref(), None, &ip)
+                .await?
+        }
+    };
+    Ok(Json(res))
+}
+
+pub async fn login_finish(
+    State(data): State<Arc<service::Data>>,
+    headers: HeaderMap,
+    Json(payload): Json<PublicKeyCredential>,
+) -> Result<extract::Json<Response>, ApiError> {
+    let webauthn = get_webauthn(&data)?;
+    let ip = data.ip_extractor.get(&headers);
+    let res = webauthn.finish_login(&ip, &payload).await?;
+    let user = match res.mfa_token {
+        Some(mfa_token) => {
+            let mfa = data.mfa.as_ref().ok_or(ApiError::NoSession())?;
+            // the user from the password login has fresh roles
+            let user = mfa.take_challenge(&mfa_token, &ip).await?;
+            if user.id != res.credential.user_id {
+                return Err(ApiError::OtherAuth(
+                    "webauthn credential of other user".to_string(),
+                ));
+            }
+            user
+        }
+        // the backend decides if the user may still log in and with what roles
+        None => data.auth_service.lookup(&res.credential.user_id).await?,
+    };
+    tracing::debug!(user = user.id, "webauthn login");
+    let response = create_session(&data, user, &ip).await?;
+    Ok(Json(response))
+}
+
+pub async fn list_credentials(
+    State(data): State<Arc<service::Data>>,
+    headers: HeaderMap,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+) -> Result<extract::Json<Vec<CredentialInfo>>, ApiError> {
+    let webauthn = get_webauthn(&data)?;
+    let session = check_session(&data, &headers, bearer).await?;
+    let res = webauthn
+        .list_credentials(&session.user.id)
+        .await?
+        .into_iter()
+        .map(|c| CredentialInfo {
+            id: c.id,
+            created: c.created,
+        })
+        .collect();
+    Ok(Json(res))
+}
+
+pub async fn remove_credential(
+    State(data): State<Arc<service::Data>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+) -> Result<(), ApiError> {
+    let webauthn = get_webauthn(&data)?;
+    let session = check_session(&data, &headers, bearer).await?;
+    webauthn.remove_credential(&session.user.id, &id).await?;
+    Ok(())
+}
+
+fn get_webauthn(data: &service::Data) -> Result<&webauthn::WebAuthn, ApiError> {
+    data.webauthn
+        .as_ref()
+        .ok_or_else(|| ApiError::Server("webauthn is not configured".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+    use crate::{
+        auth::{
+            roles::RoleMapper,
+            sample::Sample,
+            webauthn::tests::{make_webauthn, registered, USER_VERIFIED},
+        },
+        store::memory::InMemorySessionStore,
+    };
+
+    async fn passwordless(data: Arc<service::Data>, user: &str) -> Result<Response, ApiError> {
+        let webauthn = get_webauthn(&data).unwrap();
+        let mut authenticator = registered(webauthn, user).await;
+        let options = webauthn.start_login(None, None, "1.1.1.1").await.unwrap();
+        let credential = authenticator.get(&options, USER_VERIFIED);
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", HeaderValue::from_static("1.1.1.1"));
+        login_finish(State(data), headers, Json(credential))
+            .await
+            .map(|res| res.0)
+    }
+
+    fn make_data(users: &str) -> Arc<service::Data> {
+        let mut res = service::Data::for_tests(
+            Box::new(InMemorySessionStore::new()),
+            Box::new(Sample::new(users).unwrap()),
+        );
+        res.webauthn = Some(make_webauthn());
+        // chained renames, roles mapped twice would end up as ADMIN
+        res.role_mapper = Some(
+            RoleMapper::from_config(
+                serde_json::from_value(serde_json::json!({
+                    "rename": {"app_user": "USER", "USER": "ADMIN"}
+                }))
+                .unwrap(),
+            )
+            .unwrap(),
+        );
+        Arc::new(res)
+    }
+
+    #[tokio::test]
+    async fn test_passwordless_uses_backend_user() {
+        let data = make_data("olia:pass:IT:app_user");
+        let res = passwordless(data.clone(), "olia").await.unwrap();
+        let session = data.store.get(&res.session_id).await.unwrap();
+        assert_eq!(session.user.id, "olia");
+        assert_eq!(session.user.roles, vec!["USER"]);
+    }
+
+    #[tokio::test]
+    async fn test_passwordless_fails_for_removed_user() {
+        let data = make_data("jonas:pass:IT:app_user");
+        assert!(passwordless(data, "olia").await.is_err());
+    }
+}
@@ -1,14 +1,16 @@
 pub mod auth;
 pub mod model;
 pub mod store;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 pub mod tls;
 pub mod utils;
 
-use std::borrow::Cow;
+use std::{borrow::Cow, time::Duration};
 
 use async_trait::async_trait;
 use axum::http::HeaderMap;
-use model::data::SessionData;
+use model::{config::EvictPolicy, data::SessionData};
 use tokio::signal;
 use utils::secret_str::SecretString;
 
@@ -21,6 +23,82 @@ pub trait SessionStore {
     async fn get(&self, session_id: &str) -> Result<SessionData, model::store::Error>;
     async fn remove(&self, session_id: &str) -> Result<(), model::store::Error>;
     async fn mark_last_used(&self, session_id: &str, now: i64) -> Result<(), model::store::Error>;
+    // Writes the user and `refreshed` of an existing session if its `refreshed` is still
+    // `current`, the access time and the expiration stay. Returns false if another request
+    // refreshed it meanwhile, `NoSession` if it is gone, so a revoked session is not revived
+    async fn update_user(
+        &self,
+        session_id: &str,
+        data: SessionData,
+        current: i64,
+    ) -> Result<bool, model::store::Error>;
+    // Adds a new session keeping at most `max` sessions of the user, 0 - unlimited.
+    // Returns the number of evicted sessions
+    async fn add_limited(
+        &self,
+        session_id: &str,
+        data: SessionData,
+        max: usize,
+        policy: EvictPolicy,
+    ) -> Result<usize, model::store::Error>;
+    // Active sessions of the user with their ids
+    async fn list_by_user(
+        &self,
+        user_id: &str,
+    ) -> Result<Vec<(String, SessionData)>, model::store::Error>;
+    // Returns the number of removed sessions
+    async fn remove_all_for_user(&self, user_id: &str) -> Result<usize, model::store::Error>;
+}
+
+// Broadcasts session cache invalidations to all instances
+#[async_trait]
+pub trait InvalidationBus {
+    async fn publish(&self, message: &str) -> Result<(), model::store::Error>;
+}
+
+// Simple key value storage for auxiliary data, values are expected to be encrypted by the caller
+#[async_trait]
+pub trait KeyValueStore {
+    async fn get(&self, key: &str) -> Result<Option<String>, model::store::Error>;
+    async fn set(
+        &self,
+        key: &str,
+        value: &str,
+        ttl: Option<Duration>,
+    ) -> Result<(), model::store::Error>;
+    async fn remove(&self, key: &str) -> Result<(), model::store::Error>;
+    // Writes `value` (None - removes the key) only if the key still holds `current`
+    // (None - no key), returns false if it was changed meanwhile
+    async fn compare_and_set(
+        &self,
+        key: &str,
+        current: Option<&str>,
+        value: Option<&str>,
+        ttl: Option<Duration>,
+    ) -> Result<bool, model::store::Error>;
+}
+
+// Storage for webauthn credentials
+#[async_trait]
+pub trait CredentialStore {
+    async fn list(
+        &self,
+        user_id: &str,
+    ) -> Result<Vec<model::webauthn::Credential>, model::webauthn::Error>;
+    async fn get(
+        &self,
+        id: &str,
+    ) -> Result<Option<model::webauthn::Credential>, model::webauthn::Error>;
+    async fn add(
+        &self,
+        credential: model::webauthn::Credential,
+    ) -> Result<(), model::webauthn::Error>;
+    // Writes the new sign count, `Invalid` if it does not grow over the stored one
+    async fn update(
+        &self,
+        credential: model::webauthn::Credential,
+    ) -> Result<(), model::webauthn::Error>;
+    async fn remove(&self, id: &str) -> Result<(), model::webauthn::Error>;
 }
 
 #[async_trait]
@@ -30,6 +108,49 @@ pub trait AuthService {
         user: &str,
         pass: &SecretString,
     ) -> Result<model::auth::User, model::auth::Error>;
+
+    // Login by the backend picked by the client, supported by the combined auth only
+    async fn login_backend(
+        &self,
+        _backend: &str,
+        _user: &str,
+        _pass: &SecretString,
+    ) -> Result<model::auth::User, model::auth::Error> {
+        Err(model::auth::Error::Unsupported(
+            "auth backend selection is not supported".to_string(),
+        ))
+    }
+
+    // Reloads the user of a live session. `None` - not supported, keep the user.
+    // Service errors keep the user too, other errors revoke the session
+    async fn refresh_user(
+        &self,
+        _user: &model::auth::User,
+    ) -> Result<Option<model::auth::User>, model::auth::Error> {
+        Ok(None)
+    }
+
+    // Finds the user without a password, for logins proven by other means, e.g. a client certificate
+    async fn lookup(&self, _user: &str) -> Result<model::auth::User, model::auth::Error> {
+        Err(model::auth::Error::Unsupported(
+            "user lookup is not supported".to_string(),
+        ))
+    }
+}
+
+// User management for auth backends owning their users
+#[async_trait]
+pub trait UserAdmin {
+    async fn list(&self) -> Result<Vec<model::user::UserInfo>, model::user::Error>;
+    async fn get(&self, id: &str) -> Result<model::user::UserInfo, model::user::Error>;
+    async fn create(&self, user: model::user::NewUser) -> Result<(), model::user::Error>;
+    async fn update(
+        &self,
+        id: &str,
+        update: model::user::UpdateUser,
+    ) -> Result<(), model::user::Error>;
+    async fn delete(&self, id: &str) -> Result<(), model::user::Error>;
+    async fn set_roles(&self, id: &str, roles: Vec<String>) -> Result<(), model::user::Error>;
 }
 
 pub trait Encryptor {
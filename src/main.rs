@@ -1,16 +1,24 @@
-pub mod model;
+use authware::auth::resilience::{Bulkhead, CircuitBreaker, Retry, Timeout};
 use authware::auth::sample::Sample;
-use authware::model::config::SessionConfig;
+use authware::model::config::{SessionConfig, SessionLimits};
 use authware::model::service;
+use authware::store::cache::{CachedSessionStore, SessionCache};
+use authware::store::coalescing::CoalescingSessionStore;
+use authware::store::credential::KeyValueCredentialStore;
 use authware::store::encryptor::MagicEncryptor;
-use authware::store::memory::InMemorySessionStore;
-use authware::store::redis::RedisSessionStore;
+use authware::store::memory::{InMemoryKeyValueStore, InMemorySessionStore};
+use authware::store::redis::{
+    RedisInvalidationBus, RedisKeyValueStore, RedisPool, RedisSessionStore,
+};
+use authware::store::sqlite::SqliteSessionStore;
 use authware::tls::cert::generate_certificates;
 use authware::utils::ip_extractor;
-use authware::{handler, shutdown_signal, AuthService, Encryptor, IPExtractor, SessionStore};
+use authware::{
+    auth, handler, shutdown_signal, AuthService, Encryptor, IPExtractor, KeyValueStore,
+    SessionStore, UserAdmin,
+};
 use axum::http::HeaderName;
 use axum_server::tls_rustls::RustlsConfig;
-use deadpool_redis::{Config, Runtime};
 use humantime::format_duration;
 use std::net::SocketAddr;
 use std::sync::Arc;
@@ -22,7 +30,7 @@ use tower_http::timeout::TimeoutLayer;
 use tower_http::trace::TraceLayer;
 
 use axum::{
-    routing::{get, post},
+    routing::{delete, get, post, put},
     Router,
 };
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
@@ -40,15 +48,78 @@ struct Args {
     /// Inactivity timeout
     #[arg(long, env, default_value = "30m", value_parser = humantime::parse_duration)]
     inactivity_timeout: Duration,
+    /// How often the session user is reloaded from the auth backend, 0 - never
+    #[arg(long, env, default_value = "0s", value_parser = humantime::parse_duration)]
+    session_refresh: Duration,
+    /// Last access writes closer than this are coalesced and written in batches, 0 - every request writes
+    #[arg(long, env, default_value = "0s", value_parser = humantime::parse_duration)]
+    touch_granularity: Duration,
+    /// Max active sessions per user, 0 - unlimited
+    #[arg(long, env, default_value = "0")]
+    session_limit: usize,
+    /// Per role session limits overriding the default, format: ROLE=n,ROLE2=n
+    #[arg(long, env, default_value = "")]
+    session_limit_roles: String,
+    /// What to do when a login exceeds the session limit: reject, oldest or lru
+    #[arg(long, env, default_value = "reject")]
+    session_limit_policy: String,
     /// Sample users list, format: user:pass;user:pass
     #[arg(long, env, default_value = "admin:admin;user:user")]
     sample_users: String,
+    /// Users file with bcrypt/argon2 hashes, format: user:hash:department:role1,role2 per line or json
+    #[arg(long, env, default_value = "")]
+    user_file: String,
+    /// Users file change check interval, 0 - disables reload
+    #[arg(long, env, default_value = "10s", value_parser = humantime::parse_duration)]
+    user_file_reload: Duration,
+    /// Sqlite users db path, enables /auth/admin/users endpoints
+    #[arg(long, env, default_value = "")]
+    user_db: String,
+    /// Initial admin created in an empty users db, format: user:pass
+    #[arg(long, env, default_value = "")]
+    user_db_init_admin: String,
+    /// Password max age for users db, 0 - never expires
+    #[arg(long, env, default_value = "0s", value_parser = humantime::parse_duration)]
+    password_max_age: Duration,
+    /// Role required for admin endpoints
+    #[arg(long, env, default_value = "ADMIN")]
+    admin_role: String,
+    /// Role required for listing and revoking sessions of users, admin role if empty
+    #[arg(long, env, default_value = "")]
+    sessions_admin_role: String,
     /// host for certificate generation    
     #[arg(long, env, default_value = "localhost")]
     host: String,
-    /// redis url
+    /// redis url, comma separated sentinel or cluster node urls for these modes
     #[arg(long, env, default_value = "")]
     redis_url: String,
+    /// redis deployment: standalone, sentinel or cluster
+    #[arg(long, env, default_value = "standalone")]
+    redis_mode: String,
+    /// master name monitored by redis sentinels
+    #[arg(long, env, default_value = "mymaster")]
+    redis_sentinel_master: String,
+    /// Max sessions cached in memory in front of redis, 0 - no cache
+    #[arg(long, env, default_value = "0")]
+    session_cache_size: usize,
+    /// How long a cached session is served without reading redis
+    #[arg(long, env, default_value = "2s", value_parser = humantime::parse_duration)]
+    session_cache_ttl: Duration,
+    /// sqlite db path for sessions, used if no redis url is set
+    #[arg(long, env, default_value = "")]
+    sqlite_path: String,
+    /// How often expired sessions are deleted from the sqlite db
+    #[arg(long, env, default_value = "1m", value_parser = parse_interval)]
+    sqlite_sweep_interval: Duration,
+    /// Max sessions kept by the in-memory store, new logins are rejected at the cap, 0 - unlimited
+    #[arg(long, env, default_value = "0")]
+    memory_max_sessions: usize,
+    /// How often expired and idle sessions are dropped from the in-memory store
+    #[arg(long, env, default_value = "30s", value_parser = parse_interval)]
+    memory_sweep_interval: Duration,
+    /// File the in-memory sessions are saved to on shutdown and loaded from on start, empty - not saved
+    #[arg(long, env, default_value = "")]
+    memory_snapshot_path: String,
     // data encryption key
     #[arg(long, env, default_value = "", required = true)]
     encryption_key: String,
@@ -68,9 +139,174 @@ struct Args {
     // app code in authentication ws
     #[arg(long, env, default_value = "", required = false)]
     auth_app_code: String,
+    // http timeout of authentication ws calls, below auth_call_timeout to fail with the ws error
+    #[arg(long, env, default_value = "3s", value_parser = humantime::parse_duration)]
+    auth_ws_timeout: Duration,
+    // how the user's password is sent to the authentication ws: path, body or header
+    #[arg(long, env, default_value = "path")]
+    auth_ws_credentials: String,
     // app code in authentication ws
     #[arg(long, env, default_value = "false", required = false)]
     is_test_mode: bool,
+
+    // how several auth backends are combined: sequential, fallback (the next one only on
+    // a service error) or parallel
+    #[arg(long, env, default_value = "sequential")]
+    auth_strategy: String,
+    // max wait for the parallel auth strategy
+    #[arg(long, env, default_value = "10s", value_parser = humantime::parse_duration)]
+    auth_deadline: Duration,
+    // routes users to auth backends by name patterns, e.g. `*@corp=ldap,svc-*=file`
+    #[arg(long, env, default_value = "")]
+    auth_routes: String,
+    // allows clients to pick the auth backend with the `backend` login field
+    #[arg(long, env, default_value = "false")]
+    auth_backend_select: bool,
+    // deadline of one attempt of a remote auth backend call, 0 - none
+    #[arg(long, env, default_value = "4s", value_parser = humantime::parse_duration)]
+    auth_call_timeout: Duration,
+    // retries of remote auth backend service errors and timed out attempts, 0 - none
+    #[arg(long, env, default_value = "2")]
+    auth_retries: u32,
+    // first retry delay, doubled for the next ones
+    #[arg(long, env, default_value = "200ms", value_parser = humantime::parse_duration)]
+    auth_retry_backoff: Duration,
+    // service errors in a row that open the circuit of a remote auth backend, 0 - disabled
+    #[arg(long, env, default_value = "5")]
+    auth_breaker_failures: u32,
+    // how long an open circuit fails calls at once
+    #[arg(long, env, default_value = "30s", value_parser = humantime::parse_duration)]
+    auth_breaker_open: Duration,
+    // max concurrent calls to one remote auth backend, 0 - unlimited
+    #[arg(long, env, default_value = "100")]
+    auth_max_in_flight: usize,
+    // keeps successful logins of remote auth backends for this long and accepts them while
+    // the backend is unavailable, 0 - disabled
+    #[arg(long, env, default_value = "0s", value_parser = humantime::parse_duration)]
+    login_cache_staleness: Duration,
+    // json config file of role mapping applied after login
+    #[arg(long, env, default_value = "")]
+    role_map_file: String,
+    // json config file of the generic rest auth backend
+    #[arg(long, env, default_value = "", required = false)]
+    rest_auth_config: String,
+
+    // ldap url, e.g. ldaps://dc.corp.local:636
+    #[arg(long, env, default_value = "", required = false)]
+    ldap_url: String,
+    // ldap service account dn used to search users
+    #[arg(long, env, default_value = "", required = false)]
+    ldap_bind_dn: String,
+    // ldap service account pass
+    #[arg(long, env, default_value = "", required = false)]
+    ldap_bind_pass: String,
+    // ldap base dn to search users in
+    #[arg(long, env, default_value = "", required = false)]
+    ldap_base_dn: String,
+    // ldap user search filter, {user} is replaced with the login name
+    #[arg(
+        long,
+        env,
+        default_value = "(&(objectClass=user)(sAMAccountName={user}))"
+    )]
+    ldap_user_filter: String,
+    // ldap attribute for user's display name
+    #[arg(long, env, default_value = "displayName")]
+    ldap_name_attr: String,
+    // ldap attribute for user's department
+    #[arg(long, env, default_value = "department")]
+    ldap_department_attr: String,
+    // follow nested group membership
+    #[arg(long, env, default_value = "true", action = clap::ArgAction::Set)]
+    ldap_nested_groups: bool,
+    // ldap call timeout, below auth_call_timeout
+    #[arg(long, env, default_value = "3s", value_parser = humantime::parse_duration)]
+    ldap_timeout: Duration,
+
+    // oidc issuer or discovery url, enables /auth/oidc/* endpoints
+    #[arg(long, env, default_value = "", required = false)]
+    oidc_discovery_url: String,
+    // oidc client id
+    #[arg(long, env, default_value = "", required = false)]
+    oidc_client_id: String,
+    // oidc client secret, empty for public clients
+    #[arg(long, env, default_value = "", required = false)]
+    oidc_client_secret: String,
+    // oidc redirect url pointing to /auth/oidc/callback
+    #[arg(long, env, default_value = "", required = false)]
+    oidc_redirect_url: String,
+    // oidc scopes, separated by space
+    #[arg(long, env, default_value = "openid profile email")]
+    oidc_scopes: String,
+    // id token claim for user id
+    #[arg(long, env, default_value = "preferred_username")]
+    oidc_user_claim: String,
+    // id token claim for user name
+    #[arg(long, env, default_value = "name")]
+    oidc_name_claim: String,
+    // id token claim for department
+    #[arg(long, env, default_value = "department")]
+    oidc_department_claim: String,
+    // id token claim path for roles, e.g. groups or realm_access.roles
+    #[arg(long, env, default_value = "groups")]
+    oidc_roles_claim: String,
+    // idp role to authware role map, format: idp_role=ROLE;idp_role2=ROLE2
+    #[arg(long, env, default_value = "", required = false)]
+    oidc_role_map: String,
+    // url to redirect after oidc login, if empty the callback returns json
+    #[arg(long, env, default_value = "", required = false)]
+    oidc_post_login_url: String,
+
+    // enables TOTP second factor and /auth/mfa/* endpoints, needs redis_url or sqlite_path
+    #[arg(long, env, default_value = "false")]
+    mfa_enabled: bool,
+    // issuer shown in authenticator apps
+    #[arg(long, env, default_value = "authware")]
+    mfa_issuer: String,
+
+    // webauthn relying party id (domain), enables /auth/webauthn/* endpoints, needs mfa_enabled
+    // and redis_url or sqlite_path
+    #[arg(long, env, default_value = "", required = false)]
+    webauthn_rp_id: String,
+    // relying party name shown by authenticators
+    #[arg(long, env, default_value = "authware")]
+    webauthn_rp_name: String,
+    // allowed origins separated by comma, default https://<rp id>
+    #[arg(long, env, default_value = "", required = false)]
+    webauthn_origins: String,
+    // webauthn ceremony timeout
+    #[arg(long, env, default_value = "5m", value_parser = humantime::parse_duration)]
+    webauthn_timeout: Duration,
+
+    // api keys json file for machine clients of /auth
+    #[arg(long, env, default_value = "", required = false)]
+    api_keys_file: String,
+    // custom header with an api key, besides `Authorization: ApiKey <key>`
+    #[arg(long, env, default_value = "X-Api-Key")]
+    api_key_header: String,
+
+    // PEM bundle of CAs trusted for client certificates forwarded by traefik, enables cert auth on /auth
+    #[arg(long, env, default_value = "", required = false)]
+    client_cert_ca_file: String,
+    // certificate field used as the user id: cn, email, dns or upn
+    #[arg(long, env, default_value = "cn")]
+    client_cert_user_field: String,
+    // json file mapping cert users to roles: {"user": {"name": "", "department": "", "roles": [""]}}
+    #[arg(long, env, default_value = "", required = false)]
+    client_cert_users_file: String,
+    // look up cert users missing in the users file via the auth backend
+    #[arg(long, env, default_value = "false")]
+    client_cert_lookup: bool,
+    // how long looked up cert users are cached
+    #[arg(long, env, default_value = "1m", value_parser = humantime::parse_duration)]
+    client_cert_lookup_cache: Duration,
+    // accept X-Forwarded-Tls-Client-Cert-Info without the certificate, traefik must verify the chain
+    #[arg(long, env, default_value = "false")]
+    client_cert_trust_info: bool,
+    // value traefik sets in X-Client-Cert-Proxy-Secret on the routers passing client certs,
+    // cert headers without it are ignored, required for cert auth
+    #[arg(long, env, default_value = "", required = false)]
+    client_cert_proxy_secret: String,
 }
 
 async fn main_int(args: Args) -> anyhow::Result<()> {
@@ -94,21 +330,226 @@ async fn main_int(args: Args) -> anyhow::Result<()> {
     let config = SessionConfig {
         inactivity: args.inactivity_timeout.as_millis() as i64,
         session_timeout: args.session_timeout.as_millis() as i64,
+        refresh: args.session_refresh.as_millis() as i64,
+        limits: SessionLimits::new(
+            args.session_limit,
+            &args.session_limit_roles,
+            args.session_limit_policy.parse()?,
+        )?,
     };
 
-    let store: Box<dyn SessionStore + Send + Sync> = if args.redis_url.is_empty() {
-        log::warn!("Using in-memory store");
-        Box::new(InMemorySessionStore::new())
+    let redis_pool = if args.redis_url.is_empty() {
+        None
     } else {
-        log::info!("Using redis store");
-        let cfg = Config::from_url(args.redis_url.clone());
-        let pool = cfg.create_pool(Some(Runtime::Tokio1))?;
+        Some(RedisPool::new(
+            args.redis_mode.parse()?,
+            &args.redis_url,
+            &args.redis_sentinel_master,
+        )?)
+    };
+    if redis_pool.is_some() && !args.sqlite_path.is_empty() {
+        return Err(anyhow::anyhow!("Both redis url and sqlite path are set"));
+    }
+    if redis_pool.is_none() && args.session_cache_size > 0 {
+        return Err(anyhow::anyhow!("Session cache needs a redis url"));
+    }
+    if (redis_pool.is_some() || !args.sqlite_path.is_empty())
+        && !args.memory_snapshot_path.is_empty()
+    {
+        return Err(anyhow::anyhow!(
+            "Session snapshot needs the in-memory store"
+        ));
+    }
+    // kept for the metrics and to save the sessions on shutdown
+    let mut memory_store = None;
+    // mfa state and passkeys go next to the sessions
+    let mut sqlite_kv_store = None;
+    let store: Box<dyn SessionStore + Send + Sync> = match &redis_pool {
+        None if !args.sqlite_path.is_empty() => {
+            log::info!("Using sqlite store");
+            let encryptor: Box<dyn Encryptor + Send + Sync> =
+                Box::new(MagicEncryptor::new(&args.encryption_key)?);
+            let store = SqliteSessionStore::new(&args.sqlite_path, encryptor)?;
+            store.start_sweeper(args.sqlite_sweep_interval);
+            sqlite_kv_store = Some(store.key_value_store());
+            Box::new(store)
+        }
+        None => {
+            log::warn!("Using in-memory store");
+            // coalesced last access writes lag by up to the granularity
+            let store = InMemorySessionStore::with_limits(
+                (args.inactivity_timeout + args.touch_granularity).as_millis() as i64,
+                args.memory_max_sessions,
+            );
+            store.start_sweeper(args.memory_sweep_interval);
+            if !args.memory_snapshot_path.is_empty() {
+                let encryptor = MagicEncryptor::new(&args.encryption_key)?;
+                match store.load_snapshot(&args.memory_snapshot_path, &encryptor) {
+                    Ok(Some((loaded, dropped))) => {
+                        tracing::info!(loaded, dropped, "Loaded session snapshot")
+                    }
+                    Ok(None) => tracing::info!("No session snapshot"),
+                    Err(err) => {
+                        // moved aside, not to be replaced on shutdown
+                        let rejected = format!("{}.rejected", args.memory_snapshot_path);
+                        tracing::error!(
+                            error = format!("{err:#}"),
+                            moved_to = rejected,
+                            "Session snapshot rejected"
+                        );
+                        if let Err(err) = std::fs::rename(&args.memory_snapshot_path, &rejected) {
+                            tracing::warn!(error = %err, "can't move the rejected snapshot");
+                        }
+                    }
+                }
+            }
+            memory_store = Some(store.clone());
+            Box::new(store)
+        }
+        Some(pool) => {
+            tracing::info!(mode = args.redis_mode, "Using redis store");
+            let encryptor: Box<dyn Encryptor + Send + Sync> =
+                Box::new(MagicEncryptor::new(&args.encryption_key)?);
+            let store = Box::new(RedisSessionStore::new(pool.clone(), encryptor));
+            if args.session_cache_size > 0 {
+                tracing::info!(
+                    size = args.session_cache_size,
+                    ttl = format_duration(args.session_cache_ttl).to_string(),
+                    "Caching sessions"
+                );
+                let cache = SessionCache::new(
+                    args.session_cache_size,
+                    args.session_cache_ttl,
+                    &args.encryption_key,
+                );
+                let bus = RedisInvalidationBus::new(
+                    pool.clone(),
+                    args.redis_mode.parse()?,
+                    &args.redis_url,
+                    &args.redis_sentinel_master,
+                )?;
+                bus.start_subscriber(cache.clone());
+                Box::new(CachedSessionStore::new(store, cache, Box::new(bus)))
+            } else {
+                store
+            }
+        }
+    };
+    // kept to flush the queued last access writes on shutdown
+    let (store, coalescing): (Box<dyn SessionStore + Send + Sync>, _) =
+        if args.touch_granularity.is_zero() {
+            (store, None)
+        } else {
+            tracing::info!(
+                granularity = format_duration(args.touch_granularity).to_string(),
+                "Coalescing last access writes"
+            );
+            let res = CoalescingSessionStore::new(store, args.touch_granularity);
+            res.start_flusher();
+            (Box::new(res.clone()), Some(res))
+        };
+    let make_kv_store = || -> Box<dyn KeyValueStore + Send + Sync> {
+        match (&redis_pool, &sqlite_kv_store) {
+            (Some(pool), _) => Box::new(RedisKeyValueStore::new(pool.clone(), "authware:")),
+            (None, Some(store)) => Box::new(store.clone()),
+            (None, None) => Box::new(InMemoryKeyValueStore::new()),
+        }
+    };
+    let persistent_kv_store = redis_pool.is_some() || sqlite_kv_store.is_some();
+
+    let (auth, user_admin) = init_auth(&args, &make_kv_store).await?;
+
+    let oidc = init_oidc(&args)?;
+
+    let mfa = if args.mfa_enabled {
+        if !persistent_kv_store {
+            // enrollments lost on a restart would let the users in with a password only
+            return Err(anyhow::anyhow!("Mfa needs a redis url or a sqlite path"));
+        }
+        tracing::info!(issuer = args.mfa_issuer, "Using totp mfa");
         let encryptor: Box<dyn Encryptor + Send + Sync> =
             Box::new(MagicEncryptor::new(&args.encryption_key)?);
-        Box::new(RedisSessionStore::new(pool, encryptor))
+        Some(auth::totp::Mfa::new(
+            make_kv_store(),
+            encryptor,
+            &args.mfa_issuer,
+        ))
+    } else {
+        None
+    };
+
+    let webauthn = if args.webauthn_rp_id.is_empty() {
+        None
+    } else {
+        if !persistent_kv_store {
+            // lost passkeys would silently drop the users' second factor
+            return Err(anyhow::anyhow!(
+                "Webauthn needs a redis url or a sqlite path"
+            ));
+        }
+        if mfa.is_none() {
+            // passkeys as the second factor go through the mfa challenges
+            return Err(anyhow::anyhow!("Webauthn needs mfa to be enabled"));
+        }
+        tracing::info!(rp_id = args.webauthn_rp_id, "Using webauthn");
+        let credentials = KeyValueCredentialStore::new(
+            make_kv_store(),
+            Box::new(MagicEncryptor::new(&args.encryption_key)?),
+        );
+        let origins = if args.webauthn_origins.is_empty() {
+            vec![format!("https://{}", args.webauthn_rp_id)]
+        } else {
+            args.webauthn_origins
+                .split(',')
+                .map(|s| s.trim().to_string())
+                .collect()
+        };
+        Some(auth::webauthn::WebAuthn::new(
+            auth::webauthn::Config {
+                rp_id: args.webauthn_rp_id.clone(),
+                rp_name: args.webauthn_rp_name.clone(),
+                origins,
+                timeout: args.webauthn_timeout,
+            },
+            Box::new(credentials),
+            make_kv_store(),
+            Box::new(MagicEncryptor::new(&args.encryption_key)?),
+        )?)
+    };
+
+    let api_keys = if args.api_keys_file.is_empty() {
+        None
+    } else {
+        tracing::info!(path = args.api_keys_file, "Using api keys");
+        Some(auth::api_key::ApiKeys::new(
+            &args.api_keys_file,
+            &args.api_key_header,
+        )?)
+    };
+
+    let client_cert = if args.client_cert_ca_file.is_empty() {
+        None
+    } else {
+        tracing::info!(ca = args.client_cert_ca_file, "Using client cert auth");
+        Some(auth::client_cert::ClientCert::new(
+            auth::client_cert::Config {
+                ca_file: args.client_cert_ca_file.clone(),
+                user_field: args.client_cert_user_field.parse()?,
+                users_file: args.client_cert_users_file.clone(),
+                lookup: args.client_cert_lookup,
+                lookup_cache: args.client_cert_lookup_cache,
+                trust_info_header: args.client_cert_trust_info,
+                proxy_secret: args.client_cert_proxy_secret.clone(),
+            },
+        )?)
     };
 
-    let auth: Box<dyn AuthService + Send + Sync> = init_auth(&args).await?;
+    let role_mapper = if args.role_map_file.is_empty() {
+        None
+    } else {
+        tracing::info!(path = args.role_map_file, "Using role mapping");
+        Some(auth::roles::RoleMapper::new(&args.role_map_file)?)
+    };
 
     let ip_extractor: Box<dyn IPExtractor + Send + Sync> =
         Box::new(ip_extractor::Header::new(args.ip_index));
@@ -117,31 +558,109 @@ async fn main_int(args: Args) -> anyhow::Result<()> {
         store,
         auth_service: auth,
         ip_extractor,
+        oidc,
+        user_admin,
+        mfa,
+        webauthn,
+        api_keys,
+        client_cert,
+        role_mapper,
+        memory_store: memory_store.clone(),
+        admin_role: args.admin_role.clone(),
+        sessions_admin_role: if args.sessions_admin_role.is_empty() {
+            args.admin_role.clone()
+        } else {
+            args.sessions_admin_role.clone()
+        },
         is_test_mode: args.is_test_mode,
     };
     let quarded_data = Arc::new(service_data);
 
     let cors = CorsLayer::new()
-        .allow_methods([axum::http::Method::GET, axum::http::Method::POST])
+        .allow_methods([
+            axum::http::Method::GET,
+            axum::http::Method::POST,
+            axum::http::Method::PUT,
+            axum::http::Method::DELETE,
+        ])
         .allow_origin(Any)
         .allow_headers(vec![
             HeaderName::from_static("content-type"),
             HeaderName::from_static("authorization"),
         ]);
 
-    let app = Router::new()
+    let mut router = Router::new()
         .route("/auth/live", get(handler::live::handler))
         .route("/auth/login", post(handler::login::handler))
         .route("/auth/logout", post(handler::logout::handler))
         .route("/auth/keep-alive", post(handler::keep_alive::handler))
         .route("/auth/validate", get(handler::validate::handler))
         .route("/auth", get(handler::auth::handler))
-        .with_state(quarded_data)
-        .layer((
-            TraceLayer::new_for_http(),
-            TimeoutLayer::new(Duration::from_secs(15)),
-            cors,
-        ));
+        .route(
+            "/auth/admin/sessions/:user",
+            get(handler::sessions::list).delete(handler::sessions::revoke),
+        );
+    if quarded_data.user_admin.is_some() {
+        router = router
+            .route(
+                "/auth/admin/users",
+                get(handler::users::list).post(handler::users::create),
+            )
+            .route(
+                "/auth/admin/users/:id",
+                get(handler::users::get)
+                    .put(handler::users::update)
+                    .delete(handler::users::delete),
+            )
+            .route(
+                "/auth/admin/users/:id/roles",
+                put(handler::users::set_roles),
+            );
+    }
+    if quarded_data.oidc.is_some() {
+        router = router
+            .route("/auth/oidc/start", get(handler::oidc::start))
+            .route("/auth/oidc/callback", get(handler::oidc::callback));
+    }
+    if quarded_data.mfa.is_some() {
+        router = router
+            .route("/auth/login/mfa", post(handler::mfa::login))
+            .route("/auth/mfa/totp/enroll", post(handler::mfa::enroll))
+            .route("/auth/mfa/totp/confirm", post(handler::mfa::confirm))
+            .route("/auth/mfa/totp/disable", post(handler::mfa::disable));
+    }
+    if quarded_data.webauthn.is_some() {
+        router = router
+            .route(
+                "/auth/webauthn/register/start",
+                post(handler::webauthn::register_start),
+            )
+            .route(
+                "/auth/webauthn/register/finish",
+                post(handler::webauthn::register_finish),
+            )
+            .route(
+                "/auth/webauthn/login/start",
+                post(handler::webauthn::login_start),
+            )
+            .route(
+                "/auth/webauthn/login/finish",
+                post(handler::webauthn::login_finish),
+            )
+            .route(
+                "/auth/webauthn/credentials",
+                get(handler::webauthn::list_credentials),
+            )
+            .route(
+                "/auth/webauthn/credentials/:id",
+                delete(handler::webauthn::remove_credential),
+            );
+    }
+    let app = router.with_state(quarded_data).layer((
+        TraceLayer::new_for_http(),
+        TimeoutLayer::new(Duration::from_secs(15)),
+        cors,
+    ));
 
     let (cert, key_pair) = generate_certificates(&args.host)?;
     tracing::trace!("Configuring Rustls");
@@ -159,33 +678,192 @@ async fn main_int(args: Args) -> anyhow::Result<()> {
         .serve(app.into_make_service())
         .await
         .unwrap();
+    if let Some(coalescing) = coalescing {
+        let flushed = coalescing.flush().await;
+        tracing::info!(flushed, "flushed last access");
+    }
+    if let Some(memory_store) = memory_store.filter(|_| !args.memory_snapshot_path.is_empty()) {
+        let encryptor = MagicEncryptor::new(&args.encryption_key)?;
+        match memory_store.save_snapshot(&args.memory_snapshot_path, &encryptor) {
+            Ok(saved) => tracing::info!(saved, "Saved session snapshot"),
+            Err(err) => tracing::error!(error = format!("{err:#}"), "Can't save session snapshot"),
+        }
+    }
 
     tracing::info!("Bye");
     Ok(())
 }
 
-async fn init_auth(args: &Args) -> anyhow::Result<Box<dyn AuthService + Send + Sync>> {
-    let mut auths: Vec<Box<dyn AuthService + Send + Sync>> = Vec::new();
+type Auth = Box<dyn AuthService + Send + Sync>;
+type Admin = Option<Box<dyn UserAdmin + Send + Sync>>;
+
+async fn init_auth(
+    args: &Args,
+    make_kv_store: &dyn Fn() -> Box<dyn KeyValueStore + Send + Sync>,
+) -> anyhow::Result<(Auth, Admin)> {
+    let mut auths: Vec<auth::combined::Backend> = Vec::new();
+    let mut user_admin: Admin = None;
     if !args.sample_users.is_empty() {
         tracing::warn!("Using sample auth");
-        auths.push(Box::new(Sample::new(&args.sample_users)?));
+        auths.push(backend("sample", Sample::new(&args.sample_users)?));
+    }
+    if !args.user_file.is_empty() {
+        tracing::info!(path = args.user_file, "Using user file auth");
+        auths.push(backend(
+            "file",
+            auth::file::Auth::new(&args.user_file, args.user_file_reload)?,
+        ));
     }
     if !args.auth_ws_url.is_empty() {
         tracing::info!("Using sample admin3ws auth");
-        auths.push(Box::new(authware::auth::admin3ws::Auth::new(
-            &args.auth_ws_url,
-            &args.auth_ws_user,
-            args.auth_ws_pass.as_str().into(),
-            &args.auth_app_code,
-        )?));
+        auths.push(remote(
+            "admin3ws",
+            auth::admin3ws::Auth::new(
+                &args.auth_ws_url,
+                &args.auth_ws_user,
+                args.auth_ws_pass.as_str().into(),
+                &args.auth_app_code,
+                args.auth_ws_credentials.parse()?,
+                args.auth_ws_timeout,
+            )?,
+            args,
+            make_kv_store,
+        )?);
+    }
+    if !args.ldap_url.is_empty() {
+        tracing::info!("Using ldap auth");
+        auths.push(remote(
+            "ldap",
+            auth::ldap::Auth::new(auth::ldap::Config {
+                url: args.ldap_url.clone(),
+                bind_dn: args.ldap_bind_dn.clone(),
+                bind_pass: args.ldap_bind_pass.as_str().into(),
+                base_dn: args.ldap_base_dn.clone(),
+                user_filter: args.ldap_user_filter.clone(),
+                name_attr: args.ldap_name_attr.clone(),
+                department_attr: args.ldap_department_attr.clone(),
+                nested_groups: args.ldap_nested_groups,
+                timeout: args.ldap_timeout,
+            })?,
+            args,
+            make_kv_store,
+        )?);
+    }
+    if !args.rest_auth_config.is_empty() {
+        tracing::info!(path = args.rest_auth_config, "Using rest auth");
+        auths.push(remote(
+            "rest",
+            auth::rest::Auth::new(&args.rest_auth_config)?,
+            args,
+            make_kv_store,
+        )?);
+    }
+    if !args.user_db.is_empty() {
+        tracing::info!(path = args.user_db, "Using sqlite user db auth");
+        let db = auth::sqlite::Auth::new(&args.user_db, args.password_max_age)?;
+        if let Some((user, pass)) = args.user_db_init_admin.split_once(':') {
+            db.init_admin(user, pass.into(), &args.admin_role).await?;
+        }
+        auths.push(backend(auth::sqlite::BACKEND, db.clone()));
+        user_admin = Some(Box::new(db));
     }
     if auths.is_empty() {
         return Err(anyhow::anyhow!("No auth method specified"));
     }
-    if auths.len() == 1 {
-        return Ok(auths.pop().unwrap());
+    let config = auth::combined::Config {
+        strategy: args.auth_strategy.parse()?,
+        deadline: args.auth_deadline,
+        routes: auth::combined::parse_routes(&args.auth_routes)?,
+        allow_select: args.auth_backend_select,
+    };
+    Ok((
+        Box::new(auth::combined::Auths::new(auths, config)?),
+        user_admin,
+    ))
+}
+
+// Remote backends may hang or be unavailable, they get resilience wrappers and the login cache
+fn remote(
+    name: &str,
+    auth: impl AuthService + Send + Sync + 'static,
+    args: &Args,
+    make_kv_store: &dyn Fn() -> Box<dyn KeyValueStore + Send + Sync>,
+) -> anyhow::Result<auth::combined::Backend> {
+    let mut auth: Box<dyn AuthService + Send + Sync> = Box::new(auth);
+    if !args.auth_call_timeout.is_zero() {
+        auth = Box::new(Timeout::new(name, auth, args.auth_call_timeout));
+    }
+    if args.auth_retries > 0 {
+        auth = Box::new(Retry::new(
+            name,
+            auth,
+            args.auth_retries,
+            args.auth_retry_backoff,
+        ));
+    }
+    if args.auth_breaker_failures > 0 {
+        auth = Box::new(CircuitBreaker::new(
+            name,
+            auth,
+            args.auth_breaker_failures,
+            args.auth_breaker_open,
+        )?);
+    }
+    if args.auth_max_in_flight > 0 {
+        auth = Box::new(Bulkhead::new(name, auth, args.auth_max_in_flight)?);
     }
-    Ok(Box::new(authware::auth::combined::Auths::new(auths)?))
+    // outermost, an open circuit still lets cached users in
+    if !args.login_cache_staleness.is_zero() {
+        tracing::info!(name, staleness = ?args.login_cache_staleness, "Using login cache");
+        auth = Box::new(auth::login_cache::LoginCache::new(
+            name,
+            auth,
+            make_kv_store(),
+            Box::new(MagicEncryptor::new(&args.encryption_key)?),
+            args.login_cache_staleness,
+        )?);
+    }
+    Ok(auth::combined::Backend {
+        name: name.to_string(),
+        auth,
+    })
+}
+
+fn backend(name: &str, auth: impl AuthService + Send + Sync + 'static) -> auth::combined::Backend {
+    auth::combined::Backend {
+        name: name.to_string(),
+        auth: Box::new(auth),
+    }
+}
+
+fn init_oidc(args: &Args) -> anyhow::Result<Option<auth::oidc::Client>> {
+    if args.oidc_discovery_url.is_empty() {
+        return Ok(None);
+    }
+    tracing::info!("Using oidc login");
+    let encryptor: Box<dyn Encryptor + Send + Sync> =
+        Box::new(MagicEncryptor::new(&args.encryption_key)?);
+    let client = auth::oidc::Client::new(
+        auth::oidc::Config {
+            discovery_url: args.oidc_discovery_url.clone(),
+            client_id: args.oidc_client_id.clone(),
+            client_secret: args.oidc_client_secret.as_str().into(),
+            redirect_url: args.oidc_redirect_url.clone(),
+            scopes: args
+                .oidc_scopes
+                .split_whitespace()
+                .map(|s| s.to_string())
+                .collect(),
+            user_claim: args.oidc_user_claim.clone(),
+            name_claim: args.oidc_name_claim.clone(),
+            department_claim: args.oidc_department_claim.clone(),
+            roles_claim: args.oidc_roles_claim.clone(),
+            role_map: auth::oidc::parse_role_map(&args.oidc_role_map)?,
+            post_login_url: args.oidc_post_login_url.clone(),
+        },
+        encryptor,
+    )?;
+    Ok(Some(client))
 }
 
 #[tokio::main(flavor = "multi_thread", worker_threads = 2)]
@@ -202,6 +880,15 @@ async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+// a period of a background task, zero would panic the task
+fn parse_interval(s: &str) -> Result<Duration, String> {
+    match humantime::parse_duration(s) {
+        Ok(res) if res.is_zero() => Err("must be greater than zero".to_string()),
+        Ok(res) => Ok(res),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
 async fn shutdown_signal_handle(handle: axum_server::Handle) {
     shutdown_signal().await;
     tracing::trace!("Received termination signal shutting down");
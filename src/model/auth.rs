@@ -7,8 +7,16 @@ pub struct User {
     pub name: String,
     pub department: String,
     pub roles: Vec<String>,
+    // name of the backend that authenticated the user
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub backend: String,
 }
 
+// Carried by `Error::ServiceError` when a backend is known to be down or overloaded
+#[derive(Debug, Error)]
+#[error("Auth service unavailable: {0}")]
+pub struct Unavailable(pub String);
+
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("Wrong password")]
@@ -19,6 +27,9 @@ pub enum Error {
     NoAccess(),
     #[error("Other Auth error")]
     OtherAuth(String),
+    // the backend can't do the call at all, says nothing about the user
+    #[error("Not supported")]
+    Unsupported(String),
     #[error(transparent)]
     ServiceError(#[from] anyhow::Error),
 }
@@ -1,5 +1,119 @@
+use std::{collections::HashMap, str::FromStr};
+
+use crate::model::auth::User;
+
 #[derive(Clone, Debug)]
 pub struct SessionConfig {
     pub inactivity: i64,      // Unix timestamp
     pub session_timeout: i64, // Unix timestamp
+    pub refresh: i64,         // millis, 0 - user is not refreshed
+    pub limits: SessionLimits,
+}
+
+// What happens when a new login exceeds the user's session limit
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum EvictPolicy {
+    #[default]
+    Reject,
+    // session with the earliest expiration, i.e. created first
+    Oldest,
+    LeastRecentlyUsed,
+}
+
+impl FromStr for EvictPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(EvictPolicy::Reject),
+            "oldest" => Ok(EvictPolicy::Oldest),
+            "lru" => Ok(EvictPolicy::LeastRecentlyUsed),
+            _ => Err(anyhow::anyhow!(
+                "wrong session evict policy '{s}', expected reject, oldest or lru"
+            )),
+        }
+    }
+}
+
+// Max active sessions per user, 0 - unlimited
+#[derive(Clone, Debug, Default)]
+pub struct SessionLimits {
+    pub default: usize,
+    pub roles: HashMap<String, usize>,
+    pub policy: EvictPolicy,
+}
+
+impl SessionLimits {
+    // Parses role overrides, format: ROLE=n,ROLE2=n
+    pub fn new(default: usize, roles: &str, policy: EvictPolicy) -> anyhow::Result<Self> {
+        let mut res = HashMap::new();
+        for item in roles.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let (role, max) = item
+                .split_once('=')
+                .ok_or_else(|| anyhow::anyhow!("wrong session limit '{item}', expected ROLE=n"))?;
+            let max = max
+                .trim()
+                .parse()
+                .map_err(|e| anyhow::anyhow!("wrong session limit '{item}': {e}"))?;
+            res.insert(role.trim().to_string(), max);
+        }
+        Ok(SessionLimits {
+            default,
+            roles: res,
+            policy,
+        })
+    }
+
+    // The most generous override of the user's roles wins, the default applies without any
+    pub fn limit_for(&self, user: &User) -> usize {
+        let mut res: Option<usize> = None;
+        for max in user.roles.iter().filter_map(|r| self.roles.get(r)) {
+            res = match (res, *max) {
+                (_, 0) | (Some(0), _) => Some(0),
+                (Some(prev), max) => Some(prev.max(max)),
+                (None, max) => Some(max),
+            };
+        }
+        res.unwrap_or(self.default)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    fn user(roles: &[&str]) -> User {
+        User {
+            id: "olia".to_string(),
+            name: "olia".to_string(),
+            department: String::new(),
+            roles: roles.iter().map(|r| r.to_string()).collect(),
+            backend: String::new(),
+        }
+    }
+
+    #[test_case(&[], 3; "default")]
+    #[test_case(&["OTHER"], 3; "no override")]
+    #[test_case(&["USER"], 1; "override")]
+    #[test_case(&["USER", "ADMIN"], 10; "max")]
+    #[test_case(&["USER", "SVC"], 0; "unlimited")]
+    fn test_limit_for(roles: &[&str], expected: usize) {
+        let limits = SessionLimits::new(3, "USER=1, ADMIN=10,SVC=0", EvictPolicy::Reject).unwrap();
+        assert_eq!(limits.limit_for(&user(roles)), expected);
+    }
+
+    #[test_case("USER"; "no value")]
+    #[test_case("USER=x"; "not number")]
+    fn test_limits_err(input: &str) {
+        assert!(SessionLimits::new(3, input, EvictPolicy::Reject).is_err());
+    }
+
+    #[test_case("reject", Some(EvictPolicy::Reject); "reject")]
+    #[test_case("oldest", Some(EvictPolicy::Oldest); "oldest")]
+    #[test_case("lru", Some(EvictPolicy::LeastRecentlyUsed); "lru")]
+    #[test_case("other", None; "wrong")]
+    fn test_parse_policy(input: &str, expected: Option<EvictPolicy>) {
+        assert_eq!(input.parse::<EvictPolicy>().ok(), expected);
+    }
 }
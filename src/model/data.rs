@@ -8,6 +8,9 @@ pub struct SessionData {
     pub ip: String,
     pub valid_till: i64,  // Unix timestamp
     pub last_access: i64, // Unix timestamp
+    // when the user was loaded from the auth backend
+    #[serde(default)]
+    pub refreshed: i64, // Unix timestamp
 }
 
 impl SessionData {
@@ -29,6 +32,9 @@ impl SessionData {
         }
         Ok(())
     }
+    pub fn needs_refresh(&self, now: i64, dur: i64) -> bool {
+        dur > 0 && self.refreshed + dur < now
+    }
     pub fn check_ip(&self, ip: &str) -> Result<(), ApiError> {
         if self.ip != ip {
             return Err(ApiError::NoSession());
@@ -63,6 +69,14 @@ mod tests {
         assert_eq!(ok, res.is_ok());
     }
 
+    #[test_case(800, 600, true; "old")]
+    #[test_case(800, 800, false; "fresh")]
+    #[test_case(800, 0, false; "disabled")]
+    fn test_needs_refresh(now: i64, dur: i64, expected: bool) {
+        let to = session_data();
+        assert_eq!(expected, to.needs_refresh(now, dur));
+    }
+
     fn session_data() -> SessionData {
         SessionData {
             user: User {
@@ -70,10 +84,12 @@ mod tests {
                 name: "Test User".to_string(),
                 department: "Test Department".to_string(),
                 roles: vec!["admin".to_string()],
+                backend: String::new(),
             },
             ip: "2.2.2.2".to_string(),
             valid_till: 1000,
             last_access: 500,
+            refreshed: 100,
         }
     }
 }
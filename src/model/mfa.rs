@@ -0,0 +1,17 @@
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("MFA not enrolled")]
+    NotEnrolled(),
+    #[error("MFA already enrolled")]
+    AlreadyEnrolled(),
+    #[error("Wrong code")]
+    WrongCode(),
+    #[error("Too many attempts")]
+    TooManyAttempts(),
+    #[error("No MFA challenge")]
+    NoChallenge(),
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
@@ -1,5 +1,8 @@
 pub mod auth;
 pub mod config;
 pub mod data;
+pub mod mfa;
 pub mod service;
 pub mod store;
+pub mod user;
+pub mod webauthn;
@@ -1,4 +1,8 @@
-use crate::{AuthService, IPExtractor, SessionStore};
+use crate::{
+    auth::{api_key, client_cert, oidc, roles, totp, webauthn},
+    store::memory::InMemorySessionStore,
+    AuthService, IPExtractor, SessionStore, UserAdmin,
+};
 
 use super::config::SessionConfig;
 
@@ -8,5 +12,52 @@ pub struct Data {
     pub store: Box<dyn SessionStore + Send + Sync>,
     pub auth_service: Box<dyn AuthService + Send + Sync>,
     pub ip_extractor: Box<dyn IPExtractor + Send + Sync>,
+    pub oidc: Option<oidc::Client>,
+    pub user_admin: Option<Box<dyn UserAdmin + Send + Sync>>,
+    pub mfa: Option<totp::Mfa>,
+    pub webauthn: Option<webauthn::WebAuthn>,
+    pub api_keys: Option<api_key::ApiKeys>,
+    pub client_cert: Option<client_cert::ClientCert>,
+    // applied to the user roles before a session is created
+    pub role_mapper: Option<roles::RoleMapper>,
+    // set with the in-memory store, its counters go to /auth/live
+    pub memory_store: Option<InMemorySessionStore>,
+    // role required for /auth/admin/* endpoints
+    pub admin_role: String,
+    // role required for /auth/admin/sessions/* endpoints
+    pub sessions_admin_role: String,
     pub is_test_mode: bool,
 }
+
+#[cfg(test)]
+impl Data {
+    // Handler test setup with no optional features, the ip is taken from the
+    // first x-forwarded-for entry
+    pub(crate) fn for_tests(
+        store: Box<dyn SessionStore + Send + Sync>,
+        auth_service: Box<dyn AuthService + Send + Sync>,
+    ) -> Self {
+        Data {
+            config: SessionConfig {
+                inactivity: 60_000,
+                session_timeout: 600_000,
+                refresh: 0,
+                limits: Default::default(),
+            },
+            store,
+            auth_service,
+            ip_extractor: Box::new(crate::utils::ip_extractor::Header::new(0)),
+            oidc: None,
+            user_admin: None,
+            mfa: None,
+            webauthn: None,
+            api_keys: None,
+            client_cert: None,
+            role_mapper: None,
+            memory_store: None,
+            admin_role: "ADMIN".to_string(),
+            sessions_admin_role: "ADMIN".to_string(),
+            is_test_mode: false,
+        }
+    }
+}
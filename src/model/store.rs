@@ -4,6 +4,10 @@ use thiserror::Error;
 pub enum Error {
     #[error("No session`")]
     NoSession(),
+    #[error("Too many sessions")]
+    TooManySessions(),
+    #[error("Session store is full")]
+    StoreFull(),
     #[error(transparent)]
     Other(#[from] anyhow::Error),
 }
@@ -0,0 +1,44 @@
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::utils::secret_str::SecretString;
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct UserInfo {
+    pub id: String,
+    pub name: String,
+    pub department: String,
+    pub roles: Vec<String>,
+    pub disabled: bool,
+    pub pass_changed: i64, // Unix timestamp
+    pub created: i64,      // Unix timestamp
+}
+
+#[derive(Clone)]
+pub struct NewUser {
+    pub id: String,
+    pub name: String,
+    pub department: String,
+    pub pass: SecretString,
+    pub roles: Vec<String>,
+}
+
+#[derive(Clone, Default)]
+pub struct UpdateUser {
+    pub name: Option<String>,
+    pub department: Option<String>,
+    pub pass: Option<SecretString>,
+    pub disabled: Option<bool>,
+}
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("No user")]
+    NoUser(),
+    #[error("User exists")]
+    Exists(),
+    #[error("Invalid user data: {0}")]
+    Invalid(String),
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
@@ -0,0 +1,36 @@
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+// Registered passkey
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Credential {
+    // base64 url-safe credential id
+    pub id: String,
+    // owner, passwordless logins look the user up in the auth backend
+    pub user_id: String,
+    // SEC1 encoded P-256 public key
+    pub public_key: Vec<u8>,
+    pub sign_count: u32,
+    pub created: i64,
+}
+
+impl Credential {
+    // A cloned authenticator shows a count not above the stored one, zeros - no counter
+    pub fn sign_count_grows(&self, sign_count: u32) -> bool {
+        (sign_count == 0 && self.sign_count == 0) || sign_count > self.sign_count
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("No webauthn challenge")]
+    NoChallenge(),
+    #[error("Unknown credential")]
+    NoCredential(),
+    #[error("Credential exists")]
+    Exists(),
+    #[error("Invalid webauthn response: {0}")]
+    Invalid(String),
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
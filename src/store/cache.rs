@@ -0,0 +1,618 @@
+use async_trait::async_trait;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{model, model::config::EvictPolicy, InvalidationBus, SessionData, SessionStore};
+
+// invalidation messages: `s:<session key>` or `u:<user key>`
+const SESSION_PREFIX: &str = "s:";
+const USER_PREFIX: &str = "u:";
+
+struct Entry {
+    data: SessionData,
+    user: String,
+    cached_at: Instant,
+    tick: u64,
+}
+
+#[derive(Default)]
+struct State {
+    entries: HashMap<String, Entry>,
+    // tick -> key, the least recently used first
+    order: BTreeMap<u64, String>,
+    tick: u64,
+    // bumped by every invalidation, a read racing with one is not cached
+    generation: u64,
+}
+
+impl State {
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.order.remove(&entry.tick);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+        self.generation += 1;
+    }
+}
+
+// Bounded LRU of decrypted sessions shared with the invalidation subscriber.
+// It serves nothing while inactive, i.e. until the subscriber listens for invalidations,
+// so a missed message can't keep a revoked session alive
+pub struct SessionCache {
+    capacity: usize,
+    ttl: Duration,
+    secret: Vec<u8>,
+    active: AtomicBool,
+    state: Mutex<State>,
+}
+
+impl SessionCache {
+    // secret keys the hashes of session and user ids sent to other instances
+    pub fn new(capacity: usize, ttl: Duration, secret: &str) -> Arc<Self> {
+        Arc::new(SessionCache {
+            capacity,
+            ttl,
+            secret: secret.as_bytes().to_vec(),
+            active: AtomicBool::new(false),
+            state: Mutex::new(State::default()),
+        })
+    }
+
+    // Called by the subscriber, the cache is emptied on every change
+    pub fn set_active(&self, active: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.clear();
+        self.active.store(active, Ordering::SeqCst);
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    // Applies an invalidation message of any instance
+    pub fn handle(&self, message: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.generation += 1;
+        if let Some(key) = message.strip_prefix(SESSION_PREFIX) {
+            state.remove(key);
+        } else if let Some(user) = message.strip_prefix(USER_PREFIX) {
+            let keys: Vec<String> = state
+                .entries
+                .iter()
+                .filter(|(_, e)| e.user == user)
+                .map(|(k, _)| k.clone())
+                .collect();
+            for key in keys {
+                state.remove(&key);
+            }
+        } else {
+            tracing::warn!(message, "unknown cache invalidation, clearing");
+            state.clear();
+        }
+    }
+
+    fn key(&self, id: &str) -> String {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.secret).expect("hmac accepts any key size");
+        mac.update(id.as_bytes());
+        format!("{:x}", mac.finalize().into_bytes())
+    }
+
+    fn session_message(&self, session_id: &str) -> String {
+        format!("{SESSION_PREFIX}{}", self.key(session_id))
+    }
+
+    fn user_message(&self, user_id: &str) -> String {
+        format!("{USER_PREFIX}{}", self.key(user_id))
+    }
+
+    fn generation(&self) -> u64 {
+        self.state.lock().unwrap().generation
+    }
+
+    fn lookup(&self, key: &str) -> Option<SessionData> {
+        if !self.active.load(Ordering::SeqCst) {
+            return None;
+        }
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let entry = state.entries.get_mut(key)?;
+        if entry.cached_at.elapsed() > self.ttl {
+            state.remove(key);
+            return None;
+        }
+        state.order.remove(&entry.tick);
+        state.tick += 1;
+        entry.tick = state.tick;
+        state.order.insert(entry.tick, key.to_string());
+        Some(entry.data.clone())
+    }
+
+    fn insert(&self, key: String, data: SessionData, generation: u64) {
+        if !self.active.load(Ordering::SeqCst) || self.capacity == 0 {
+            return;
+        }
+        let user = self.key(&data.user.id);
+        let mut state = self.state.lock().unwrap();
+        if state.generation != generation {
+            return;
+        }
+        state.remove(&key);
+        while state.entries.len() >= self.capacity {
+            match state.order.pop_first() {
+                Some((_, oldest)) => {
+                    state.entries.remove(&oldest);
+                }
+                None => break,
+            }
+        }
+        state.tick += 1;
+        let tick = state.tick;
+        state.order.insert(tick, key.clone());
+        state.entries.insert(
+            key,
+            Entry {
+                data,
+                user,
+                cached_at: Instant::now(),
+                tick,
+            },
+        );
+    }
+
+    fn touch(&self, key: &str, now: i64) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(entry) = state.entries.get_mut(key) {
+            entry.data.last_access = entry.data.last_access.max(now);
+        }
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.state.lock().unwrap().entries.len()
+    }
+}
+
+// Caching decorator of a shared store, e.g. redis. Changes are published to the bus,
+// so other instances drop their copies; a cached copy lives at most the ttl anyway
+pub struct CachedSessionStore {
+    inner: Box<dyn SessionStore + Send + Sync>,
+    cache: Arc<SessionCache>,
+    bus: Box<dyn InvalidationBus + Send + Sync>,
+}
+
+impl CachedSessionStore {
+    pub fn new(
+        inner: Box<dyn SessionStore + Send + Sync>,
+        cache: Arc<SessionCache>,
+        bus: Box<dyn InvalidationBus + Send + Sync>,
+    ) -> Self {
+        CachedSessionStore { inner, cache, bus }
+    }
+
+    async fn invalidate(&self, message: String) -> Result<(), model::store::Error> {
+        self.cache.handle(&message);
+        self.bus.publish(&message).await
+    }
+
+    // session updates are not security critical, a failed publish just logs. Evicted
+    // sessions are revoked, so a failed publish of the user fails the call as a logout
+    async fn invalidate_changed(
+        &self,
+        session_id: &str,
+        evicted: usize,
+        user_id: &str,
+    ) -> Result<(), model::store::Error> {
+        if let Err(err) = self
+            .invalidate(self.cache.session_message(session_id))
+            .await
+        {
+            tracing::warn!(error = %err, "can't publish cache invalidation");
+        }
+        if evicted > 0 {
+            self.invalidate(self.cache.user_message(user_id)).await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl SessionStore for CachedSessionStore {
+    async fn add(&self, session_id: &str, data: SessionData) -> Result<(), model::store::Error> {
+        let user_id = data.user.id.clone();
+        self.inner.add(session_id, data).await?;
+        self.invalidate_changed(session_id, 0, &user_id).await
+    }
+
+    async fn get(&self, session_id: &str) -> Result<SessionData, model::store::Error> {
+        let key = self.cache.key(session_id);
+        if let Some(res) = self.cache.lookup(&key) {
+            return Ok(res);
+        }
+        let generation = self.cache.generation();
+        let res = self.inner.get(session_id).await?;
+        self.cache.insert(key, res.clone(), generation);
+        Ok(res)
+    }
+
+    // a logout must reach every instance, so a failed publish fails the call
+    async fn remove(&self, session_id: &str) -> Result<(), model::store::Error> {
+        let res = self.inner.remove(session_id).await;
+        self.invalidate(self.cache.session_message(session_id))
+            .await?;
+        res
+    }
+
+    async fn mark_last_used(&self, session_id: &str, now: i64) -> Result<(), model::store::Error> {
+        self.inner.mark_last_used(session_id, now).await?;
+        self.cache.touch(&self.cache.key(session_id), now);
+        Ok(())
+    }
+
+    async fn update_user(
+        &self,
+        session_id: &str,
+        data: SessionData,
+        current: i64,
+    ) -> Result<bool, model::store::Error> {
+        let user_id = data.user.id.clone();
+        let res = self.inner.update_user(session_id, data, current).await?;
+        if res {
+            self.invalidate_changed(session_id, 0, &user_id).await?;
+        }
+        Ok(res)
+    }
+
+    async fn add_limited(
+        &self,
+        session_id: &str,
+        data: SessionData,
+        max: usize,
+        policy: EvictPolicy,
+    ) -> Result<usize, model::store::Error> {
+        let user_id = data.user.id.clone();
+        let res = self
+            .inner
+            .add_limited(session_id, data, max, policy)
+            .await?;
+        self.invalidate_changed(session_id, res, &user_id).await?;
+        Ok(res)
+    }
+
+    async fn list_by_user(
+        &self,
+        user_id: &str,
+    ) -> Result<Vec<(String, SessionData)>, model::store::Error> {
+        self.inner.list_by_user(user_id).await
+    }
+
+    async fn remove_all_for_user(&self, user_id: &str) -> Result<usize, model::store::Error> {
+        let res = self.inner.remove_all_for_user(user_id).await;
+        self.invalidate(self.cache.user_message(user_id)).await?;
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{model::auth::User, store::memory::InMemorySessionStore};
+    use chrono::Utc;
+    use std::sync::atomic::AtomicUsize;
+
+    // the store shared by the instances, counts reads
+    struct Shared {
+        inner: Arc<InMemorySessionStore>,
+        gets: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl SessionStore for Shared {
+        async fn add(&self, id: &str, data: SessionData) -> Result<(), model::store::Error> {
+            self.inner.add(id, data).await
+        }
+        async fn get(&self, id: &str) -> Result<SessionData, model::store::Error> {
+            self.gets.fetch_add(1, Ordering::SeqCst);
+            self.inner.get(id).await
+        }
+        async fn remove(&self, id: &str) -> Result<(), model::store::Error> {
+            self.inner.remove(id).await
+        }
+        async fn mark_last_used(&self, id: &str, now: i64) -> Result<(), model::store::Error> {
+            self.inner.mark_last_used(id, now).await
+        }
+        async fn update_user(
+            &self,
+            id: &str,
+            data: SessionData,
+            current: i64,
+        ) -> Result<bool, model::store::Error> {
+            self.inner.update_user(id, data, current).await
+        }
+        async fn add_limited(
+            &self,
+            id: &str,
+            data: SessionData,
+            max: usize,
+            policy: EvictPolicy,
+        ) -> Result<usize, model::store::Error> {
+            self.inner.add_limited(id, data, max, policy).await
+        }
+        async fn list_by_user(
+            &self,
+            user_id: &str,
+        ) -> Result<Vec<(String, SessionData)>, model::store::Error> {
+            self.inner.list_by_user(user_id).await
+        }
+        async fn remove_all_for_user(&self, user_id: &str) -> Result<usize, model::store::Error> {
+            self.inner.remove_all_for_user(user_id).await
+        }
+    }
+
+    // delivers the messages to all caches at once
+    #[derive(Clone, Default)]
+    struct LocalBus {
+        caches: Arc<Mutex<Vec<Arc<SessionCache>>>>,
+        fail: Arc<AtomicBool>,
+    }
+
+    #[async_trait]
+    impl InvalidationBus for LocalBus {
+        async fn publish(&self, message: &str) -> Result<(), model::store::Error> {
+            if self.fail.load(Ordering::SeqCst) {
+                return Err(anyhow::anyhow!("bus down").into());
+            }
+            for cache in self.caches.lock().unwrap().iter() {
+                cache.handle(message);
+            }
+            Ok(())
+        }
+    }
+
+    struct Cluster {
+        instances: Vec<(CachedSessionStore, Arc<SessionCache>)>,
+        gets: Arc<AtomicUsize>,
+        bus: LocalBus,
+    }
+
+    fn make_cluster(count: usize, capacity: usize, ttl: Duration) -> Cluster {
+        let store = Arc::new(InMemorySessionStore::new());
+        let gets = Arc::new(AtomicUsize::new(0));
+        let bus = LocalBus::default();
+        let instances = (0..count)
+            .map(|_| {
+                let cache = SessionCache::new(capacity, ttl, "secret");
+                cache.set_active(true);
+                bus.caches.lock().unwrap().push(cache.clone());
+                let inner = Shared {
+                    inner: store.clone(),
+                    gets: gets.clone(),
+                };
+                (
+                    CachedSessionStore::new(Box::new(inner), cache.clone(), Box::new(bus.clone())),
+                    cache,
+                )
+            })
+            .collect();
+        Cluster {
+            instances,
+            gets,
+            bus,
+        }
+    }
+
+    fn session_data(user: &str) -> SessionData {
+        let now = Utc::now().timestamp_millis();
+        SessionData {
+            user: User {
+                id: user.to_string(),
+                name: user.to_string(),
+                department: String::new(),
+                roles: vec!["USER".to_string()],
+                backend: String::new(),
+            },
+            ip: "1.1.1.1".to_string(),
+            valid_till: now + 60_000,
+            last_access: now,
+            refreshed: now,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_cached() {
+        let cluster = make_cluster(1, 10, Duration::from_secs(60));
+        let (store, _) = &cluster.instances[0];
+        store.add("s1", session_data("olia")).await.unwrap();
+        for _ in 0..10 {
+            assert_eq!(store.get("s1").await.unwrap().user.id, "olia");
+        }
+        assert_eq!(cluster.gets.load(Ordering::SeqCst), 1);
+        assert!(store.get("s2").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_ttl() {
+        let cluster = make_cluster(1, 10, Duration::from_millis(50));
+        let (store, _) = &cluster.instances[0];
+        store.add("s1", session_data("olia")).await.unwrap();
+        store.get("s1").await.unwrap();
+        store.get("s1").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        store.get("s1").await.unwrap();
+        assert_eq!(cluster.gets.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_inactive_reads_through() {
+        let cluster = make_cluster(1, 10, Duration::from_secs(60));
+        let (store, cache) = &cluster.instances[0];
+        store.add("s1", session_data("olia")).await.unwrap();
+        store.get("s1").await.unwrap();
+        cache.set_active(false);
+        store.get("s1").await.unwrap();
+        store.get("s1").await.unwrap();
+        assert_eq!(cluster.gets.load(Ordering::SeqCst), 3);
+        assert_eq!(cache.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_lru() {
+        let cluster = make_cluster(1, 2, Duration::from_secs(60));
+        let (store, cache) = &cluster.instances[0];
+        for id in ["s1", "s2", "s3"] {
+            store.add(id, session_data("olia")).await.unwrap();
+        }
+        store.get("s1").await.unwrap();
+        store.get("s2").await.unwrap();
+        store.get("s1").await.unwrap();
+        store.get("s3").await.unwrap();
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cluster.gets.load(Ordering::SeqCst), 3);
+        // s2 was evicted
+        store.get("s1").await.unwrap();
+        store.get("s2").await.unwrap();
+        assert_eq!(cluster.gets.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn test_remove_reaches_all_instances() {
+        let cluster = make_cluster(3, 10, Duration::from_secs(60));
+        cluster.instances[0]
+            .0
+            .add("s1", session_data("olia"))
+            .await
+            .unwrap();
+        for (store, _) in &cluster.instances {
+            store.get("s1").await.unwrap();
+        }
+        cluster.instances[1].0.remove("s1").await.unwrap();
+        for (store, cache) in &cluster.instances {
+            assert!(store.get("s1").await.is_err());
+            assert_eq!(cache.len(), 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_revoke_user_reaches_all_instances() {
+        let cluster = make_cluster(2, 10, Duration::from_secs(60));
+        let (a, _) = &cluster.instances[0];
+        let (b, cache) = &cluster.instances[1];
+        a.add("s1", session_data("olia")).await.unwrap();
+        a.add("s2", session_data("olia")).await.unwrap();
+        a.add("s3", session_data("jonas")).await.unwrap();
+        for id in ["s1", "s2", "s3"] {
+            b.get(id).await.unwrap();
+        }
+        assert_eq!(a.remove_all_for_user("olia").await.unwrap(), 2);
+        assert_eq!(cache.len(), 1);
+        assert!(b.get("s1").await.is_err());
+        assert!(b.get("s2").await.is_err());
+        assert!(b.get("s3").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_evicted_reach_all_instances() {
+        let cluster = make_cluster(2, 10, Duration::from_secs(60));
+        let (a, _) = &cluster.instances[0];
+        let (b, _) = &cluster.instances[1];
+        a.add("s1", session_data("olia")).await.unwrap();
+        b.get("s1").await.unwrap();
+        let evicted = a
+            .add_limited("s2", session_data("olia"), 1, EvictPolicy::Oldest)
+            .await
+            .unwrap();
+        assert_eq!(evicted, 1);
+        assert!(b.get("s1").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_update_reaches_all_instances() {
+        let cluster = make_cluster(2, 10, Duration::from_secs(60));
+        let (a, _) = &cluster.instances[0];
+        let (b, _) = &cluster.instances[1];
+        let mut data = session_data("olia");
+        a.add("s1", data.clone()).await.unwrap();
+        b.get("s1").await.unwrap();
+        data.user.roles = vec!["ADMIN".to_string()];
+        a.add("s1", data).await.unwrap();
+        assert_eq!(b.get("s1").await.unwrap().user.roles, vec!["ADMIN"]);
+    }
+
+    #[tokio::test]
+    async fn test_remove_fails_without_bus() {
+        let cluster = make_cluster(1, 10, Duration::from_secs(60));
+        let (store, _) = &cluster.instances[0];
+        store.add("s1", session_data("olia")).await.unwrap();
+        cluster.bus.fail.store(true, Ordering::SeqCst);
+        assert!(store.remove("s1").await.is_err());
+        assert!(store.get("s1").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_eviction_fails_without_bus() {
+        let cluster = make_cluster(1, 10, Duration::from_secs(60));
+        let (store, _) = &cluster.instances[0];
+        store.add("s1", session_data("olia")).await.unwrap();
+        cluster.bus.fail.store(true, Ordering::SeqCst);
+        // an update without evictions only logs
+        store.add("s2", session_data("other")).await.unwrap();
+        assert!(store
+            .add_limited("s3", session_data("olia"), 1, EvictPolicy::Oldest)
+            .await
+            .is_err());
+        assert!(store
+            .add_limited("s4", session_data("olia"), 5, EvictPolicy::Oldest)
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_touch_updates_cached() {
+        let cluster = make_cluster(1, 10, Duration::from_secs(60));
+        let (store, _) = &cluster.instances[0];
+        let data = session_data("olia");
+        store.add("s1", data.clone()).await.unwrap();
+        store.get("s1").await.unwrap();
+        store
+            .mark_last_used("s1", data.last_access + 1_000)
+            .await
+            .unwrap();
+        assert_eq!(
+            store.get("s1").await.unwrap().last_access,
+            data.last_access + 1_000
+        );
+        assert_eq!(cluster.gets.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_racing_read_not_cached() {
+        let cache = SessionCache::new(10, Duration::from_secs(60), "secret");
+        cache.set_active(true);
+        let key = cache.key("s1");
+        let generation = cache.generation();
+        cache.handle(&cache.session_message("s1"));
+        cache.insert(key.clone(), session_data("olia"), generation);
+        assert!(cache.lookup(&key).is_none());
+    }
+
+    #[test]
+    fn test_keys_hide_ids() {
+        let cache = SessionCache::new(10, Duration::from_secs(60), "secret");
+        let other = SessionCache::new(10, Duration::from_secs(60), "other");
+        assert!(!cache.user_message("olia").contains("olia"));
+        assert_ne!(cache.key("olia"), other.key("olia"));
+        assert_eq!(cache.key("olia"), cache.key("olia"));
+    }
+}
@@ -0,0 +1,379 @@
+use async_trait::async_trait;
+use chrono::Utc;
+use futures::StreamExt;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, Weak},
+    time::Duration,
+};
+
+use crate::{model, model::config::EvictPolicy, SessionData, SessionStore};
+
+// parallel writes of one flush
+const FLUSH_CONCURRENCY: usize = 16;
+
+// Write-behind decorator for last access updates. A touch closer than half of the
+// granularity to the last known stored access is skipped, others are queued and
+// written by the flusher every half of the granularity. So the stored last access
+// lags the real one by at most the granularity, sessions read through this store
+// see the queued touches at once
+#[derive(Clone)]
+pub struct CoalescingSessionStore {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    inner: Box<dyn SessionStore + Send + Sync>,
+    step: i64, // millis
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    // session id -> last access to write
+    pending: HashMap<String, i64>,
+    // session id -> last access stored or queued
+    known: HashMap<String, i64>,
+}
+
+impl CoalescingSessionStore {
+    pub fn new(inner: Box<dyn SessionStore + Send + Sync>, granularity: Duration) -> Self {
+        CoalescingSessionStore {
+            shared: Arc::new(Shared {
+                inner,
+                step: max_step(granularity),
+                state: Mutex::new(State::default()),
+            }),
+        }
+    }
+
+    // Flushes queued touches until the store is dropped
+    pub fn start_flusher(&self) -> tokio::task::JoinHandle<()> {
+        let shared = Arc::downgrade(&self.shared);
+        let every = Duration::from_millis(self.shared.step as u64);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(every);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                if flush(&shared).await.is_none() {
+                    break;
+                }
+            }
+        })
+    }
+
+    // Writes the queued touches, e.g. on shutdown. Returns the number of flushed ones
+    pub async fn flush(&self) -> usize {
+        flush(&Arc::downgrade(&self.shared)).await.unwrap_or(0)
+    }
+}
+
+fn max_step(granularity: Duration) -> i64 {
+    (granularity.as_millis() as i64 / 2).max(1)
+}
+
+async fn flush(shared: &Weak<Shared>) -> Option<usize> {
+    let shared = shared.upgrade()?;
+    let pending = {
+        let mut state = shared.state.lock().unwrap();
+        // older entries can't cause skips anymore
+        let now = Utc::now().timestamp_millis();
+        state.known.retain(|_, at| *at + shared.step > now);
+        std::mem::take(&mut state.pending)
+    };
+    if pending.is_empty() {
+        return Some(0);
+    }
+    let failed = Mutex::new(Vec::new());
+    futures::stream::iter(pending.iter())
+        .for_each_concurrent(FLUSH_CONCURRENCY, |(session_id, at)| {
+            let (shared, failed) = (&shared, &failed);
+            async move {
+                match shared.inner.mark_last_used(session_id, *at).await {
+                    Ok(()) | Err(model::store::Error::NoSession()) => {}
+                    Err(err) => {
+                        tracing::warn!(error = %err, "can't write last access");
+                        failed.lock().unwrap().push((session_id.clone(), *at));
+                    }
+                }
+            }
+        })
+        .await;
+    let failed = failed.into_inner().unwrap();
+    let res = pending.len() - failed.len();
+    if !failed.is_empty() {
+        // retried by the next flush unless newer touches are queued
+        let mut state = shared.state.lock().unwrap();
+        for (session_id, at) in failed {
+            let v = state.pending.entry(session_id).or_insert(at);
+            *v = (*v).max(at);
+        }
+    }
+    tracing::trace!(written = res, "flushed last access");
+    Some(res)
+}
+
+impl Shared {
+    fn remember(&self, session_id: &str, at: i64) {
+        let mut state = self.state.lock().unwrap();
+        let v = state.known.entry(session_id.to_string()).or_insert(at);
+        *v = (*v).max(at);
+    }
+}
+
+#[async_trait]
+impl SessionStore for CoalescingSessionStore {
+    async fn add(&self, session_id: &str, data: SessionData) -> Result<(), model::store::Error> {
+        let last_access = data.last_access;
+        self.shared.inner.add(session_id, data).await?;
+        self.shared.remember(session_id, last_access);
+        Ok(())
+    }
+
+    async fn get(&self, session_id: &str) -> Result<SessionData, model::store::Error> {
+        let mut res = self.shared.inner.get(session_id).await?;
+        let mut state = self.shared.state.lock().unwrap();
+        if let Some(at) = state.pending.get(session_id) {
+            res.last_access = res.last_access.max(*at);
+        }
+        let v = state
+            .known
+            .entry(session_id.to_string())
+            .or_insert(res.last_access);
+        *v = (*v).max(res.last_access);
+        Ok(res)
+    }
+
+    async fn remove(&self, session_id: &str) -> Result<(), model::store::Error> {
+        {
+            let mut state = self.shared.state.lock().unwrap();
+            state.pending.remove(session_id);
+            state.known.remove(session_id);
+        }
+        self.shared.inner.remove(session_id).await
+    }
+
+    async fn mark_last_used(&self, session_id: &str, now: i64) -> Result<(), model::store::Error> {
+        let mut state = self.shared.state.lock().unwrap();
+        if let Some(at) = state.known.get(session_id) {
+            if now < *at + self.shared.step {
+                return Ok(());
+            }
+        }
+        state.known.insert(session_id.to_string(), now);
+        let v = state.pending.entry(session_id.to_string()).or_insert(now);
+        *v = (*v).max(now);
+        Ok(())
+    }
+
+    async fn update_user(
+        &self,
+        session_id: &str,
+        data: SessionData,
+        current: i64,
+    ) -> Result<bool, model::store::Error> {
+        self.shared
+            .inner
+            .update_user(session_id, data, current)
+            .await
+    }
+
+    async fn add_limited(
+        &self,
+        session_id: &str,
+        data: SessionData,
+        max: usize,
+        policy: EvictPolicy,
+    ) -> Result<usize, model::store::Error> {
+        let last_access = data.last_access;
+        let res = self
+            .shared
+            .inner
+            .add_limited(session_id, data, max, policy)
+            .await?;
+        self.shared.remember(session_id, last_access);
+        Ok(res)
+    }
+
+    async fn list_by_user(
+        &self,
+        user_id: &str,
+    ) -> Result<Vec<(String, SessionData)>, model::store::Error> {
+        let mut res = self.shared.inner.list_by_user(user_id).await?;
+        let state = self.shared.state.lock().unwrap();
+        for (session_id, data) in res.iter_mut() {
+            if let Some(at) = state.pending.get(session_id) {
+                data.last_access = data.last_access.max(*at);
+            }
+        }
+        Ok(res)
+    }
+
+    // queued touches of the removed sessions are dropped by the store on flush
+    async fn remove_all_for_user(&self, user_id: &str) -> Result<usize, model::store::Error> {
+        self.shared.inner.remove_all_for_user(user_id).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{model::auth::User, store::memory::InMemorySessionStore};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // counts the last access writes
+    struct Counting {
+        inner: InMemorySessionStore,
+        writes: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl SessionStore for Counting {
+        async fn add(&self, id: &str, data: SessionData) -> Result<(), model::store::Error> {
+            self.inner.add(id, data).await
+        }
+        async fn get(&self, id: &str) -> Result<SessionData, model::store::Error> {
+            self.inner.get(id).await
+        }
+        async fn remove(&self, id: &str) -> Result<(), model::store::Error> {
+            self.inner.remove(id).await
+        }
+        async fn mark_last_used(&self, id: &str, now: i64) -> Result<(), model::store::Error> {
+            self.writes.fetch_add(1, Ordering::SeqCst);
+            self.inner.mark_last_used(id, now).await
+        }
+        async fn update_user(
+            &self,
+            id: &str,
+            data: SessionData,
+            current: i64,
+        ) -> Result<bool, model::store::Error> {
+            self.inner.update_user(id, data, current).await
+        }
+        async fn add_limited(
+            &self,
+            id: &str,
+            data: SessionData,
+            max: usize,
+            policy: EvictPolicy,
+        ) -> Result<usize, model::store::Error> {
+            self.inner.add_limited(id, data, max, policy).await
+        }
+        async fn list_by_user(
+            &self,
+            user_id: &str,
+        ) -> Result<Vec<(String, SessionData)>, model::store::Error> {
+            self.inner.list_by_user(user_id).await
+        }
+        async fn remove_all_for_user(&self, user_id: &str) -> Result<usize, model::store::Error> {
+            self.inner.remove_all_for_user(user_id).await
+        }
+    }
+
+    fn make_store(granularity_ms: u64) -> (CoalescingSessionStore, Arc<AtomicUsize>) {
+        let writes = Arc::new(AtomicUsize::new(0));
+        let inner = Counting {
+            inner: InMemorySessionStore::new(),
+            writes: writes.clone(),
+        };
+        (
+            CoalescingSessionStore::new(Box::new(inner), Duration::from_millis(granularity_ms)),
+            writes,
+        )
+    }
+
+    fn session_data(now: i64) -> SessionData {
+        SessionData {
+            user: User {
+                id: "olia".to_string(),
+                name: "olia".to_string(),
+                department: String::new(),
+                roles: vec![],
+                backend: String::new(),
+            },
+            ip: "1.1.1.1".to_string(),
+            valid_till: now + 3_600_000,
+            last_access: now,
+            refreshed: now,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_skips_close_touches() {
+        let (store, writes) = make_store(10_000);
+        let now = Utc::now().timestamp_millis();
+        store.add("s1", session_data(now)).await.unwrap();
+        for i in 0..50 {
+            store.get("s1").await.unwrap();
+            store.mark_last_used("s1", now + i * 100).await.unwrap();
+        }
+        assert_eq!(store.flush().await, 0);
+        assert_eq!(writes.load(Ordering::SeqCst), 0);
+
+        store.mark_last_used("s1", now + 5_000).await.unwrap();
+        store.mark_last_used("s1", now + 6_000).await.unwrap();
+        assert_eq!(store.get("s1").await.unwrap().last_access, now + 5_000);
+        assert_eq!(store.flush().await, 1);
+        assert_eq!(writes.load(Ordering::SeqCst), 1);
+        assert_eq!(store.get("s1").await.unwrap().last_access, now + 5_000);
+    }
+
+    #[tokio::test]
+    async fn test_stored_lags_less_than_granularity() {
+        let granularity = 10_000;
+        let (store, writes) = make_store(granularity as u64);
+        let start = Utc::now().timestamp_millis();
+        store.add("s1", session_data(start)).await.unwrap();
+        // a request every 250ms, the flusher runs every half of the granularity
+        let mut now = start;
+        while now < start + 120_000 {
+            now += 250;
+            store.get("s1").await.unwrap();
+            store.mark_last_used("s1", now).await.unwrap();
+            if (now - start) % (granularity / 2) == 0 {
+                store.flush().await;
+            }
+            let stored = store.shared.inner.get("s1").await.unwrap();
+            assert!(now - stored.last_access <= granularity);
+            // still active for another instance with the inactivity of the granularity
+            assert!(stored.check_inactivity(now, granularity).is_ok());
+        }
+        assert!(writes.load(Ordering::SeqCst) <= 120_000 / (granularity as usize / 2) + 1);
+    }
+
+    #[tokio::test]
+    async fn test_remove_drops_pending() {
+        let (store, writes) = make_store(1_000);
+        let now = Utc::now().timestamp_millis();
+        store.add("s1", session_data(now)).await.unwrap();
+        store.add("s2", session_data(now)).await.unwrap();
+        store.mark_last_used("s1", now + 2_000).await.unwrap();
+        store.mark_last_used("s2", now + 2_000).await.unwrap();
+        store.remove("s1").await.unwrap();
+        assert_eq!(store.flush().await, 1);
+        assert_eq!(writes.load(Ordering::SeqCst), 1);
+
+        // removed by the user, the queued touch is ignored
+        store.mark_last_used("s2", now + 4_000).await.unwrap();
+        assert_eq!(store.remove_all_for_user("olia").await.unwrap(), 1);
+        assert_eq!(store.flush().await, 1);
+        assert!(store.get("s2").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_flusher() {
+        let (store, writes) = make_store(100);
+        let now = Utc::now().timestamp_millis();
+        store.add("s1", session_data(now)).await.unwrap();
+        store.mark_last_used("s1", now + 1_000).await.unwrap();
+        let handle = store.start_flusher();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(writes.load(Ordering::SeqCst), 1);
+        drop(store);
+        tokio::time::timeout(Duration::from_secs(1), handle)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
@@ -0,0 +1,251 @@
+use async_trait::async_trait;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    model::webauthn::{Credential, Error},
+    CredentialStore, Encryptor, KeyValueStore,
+};
+
+const USER_PREFIX: &str = "webauthn-user:";
+const CREDENTIAL_PREFIX: &str = "webauthn-cred:";
+// compare and set attempts of an update under contention
+const MAX_UPDATE_TRIES: usize = 10;
+
+// Keeps encrypted webauthn credentials in a key value store. Changes are compare and set
+// writes, so concurrent requests of any instance don't lose each other's updates
+pub struct KeyValueCredentialStore {
+    store: Box<dyn KeyValueStore + Send + Sync>,
+    encryptor: Box<dyn Encryptor + Send + Sync>,
+}
+
+impl KeyValueCredentialStore {
+    pub fn new(
+        store: Box<dyn KeyValueStore + Send + Sync>,
+        encryptor: Box<dyn Encryptor + Send + Sync>,
+    ) -> Self {
+        KeyValueCredentialStore { store, encryptor }
+    }
+
+    fn user_key(&self, user_id: &str) -> String {
+        format!("{USER_PREFIX}{}", self.encryptor.encrypt(user_id))
+    }
+
+    fn credential_key(&self, id: &str) -> String {
+        format!("{CREDENTIAL_PREFIX}{}", self.encryptor.encrypt(id))
+    }
+
+    // Read-check-write of a value: `f` gets the stored value and returns the new one
+    // (None - remove), an error leaves the value as is. `f` runs again if a concurrent
+    // request changed the value
+    async fn update_value<T, R>(
+        &self,
+        key: &str,
+        f: impl Fn(Option<T>) -> Result<(Option<T>, R), Error>,
+    ) -> Result<R, Error>
+    where
+        T: Serialize + DeserializeOwned,
+    {
+        for _ in 0..MAX_UPDATE_TRIES {
+            let current = self
+                .store
+                .get(key)
+                .await
+                .map_err(|e| anyhow::anyhow!("credential store get: {e}"))?;
+            let value = current.as_deref().map(|v| self.decode(v)).transpose()?;
+            let (value, res) = f(value)?;
+            let value = value.map(|v| self.encode(&v)).transpose()?;
+            if self
+                .store
+                .compare_and_set(key, current.as_deref(), value.as_deref(), None)
+                .await
+                .map_err(|e| anyhow::anyhow!("credential store compare and set: {e}"))?
+            {
+                return Ok(res);
+            }
+            tracing::debug!("credential changed concurrently, retrying");
+        }
+        Err(anyhow::anyhow!("credential keeps changing concurrently").into())
+    }
+
+    async fn get_decrypted<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, Error> {
+        let value = self
+            .store
+            .get(key)
+            .await
+            .map_err(|e| anyhow::anyhow!("credential store get: {e}"))?;
+        value.map(|value| self.decode(&value)).transpose()
+    }
+
+    fn decode<T: DeserializeOwned>(&self, value: &str) -> Result<T, Error> {
+        let value = self.encryptor.decrypt(value)?;
+        Ok(serde_json::from_str(&value)
+            .map_err(|e| anyhow::anyhow!("Deserialization error: {:?}", e))?)
+    }
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<String, Error> {
+        let value = serde_json::to_string(value)
+            .map_err(|e| anyhow::anyhow!("Serialization error: {:?}", e))?;
+        Ok(self.encryptor.encrypt(&value))
+    }
+}
+
+#[async_trait]
+impl CredentialStore for KeyValueCredentialStore {
+    async fn list(&self, user_id: &str) -> Result<Vec<Credential>, Error> {
+        let ids: Vec<String> = self
+            .get_decrypted(&self.user_key(user_id))
+            .await?
+            .unwrap_or_default();
+        let mut res = Vec::with_capacity(ids.len());
+        for id in ids {
+            if let Some(credential) = self.get(&id).await? {
+                res.push(credential);
+            }
+        }
+        Ok(res)
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<Credential>, Error> {
+        self.get_decrypted(&self.credential_key(id)).await
+    }
+
+    async fn add(&self, credential: Credential) -> Result<(), Error> {
+        self.update_value(
+            &self.credential_key(&credential.id),
+            |stored: Option<Credential>| match stored {
+                Some(_) => Err(Error::Exists()),
+                None => Ok((Some(credential.clone()), ())),
+            },
+        )
+        .await?;
+        self.update_value(
+            &self.user_key(&credential.user_id),
+            |ids: Option<Vec<String>>| {
+                let mut ids = ids.unwrap_or_default();
+                ids.push(credential.id.clone());
+                Ok((Some(ids), ()))
+            },
+        )
+        .await
+    }
+
+    async fn update(&self, credential: Credential) -> Result<(), Error> {
+        // a concurrent login with the same count, e.g. by a clone, loses here
+        self.update_value(
+            &self.credential_key(&credential.id),
+            |stored: Option<Credential>| {
+                let stored = stored.ok_or(Error::NoCredential())?;
+                if !stored.sign_count_grows(credential.sign_count) {
+                    return Err(Error::Invalid("sign count".to_string()));
+                }
+                Ok((Some(credential.clone()), ()))
+            },
+        )
+        .await
+    }
+
+    async fn remove(&self, id: &str) -> Result<(), Error> {
+        let credential = self.get(id).await?.ok_or(Error::NoCredential())?;
+        self.update_value(
+            &self.user_key(&credential.user_id),
+            |ids: Option<Vec<String>>| {
+                let mut ids = ids.unwrap_or_default();
+                ids.retain(|v| v != id);
+                Ok((Some(ids), ()))
+            },
+        )
+        .await?;
+        self.update_value(&self.credential_key(id), |stored: Option<Credential>| {
+            stored.ok_or(Error::NoCredential())?;
+            Ok((None, ()))
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{
+        encryptor::MagicEncryptor,
+        memory::{InMemoryKeyValueStore, YieldingKeyValueStore},
+    };
+
+    fn credential(id: &str, user: &str) -> Credential {
+        Credential {
+            id: id.to_string(),
+            user_id: user.to_string(),
+            public_key: vec![4, 1, 2],
+            sign_count: 0,
+            created: 10,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_credentials() {
+        let store = KeyValueCredentialStore::new(
+            Box::new(InMemoryKeyValueStore::new()),
+            Box::new(MagicEncryptor::new("1234567890123456").unwrap()),
+        );
+        assert!(store.list("olia").await.unwrap().is_empty());
+        store.add(credential("a", "olia")).await.unwrap();
+        store.add(credential("b", "olia")).await.unwrap();
+        store.add(credential("c", "jonas")).await.unwrap();
+        assert!(matches!(
+            store.add(credential("a", "jonas")).await,
+            Err(Error::Exists())
+        ));
+        let ids: Vec<String> = store
+            .list("olia")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|c| c.id)
+            .collect();
+        assert_eq!(ids, vec!["a", "b"]);
+
+        let mut c = store.get("b").await.unwrap().unwrap();
+        c.sign_count = 5;
+        store.update(c.clone()).await.unwrap();
+        assert_eq!(store.get("b").await.unwrap(), Some(c.clone()));
+        assert!(matches!(
+            store.update(c.clone()).await,
+            Err(Error::Invalid(_))
+        ));
+        assert!(matches!(
+            store.update(credential("x", "olia")).await,
+            Err(Error::NoCredential())
+        ));
+        assert_eq!(store.get("x").await.unwrap(), None);
+
+        store.remove("a").await.unwrap();
+        assert_eq!(store.get("a").await.unwrap(), None);
+        assert_eq!(store.list("olia").await.unwrap(), vec![c]);
+        assert!(matches!(
+            store.remove("a").await,
+            Err(Error::NoCredential())
+        ));
+        store.add(credential("a", "jonas")).await.unwrap();
+        assert_eq!(store.list("jonas").await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_changes() {
+        let store = KeyValueCredentialStore::new(
+            Box::new(YieldingKeyValueStore::new()),
+            Box::new(MagicEncryptor::new("1234567890123456").unwrap()),
+        );
+        let (a, b) = tokio::join!(
+            store.add(credential("a", "olia")),
+            store.add(credential("b", "olia"))
+        );
+        assert!(a.is_ok() && b.is_ok());
+        assert_eq!(store.list("olia").await.unwrap().len(), 2);
+
+        // a clone replaying the same count
+        let mut c = credential("a", "olia");
+        c.sign_count = 1;
+        let (a, b) = tokio::join!(store.update(c.clone()), store.update(c));
+        assert!(a.is_ok() != b.is_ok());
+    }
+}
@@ -1,73 +1,449 @@
+use anyhow::Context;
 use async_trait::async_trait;
 use chrono::Utc;
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::{BTreeSet, HashMap},
-    sync::Arc,
+    collections::{hash_map::RandomState, HashMap, HashSet},
+    fs,
+    hash::BuildHasher,
+    io::Write,
+    os::unix::fs::OpenOptionsExt,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::Duration,
 };
-use tokio::sync::Mutex;
 
-use crate::{model, SessionData, SessionStore};
+use crate::{
+    model, model::config::EvictPolicy, Encryptor, KeyValueStore, SessionData, SessionStore,
+};
+
+const SHARDS: usize = 16;
+const SNAPSHOT_VERSION: u32 = 1;
 
+// Sessions are spread over shards by id, so /auth calls of different sessions don't
+// wait for each other. Logins and removals also take the user index lock, always before
+// the shard locks. Sweeps go shard by shard and index only the dropped sessions
 struct DB {
-    store: HashMap<String, SessionData>,
-    expirations: BTreeSet<(i64, String)>,
+    shards: Vec<Mutex<HashMap<String, SessionData>>>,
+    hasher: RandomState,
+    // user id -> session ids
+    by_user: Mutex<Users>,
+    // millis, 0 - idle sessions are not swept
+    inactivity: i64,
+    // 0 - unlimited
+    max_sessions: usize,
+    count: AtomicUsize,
+    expired: AtomicU64,
+    idle: AtomicU64,
+    rejected: AtomicU64,
+}
+
+// Counters since the start, reported by /auth/live
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize)]
+pub struct MemoryStoreMetrics {
+    pub sessions: usize,
+    // swept after valid_till
+    pub expired: u64,
+    // swept after the inactivity timeout
+    pub idle: u64,
+    // new sessions rejected by the cap
+    pub rejected: u64,
 }
 
+#[derive(Clone)]
 pub struct InMemorySessionStore {
-    store: Arc<Mutex<DB>>,
+    db: Arc<DB>,
 }
 
+// Live sessions saved on shutdown, the whole document is encrypted
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    version: u32,
+    sessions: Vec<(String, SessionData)>,
+}
+
+type Users = HashMap<String, HashSet<String>>;
+
 impl DB {
-    fn new() -> Self {
+    fn new(inactivity: i64, max_sessions: usize) -> Self {
         DB {
-            store: HashMap::new(),
-            expirations: BTreeSet::new(),
+            shards: (0..SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+            hasher: RandomState::new(),
+            by_user: Mutex::new(HashMap::new()),
+            inactivity,
+            max_sessions,
+            count: AtomicUsize::new(0),
+            expired: AtomicU64::new(0),
+            idle: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
         }
     }
-    fn insert(&mut self, session_id: &str, data: SessionData) {
-        self.expirations
-            .insert((data.valid_till, session_id.to_string()));
-        self.store.insert(session_id.to_string(), data);
-        self.remove_expired();
+
+    fn shard(&self, session_id: &str) -> &Mutex<HashMap<String, SessionData>> {
+        &self.shards[self.hasher.hash_one(session_id) as usize % SHARDS]
+    }
+
+    fn insert(
+        &self,
+        session_id: &str,
+        data: SessionData,
+        now: i64,
+    ) -> Result<(), model::store::Error> {
+        let mut users = self.by_user.lock().unwrap();
+        self.insert_int(&mut users, session_id, data, now)
     }
-    fn get(&mut self, session_id: &str) -> Option<&SessionData> {
-        self.remove_expired();
-        self.store.get(session_id)
+
+    fn insert_int(
+        &self,
+        users: &mut Users,
+        session_id: &str,
+        data: SessionData,
+        now: i64,
+    ) -> Result<(), model::store::Error> {
+        let mut shard = self.shard(session_id).lock().unwrap();
+        let exists = shard.contains_key(session_id);
+        if data.valid_till <= now {
+            drop(shard);
+            self.remove_int(users, session_id);
+            return Ok(());
+        }
+        if !exists
+            && self.max_sessions > 0
+            && self.count.load(Ordering::SeqCst) >= self.max_sessions
+        {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            return Err(model::store::Error::StoreFull());
+        }
+        let user_id = data.user.id.clone();
+        match shard.insert(session_id.to_string(), data) {
+            Some(old) if old.user.id != user_id => unindex(users, &old.user.id, session_id),
+            Some(_) => {}
+            None => {
+                self.count.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        users
+            .entry(user_id)
+            .or_default()
+            .insert(session_id.to_string());
+        Ok(())
     }
-    fn get_mut(&mut self, session_id: &str) -> Option<&mut SessionData> {
-        self.remove_expired();
-        self.store.get_mut(session_id)
+
+    fn insert_limited(
+        &self,
+        session_id: &str,
+        data: SessionData,
+        max: usize,
+        policy: EvictPolicy,
+        now: i64,
+    ) -> Result<usize, model::store::Error> {
+        let mut users = self.by_user.lock().unwrap();
+        let mut evicted = 0;
+        let exists = self
+            .shard(session_id)
+            .lock()
+            .unwrap()
+            .contains_key(session_id);
+        if max > 0 && !exists {
+            let mut sessions: Vec<(i64, String)> = self
+                .list_int(&users, &data.user.id, now)
+                .into_iter()
+                .map(|(id, s)| match policy {
+                    EvictPolicy::LeastRecentlyUsed => (s.last_access, id),
+                    _ => (s.valid_till, id),
+                })
+                .collect();
+            if sessions.len() >= max {
+                if policy == EvictPolicy::Reject {
+                    return Err(model::store::Error::TooManySessions());
+                }
+                sessions.sort();
+                for (_, id) in sessions.iter().take(sessions.len() + 1 - max) {
+                    self.remove_int(&mut users, id);
+                    evicted += 1;
+                }
+            }
+        }
+        self.insert_int(&mut users, session_id, data, now)?;
+        Ok(evicted)
     }
-    fn remove(&mut self, session_id: &str) -> Option<SessionData> {
-        self.store.remove(session_id)
-        // it leaves the expired entry in the expirations set, it will be removed after expiration
+
+    fn get(&self, session_id: &str, now: i64) -> Option<SessionData> {
+        let shard = self.shard(session_id).lock().unwrap();
+        shard
+            .get(session_id)
+            .filter(|data| data.valid_till > now)
+            .cloned()
     }
 
-    fn remove_expired_int(&mut self, now: i64) {
-        let mut to_remove = Vec::new();
-        for (expiry_time, key) in &self.expirations {
-            if *expiry_time > now {
-                break;
+    fn touch(&self, session_id: &str, now: i64) -> bool {
+        let mut shard = self.shard(session_id).lock().unwrap();
+        match shard
+            .get_mut(session_id)
+            .filter(|data| data.valid_till > now)
+        {
+            Some(data) => {
+                data.last_access = now;
+                true
             }
-            to_remove.push((*expiry_time, key.clone()));
+            None => false,
         }
-        for (expiry_time, key) in to_remove {
-            self.store.remove(&key);
-            self.expirations.remove(&(expiry_time, key));
+    }
+
+    // None - no session, the user id stays the same, so the index is untouched
+    fn update_user(
+        &self,
+        session_id: &str,
+        data: SessionData,
+        current: i64,
+        now: i64,
+    ) -> Option<bool> {
+        let mut shard = self.shard(session_id).lock().unwrap();
+        let stored = shard
+            .get_mut(session_id)
+            .filter(|stored| stored.valid_till > now)?;
+        if stored.refreshed != current {
+            return Some(false);
         }
+        stored.user = data.user;
+        stored.refreshed = data.refreshed;
+        Some(true)
     }
 
-    fn remove_expired(&mut self) {
-        let now = Utc::now().timestamp_millis();
-        self.remove_expired_int(now);
+    fn remove(&self, session_id: &str) -> Option<SessionData> {
+        let mut users = self.by_user.lock().unwrap();
+        self.remove_int(&mut users, session_id)
+    }
+
+    fn remove_int(&self, users: &mut Users, session_id: &str) -> Option<SessionData> {
+        let res = self.shard(session_id).lock().unwrap().remove(session_id);
+        if let Some(data) = &res {
+            self.count.fetch_sub(1, Ordering::SeqCst);
+            unindex(users, &data.user.id, session_id);
+        }
+        res
+    }
+
+    fn list_by_user(&self, user_id: &str, now: i64) -> Vec<(String, SessionData)> {
+        let users = self.by_user.lock().unwrap();
+        self.list_int(&users, user_id, now)
+    }
+
+    fn list_int(&self, users: &Users, user_id: &str, now: i64) -> Vec<(String, SessionData)> {
+        users.get(user_id).map_or_else(Vec::new, |ids| {
+            ids.iter()
+                .filter_map(|id| self.get(id, now).map(|data| (id.clone(), data)))
+                .collect()
+        })
+    }
+
+    fn remove_all_for_user(&self, user_id: &str) -> usize {
+        let mut users = self.by_user.lock().unwrap();
+        let ids = users.remove(user_id).unwrap_or_default();
+        let mut res = 0;
+        for id in ids {
+            if self.shard(&id).lock().unwrap().remove(&id).is_some() {
+                self.count.fetch_sub(1, Ordering::SeqCst);
+                res += 1;
+            }
+        }
+        res
+    }
+
+    // Drops expired and idle sessions, returns how many of each
+    #[cfg(test)]
+    fn sweep(&self, now: i64) -> (usize, usize) {
+        (0..SHARDS).fold((0, 0), |(expired, idle), i| {
+            let res = self.sweep_shard(i, now);
+            (expired + res.0, idle + res.1)
+        })
+    }
+
+    // Sweeps one shard, the user index is locked only to drop the swept sessions
+    fn sweep_shard(&self, i: usize, now: i64) -> (usize, usize) {
+        let (mut expired, mut idle) = (0, 0);
+        let mut dropped = Vec::new();
+        self.shards[i].lock().unwrap().retain(|id, data| {
+            let keep = if data.valid_till <= now {
+                expired += 1;
+                false
+            } else if !self.is_live(data, now) {
+                idle += 1;
+                false
+            } else {
+                true
+            };
+            if !keep {
+                dropped.push((data.user.id.clone(), id.clone()));
+            }
+            keep
+        });
+        self.count.fetch_sub(expired + idle, Ordering::SeqCst);
+        self.expired.fetch_add(expired as u64, Ordering::Relaxed);
+        self.idle.fetch_add(idle as u64, Ordering::Relaxed);
+        if !dropped.is_empty() {
+            let mut users = self.by_user.lock().unwrap();
+            let shard = self.shards[i].lock().unwrap();
+            for (user_id, id) in dropped {
+                // a login may have taken the id meanwhile
+                if shard.get(&id).is_none_or(|data| data.user.id != user_id) {
+                    unindex(&mut users, &user_id, &id);
+                }
+            }
+        }
+        (expired, idle)
+    }
+
+    fn is_live(&self, data: &SessionData, now: i64) -> bool {
+        data.valid_till > now && (self.inactivity == 0 || data.last_access + self.inactivity >= now)
+    }
+
+    fn live(&self, now: i64) -> Vec<(String, SessionData)> {
+        let mut res = Vec::new();
+        for shard in &self.shards {
+            let shard = shard.lock().unwrap();
+            res.extend(
+                shard
+                    .iter()
+                    .filter(|(_, data)| self.is_live(data, now))
+                    .map(|(id, data)| (id.clone(), data.clone())),
+            );
+        }
+        res
+    }
+
+    fn metrics(&self) -> MemoryStoreMetrics {
+        MemoryStoreMetrics {
+            sessions: self.count.load(Ordering::SeqCst),
+            expired: self.expired.load(Ordering::Relaxed),
+            idle: self.idle.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+        }
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.shards.iter().map(|s| s.lock().unwrap().len()).sum()
+    }
+}
+
+fn unindex(users: &mut Users, user_id: &str, session_id: &str) {
+    if let Some(ids) = users.get_mut(user_id) {
+        ids.remove(session_id);
+        if ids.is_empty() {
+            users.remove(user_id);
+        }
     }
 }
 
 impl InMemorySessionStore {
     pub fn new() -> Self {
+        Self::with_limits(0, 0)
+    }
+
+    // inactivity in millis, sessions idle longer are swept, 0 - only expired ones.
+    // max_sessions caps all sessions, new ones are rejected at the cap, 0 - unlimited
+    pub fn with_limits(inactivity: i64, max_sessions: usize) -> Self {
         InMemorySessionStore {
-            store: Arc::new(Mutex::new(DB::new())),
+            db: Arc::new(DB::new(inactivity, max_sessions)),
+        }
+    }
+
+    // Sweeps expired and idle sessions until the store is dropped
+    pub fn start_sweeper(&self, every: Duration) -> tokio::task::JoinHandle<()> {
+        let db: Weak<DB> = Arc::downgrade(&self.db);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(every);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let Some(db) = db.upgrade() else {
+                    break;
+                };
+                let now = Utc::now().timestamp_millis();
+                let (mut expired, mut idle) = (0, 0);
+                for i in 0..SHARDS {
+                    let res = db.sweep_shard(i, now);
+                    (expired, idle) = (expired + res.0, idle + res.1);
+                    // logins and /auth calls go between the shards
+                    tokio::task::yield_now().await;
+                }
+                let metrics = db.metrics();
+                tracing::debug!(
+                    expired,
+                    idle,
+                    sessions = metrics.sessions,
+                    rejected = metrics.rejected,
+                    "swept sessions"
+                );
+            }
+        })
+    }
+
+    pub fn metrics(&self) -> MemoryStoreMetrics {
+        self.db.metrics()
+    }
+
+    // Writes the live sessions to the file encrypted as one document. The file is
+    // replaced atomically and readable by the owner only. Returns the saved count
+    pub fn save_snapshot(&self, path: &str, encryptor: &dyn Encryptor) -> anyhow::Result<usize> {
+        let snapshot = Snapshot {
+            version: SNAPSHOT_VERSION,
+            sessions: self.db.live(Utc::now().timestamp_millis()),
+        };
+        let data = encryptor.encrypt(&serde_json::to_string(&snapshot)?);
+        let tmp = format!("{path}.tmp");
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp)
+            .with_context(|| format!("can't create {tmp}"))?;
+        file.write_all(data.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp, path).with_context(|| format!("can't rename {tmp} to {path}"))?;
+        Ok(snapshot.sessions.len())
+    }
+
+    // Loads the sessions saved by save_snapshot, expired and idle ones and the ones
+    // over the cap are dropped. The file is deleted once loaded, so a crash later
+    // can't bring back sessions logged out meanwhile. A file that can't be decrypted
+    // or parsed is an error and left as is. Returns the loaded and dropped counts,
+    // None if there is no file
+    pub fn load_snapshot(
+        &self,
+        path: &str,
+        encryptor: &dyn Encryptor,
+    ) -> anyhow::Result<Option<(usize, usize)>> {
+        let data = match fs::read_to_string(path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err).with_context(|| format!("can't read {path}")),
+        };
+        let data = encryptor
+            .decrypt(data.trim())
+            .context("can't decrypt the snapshot, wrong encryption key or corrupted file")?;
+        let snapshot: Snapshot = serde_json::from_str(&data).context("can't parse the snapshot")?;
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(anyhow::anyhow!(
+                "unsupported snapshot version {}",
+                snapshot.version
+            ));
+        }
+        let now = Utc::now().timestamp_millis();
+        let (mut loaded, mut dropped) = (0, 0);
+        for (session_id, data) in snapshot.sessions {
+            if self.db.is_live(&data, now) && self.db.insert(&session_id, data, now).is_ok() {
+                loaded += 1;
+            } else {
+                dropped += 1;
+            }
         }
+        fs::remove_file(path).with_context(|| format!("can't delete {path}"))?;
+        Ok(Some((loaded, dropped)))
     }
 }
 
@@ -81,99 +457,748 @@ impl Default for InMemorySessionStore {
 impl SessionStore for InMemorySessionStore {
     async fn add(&self, session_id: &str, data: SessionData) -> Result<(), model::store::Error> {
         tracing::trace!("Adding session: {}", session_id);
-        let mut store = self.store.lock().await;
-        store.insert(session_id, data);
-        Ok(())
+        self.db
+            .insert(session_id, data, Utc::now().timestamp_millis())
     }
 
     async fn get(&self, session_id: &str) -> Result<SessionData, model::store::Error> {
-        let mut store = self.store.lock().await;
-        match store.get(session_id) {
-            Some(data) => Ok(data.clone()),
-            None => Err(model::store::Error::NoSession()),
-        }
+        self.db
+            .get(session_id, Utc::now().timestamp_millis())
+            .ok_or(model::store::Error::NoSession())
     }
 
     async fn remove(&self, session_id: &str) -> Result<(), model::store::Error> {
-        let mut store = self.store.lock().await;
-        match store.remove(session_id) {
+        match self.db.remove(session_id) {
             Some(_) => Ok(()),
             None => Err(model::store::Error::NoSession()),
         }
     }
+
     async fn mark_last_used(&self, session_id: &str, now: i64) -> Result<(), model::store::Error> {
+        if self.db.touch(session_id, now) {
+            Ok(())
+        } else {
+            Err(model::store::Error::NoSession())
+        }
+    }
+
+    async fn update_user(
+        &self,
+        session_id: &str,
+        data: SessionData,
+        current: i64,
+    ) -> Result<bool, model::store::Error> {
+        self.db
+            .update_user(session_id, data, current, Utc::now().timestamp_millis())
+            .ok_or(model::store::Error::NoSession())
+    }
+
+    async fn add_limited(
+        &self,
+        session_id: &str,
+        data: SessionData,
+        max: usize,
+        policy: EvictPolicy,
+    ) -> Result<usize, model::store::Error> {
+        tracing::trace!("Adding session: {}", session_id);
+        self.db
+            .insert_limited(session_id, data, max, policy, Utc::now().timestamp_millis())
+    }
+
+    async fn list_by_user(
+        &self,
+        user_id: &str,
+    ) -> Result<Vec<(String, SessionData)>, model::store::Error> {
+        Ok(self.db.list_by_user(user_id, Utc::now().timestamp_millis()))
+    }
+
+    async fn remove_all_for_user(&self, user_id: &str) -> Result<usize, model::store::Error> {
+        Ok(self.db.remove_all_for_user(user_id))
+    }
+}
+
+// value and expiration time in millis
+type KeyValue = (String, Option<i64>);
+
+#[derive(Clone)]
+pub struct InMemoryKeyValueStore {
+    store: Arc<tokio::sync::Mutex<HashMap<String, KeyValue>>>,
+}
+
+impl InMemoryKeyValueStore {
+    pub fn new() -> Self {
+        InMemoryKeyValueStore {
+            store: Arc::new(tokio::sync::Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+impl Default for InMemoryKeyValueStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl KeyValueStore for InMemoryKeyValueStore {
+    async fn get(&self, key: &str) -> Result<Option<String>, model::store::Error> {
+        let store = self.store.lock().await;
+        let now = Utc::now().timestamp_millis();
+        Ok(store
+            .get(key)
+            .filter(|(_, till)| till.is_none_or(|till| till > now))
+            .map(|(value, _)| value.clone()))
+    }
+
+    async fn set(
+        &self,
+        key: &str,
+        value: &str,
+        ttl: Option<Duration>,
+    ) -> Result<(), model::store::Error> {
         let mut store = self.store.lock().await;
-        match store.get_mut(session_id) {
-            Some(data) => {
-                data.last_access = now;
-                Ok(())
+        let now = Utc::now().timestamp_millis();
+        store.retain(|_, (_, till)| till.is_none_or(|till| till > now));
+        let till = ttl.map(|ttl| now + ttl.as_millis() as i64);
+        store.insert(key.to_string(), (value.to_string(), till));
+        Ok(())
+    }
+
+    async fn remove(&self, key: &str) -> Result<(), model::store::Error> {
+        let mut store = self.store.lock().await;
+        store.remove(key);
+        Ok(())
+    }
+
+    async fn compare_and_set(
+        &self,
+        key: &str,
+        current: Option<&str>,
+        value: Option<&str>,
+        ttl: Option<Duration>,
+    ) -> Result<bool, model::store::Error> {
+        let mut store = self.store.lock().await;
+        let now = Utc::now().timestamp_millis();
+        store.retain(|_, (_, till)| till.is_none_or(|till| till > now));
+        if store.get(key).map(|(value, _)| value.as_str()) != current {
+            return Ok(false);
+        }
+        match value {
+            Some(value) => {
+                let till = ttl.map(|ttl| now + ttl.as_millis() as i64);
+                store.insert(key.to_string(), (value.to_string(), till));
+            }
+            None => {
+                store.remove(key);
             }
-            None => Err(model::store::Error::NoSession()),
         }
+        Ok(true)
+    }
+}
+
+// Lets other requests run between a read and a write, as a remote store does
+#[cfg(test)]
+#[derive(Default)]
+pub(crate) struct YieldingKeyValueStore(InMemoryKeyValueStore);
+
+#[cfg(test)]
+impl YieldingKeyValueStore {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl KeyValueStore for YieldingKeyValueStore {
+    async fn get(&self, key: &str) -> Result<Option<String>, model::store::Error> {
+        let res = self.0.get(key).await;
+        tokio::task::yield_now().await;
+        res
+    }
+
+    async fn set(
+        &self,
+        key: &str,
+        value: &str,
+        ttl: Option<Duration>,
+    ) -> Result<(), model::store::Error> {
+        self.0.set(key, value, ttl).await
+    }
+
+    async fn remove(&self, key: &str) -> Result<(), model::store::Error> {
+        self.0.remove(key).await
+    }
+
+    async fn compare_and_set(
+        &self,
+        key: &str,
+        current: Option<&str>,
+        value: Option<&str>,
+        ttl: Option<Duration>,
+    ) -> Result<bool, model::store::Error> {
+        self.0.compare_and_set(key, current, value, ttl).await
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::model::auth::User;
+    use crate::{model::auth::User, store::encryptor::MagicEncryptor};
+    use test_case::test_case;
 
     use super::*;
 
     fn _session_data(at: i64) -> SessionData {
+        _user_session_data("test_user", at)
+    }
+
+    fn _user_session_data(user: &str, at: i64) -> SessionData {
         SessionData {
             user: User {
-                id: "test_user".to_string(),
+                id: user.to_string(),
                 name: "Test User".to_string(),
                 department: "Test Department".to_string(),
                 roles: vec!["admin".to_string()],
+                backend: String::new(),
             },
             ip: "".to_string(),
             valid_till: at,
             last_access: 20,
+            refreshed: 20,
         }
     }
 
+    fn _now() -> i64 {
+        Utc::now().timestamp_millis()
+    }
+
+    fn _users(db: &DB) -> usize {
+        db.by_user.lock().unwrap().len()
+    }
+
     #[test]
     fn test_db_add() {
-        let mut db = DB::new();
+        let db = DB::new(0, 0);
         let session_id = "test";
-        let data = _session_data(Utc::now().timestamp_millis() + 1000);
-        db.insert(session_id, data.clone());
-        assert_eq!(db.store.len(), 1);
-        assert_eq!(db.expirations.len(), 1);
-        assert_eq!(db.store.get(session_id), Some(&data));
+        let data = _session_data(_now() + 1000);
+        db.insert(session_id, data.clone(), _now()).unwrap();
+        assert_eq!(db.len(), 1);
+        assert_eq!(db.metrics().sessions, 1);
+        assert_eq!(db.get(session_id, _now()), Some(data));
     }
 
     #[test]
     fn test_db_add_expired() {
-        let mut db = DB::new();
+        let db = DB::new(0, 0);
         let session_id = "test";
-        let data = _session_data(Utc::now().timestamp_millis() - 1000);
-        db.insert(session_id, data.clone());
-        assert_eq!(db.store.len(), 0);
-        assert_eq!(db.expirations.len(), 0);
-        assert_eq!(db.store.get(session_id), None);
+        let data = _session_data(_now() - 1000);
+        db.insert(session_id, data.clone(), _now()).unwrap();
+        assert_eq!(db.len(), 0);
+        assert_eq!(_users(&db), 0);
+        assert_eq!(db.get(session_id, _now()), None);
     }
 
     #[test]
     fn test_db_get_expired() {
-        let mut db = DB::new();
+        let db = DB::new(0, 0);
         let session_id = "test";
-        let data = _session_data(Utc::now().timestamp_millis() + 1000);
-        db.insert(session_id, data.clone());
-        assert_eq!(db.store.len(), 1);
-        db.remove_expired_int(Utc::now().timestamp_millis() + 1001);
-        assert_eq!(db.store.get(session_id), None);
+        let now = _now();
+        let data = _session_data(now + 1000);
+        db.insert(session_id, data.clone(), now).unwrap();
+        assert_eq!(db.len(), 1);
+        // hidden at once, dropped by the sweep
+        assert_eq!(db.get(session_id, now + 1001), None);
+        assert!(!db.touch(session_id, now + 1001));
+        assert_eq!(db.len(), 1);
+        assert_eq!(db.sweep(now + 1001), (1, 0));
+        assert_eq!(db.len(), 0);
+        assert_eq!(db.metrics().sessions, 0);
+    }
+
+    #[tokio::test]
+    async fn test_key_value() {
+        let store = InMemoryKeyValueStore::new();
+        assert_eq!(store.get("k").await.unwrap(), None);
+        store.set("k", "v", None).await.unwrap();
+        store
+            .set("k2", "v2", Some(Duration::from_millis(20)))
+            .await
+            .unwrap();
+        assert_eq!(store.get("k").await.unwrap(), Some("v".to_string()));
+        assert_eq!(store.get("k2").await.unwrap(), Some("v2".to_string()));
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert_eq!(store.get("k2").await.unwrap(), None);
+        store.remove("k").await.unwrap();
+        assert_eq!(store.get("k").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_key_value_compare_and_set() {
+        let store = InMemoryKeyValueStore::new();
+        assert!(store
+            .compare_and_set("k", None, Some("v"), None)
+            .await
+            .unwrap());
+        assert!(!store
+            .compare_and_set("k", None, Some("v2"), None)
+            .await
+            .unwrap());
+        assert!(!store
+            .compare_and_set("k", Some("other"), Some("v2"), None)
+            .await
+            .unwrap());
+        assert_eq!(store.get("k").await.unwrap(), Some("v".to_string()));
+        assert!(store
+            .compare_and_set("k", Some("v"), Some("v2"), Some(Duration::from_millis(20)))
+            .await
+            .unwrap());
+        assert_eq!(store.get("k").await.unwrap(), Some("v2".to_string()));
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        // expired is the same as missing
+        assert!(!store
+            .compare_and_set("k", Some("v2"), None, None)
+            .await
+            .unwrap());
+        store.set("k", "v", None).await.unwrap();
+        assert!(store
+            .compare_and_set("k", Some("v"), None, None)
+            .await
+            .unwrap());
+        assert_eq!(store.get("k").await.unwrap(), None);
     }
 
     #[test]
     fn test_db_remove() {
-        let mut db = DB::new();
+        let db = DB::new(0, 0);
         let session_id = "test";
-        let data = _session_data(Utc::now().timestamp_millis() + 1000);
-        db.insert(session_id, data.clone());
-        assert_eq!(db.store.len(), 1);
+        let data = _session_data(_now() + 1000);
+        db.insert(session_id, data.clone(), _now()).unwrap();
+        assert_eq!(db.len(), 1);
         db.remove(session_id);
-        assert_eq!(db.store.get(session_id), None);
+        assert_eq!(db.get(session_id, _now()), None);
+        // nothing left behind
+        assert_eq!(db.len(), 0);
+        assert_eq!(_users(&db), 0);
+        assert_eq!(db.metrics().sessions, 0);
+        assert_eq!(db.sweep(_now() + 2000), (0, 0));
+    }
+
+    #[test]
+    fn test_db_by_user() {
+        let db = DB::new(0, 0);
+        let now = _now();
+        let at = now + 1000;
+        db.insert("s1", _user_session_data("olia", at), now)
+            .unwrap();
+        db.insert("s2", _user_session_data("olia", at), now)
+            .unwrap();
+        db.insert("s3", _user_session_data("jonas", at), now)
+            .unwrap();
+        let mut ids: Vec<String> = db
+            .list_by_user("olia", now)
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        ids.sort();
+        assert_eq!(ids, vec!["s1", "s2"]);
+        db.remove("s1");
+        assert_eq!(db.list_by_user("olia", now).len(), 1);
+        assert_eq!(db.list_by_user("nobody", now).len(), 0);
+        db.insert("s3", _user_session_data("olia", at), now)
+            .unwrap();
+        assert_eq!(db.list_by_user("jonas", now).len(), 0);
+        assert_eq!(db.remove_all_for_user("olia"), 2);
+        assert_eq!(db.list_by_user("olia", now).len(), 0);
+        assert_eq!(db.len(), 0);
+        assert_eq!(_users(&db), 0);
+        assert_eq!(db.metrics().sessions, 0);
+    }
+
+    #[test]
+    fn test_db_by_user_expired() {
+        let db = DB::new(0, 0);
+        let now = _now();
+        db.insert("s1", _user_session_data("olia", now + 1000), now)
+            .unwrap();
+        db.insert("s2", _user_session_data("olia", now + 5000), now)
+            .unwrap();
+        let ids: Vec<String> = db
+            .list_by_user("olia", now + 1001)
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        assert_eq!(ids, vec!["s2"]);
+        db.sweep(now + 5001);
+        assert_eq!(_users(&db), 0);
+    }
+
+    #[test]
+    fn test_db_readd_keeps_later_expiration() {
+        let db = DB::new(0, 0);
+        let now = _now();
+        db.insert("s1", _user_session_data("olia", now + 1000), now)
+            .unwrap();
+        db.insert("s1", _user_session_data("olia", now + 5000), now)
+            .unwrap();
+        db.sweep(now + 1001);
+        assert!(db.get("s1", now + 1001).is_some());
+        assert_eq!(db.metrics().sessions, 1);
+    }
+
+    #[test]
+    fn test_db_sweep_idle() {
+        let now = _now();
+        let db = DB::new(1000, 0);
+        db.insert(
+            "s1",
+            _session_data_used("olia", now + 10000, now - 2000),
+            now,
+        )
+        .unwrap();
+        db.insert(
+            "s2",
+            _session_data_used("olia", now + 10000, now - 500),
+            now,
+        )
+        .unwrap();
+        db.insert("s3", _session_data_used("jonas", now + 100, now), now)
+            .unwrap();
+        assert_eq!(db.sweep(now + 200), (1, 1));
+        assert_eq!(_user_sessions(&db, "olia"), vec!["s2"]);
+        assert_eq!(_users(&db), 1);
+        let metrics = db.metrics();
+        assert_eq!(
+            metrics,
+            MemoryStoreMetrics {
+                sessions: 1,
+                expired: 1,
+                idle: 1,
+                rejected: 0
+            }
+        );
+        // without inactivity only expired ones go
+        let db = DB::new(0, 0);
+        db.insert(
+            "s1",
+            _session_data_used("olia", now + 10000, now - 2000),
+            now,
+        )
+        .unwrap();
+        assert_eq!(db.sweep(now), (0, 0));
+    }
+
+    #[test]
+    fn test_db_cap() {
+        let now = _now();
+        let db = DB::new(0, 2);
+        db.insert("s1", _user_session_data("olia", now + 1000), now)
+            .unwrap();
+        db.insert("s2", _user_session_data("jonas", now + 1000), now)
+            .unwrap();
+        let res = db.insert("s3", _user_session_data("olia", now + 1000), now);
+        assert!(matches!(res, Err(model::store::Error::StoreFull())));
+        let res = db.insert_limited(
+            "s3",
+            _user_session_data("olia", now + 1000),
+            5,
+            EvictPolicy::Oldest,
+            now,
+        );
+        assert!(matches!(res, Err(model::store::Error::StoreFull())));
+        // updates fit
+        db.insert("s1", _user_session_data("olia", now + 2000), now)
+            .unwrap();
+        db.remove("s2");
+        db.insert("s3", _user_session_data("olia", now + 1000), now)
+            .unwrap();
+        assert_eq!(db.metrics().rejected, 2);
+        assert_eq!(db.metrics().sessions, 2);
+        // sweeping makes room
+        db.sweep(now + 1500);
+        db.insert("s4", _user_session_data("olia", now + 3000), now + 1500)
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sweeper() {
+        let store = InMemorySessionStore::with_limits(0, 0);
+        store.add("s1", _session_data(_now() + 20)).await.unwrap();
+        let handle = store.start_sweeper(Duration::from_millis(10));
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(store.metrics().sessions, 0);
+        assert_eq!(store.metrics().expired, 1);
+        drop(store);
+        tokio::time::timeout(Duration::from_secs(1), handle)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_store_remove_all_for_user() {
+        let store = InMemorySessionStore::new();
+        let at = Utc::now().timestamp_millis() + 1000;
+        store
+            .add("s1", _user_session_data("olia", at))
+            .await
+            .unwrap();
+        store
+            .add("s2", _user_session_data("jonas", at))
+            .await
+            .unwrap();
+        assert_eq!(store.remove_all_for_user("olia").await.unwrap(), 1);
+        assert!(store.get("s1").await.is_err());
+        assert!(store.get("s2").await.is_ok());
+        assert_eq!(store.list_by_user("jonas").await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_store_update_user() {
+        let store = InMemorySessionStore::new();
+        let at = Utc::now().timestamp_millis() + 1000;
+        store
+            .add("s1", _user_session_data("olia", at))
+            .await
+            .unwrap();
+        store.mark_last_used("s1", 30).await.unwrap();
+        let mut data = _user_session_data("olia", at);
+        data.user.roles = vec!["user".to_string()];
+        data.refreshed = 40;
+        assert!(!store.update_user("s1", data.clone(), 10).await.unwrap());
+        assert!(store.update_user("s1", data.clone(), 20).await.unwrap());
+        let res = store.get("s1").await.unwrap();
+        assert_eq!(
+            (res.user, res.refreshed, res.last_access),
+            (data.user.clone(), 40, 30)
+        );
+        store.remove("s1").await.unwrap();
+        assert!(matches!(
+            store.update_user("s1", data, 40).await,
+            Err(model::store::Error::NoSession())
+        ));
+        assert!(store.get("s1").await.is_err());
+    }
+
+    fn _session_data_used(user: &str, at: i64, last_access: i64) -> SessionData {
+        SessionData {
+            last_access,
+            .._user_session_data(user, at)
+        }
+    }
+
+    fn _user_sessions(db: &DB, user: &str) -> Vec<String> {
+        let mut res: Vec<String> = db
+            .list_by_user(user, _now())
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        res.sort();
+        res
+    }
+
+    #[test_case(EvictPolicy::Oldest, Some(vec!["s2", "s3", "s4"]); "oldest")]
+    #[test_case(EvictPolicy::LeastRecentlyUsed, Some(vec!["s1", "s3", "s4"]); "lru")]
+    #[test_case(EvictPolicy::Reject, None; "reject")]
+    fn test_db_insert_limited(policy: EvictPolicy, expected: Option<Vec<&str>>) {
+        let db = DB::new(0, 0);
+        let now = _now();
+        let at = now + 10000;
+        db.insert_limited("s1", _session_data_used("olia", at, 40), 3, policy, now)
+            .unwrap();
+        db.insert_limited("s2", _session_data_used("olia", at + 1, 10), 3, policy, now)
+            .unwrap();
+        db.insert_limited("s3", _session_data_used("olia", at + 2, 30), 3, policy, now)
+            .unwrap();
+        db.insert_limited("j1", _session_data_used("jonas", at, 10), 3, policy, now)
+            .unwrap();
+        let res = db.insert_limited("s4", _session_data_used("olia", at + 3, 50), 3, policy, now);
+        match expected {
+            Some(expected) => {
+                assert_eq!(res.unwrap(), 1);
+                assert_eq!(_user_sessions(&db, "olia"), expected);
+                assert_eq!(db.metrics().sessions, 4);
+            }
+            None => {
+                assert!(matches!(res, Err(model::store::Error::TooManySessions())));
+                assert_eq!(_user_sessions(&db, "olia"), vec!["s1", "s2", "s3"]);
+            }
+        }
+        assert_eq!(_user_sessions(&db, "jonas"), vec!["j1"]);
+    }
+
+    #[test]
+    fn test_db_insert_limited_lowered() {
+        let db = DB::new(0, 0);
+        let now = _now();
+        let at = now + 10000;
+        for (i, id) in ["s1", "s2", "s3"].iter().enumerate() {
+            db.insert_limited(
+                id,
+                _session_data_used("olia", at + i as i64, 0),
+                0,
+                EvictPolicy::Oldest,
+                now,
+            )
+            .unwrap();
+        }
+        let res = db.insert_limited(
+            "s4",
+            _session_data_used("olia", at + 5, 0),
+            1,
+            EvictPolicy::Oldest,
+            now,
+        );
+        assert_eq!(res.unwrap(), 3);
+        assert_eq!(_user_sessions(&db, "olia"), vec!["s4"]);
+        // an existing session is updated without eviction
+        let res = db.insert_limited(
+            "s4",
+            _session_data_used("olia", at + 5, 1),
+            1,
+            EvictPolicy::Reject,
+            now,
+        );
+        assert_eq!(res.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_store_add_limited_parallel() {
+        let store = Arc::new(InMemorySessionStore::new());
+        let at = Utc::now().timestamp_millis() + 10000;
+        let mut tasks = Vec::new();
+        for i in 0..20 {
+            let store = store.clone();
+            tasks.push(tokio::spawn(async move {
+                store
+                    .add_limited(
+                        &format!("s{i}"),
+                        _user_session_data("olia", at),
+                        5,
+                        EvictPolicy::Reject,
+                    )
+                    .await
+                    .is_ok()
+            }));
+        }
+        let mut ok = 0;
+        for task in tasks {
+            if task.await.unwrap() {
+                ok += 1;
+            }
+        }
+        assert_eq!(ok, 5);
+        assert_eq!(store.list_by_user("olia").await.unwrap().len(), 5);
+    }
+
+    fn _snapshot_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!(
+                "authware-{name}-{}.snapshot",
+                Utc::now().timestamp_nanos_opt().unwrap()
+            ))
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    fn _encryptor(key: &str) -> MagicEncryptor {
+        MagicEncryptor::new(key).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_snapshot() {
+        let path = _snapshot_path("snapshot");
+        let now = _now();
+        let store = InMemorySessionStore::with_limits(60_000, 0);
+        let mut idle = _user_session_data("olia", now + 10_000);
+        idle.last_access = now - 120_000;
+        let mut active = _user_session_data("olia", now + 10_000);
+        active.last_access = now;
+        store.db.insert("idle", idle, now - 1).unwrap();
+        store.db.insert("s1", active.clone(), now).unwrap();
+        store
+            .db
+            .insert("s2", _user_session_data("other", now + 1_000), now)
+            .unwrap();
+        let saved = store
+            .save_snapshot(&path, &_encryptor("0123456789abcdef"))
+            .unwrap();
+        assert_eq!(saved, 1);
+        let data = fs::read_to_string(&path).unwrap();
+        assert!(!data.contains("olia"));
+        assert!(!data.contains("s1"));
+
+        let restored = InMemorySessionStore::new();
+        let res = restored
+            .load_snapshot(&path, &_encryptor("0123456789abcdef"))
+            .unwrap();
+        assert_eq!(res, Some((1, 0)));
+        assert_eq!(restored.get("s1").await.unwrap(), active);
+        assert_eq!(restored.list_by_user("olia").await.unwrap().len(), 1);
+        // loaded once
+        assert!(!std::path::Path::new(&path).exists());
+        let res = restored
+            .load_snapshot(&path, &_encryptor("0123456789abcdef"))
+            .unwrap();
+        assert_eq!(res, None);
+    }
+
+    #[test]
+    fn test_snapshot_drops_expired() {
+        let path = _snapshot_path("expired");
+        let now = _now();
+        let store = InMemorySessionStore::new();
+        store
+            .db
+            .insert("s1", _session_data(now + 100_000), now)
+            .unwrap();
+        store
+            .db
+            .insert("s2", _session_data(now + 200), now)
+            .unwrap();
+        let saved = store
+            .save_snapshot(&path, &_encryptor("0123456789abcdef"))
+            .unwrap();
+        assert_eq!(saved, 2);
+        std::thread::sleep(Duration::from_millis(250));
+        let restored = InMemorySessionStore::new();
+        let res = restored
+            .load_snapshot(&path, &_encryptor("0123456789abcdef"))
+            .unwrap();
+        assert_eq!(res, Some((1, 1)));
+        assert_eq!(restored.metrics().sessions, 1);
+        assert!(restored.db.get("s1", _now()).is_some());
+    }
+
+    #[test_case(None, "other-key-123456"; "wrong key")]
+    #[test_case(Some("garbage"), "0123456789abcdef"; "garbage")]
+    #[test_case(Some(""), "0123456789abcdef"; "empty")]
+    #[test_case(Some("DgXh9i/pIea5iXvXZg15dw=="), "0123456789abcdef"; "not json")]
+    #[test_case(Some("truncate"), "0123456789abcdef"; "truncated")]
+    fn test_snapshot_rejected(content: Option<&str>, key: &str) {
+        let path = _snapshot_path("rejected");
+        let now = _now();
+        let store = InMemorySessionStore::new();
+        store
+            .db
+            .insert("s1", _session_data(now + 100_000), now)
+            .unwrap();
+        store
+            .save_snapshot(&path, &_encryptor("0123456789abcdef"))
+            .unwrap();
+        match content {
+            Some("truncate") => {
+                let data = fs::read_to_string(&path).unwrap();
+                fs::write(&path, &data[..data.len() / 2]).unwrap();
+            }
+            Some(content) => fs::write(&path, content).unwrap(),
+            None => {}
+        }
+        let restored = InMemorySessionStore::new();
+        assert!(restored.load_snapshot(&path, &_encryptor(key)).is_err());
+        assert_eq!(restored.metrics().sessions, 0);
+        // kept for a look
+        assert!(std::path::Path::new(&path).exists());
+        fs::remove_file(&path).unwrap();
     }
 }
@@ -1,3 +1,7 @@
+pub mod cache;
+pub mod coalescing;
+pub mod credential;
 pub mod encryptor;
 pub mod memory;
 pub mod redis;
+pub mod sqlite;
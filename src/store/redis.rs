@@ -1,47 +1,505 @@
 use async_trait::async_trait;
 use chrono::Utc;
-use deadpool_redis::redis::AsyncCommands;
-use deadpool_redis::{Connection, Pool};
+use deadpool_redis::redis::{self, aio::ConnectionLike, AsyncCommands, ErrorKind, RedisError};
+use deadpool_redis::{cluster, sentinel, Runtime};
+use futures::StreamExt;
 use std::cmp::max;
+use std::str::FromStr;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
 
-use crate::{model, Encryptor, SessionData, SessionStore};
+use crate::{
+    model, model::config::EvictPolicy, store::cache::SessionCache, Encryptor, InvalidationBus,
+    KeyValueStore, SessionData, SessionStore,
+};
+
+// per user sorted sets of session keys, scored by expiration and by last access.
+// Both share the `{user}` hash tag, so scripts over them stay in one cluster slot
+const USER_INDEX_PREFIX: &str = "user-sessions:";
+const USER_LRU_PREFIX: &str = "user-sessions-lru:";
+
+// KEYS: user index, user lru index
+// ARGV: session key, valid till, last access, now, max sessions (0 - unlimited), policy.
+// Returns the evicted session keys, nil if the limit is reached.
+// The indexes live as long as the longest session of the user
+const INDEX_SCRIPT: &str = r"
+for _, key in ipairs(redis.call('ZRANGEBYSCORE', KEYS[1], '-inf', ARGV[4])) do
+    redis.call('ZREM', KEYS[1], key)
+    redis.call('ZREM', KEYS[2], key)
+end
+local limit = tonumber(ARGV[5])
+local evicted = {}
+if limit > 0 and not redis.call('ZSCORE', KEYS[1], ARGV[1]) then
+    local count = redis.call('ZCARD', KEYS[1])
+    if count >= limit then
+        if ARGV[6] == 'reject' then
+            return false
+        end
+        local from = KEYS[1]
+        if ARGV[6] == 'lru' then
+            from = KEYS[2]
+        end
+        evicted = redis.call('ZRANGE', from, 0, count - limit)
+        for _, key in ipairs(evicted) do
+            redis.call('ZREM', KEYS[1], key)
+            redis.call('ZREM', KEYS[2], key)
+        end
+    end
+end
+redis.call('ZADD', KEYS[1], ARGV[2], ARGV[1])
+redis.call('ZADD', KEYS[2], ARGV[3], ARGV[1])
+local ttl = math.ceil((tonumber(ARGV[2]) - tonumber(ARGV[4])) / 1000)
+for i = 1, 2 do
+    if ttl > 0 and redis.call('TTL', KEYS[i]) < ttl then
+        redis.call('EXPIRE', KEYS[i], ttl)
+    end
+end
+return evicted
+";
+
+// KEYS: user index, user lru index; returns and drops the session keys of the user
+const DROP_INDEX_SCRIPT: &str = r"
+local keys = redis.call('ZRANGE', KEYS[1], 0, -1)
+redis.call('DEL', KEYS[1], KEYS[2])
+return keys
+";
+
+// A session is a hash: `data` - the encrypted session, `last_access` - the encrypted
+// access time, `touched` - the same time in clear for ordering (the lru index has it
+// anyway), `refreshed` - when the user was loaded, in clear for the refresh claims,
+// `index` and `lru` - the user index keys. The key expires at `valid_till`.
+//
+// KEYS: session key
+// ARGV: data, encrypted last access, last access, user index, user lru index, valid till,
+// refreshed.
+// Keeps a newer last access of a concurrent touch.
+// Returns the index keys of the previous owner if the session moved to another user
+const SESSION_SCRIPT: &str = r"
+local old = {}
+if redis.call('TYPE', KEYS[1]).ok == 'hash' then
+    old = redis.call('HMGET', KEYS[1], 'index', 'lru')
+    if old[1] == ARGV[4] then
+        old = {}
+    end
+else
+    redis.call('DEL', KEYS[1])
+end
+redis.call('HSET', KEYS[1], 'data', ARGV[1], 'index', ARGV[4], 'lru', ARGV[5], 'refreshed', ARGV[7])
+local touched = tonumber(redis.call('HGET', KEYS[1], 'touched') or '0')
+if tonumber(ARGV[3]) >= touched then
+    redis.call('HSET', KEYS[1], 'last_access', ARGV[2], 'touched', ARGV[3])
+end
+redis.call('PEXPIREAT', KEYS[1], ARGV[6])
+return old
+";
+
+// KEYS: session key
+// ARGV: encrypted last access, last access.
+// Updates only an existing session and never moves the access time back, the expiration
+// is untouched. Returns the user lru index key, nil if there is no session,
+// 0 for a session in the old string layout
+const TOUCH_SCRIPT: &str = r"
+local kind = redis.call('TYPE', KEYS[1]).ok
+if kind == 'none' then
+    return false
+end
+if kind ~= 'hash' then
+    return 0
+end
+local touched = tonumber(redis.call('HGET', KEYS[1], 'touched') or '0')
+if tonumber(ARGV[2]) > touched then
+    redis.call('HSET', KEYS[1], 'last_access', ARGV[1], 'touched', ARGV[2])
+end
+return redis.call('HGET', KEYS[1], 'lru')
+";
+
+// KEYS: key
+// ARGV: 1 if the key must exist, its expected value, 1 to set the value (else the key is
+// removed), the value, ttl in millis (0 - none).
+// Returns 1 if written, 0 if the key holds something else
+const COMPARE_AND_SET_SCRIPT: &str = r"
+local old = redis.call('GET', KEYS[1])
+if ARGV[1] == '1' then
+    if old ~= ARGV[2] then
+        return 0
+    end
+elseif old then
+    return 0
+end
+if ARGV[3] ~= '1' then
+    redis.call('DEL', KEYS[1])
+elseif tonumber(ARGV[5]) > 0 then
+    redis.call('SET', KEYS[1], ARGV[4], 'PX', ARGV[5])
+else
+    redis.call('SET', KEYS[1], ARGV[4])
+end
+return 1
+";
+
+// KEYS: session key
+// ARGV: expected refreshed, data, refreshed.
+// Replaces the blob of an existing session still refreshed at the expected time, a hash
+// without `refreshed` is taken as such. The access time and the expiration stay.
+// Returns 1 if written, 0 if refreshed by another request or in the old string layout
+// (rewritten by the next touch), nil if there is no session
+const UPDATE_USER_SCRIPT: &str = r"
+local kind = redis.call('TYPE', KEYS[1]).ok
+if kind == 'none' then
+    return false
+end
+if kind ~= 'hash' then
+    return 0
+end
+local refreshed = redis.call('HGET', KEYS[1], 'refreshed')
+if refreshed and refreshed ~= ARGV[1] then
+    return 0
+end
+redis.call('HSET', KEYS[1], 'data', ARGV[2], 'refreshed', ARGV[3])
+return 1
+";
+
+// KEYS: session key; returns the user index keys, nil if there is no session
+const REMOVE_SCRIPT: &str = r"
+local kind = redis.call('TYPE', KEYS[1]).ok
+if kind == 'none' then
+    return false
+end
+local res = {}
+if kind == 'hash' then
+    res = redis.call('HMGET', KEYS[1], 'index', 'lru')
+end
+redis.call('DEL', KEYS[1])
+return res
+";
+
+const INVALIDATION_CHANNEL: &str = "authware:session-invalidations";
+const RESUBSCRIBE_BACKOFF: Duration = Duration::from_secs(1);
+// every subscriber publishes a heartbeat, a subscription that gets no message for
+// the timeout is taken as half-open and renewed
+const HEARTBEAT_MESSAGE: &str = "heartbeat";
+const HEARTBEAT_EVERY: Duration = Duration::from_secs(1);
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(3);
+
+// attempts to get a connection, e.g. while sentinels promote a new master
+const CONNECT_ATTEMPTS: u32 = 3;
+const CONNECT_BACKOFF: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RedisMode {
+    Standalone,
+    Sentinel,
+    Cluster,
+}
+
+impl FromStr for RedisMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "standalone" => Ok(RedisMode::Standalone),
+            "sentinel" => Ok(RedisMode::Sentinel),
+            "cluster" => Ok(RedisMode::Cluster),
+            _ => Err(anyhow::anyhow!(
+                "wrong redis mode '{s}', expected standalone, sentinel or cluster"
+            )),
+        }
+    }
+}
+
+// Connection pool to a single redis, a sentinel managed master or a cluster
+#[derive(Clone)]
+pub enum RedisPool {
+    Standalone(deadpool_redis::Pool),
+    Sentinel(sentinel::Pool),
+    Cluster(cluster::Pool),
+}
+
+impl RedisPool {
+    // urls are comma separated: sentinels for the sentinel mode, seed nodes for the cluster
+    pub fn new(mode: RedisMode, urls: &str, master: &str) -> anyhow::Result<Self> {
+        let list = split_urls(urls)?;
+        tracing::debug!(mode = ?mode, nodes = list.len(), "init redis pool");
+        Ok(match mode {
+            RedisMode::Standalone => {
+                if list.len() > 1 {
+                    return Err(anyhow::anyhow!("Many redis urls for the standalone mode"));
+                }
+                RedisPool::Standalone(
+                    deadpool_redis::Config::from_url(list[0].clone())
+                        .create_pool(Some(Runtime::Tokio1))?,
+                )
+            }
+            RedisMode::Sentinel => RedisPool::Sentinel(
+                sentinel::Config::from_urls(
+                    list,
+                    master.to_string(),
+                    sentinel::SentinelServerType::Master,
+                )
+                .create_pool(Some(Runtime::Tokio1))?,
+            ),
+            RedisMode::Cluster => RedisPool::Cluster(
+                cluster::Config::from_urls(list).create_pool(Some(Runtime::Tokio1))?,
+            ),
+        })
+    }
+
+    pub async fn get(&self) -> Result<RedisConnection, model::store::Error> {
+        let mut attempt = 1;
+        loop {
+            let res = match self {
+                RedisPool::Standalone(pool) => pool
+                    .get()
+                    .await
+                    .map(Inner::Standalone)
+                    .map_err(|e| anyhow::anyhow!("{e:?}")),
+                RedisPool::Sentinel(pool) => pool
+                    .get()
+                    .await
+                    .map(Inner::Sentinel)
+                    .map_err(|e| anyhow::anyhow!("{e:?}")),
+                RedisPool::Cluster(pool) => pool
+                    .get()
+                    .await
+                    .map(Inner::Cluster)
+                    .map_err(|e| anyhow::anyhow!("{e:?}")),
+            };
+            match res {
+                Ok(conn) => {
+                    return Ok(RedisConnection {
+                        conn: Some(conn),
+                        broken: false,
+                    })
+                }
+                Err(err) if attempt < CONNECT_ATTEMPTS => {
+                    tracing::warn!(attempt, error = %err, "redis connection failed, retrying");
+                    tokio::time::sleep(CONNECT_BACKOFF * attempt).await;
+                    attempt += 1;
+                }
+                Err(err) => return Err(anyhow::anyhow!("Connection error: {err}").into()),
+            }
+        }
+    }
+}
+
+fn split_urls(urls: &str) -> anyhow::Result<Vec<String>> {
+    let res: Vec<String> = urls
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(String::from)
+        .collect();
+    if res.is_empty() {
+        return Err(anyhow::anyhow!("Empty redis url"));
+    }
+    Ok(res)
+}
+
+enum Inner {
+    Standalone(deadpool_redis::Connection),
+    Sentinel(sentinel::Connection),
+    Cluster(cluster::Connection),
+}
+
+// Pooled connection. It is dropped instead of going back to the pool after an error
+// that needs a reconnect, e.g. a master demoted by sentinels answers READONLY
+pub struct RedisConnection {
+    conn: Option<Inner>,
+    broken: bool,
+}
+
+fn needs_reconnect(err: &RedisError) -> bool {
+    err.is_unrecoverable_error() || err.kind() == ErrorKind::ReadOnly
+}
+
+impl ConnectionLike for RedisConnection {
+    fn req_packed_command<'a>(
+        &'a mut self,
+        cmd: &'a redis::Cmd,
+    ) -> redis::RedisFuture<'a, redis::Value> {
+        let broken = &mut self.broken;
+        let fut = match self.conn.as_mut().expect("connection") {
+            Inner::Standalone(c) => c.req_packed_command(cmd),
+            Inner::Sentinel(c) => c.req_packed_command(cmd),
+            Inner::Cluster(c) => c.req_packed_command(cmd),
+        };
+        Box::pin(async move {
+            let res = fut.await;
+            if let Err(err) = &res {
+                *broken |= needs_reconnect(err);
+            }
+            res
+        })
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a redis::Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> redis::RedisFuture<'a, Vec<redis::Value>> {
+        let broken = &mut self.broken;
+        let fut = match self.conn.as_mut().expect("connection") {
+            Inner::Standalone(c) => c.req_packed_commands(cmd, offset, count),
+            Inner::Sentinel(c) => c.req_packed_commands(cmd, offset, count),
+            Inner::Cluster(c) => c.req_packed_commands(cmd, offset, count),
+        };
+        Box::pin(async move {
+            let res = fut.await;
+            if let Err(err) = &res {
+                *broken |= needs_reconnect(err);
+            }
+            res
+        })
+    }
+
+    fn get_db(&self) -> i64 {
+        match self.conn.as_ref().expect("connection") {
+            Inner::Standalone(c) => c.get_db(),
+            Inner::Sentinel(c) => c.get_db(),
+            Inner::Cluster(c) => c.get_db(),
+        }
+    }
+}
+
+impl Drop for RedisConnection {
+    fn drop(&mut self) {
+        if !self.broken {
+            return;
+        }
+        tracing::warn!("dropping broken redis connection");
+        match self.conn.take() {
+            Some(Inner::Standalone(c)) => drop(deadpool_redis::Connection::take(c)),
+            Some(Inner::Sentinel(c)) => drop(sentinel::Connection::take(c)),
+            Some(Inner::Cluster(c)) => drop(cluster::Connection::take(c)),
+            None => {}
+        }
+    }
+}
 
 pub struct RedisSessionStore {
-    pool: Pool,
+    pool: RedisPool,
     encryptor: Box<dyn Encryptor + Send + Sync>,
 }
 
 impl RedisSessionStore {
-    pub fn new(pool: Pool, encryptor: Box<dyn Encryptor + Send + Sync>) -> Self {
+    pub fn new(pool: RedisPool, encryptor: Box<dyn Encryptor + Send + Sync>) -> Self {
         RedisSessionStore { pool, encryptor }
     }
-    async fn get_conn(&self) -> Result<Connection, model::store::Error> {
-        self.pool
-            .get()
-            .await
-            .map_err(|e| anyhow::anyhow!("Connection error: {:?}", e).into())
+
+    async fn get_conn(&self) -> Result<RedisConnection, model::store::Error> {
+        self.pool.get().await
     }
 
+    // Session keys may live in any slot, so each one is written by its own command
     async fn add_int(
         &self,
-        conn: &mut Connection,
+        conn: &mut RedisConnection,
         session_id: &str,
         data: SessionData,
-    ) -> Result<(), model::store::Error> {
+        max_sessions: usize,
+        policy: EvictPolicy,
+    ) -> Result<usize, model::store::Error> {
         let serialized_data = serde_json::to_string(&data)
             .map_err(|e| anyhow::anyhow!("Serialization error: {:?}", e))?;
         let now = Utc::now();
-        let secs = (max(data.valid_till - now.timestamp_millis(), 0) / 1000) as u64;
-        tracing::debug!("Session valid for: {} secs", secs);
-        let _: () = conn
-            .set_ex(
-                self.get_enc_str(session_id),
-                self.get_enc_str(&serialized_data),
-                secs,
-            )
+        tracing::debug!(
+            "Session valid for: {} ms",
+            data.valid_till - now.timestamp_millis()
+        );
+        let policy = match policy {
+            EvictPolicy::Reject => "reject",
+            EvictPolicy::Oldest => "oldest",
+            EvictPolicy::LeastRecentlyUsed => "lru",
+        };
+        let key = self.get_enc_str(session_id);
+        let (index, lru) = self.user_keys(&data.user.id);
+        let evicted: Option<Vec<String>> = redis::cmd("EVAL")
+            .arg(INDEX_SCRIPT)
+            .arg(2)
+            .arg(&index)
+            .arg(&lru)
+            .arg(&key)
+            .arg(data.valid_till)
+            .arg(data.last_access)
+            .arg(now.timestamp_millis())
+            .arg(max_sessions)
+            .arg(policy)
+            .query_async(conn)
             .await
-            .map_err(|e| anyhow::anyhow!("Redis set error: {:?}", e))?;
-        Ok(())
+            .map_err(|e| anyhow::anyhow!("Redis index error: {:?}", e))?;
+        let evicted = evicted.ok_or(model::store::Error::TooManySessions())?;
+        let res: Result<Vec<Option<String>>, _> = redis::cmd("EVAL")
+            .arg(SESSION_SCRIPT)
+            .arg(1)
+            .arg(&key)
+            .arg(self.get_enc_str(&serialized_data))
+            .arg(self.get_enc_str(&data.last_access.to_string()))
+            .arg(data.last_access)
+            .arg(&index)
+            .arg(&lru)
+            .arg(data.valid_till)
+            .arg(data.refreshed)
+            .query_async(conn)
+            .await;
+        let old = match res {
+            Ok(old) => old,
+            Err(e) => {
+                let _: Result<(), _> = redis::pipe()
+                    .zrem(&index, &key)
+                    .zrem(&lru, &key)
+                    .query_async(conn)
+                    .await;
+                return Err(anyhow::anyhow!("Redis set error: {:?}", e).into());
+            }
+        };
+        if let [Some(old_index), Some(old_lru)] = old.as_slice() {
+            self.unindex(conn, &key, old_index, old_lru).await;
+        }
+        for victim in &evicted {
+            let res: Result<usize, _> = conn.del(victim).await;
+            if let Err(e) = res {
+                tracing::warn!(error = ?e, "can't delete evicted session");
+            }
+        }
+        Ok(evicted.len())
+    }
+
+    // stale index entries are also dropped by the next add of the user
+    async fn unindex(&self, conn: &mut RedisConnection, key: &str, index: &str, lru: &str) {
+        let res: Result<(usize, usize), _> = redis::pipe()
+            .zrem(index, key)
+            .zrem(lru, key)
+            .query_async(conn)
+            .await;
+        if let Err(e) = res {
+            tracing::warn!(error = ?e, "can't drop session from the user index");
+        }
+    }
+
+    fn user_keys(&self, user_id: &str) -> (String, String) {
+        let tag = self.get_enc_str(user_id);
+        (
+            format!("{USER_INDEX_PREFIX}{{{tag}}}"),
+            format!("{USER_LRU_PREFIX}{{{tag}}}"),
+        )
+    }
+
+    // the separately stored last access wins over the one in the session blob
+    fn parse(
+        &self,
+        serialized_data: &str,
+        last_access: Option<&str>,
+    ) -> Result<SessionData, model::store::Error> {
+        let mut session_data: SessionData =
+            serde_json::from_str(self.get_dec_str(serialized_data)?.as_str())
+                .map_err(|e| anyhow::anyhow!("Deserialization error: {:?}", e))?;
+        if let Some(last_access) = last_access {
+            session_data.last_access = self
+                .get_dec_str(last_access)?
+                .parse()
+                .map_err(|e| anyhow::anyhow!("Deserialization error: {:?}", e))?;
+        }
+        Ok(session_data)
     }
 
     fn get_enc_str(&self, data: &str) -> String {
@@ -52,25 +510,37 @@ impl RedisSessionStore {
         self.encryptor.decrypt(data)
     }
 
+    // reads a session by its encrypted key, sessions written as plain strings
+    // by older versions are still understood
+    async fn read(
+        &self,
+        conn: &mut RedisConnection,
+        key: &str,
+    ) -> Result<Option<SessionData>, model::store::Error> {
+        let res: Result<(Option<String>, Option<String>), RedisError> =
+            conn.hget(key, &["data", "last_access"]).await;
+        match res {
+            Ok((Some(data), last_access)) => Ok(Some(self.parse(&data, last_access.as_deref())?)),
+            Ok((None, _)) => Ok(None),
+            Err(e) if e.code() == Some("WRONGTYPE") => {
+                let data: Option<String> = conn
+                    .get(key)
+                    .await
+                    .map_err(|e| anyhow::anyhow!("Redis get error: {:?}", e))?;
+                data.map(|data| self.parse(&data, None)).transpose()
+            }
+            Err(e) => Err(anyhow::anyhow!("Redis get error: {:?}", e).into()),
+        }
+    }
+
     async fn get_int(
         &self,
-        conn: &mut Connection,
+        conn: &mut RedisConnection,
         session_id: &str,
     ) -> Result<SessionData, model::store::Error> {
-        let data: Option<String> = conn
-            .get(self.get_enc_str(session_id))
-            .await
-            .map_err(|e| anyhow::anyhow!("Redis get error: {:?}", e))?;
-
-        match data {
-            Some(serialized_data) => {
-                let session_data: SessionData =
-                    serde_json::from_str(self.get_dec_str(&serialized_data)?.as_str())
-                        .map_err(|e| anyhow::anyhow!("Deserialization error: {:?}", e))?;
-                Ok(session_data)
-            }
-            None => Err(model::store::Error::NoSession()),
-        }
+        self.read(conn, &self.get_enc_str(session_id))
+            .await?
+            .ok_or(model::store::Error::NoSession())
     }
 }
 
@@ -79,7 +549,22 @@ impl SessionStore for RedisSessionStore {
     async fn add(&self, session_id: &str, data: SessionData) -> Result<(), model::store::Error> {
         tracing::trace!("Adding session: {}", session_id);
         let mut conn = self.get_conn().await?;
-        self.add_int(&mut conn, session_id, data).await
+        self.add_int(&mut conn, session_id, data, 0, EvictPolicy::Reject)
+            .await?;
+        Ok(())
+    }
+
+    async fn add_limited(
+        &self,
+        session_id: &str,
+        data: SessionData,
+        max_sessions: usize,
+        policy: EvictPolicy,
+    ) -> Result<usize, model::store::Error> {
+        tracing::trace!("Adding session: {}", session_id);
+        let mut conn = self.get_conn().await?;
+        self.add_int(&mut conn, session_id, data, max_sessions, policy)
+            .await
     }
 
     async fn get(&self, session_id: &str) -> Result<SessionData, model::store::Error> {
@@ -89,21 +574,424 @@ impl SessionStore for RedisSessionStore {
 
     async fn remove(&self, session_id: &str) -> Result<(), model::store::Error> {
         let mut conn = self.get_conn().await?;
-        let result: usize = conn
-            .del(self.get_enc_str(session_id))
+        let key = self.get_enc_str(session_id);
+        let res: Option<Vec<Option<String>>> = redis::cmd("EVAL")
+            .arg(REMOVE_SCRIPT)
+            .arg(1)
+            .arg(&key)
+            .query_async(&mut conn)
             .await
             .map_err(|e| anyhow::anyhow!("Redis delete error: {:?}", e))?;
-        if result == 0 {
-            Err(model::store::Error::NoSession())
-        } else {
-            Ok(())
+        let res = res.ok_or(model::store::Error::NoSession())?;
+        if let [Some(index), Some(lru)] = res.as_slice() {
+            self.unindex(&mut conn, &key, index, lru).await;
         }
+        Ok(())
     }
 
+    // A single atomic field update, the session blob and its expiration stay as they are
     async fn mark_last_used(&self, session_id: &str, now: i64) -> Result<(), model::store::Error> {
         let mut conn = self.get_conn().await?;
-        let mut session_data = self.get_int(&mut conn, session_id).await?;
-        session_data.last_access = now;
-        self.add_int(&mut conn, session_id, session_data).await
+        let key = self.get_enc_str(session_id);
+        let res: redis::Value = redis::cmd("EVAL")
+            .arg(TOUCH_SCRIPT)
+            .arg(1)
+            .arg(&key)
+            .arg(self.get_enc_str(&now.to_string()))
+            .arg(now)
+            .query_async(&mut conn)
+            .await
+            .map_err(|e| anyhow::anyhow!("Redis touch error: {:?}", e))?;
+        let lru: String = match res {
+            redis::Value::Nil => return Err(model::store::Error::NoSession()),
+            // rewritten in the hash layout
+            redis::Value::Int(_) => {
+                let mut session_data = self.get_int(&mut conn, session_id).await?;
+                session_data.last_access = now;
+                self.add_int(&mut conn, session_id, session_data, 0, EvictPolicy::Reject)
+                    .await?;
+                return Ok(());
+            }
+            other => redis::from_redis_value(&other)
+                .map_err(|e| anyhow::anyhow!("Redis touch error: {:?}", e))?,
+        };
+        let _: usize = redis::cmd("ZADD")
+            .arg(&lru)
+            .arg("XX")
+            .arg("GT")
+            .arg(now)
+            .arg(&key)
+            .query_async(&mut conn)
+            .await
+            .map_err(|e| anyhow::anyhow!("Redis touch error: {:?}", e))?;
+        Ok(())
+    }
+
+    async fn update_user(
+        &self,
+        session_id: &str,
+        data: SessionData,
+        current: i64,
+    ) -> Result<bool, model::store::Error> {
+        let serialized_data = serde_json::to_string(&data)
+            .map_err(|e| anyhow::anyhow!("Serialization error: {:?}", e))?;
+        let mut conn = self.get_conn().await?;
+        let res: Option<usize> = redis::cmd("EVAL")
+            .arg(UPDATE_USER_SCRIPT)
+            .arg(1)
+            .arg(self.get_enc_str(session_id))
+            .arg(current)
+            .arg(self.get_enc_str(&serialized_data))
+            .arg(data.refreshed)
+            .query_async(&mut conn)
+            .await
+            .map_err(|e| anyhow::anyhow!("Redis update error: {:?}", e))?;
+        Ok(res.ok_or(model::store::Error::NoSession())? == 1)
+    }
+
+    async fn list_by_user(
+        &self,
+        user_id: &str,
+    ) -> Result<Vec<(String, SessionData)>, model::store::Error> {
+        let mut conn = self.get_conn().await?;
+        let (index, _) = self.user_keys(user_id);
+        let keys: Vec<String> = conn
+            .zrangebyscore(&index, Utc::now().timestamp_millis(), "+inf")
+            .await
+            .map_err(|e| anyhow::anyhow!("Redis zrange error: {:?}", e))?;
+        let mut res = Vec::new();
+        for key in keys {
+            // stale index entries are dropped by the next add
+            if let Some(data) = self.read(&mut conn, &key).await? {
+                res.push((self.get_dec_str(&key)?, data));
+            }
+        }
+        Ok(res)
+    }
+
+    async fn remove_all_for_user(&self, user_id: &str) -> Result<usize, model::store::Error> {
+        let mut conn = self.get_conn().await?;
+        let (index, lru) = self.user_keys(user_id);
+        let keys: Vec<String> = redis::cmd("EVAL")
+            .arg(DROP_INDEX_SCRIPT)
+            .arg(2)
+            .arg(&index)
+            .arg(&lru)
+            .query_async(&mut conn)
+            .await
+            .map_err(|e| anyhow::anyhow!("Redis delete error: {:?}", e))?;
+        let mut res = 0;
+        for key in keys {
+            let n: usize = conn
+                .del(&key)
+                .await
+                .map_err(|e| anyhow::anyhow!("Redis delete error: {:?}", e))?;
+            res += n;
+        }
+        Ok(res)
+    }
+}
+
+// Session cache invalidations of all instances go through redis pub/sub. Published
+// messages reach the subscribers of every cluster node and of the sentinel replicas
+pub struct RedisInvalidationBus {
+    pool: RedisPool,
+    mode: RedisMode,
+    urls: Vec<String>,
+    master: String,
+}
+
+impl RedisInvalidationBus {
+    pub fn new(pool: RedisPool, mode: RedisMode, urls: &str, master: &str) -> anyhow::Result<Self> {
+        Ok(RedisInvalidationBus {
+            pool,
+            mode,
+            urls: split_urls(urls)?,
+            master: master.to_string(),
+        })
+    }
+
+    // Feeds the invalidations to the cache until it is dropped. The cache serves
+    // sessions only while subscribed and heartbeats arrive, it is emptied on every reconnect
+    pub fn start_subscriber(&self, cache: Arc<SessionCache>) -> tokio::task::JoinHandle<()> {
+        let cache = Arc::downgrade(&cache);
+        let (mode, urls, master) = (self.mode, self.urls.clone(), self.master.clone());
+        let pool = self.pool.clone();
+        tokio::spawn(async move {
+            while cache.strong_count() > 0 {
+                match subscribe(mode, &urls, &master).await {
+                    Ok(mut pubsub) => {
+                        tracing::info!("subscribed to session invalidations");
+                        if !listen(&mut pubsub, &cache, &pool).await {
+                            break;
+                        }
+                    }
+                    Err(err) => {
+                        tracing::warn!(error = %err, "can't subscribe to session invalidations")
+                    }
+                }
+                tokio::time::sleep(RESUBSCRIBE_BACKOFF).await;
+            }
+        })
+    }
+}
+
+// a sentinel managed master or the first node answering
+async fn subscribe(
+    mode: RedisMode,
+    urls: &[String],
+    master: &str,
+) -> redis::RedisResult<redis::aio::PubSub> {
+    let clients = match mode {
+        RedisMode::Sentinel => vec![
+            redis::sentinel::Sentinel::build(urls.to_vec())?
+                .async_master_for(master, None)
+                .await,
+        ],
+        _ => urls
+            .iter()
+            .map(|url| redis::Client::open(url.as_str()))
+            .collect(),
+    };
+    let mut res = Err(RedisError::from((ErrorKind::IoError, "no redis url")));
+    for client in clients {
+        res = async {
+            let mut pubsub = client?.get_async_pubsub().await?;
+            pubsub.subscribe(INVALIDATION_CHANNEL).await?;
+            Ok(pubsub)
+        }
+        .await;
+        if res.is_ok() {
+            break;
+        }
+    }
+    res
+}
+
+// Returns false if the cache is gone
+async fn listen(
+    pubsub: &mut redis::aio::PubSub,
+    cache: &Weak<SessionCache>,
+    pool: &RedisPool,
+) -> bool {
+    match cache.upgrade() {
+        Some(cache) => cache.set_active(true),
+        None => return false,
+    }
+    let mut messages = pubsub.on_message();
+    let mut heartbeat = tokio::time::interval(HEARTBEAT_EVERY);
+    let mut last_seen = Instant::now();
+    loop {
+        tokio::select! {
+            msg = messages.next() => {
+                let Some(msg) = msg else {
+                    tracing::warn!("session invalidations subscription lost");
+                    break;
+                };
+                last_seen = Instant::now();
+                let Some(cache) = cache.upgrade() else {
+                    return false;
+                };
+                match msg.get_payload::<String>() {
+                    Ok(message) if message == HEARTBEAT_MESSAGE => {}
+                    Ok(message) => cache.handle(&message),
+                    Err(err) => tracing::warn!(error = %err, "wrong session invalidation"),
+                }
+            }
+            _ = heartbeat.tick() => {
+                if last_seen.elapsed() > HEARTBEAT_TIMEOUT {
+                    tracing::warn!("no session invalidation heartbeat, resubscribing");
+                    break;
+                }
+                if cache.strong_count() == 0 {
+                    return false;
+                }
+                // the next tick checks the outcome
+                let res = tokio::time::timeout(HEARTBEAT_EVERY, publish(pool, HEARTBEAT_MESSAGE));
+                if let Ok(Err(err)) = res.await {
+                    tracing::debug!(error = %err, "can't publish heartbeat");
+                }
+            }
+        }
+    }
+    match cache.upgrade() {
+        Some(cache) => {
+            cache.set_active(false);
+            true
+        }
+        None => false,
+    }
+}
+
+#[async_trait]
+impl InvalidationBus for RedisInvalidationBus {
+    async fn publish(&self, message: &str) -> Result<(), model::store::Error> {
+        publish(&self.pool, message).await
+    }
+}
+
+async fn publish(pool: &RedisPool, message: &str) -> Result<(), model::store::Error> {
+    let mut conn = pool.get().await?;
+    let _: usize = conn
+        .publish(INVALIDATION_CHANNEL, message)
+        .await
+        .map_err(|e| anyhow::anyhow!("Redis publish error: {:?}", e))?;
+    Ok(())
+}
+
+pub struct RedisKeyValueStore {
+    pool: RedisPool,
+    prefix: String,
+}
+
+impl RedisKeyValueStore {
+    pub fn new(pool: RedisPool, prefix: &str) -> Self {
+        RedisKeyValueStore {
+            pool,
+            prefix: prefix.to_string(),
+        }
+    }
+
+    async fn get_conn(&self) -> Result<RedisConnection, model::store::Error> {
+        self.pool.get().await
+    }
+
+    fn make_key(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+}
+
+#[async_trait]
+impl KeyValueStore for RedisKeyValueStore {
+    async fn get(&self, key: &str) -> Result<Option<String>, model::store::Error> {
+        let mut conn = self.get_conn().await?;
+        let res: Option<String> = conn
+            .get(self.make_key(key))
+            .await
+            .map_err(|e| anyhow::anyhow!("Redis get error: {:?}", e))?;
+        Ok(res)
+    }
+
+    async fn set(
+        &self,
+        key: &str,
+        value: &str,
+        ttl: Option<Duration>,
+    ) -> Result<(), model::store::Error> {
+        let mut conn = self.get_conn().await?;
+        let res: Result<(), _> = match ttl {
+            Some(ttl) => {
+                conn.pset_ex(self.make_key(key), value, max(ttl.as_millis() as u64, 1))
+                    .await
+            }
+            None => conn.set(self.make_key(key), value).await,
+        };
+        res.map_err(|e| anyhow::anyhow!("Redis set error: {:?}", e))?;
+        Ok(())
+    }
+
+    async fn remove(&self, key: &str) -> Result<(), model::store::Error> {
+        let mut conn = self.get_conn().await?;
+        let _: usize = conn
+            .del(self.make_key(key))
+            .await
+            .map_err(|e| anyhow::anyhow!("Redis delete error: {:?}", e))?;
+        Ok(())
+    }
+
+    async fn compare_and_set(
+        &self,
+        key: &str,
+        current: Option<&str>,
+        value: Option<&str>,
+        ttl: Option<Duration>,
+    ) -> Result<bool, model::store::Error> {
+        let mut conn = self.get_conn().await?;
+        let res: usize = redis::cmd("EVAL")
+            .arg(COMPARE_AND_SET_SCRIPT)
+            .arg(1)
+            .arg(self.make_key(key))
+            .arg(current.is_some() as u8)
+            .arg(current.unwrap_or(""))
+            .arg(value.is_some() as u8)
+            .arg(value.unwrap_or(""))
+            .arg(ttl.map_or(0, |ttl| max(ttl.as_millis() as u64, 1)))
+            .query_async(&mut conn)
+            .await
+            .map_err(|e| anyhow::anyhow!("Redis compare and set error: {:?}", e))?;
+        Ok(res == 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::encryptor::MagicEncryptor;
+    use redis::cluster_routing::get_slot;
+    use test_case::test_case;
+
+    #[test_case("standalone", Some(RedisMode::Standalone); "standalone")]
+    #[test_case("sentinel", Some(RedisMode::Sentinel); "sentinel")]
+    #[test_case("cluster", Some(RedisMode::Cluster); "cluster")]
+    #[test_case("other", None; "wrong")]
+    fn test_parse_mode(input: &str, expected: Option<RedisMode>) {
+        assert_eq!(input.parse::<RedisMode>().ok(), expected);
+    }
+
+    #[test_case(RedisMode::Standalone, "redis://a:6379", true; "standalone")]
+    #[test_case(RedisMode::Standalone, "redis://a:6379,redis://b:6379", false; "standalone many")]
+    #[test_case(RedisMode::Sentinel, "redis://a:26379, redis://b:26379", true; "sentinel")]
+    #[test_case(RedisMode::Cluster, "redis://a:7000,redis://b:7001", true; "cluster")]
+    #[test_case(RedisMode::Cluster, " , ", false; "empty")]
+    #[tokio::test]
+    async fn test_pool_new(mode: RedisMode, urls: &str, ok: bool) {
+        assert_eq!(RedisPool::new(mode, urls, "mymaster").is_ok(), ok);
+    }
+
+    #[tokio::test]
+    async fn test_user_keys_share_slot() {
+        let store = RedisSessionStore::new(
+            RedisPool::new(RedisMode::Standalone, "redis://localhost:6379", "").unwrap(),
+            Box::new(MagicEncryptor::new("0123456789abcdef").unwrap()),
+        );
+        for user in ["olia", "jonas", "a{b}c", "}{"] {
+            let (index, lru) = store.user_keys(user);
+            assert_ne!(index, lru);
+            assert_eq!(
+                get_slot(index.as_bytes()),
+                get_slot(lru.as_bytes()),
+                "{user}"
+            );
+            assert!(!index.contains(user));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_parse_last_access() {
+        let store = RedisSessionStore::new(
+            RedisPool::new(RedisMode::Standalone, "redis://localhost:6379", "").unwrap(),
+            Box::new(MagicEncryptor::new("0123456789abcdef").unwrap()),
+        );
+        let data = SessionData {
+            user: crate::model::auth::User {
+                id: "olia".to_string(),
+                name: "olia".to_string(),
+                department: String::new(),
+                roles: vec!["USER".to_string()],
+                backend: String::new(),
+            },
+            ip: "1.1.1.1".to_string(),
+            valid_till: 3000,
+            last_access: 1000,
+            refreshed: 1000,
+        };
+        let blob = store.get_enc_str(&serde_json::to_string(&data).unwrap());
+        assert_eq!(store.parse(&blob, None).unwrap().last_access, 1000);
+        let touched = store.get_enc_str("2000");
+        let res = store.parse(&blob, Some(&touched)).unwrap();
+        assert_eq!(res.last_access, 2000);
+        assert_eq!(res.valid_till, 3000);
+        assert!(store.parse(&blob, Some(&store.get_enc_str("x"))).is_err());
+        assert!(store.parse("olia", None).is_err());
     }
 }
@@ -0,0 +1,727 @@
+use std::{
+    sync::{Arc, Mutex, Weak},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use chrono::Utc;
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+
+use crate::{
+    model, model::config::EvictPolicy, Encryptor, KeyValueStore, SessionData, SessionStore,
+};
+
+// ids, user ids and data are encrypted, times are kept open for the expiry and lru indexes
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS sessions (
+    id TEXT PRIMARY KEY NOT NULL,
+    user_key TEXT NOT NULL,
+    data TEXT NOT NULL,
+    valid_till INTEGER NOT NULL,
+    last_access INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS sessions_valid_till ON sessions(valid_till);
+CREATE INDEX IF NOT EXISTS sessions_user_key ON sessions(user_key);
+CREATE TABLE IF NOT EXISTS kv (
+    key TEXT PRIMARY KEY NOT NULL,
+    value TEXT NOT NULL,
+    valid_till INTEGER
+);
+";
+
+// Sessions in an embedded sqlite db, they survive restarts of a single node deployment
+pub struct SqliteSessionStore {
+    db: Arc<Mutex<Connection>>,
+    encryptor: Arc<dyn Encryptor + Send + Sync>,
+}
+
+impl SqliteSessionStore {
+    pub fn new(path: &str, encryptor: Box<dyn Encryptor + Send + Sync>) -> anyhow::Result<Self> {
+        tracing::debug!(path, "init sqlite session store");
+        if path.is_empty() {
+            return Err(anyhow::anyhow!("Empty sqlite path"));
+        }
+        let conn = Connection::open(path).map_err(|e| anyhow::anyhow!("can't open {path}: {e}"))?;
+        conn.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+        conn.pragma_update(None, "synchronous", "NORMAL")?;
+        conn.busy_timeout(Duration::from_secs(5))?;
+        conn.execute_batch(SCHEMA)
+            .map_err(|e| anyhow::anyhow!("can't init schema: {e}"))?;
+        Ok(SqliteSessionStore {
+            db: Arc::new(Mutex::new(conn)),
+            encryptor: encryptor.into(),
+        })
+    }
+
+    // Deletes expired sessions periodically, stops when the store is dropped
+    pub fn start_sweeper(&self, every: Duration) -> tokio::task::JoinHandle<()> {
+        let db = Arc::downgrade(&self.db);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(every);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                match sweep(db.clone()).await {
+                    Ok(Some(removed)) => {
+                        tracing::debug!(removed, "swept expired sessions");
+                    }
+                    Ok(None) => break,
+                    Err(err) => tracing::warn!(error = %err, "session sweep failed"),
+                }
+            }
+        })
+    }
+
+    // Key value store in the same db, swept by the same sweeper
+    pub fn key_value_store(&self) -> SqliteKeyValueStore {
+        SqliteKeyValueStore {
+            db: self.db.clone(),
+        }
+    }
+
+    async fn call<F, R>(&self, f: F) -> Result<R, model::store::Error>
+    where
+        F: FnOnce(&mut Connection, &Codec) -> Result<R, model::store::Error> + Send + 'static,
+        R: Send + 'static,
+    {
+        let db = self.db.clone();
+        let codec = Codec {
+            encryptor: self.encryptor.clone(),
+        };
+        tokio::task::spawn_blocking(move || {
+            let mut conn = db
+                .lock()
+                .map_err(|e| anyhow::anyhow!("db lock poisoned: {e}"))?;
+            f(&mut conn, &codec)
+        })
+        .await
+        .map_err(|e| anyhow::anyhow!("db task: {e}"))?
+    }
+}
+
+struct Codec {
+    encryptor: Arc<dyn Encryptor + Send + Sync>,
+}
+
+impl Codec {
+    fn key(&self, session_id: &str) -> String {
+        self.encryptor.encrypt(session_id)
+    }
+
+    fn user_key(&self, user_id: &str) -> String {
+        self.encryptor.encrypt(user_id)
+    }
+
+    fn encode(&self, data: &SessionData) -> Result<String, model::store::Error> {
+        let res = serde_json::to_string(data)
+            .map_err(|e| anyhow::anyhow!("Serialization error: {:?}", e))?;
+        Ok(self.encryptor.encrypt(&res))
+    }
+
+    fn decode(&self, data: &str) -> Result<SessionData, model::store::Error> {
+        let res = serde_json::from_str(&self.encryptor.decrypt(data)?)
+            .map_err(|e| anyhow::anyhow!("Deserialization error: {:?}", e))?;
+        Ok(res)
+    }
+}
+
+async fn sweep(db: Weak<Mutex<Connection>>) -> anyhow::Result<Option<usize>> {
+    tokio::task::spawn_blocking(move || {
+        let db = match db.upgrade() {
+            Some(db) => db,
+            None => return Ok(None),
+        };
+        let conn = db
+            .lock()
+            .map_err(|e| anyhow::anyhow!("db lock poisoned: {e}"))?;
+        let now = Utc::now().timestamp_millis();
+        let res = conn.execute("DELETE FROM sessions WHERE valid_till <= ?1", params![now])?;
+        conn.execute("DELETE FROM kv WHERE valid_till <= ?1", params![now])?;
+        Ok(Some(res))
+    })
+    .await?
+}
+
+fn db_err(e: rusqlite::Error) -> model::store::Error {
+    anyhow::anyhow!("sqlite error: {e}").into()
+}
+
+fn upsert(
+    conn: &Connection,
+    codec: &Codec,
+    session_id: &str,
+    data: &SessionData,
+) -> Result<(), model::store::Error> {
+    conn.execute(
+        "INSERT INTO sessions (id, user_key, data, valid_till, last_access)
+         VALUES (?1, ?2, ?3, ?4, ?5)
+         ON CONFLICT(id) DO UPDATE SET user_key = excluded.user_key, data = excluded.data,
+            valid_till = excluded.valid_till, last_access = excluded.last_access",
+        params![
+            codec.key(session_id),
+            codec.user_key(&data.user.id),
+            codec.encode(data)?,
+            data.valid_till,
+            data.last_access
+        ],
+    )
+    .map_err(db_err)?;
+    Ok(())
+}
+
+fn select(
+    conn: &Connection,
+    codec: &Codec,
+    session_id: &str,
+) -> Result<SessionData, model::store::Error> {
+    let data: Option<String> = conn
+        .query_row(
+            "SELECT data FROM sessions WHERE id = ?1 AND valid_till > ?2",
+            params![codec.key(session_id), Utc::now().timestamp_millis()],
+            |r| r.get(0),
+        )
+        .optional()
+        .map_err(db_err)?;
+    match data {
+        Some(data) => codec.decode(&data),
+        None => Err(model::store::Error::NoSession()),
+    }
+}
+
+#[async_trait]
+impl SessionStore for SqliteSessionStore {
+    async fn add(&self, session_id: &str, data: SessionData) -> Result<(), model::store::Error> {
+        tracing::trace!("Adding session: {}", session_id);
+        let session_id = session_id.to_string();
+        self.call(move |conn, codec| upsert(conn, codec, &session_id, &data))
+            .await
+    }
+
+    async fn get(&self, session_id: &str) -> Result<SessionData, model::store::Error> {
+        let session_id = session_id.to_string();
+        self.call(move |conn, codec| select(conn, codec, &session_id))
+            .await
+    }
+
+    async fn remove(&self, session_id: &str) -> Result<(), model::store::Error> {
+        let session_id = session_id.to_string();
+        self.call(move |conn, codec| {
+            let res = conn
+                .execute(
+                    "DELETE FROM sessions WHERE id = ?1",
+                    params![codec.key(&session_id)],
+                )
+                .map_err(db_err)?;
+            match res {
+                0 => Err(model::store::Error::NoSession()),
+                _ => Ok(()),
+            }
+        })
+        .await
+    }
+
+    async fn mark_last_used(&self, session_id: &str, now: i64) -> Result<(), model::store::Error> {
+        let session_id = session_id.to_string();
+        self.call(move |conn, codec| {
+            let tx = conn
+                .transaction_with_behavior(TransactionBehavior::Immediate)
+                .map_err(db_err)?;
+            let mut data = select(&tx, codec, &session_id)?;
+            data.last_access = now;
+            upsert(&tx, codec, &session_id, &data)?;
+            tx.commit().map_err(db_err)
+        })
+        .await
+    }
+
+    async fn update_user(
+        &self,
+        session_id: &str,
+        data: SessionData,
+        current: i64,
+    ) -> Result<bool, model::store::Error> {
+        let session_id = session_id.to_string();
+        self.call(move |conn, codec| {
+            let tx = conn
+                .transaction_with_behavior(TransactionBehavior::Immediate)
+                .map_err(db_err)?;
+            let mut stored = select(&tx, codec, &session_id)?;
+            if stored.refreshed != current {
+                return Ok(false);
+            }
+            stored.user = data.user;
+            stored.refreshed = data.refreshed;
+            upsert(&tx, codec, &session_id, &stored)?;
+            tx.commit().map_err(db_err)?;
+            Ok(true)
+        })
+        .await
+    }
+
+    async fn add_limited(
+        &self,
+        session_id: &str,
+        data: SessionData,
+        max: usize,
+        policy: EvictPolicy,
+    ) -> Result<usize, model::store::Error> {
+        tracing::trace!("Adding session: {}", session_id);
+        let session_id = session_id.to_string();
+        self.call(move |conn, codec| {
+            let tx = conn
+                .transaction_with_behavior(TransactionBehavior::Immediate)
+                .map_err(db_err)?;
+            let key = codec.key(&session_id);
+            let user_key = codec.user_key(&data.user.id);
+            let now = Utc::now().timestamp_millis();
+            let mut evicted = 0;
+            let exists: bool = tx
+                .query_row(
+                    "SELECT EXISTS(SELECT 1 FROM sessions WHERE id = ?1)",
+                    params![key],
+                    |r| r.get(0),
+                )
+                .map_err(db_err)?;
+            if max > 0 && !exists {
+                tx.execute(
+                    "DELETE FROM sessions WHERE user_key = ?1 AND valid_till <= ?2",
+                    params![user_key, now],
+                )
+                .map_err(db_err)?;
+                let count: usize = tx
+                    .query_row(
+                        "SELECT COUNT(*) FROM sessions WHERE user_key = ?1",
+                        params![user_key],
+                        |r| r.get(0),
+                    )
+                    .map_err(db_err)?;
+                if count >= max {
+                    let order = match policy {
+                        EvictPolicy::Reject => return Err(model::store::Error::TooManySessions()),
+                        EvictPolicy::Oldest => "valid_till",
+                        EvictPolicy::LeastRecentlyUsed => "last_access",
+                    };
+                    evicted = tx
+                        .execute(
+                            &format!(
+                                "DELETE FROM sessions WHERE id IN (SELECT id FROM sessions
+                                 WHERE user_key = ?1 ORDER BY {order} LIMIT ?2)"
+                            ),
+                            params![user_key, count + 1 - max],
+                        )
+                        .map_err(db_err)?;
+                }
+            }
+            upsert(&tx, codec, &session_id, &data)?;
+            tx.commit().map_err(db_err)?;
+            Ok(evicted)
+        })
+        .await
+    }
+
+    async fn list_by_user(
+        &self,
+        user_id: &str,
+    ) -> Result<Vec<(String, SessionData)>, model::store::Error> {
+        let user_id = user_id.to_string();
+        self.call(move |conn, codec| {
+            let mut stmt = conn
+                .prepare("SELECT id, data FROM sessions WHERE user_key = ?1 AND valid_till > ?2")
+                .map_err(db_err)?;
+            let rows = stmt
+                .query_map(
+                    params![codec.user_key(&user_id), Utc::now().timestamp_millis()],
+                    |r| Ok((r.get::<_, String>(0)?, r.get::<_, String>(1)?)),
+                )
+                .map_err(db_err)?;
+            let mut res = Vec::new();
+            for row in rows {
+                let (key, data) = row.map_err(db_err)?;
+                res.push((codec.encryptor.decrypt(&key)?, codec.decode(&data)?));
+            }
+            Ok(res)
+        })
+        .await
+    }
+
+    async fn remove_all_for_user(&self, user_id: &str) -> Result<usize, model::store::Error> {
+        let user_id = user_id.to_string();
+        self.call(move |conn, codec| {
+            conn.execute(
+                "DELETE FROM sessions WHERE user_key = ?1",
+                params![codec.user_key(&user_id)],
+            )
+            .map_err(db_err)
+        })
+        .await
+    }
+}
+
+// Auxiliary values, e.g. mfa state and passkeys, kept next to the sessions so they
+// survive restarts too. Values come encrypted by the callers
+#[derive(Clone)]
+pub struct SqliteKeyValueStore {
+    db: Arc<Mutex<Connection>>,
+}
+
+impl SqliteKeyValueStore {
+    async fn call<F, R>(&self, f: F) -> Result<R, model::store::Error>
+    where
+        F: FnOnce(&mut Connection) -> Result<R, model::store::Error> + Send + 'static,
+        R: Send + 'static,
+    {
+        let db = self.db.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut conn = db
+                .lock()
+                .map_err(|e| anyhow::anyhow!("db lock poisoned: {e}"))?;
+            f(&mut conn)
+        })
+        .await
+        .map_err(|e| anyhow::anyhow!("db task: {e}"))?
+    }
+}
+
+fn select_value(conn: &Connection, key: &str) -> Result<Option<String>, model::store::Error> {
+    conn.query_row(
+        "SELECT value FROM kv WHERE key = ?1 AND (valid_till IS NULL OR valid_till > ?2)",
+        params![key, Utc::now().timestamp_millis()],
+        |r| r.get(0),
+    )
+    .optional()
+    .map_err(db_err)
+}
+
+fn upsert_value(
+    conn: &Connection,
+    key: &str,
+    value: &str,
+    ttl: Option<Duration>,
+) -> Result<(), model::store::Error> {
+    let valid_till = ttl.map(|ttl| Utc::now().timestamp_millis() + ttl.as_millis() as i64);
+    conn.execute(
+        "INSERT INTO kv (key, value, valid_till) VALUES (?1, ?2, ?3)
+         ON CONFLICT(key) DO UPDATE SET value = excluded.value, valid_till = excluded.valid_till",
+        params![key, value, valid_till],
+    )
+    .map_err(db_err)?;
+    Ok(())
+}
+
+#[async_trait]
+impl KeyValueStore for SqliteKeyValueStore {
+    async fn get(&self, key: &str) -> Result<Option<String>, model::store::Error> {
+        let key = key.to_string();
+        self.call(move |conn| select_value(conn, &key)).await
+    }
+
+    async fn set(
+        &self,
+        key: &str,
+        value: &str,
+        ttl: Option<Duration>,
+    ) -> Result<(), model::store::Error> {
+        let (key, value) = (key.to_string(), value.to_string());
+        self.call(move |conn| upsert_value(conn, &key, &value, ttl))
+            .await
+    }
+
+    async fn remove(&self, key: &str) -> Result<(), model::store::Error> {
+        let key = key.to_string();
+        self.call(move |conn| {
+            conn.execute("DELETE FROM kv WHERE key = ?1", params![key])
+                .map_err(db_err)?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn compare_and_set(
+        &self,
+        key: &str,
+        current: Option<&str>,
+        value: Option<&str>,
+        ttl: Option<Duration>,
+    ) -> Result<bool, model::store::Error> {
+        let key = key.to_string();
+        let current = current.map(str::to_string);
+        let value = value.map(str::to_string);
+        self.call(move |conn| {
+            let tx = conn
+                .transaction_with_behavior(TransactionBehavior::Immediate)
+                .map_err(db_err)?;
+            if select_value(&tx, &key)? != current {
+                return Ok(false);
+            }
+            match value {
+                Some(value) => upsert_value(&tx, &key, &value, ttl)?,
+                None => {
+                    tx.execute("DELETE FROM kv WHERE key = ?1", params![key])
+                        .map_err(db_err)?;
+                }
+            }
+            tx.commit().map_err(db_err)?;
+            Ok(true)
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use test_case::test_case;
+
+    use super::*;
+    use crate::{model::auth::User, store::encryptor::MagicEncryptor};
+
+    fn make_store(path: &str) -> SqliteSessionStore {
+        SqliteSessionStore::new(
+            path,
+            Box::new(MagicEncryptor::new("0123456789abcdef").unwrap()),
+        )
+        .unwrap()
+    }
+
+    fn session_data(user: &str, valid_till: i64, last_access: i64) -> SessionData {
+        SessionData {
+            user: User {
+                id: user.to_string(),
+                name: "Olia".to_string(),
+                department: "IT".to_string(),
+                roles: vec!["USER".to_string()],
+                backend: String::new(),
+            },
+            ip: "1.1.1.1".to_string(),
+            valid_till,
+            last_access,
+            refreshed: last_access,
+        }
+    }
+
+    async fn user_sessions(store: &SqliteSessionStore, user: &str) -> Vec<String> {
+        let mut res: Vec<String> = store
+            .list_by_user(user)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        res.sort();
+        res
+    }
+
+    #[tokio::test]
+    async fn test_add_get_remove() {
+        let store = make_store(":memory:");
+        let now = Utc::now().timestamp_millis();
+        let data = session_data("olia", now + 10000, now);
+        store.add("s1", data.clone()).await.unwrap();
+        assert_eq!(store.get("s1").await.unwrap(), data);
+        store.mark_last_used("s1", now + 5).await.unwrap();
+        assert_eq!(store.get("s1").await.unwrap().last_access, now + 5);
+        store.remove("s1").await.unwrap();
+        assert!(matches!(
+            store.get("s1").await,
+            Err(model::store::Error::NoSession())
+        ));
+        assert!(matches!(
+            store.remove("s1").await,
+            Err(model::store::Error::NoSession())
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_update_user() {
+        let store = make_store(":memory:");
+        let now = Utc::now().timestamp_millis();
+        store
+            .add("s1", session_data("olia", now + 10000, now))
+            .await
+            .unwrap();
+        store.mark_last_used("s1", now + 5).await.unwrap();
+        let mut data = session_data("olia", now + 10000, now);
+        data.user.roles = vec!["ADMIN".to_string()];
+        data.refreshed = now + 10;
+        assert!(!store
+            .update_user("s1", data.clone(), now - 1)
+            .await
+            .unwrap());
+        assert!(store.update_user("s1", data.clone(), now).await.unwrap());
+        let res = store.get("s1").await.unwrap();
+        assert_eq!(res.user, data.user);
+        assert_eq!((res.refreshed, res.last_access), (now + 10, now + 5));
+        store.remove("s1").await.unwrap();
+        assert!(matches!(
+            store.update_user("s1", data, now + 10).await,
+            Err(model::store::Error::NoSession())
+        ));
+        assert!(store.get("s1").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_expired() {
+        let store = make_store(":memory:");
+        let now = Utc::now().timestamp_millis();
+        store
+            .add("s1", session_data("olia", now - 1, now))
+            .await
+            .unwrap();
+        assert!(store.get("s1").await.is_err());
+        assert!(store.list_by_user("olia").await.unwrap().is_empty());
+        assert_eq!(sweep(Arc::downgrade(&store.db)).await.unwrap(), Some(1));
+    }
+
+    #[tokio::test]
+    async fn test_sweeper_stops() {
+        let store = make_store(":memory:");
+        let handle = store.start_sweeper(Duration::from_millis(5));
+        drop(store);
+        tokio::time::timeout(Duration::from_secs(1), handle)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_and_persistent() {
+        let path = std::env::temp_dir().join(format!(
+            "authware-sessions-{}.db",
+            Utc::now().timestamp_nanos_opt().unwrap()
+        ));
+        let path = path.to_str().unwrap();
+        let now = Utc::now().timestamp_millis();
+        {
+            let store = make_store(path);
+            store
+                .add("secret-session", session_data("olia", now + 10000, now))
+                .await
+                .unwrap();
+        }
+        let store = make_store(path);
+        assert_eq!(store.get("secret-session").await.unwrap().user.id, "olia");
+        let raw: String = store
+            .call(|conn, _| {
+                conn.query_row("SELECT id || user_key || data FROM sessions", [], |r| {
+                    r.get(0)
+                })
+                .map_err(db_err)
+            })
+            .await
+            .unwrap();
+        assert!(!raw.contains("secret-session"));
+        assert!(!raw.contains("olia"));
+        drop(store);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{path}{suffix}"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_key_value() {
+        let store = make_store(":memory:").key_value_store();
+        assert_eq!(store.get("k").await.unwrap(), None);
+        store.set("k", "v", None).await.unwrap();
+        store
+            .set("k2", "v2", Some(Duration::from_millis(20)))
+            .await
+            .unwrap();
+        assert_eq!(store.get("k").await.unwrap(), Some("v".to_string()));
+        assert_eq!(store.get("k2").await.unwrap(), Some("v2".to_string()));
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert_eq!(store.get("k2").await.unwrap(), None);
+        assert!(!store
+            .compare_and_set("k", None, Some("v3"), None)
+            .await
+            .unwrap());
+        assert!(!store
+            .compare_and_set("k2", Some("v2"), Some("v3"), None)
+            .await
+            .unwrap());
+        assert!(store
+            .compare_and_set("k2", None, Some("v3"), None)
+            .await
+            .unwrap());
+        assert!(store
+            .compare_and_set("k", Some("v"), None, None)
+            .await
+            .unwrap());
+        assert_eq!(store.get("k").await.unwrap(), None);
+        store.remove("k2").await.unwrap();
+        assert_eq!(store.get("k2").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_key_value_persistent() {
+        let path = std::env::temp_dir().join(format!(
+            "authware-kv-{}.db",
+            Utc::now().timestamp_nanos_opt().unwrap()
+        ));
+        let path = path.to_str().unwrap();
+        make_store(path)
+            .key_value_store()
+            .set("mfa:olia", "state", None)
+            .await
+            .unwrap();
+        let store = make_store(path).key_value_store();
+        assert_eq!(
+            store.get("mfa:olia").await.unwrap(),
+            Some("state".to_string())
+        );
+        drop(store);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{path}{suffix}"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_by_user() {
+        let store = make_store(":memory:");
+        let now = Utc::now().timestamp_millis();
+        store
+            .add("s1", session_data("olia", now + 10000, now))
+            .await
+            .unwrap();
+        store
+            .add("s2", session_data("olia", now + 10000, now))
+            .await
+            .unwrap();
+        store
+            .add("j1", session_data("jonas", now + 10000, now))
+            .await
+            .unwrap();
+        assert_eq!(user_sessions(&store, "olia").await, vec!["s1", "s2"]);
+        assert_eq!(store.remove_all_for_user("olia").await.unwrap(), 2);
+        assert!(user_sessions(&store, "olia").await.is_empty());
+        assert_eq!(user_sessions(&store, "jonas").await, vec!["j1"]);
+    }
+
+    #[test_case(EvictPolicy::Oldest, Some(vec!["s2", "s3", "s4"]); "oldest")]
+    #[test_case(EvictPolicy::LeastRecentlyUsed, Some(vec!["s1", "s3", "s4"]); "lru")]
+    #[test_case(EvictPolicy::Reject, None; "reject")]
+    #[tokio::test]
+    async fn test_add_limited(policy: EvictPolicy, expected: Option<Vec<&str>>) {
+        let store = make_store(":memory:");
+        let at = Utc::now().timestamp_millis() + 10000;
+        for (id, valid_till, last_access) in
+            [("s1", at, 40), ("s2", at + 1, 10), ("s3", at + 2, 30)]
+        {
+            store
+                .add_limited(id, session_data("olia", valid_till, last_access), 3, policy)
+                .await
+                .unwrap();
+        }
+        let res = store
+            .add_limited("s4", session_data("olia", at + 3, 50), 3, policy)
+            .await;
+        match expected {
+            Some(expected) => {
+                assert_eq!(res.unwrap(), 1);
+                assert_eq!(user_sessions(&store, "olia").await, expected);
+            }
+            None => {
+                assert!(matches!(res, Err(model::store::Error::TooManySessions())));
+                assert_eq!(user_sessions(&store, "olia").await, vec!["s1", "s2", "s3"]);
+            }
+        }
+    }
+}
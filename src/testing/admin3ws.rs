@@ -0,0 +1,277 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU32, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use axum::{
+    extract::{Form, Path, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use axum_extra::{
+    headers::{authorization::Basic, Authorization},
+    TypedHeader,
+};
+use serde::Deserialize;
+
+use crate::auth::admin3ws::PASS_HEADER;
+
+// Scripted admin3ws web service for tests, e.g.:
+//
+//  {
+//    "ws_user": "svc", "ws_pass": "svc-pass", "app_code": "app",
+//    "users": {
+//      "olia": {"pass": "olia1", "first_name": "Olia", "department": "IT", "roles": ["USER"]},
+//      "expired": {"pass": "x", "code": 2},
+//      "flaky": {"pass": "x", "roles": ["USER"], "failures": 1, "latency_ms": 100}
+//    }
+//  }
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Config {
+    // basic auth of the ws, not checked if empty
+    #[serde(default)]
+    pub ws_user: String,
+    #[serde(default)]
+    pub ws_pass: String,
+    // not checked if empty
+    #[serde(default)]
+    pub app_code: String,
+    #[serde(default)]
+    pub users: HashMap<String, User>,
+    // added to every call
+    #[serde(default)]
+    pub latency_ms: u64,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct User {
+    pub pass: String,
+    #[serde(default)]
+    pub first_name: String,
+    #[serde(default)]
+    pub last_name: String,
+    #[serde(default)]
+    pub department: String,
+    #[serde(default)]
+    pub roles: Vec<String>,
+    // ws error code answered to authenticate_details, e.g. 2 - expired password
+    #[serde(default)]
+    pub code: Option<i32>,
+    #[serde(default)]
+    pub latency_ms: u64,
+    // the first calls of the user fail with 500
+    #[serde(default)]
+    pub failures: u32,
+}
+
+pub struct Mock {
+    config: Config,
+    calls: AtomicUsize,
+    user_calls: Mutex<HashMap<String, u32>>,
+    fail_next: AtomicU32,
+}
+
+impl Mock {
+    pub fn new(config: Config) -> Arc<Self> {
+        Arc::new(Mock {
+            config,
+            calls: AtomicUsize::new(0),
+            user_calls: Mutex::new(HashMap::new()),
+            fail_next: AtomicU32::new(0),
+        })
+    }
+
+    pub fn from_file(path: &str) -> anyhow::Result<Arc<Self>> {
+        let content =
+            std::fs::read_to_string(path).map_err(|e| anyhow::anyhow!("can't read {path}: {e}"))?;
+        let config: Config = serde_json::from_str(&content)
+            .map_err(|e| anyhow::anyhow!("can't parse {path}: {e}"))?;
+        Ok(Self::new(config))
+    }
+
+    // all calls served, failed ones too
+    pub fn calls(&self) -> usize {
+        self.calls.load(Ordering::SeqCst)
+    }
+
+    // the next calls of any user fail with 503
+    pub fn fail_next(&self, count: u32) {
+        self.fail_next.store(count, Ordering::SeqCst);
+    }
+
+    pub fn router(self: &Arc<Self>) -> Router {
+        Router::new()
+            .route(
+                "/authenticate_details/:app/:user/:pass",
+                get(details_in_path),
+            )
+            .route(
+                "/authenticate_details/:app",
+                axum::routing::post(details_in_body),
+            )
+            .route("/authenticate_details/:app/:user", get(details_in_header))
+            .route("/get_roles/:app/:user", get(roles))
+            .with_state(self.clone())
+    }
+
+    // Serves the mock on the address in the background, use port 0 for a free one
+    pub async fn start(self: &Arc<Self>, addr: &str) -> anyhow::Result<SocketAddr> {
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        let addr = listener.local_addr()?;
+        let app = self.router();
+        tokio::spawn(async move {
+            if let Err(err) = axum::serve(listener, app).await {
+                tracing::error!(err = %err, "admin3ws mock");
+            }
+        });
+        Ok(addr)
+    }
+
+    // common checks and injected failures, `None` lets the call through
+    async fn check(
+        &self,
+        app: &str,
+        user: &str,
+        basic: Option<TypedHeader<Authorization<Basic>>>,
+    ) -> Option<Response> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        let latency =
+            self.config.latency_ms + self.config.users.get(user).map_or(0, |u| u.latency_ms);
+        if latency > 0 {
+            tokio::time::sleep(Duration::from_millis(latency)).await;
+        }
+        if self
+            .fail_next
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            .is_ok()
+        {
+            return Some((StatusCode::SERVICE_UNAVAILABLE, "injected failure").into_response());
+        }
+        if let Some(u) = self.config.users.get(user) {
+            let mut user_calls = self.user_calls.lock().unwrap();
+            let call = user_calls.entry(user.to_string()).or_default();
+            *call += 1;
+            if *call <= u.failures {
+                return Some(
+                    (StatusCode::INTERNAL_SERVER_ERROR, "scripted failure").into_response(),
+                );
+            }
+        }
+        if !self.config.ws_user.is_empty() {
+            let ok = basic.is_some_and(|TypedHeader(Authorization(b))| {
+                b.username() == self.config.ws_user && b.password() == self.config.ws_pass
+            });
+            if !ok {
+                return Some(StatusCode::UNAUTHORIZED.into_response());
+            }
+        }
+        if !self.config.app_code.is_empty() && app != self.config.app_code {
+            return Some(StatusCode::NOT_FOUND.into_response());
+        }
+        None
+    }
+
+    async fn details(
+        &self,
+        app: &str,
+        user: &str,
+        pass: Option<&str>,
+        basic: Option<TypedHeader<Authorization<Basic>>>,
+    ) -> Response {
+        if let Some(res) = self.check(app, user, basic).await {
+            return res;
+        }
+        let u = match self.config.users.get(user) {
+            Some(u) => u,
+            None => return "1".into_response(),
+        };
+        if let Some(code) = u.code {
+            return code.to_string().into_response();
+        }
+        if pass != Some(u.pass.as_str()) {
+            return "1".into_response();
+        }
+        let department = if u.department.is_empty() {
+            String::new()
+        } else {
+            format!(
+                "<organizationUnit><name>{}</name></organizationUnit>",
+                xml_escape(&u.department)
+            )
+        };
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?><user><firstName>{}</firstName><lastName>{}</lastName>{department}</user>"#,
+            xml_escape(&u.first_name),
+            xml_escape(&u.last_name)
+        )
+        .into_response()
+    }
+}
+
+async fn details_in_path(
+    State(mock): State<Arc<Mock>>,
+    Path((app, user, pass)): Path<(String, String, String)>,
+    basic: Option<TypedHeader<Authorization<Basic>>>,
+) -> Response {
+    mock.details(&app, &user, Some(&pass), basic).await
+}
+
+async fn details_in_body(
+    State(mock): State<Arc<Mock>>,
+    Path(app): Path<String>,
+    basic: Option<TypedHeader<Authorization<Basic>>>,
+    Form(form): Form<HashMap<String, String>>,
+) -> Response {
+    let user = form.get("user").cloned().unwrap_or_default();
+    mock.details(&app, &user, form.get("pass").map(|s| s.as_str()), basic)
+        .await
+}
+
+async fn details_in_header(
+    State(mock): State<Arc<Mock>>,
+    Path((app, user)): Path<(String, String)>,
+    basic: Option<TypedHeader<Authorization<Basic>>>,
+    headers: HeaderMap,
+) -> Response {
+    let pass = headers.get(PASS_HEADER).and_then(|h| h.to_str().ok());
+    mock.details(&app, &user, pass, basic).await
+}
+
+async fn roles(
+    State(mock): State<Arc<Mock>>,
+    Path((app, user)): Path<(String, String)>,
+    basic: Option<TypedHeader<Authorization<Basic>>>,
+) -> Response {
+    if let Some(res) = mock.check(&app, &user, basic).await {
+        return res;
+    }
+    let u = match mock.config.users.get(&user) {
+        Some(u) => u,
+        None => return "1".into_response(),
+    };
+    let roles: String = u
+        .roles
+        .iter()
+        .map(|r| format!("<role><name>{}</name></role>", xml_escape(r)))
+        .collect();
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?><roles user="{}" application="{}">{roles}</roles>"#,
+        xml_escape(&user),
+        xml_escape(&app)
+    )
+    .into_response()
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
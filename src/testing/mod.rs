@@ -0,0 +1,2 @@
+// Test doubles of external services, used by tests and the mock binaries
+pub mod admin3ws;
@@ -38,3 +38,8 @@ impl From<String> for SecretString {
         SecretString(secret)
     }
 }
+
+// Compares secrets in time independent of where they differ
+pub fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
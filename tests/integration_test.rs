@@ -360,3 +360,103 @@ async fn test_successful_auth_ip() {
     assert_eq!(response.status(), reqwest::StatusCode::UNAUTHORIZED);
     tracing::info!("Test wrong ip passed");
 }
+
+// users of tests/admin3ws-mock.json
+async fn login_ws(user: &str, pass: &str) -> (StatusCode, String) {
+    let client = create_client();
+    let url = format!("{}/login", get_auth_service_url());
+    let response = client
+        .post(url)
+        .json(&json!({ "user": user, "pass": pass }))
+        .send()
+        .await
+        .expect("Failed to send request");
+    let status = response.status();
+    (status, response.text().await.expect("Failed to read body"))
+}
+
+#[tokio::test]
+async fn test_admin3ws_login() {
+    init_wait_for_ready().await;
+    let (status, body) = login_ws("olia", "olia-pass").await;
+    assert_eq!(status, StatusCode::OK);
+    let body: serde_json::Value = serde_json::from_str(&body).expect("Failed to parse JSON");
+    assert_eq!(body["user"]["name"], "Olia Olialia");
+    assert_eq!(body["user"]["department"], "IT");
+    assert_eq!(body["user"]["roles"], json!(["USER", "ADMIN"]));
+    assert_eq!(body["user"]["backend"], "admin3ws");
+}
+
+#[tokio::test]
+async fn test_admin3ws_login_fails() {
+    init_wait_for_ready().await;
+    for (user, pass, expected) in [
+        ("olia", "wrong", "Wrong user or password"),
+        ("nobody", "pass", "Wrong user or password"),
+        ("expired", "expired-pass", "Password expired"),
+        ("noroles", "noroles-pass", "No access"),
+    ] {
+        let (status, body) = login_ws(user, pass).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED, "{user}");
+        assert_eq!(body, expected, "{user}");
+    }
+}
+
+#[tokio::test]
+async fn test_admin3ws_login_retried() {
+    init_wait_for_ready().await;
+    let (status, _) = login_ws("flaky", "flaky-pass").await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+async fn ws_session_id(user: &str, pass: &str) -> String {
+    let (status, body) = login_ws(user, pass).await;
+    assert_eq!(status, StatusCode::OK);
+    let body: serde_json::Value = serde_json::from_str(&body).expect("Failed to parse JSON");
+    body["session_id"].as_str().unwrap().to_string()
+}
+
+#[tokio::test]
+async fn test_admin_revoke_sessions() {
+    init_wait_for_ready().await;
+    let admin = ws_session_id("olia", "olia-pass").await;
+    let token = ws_session_id("flaky", "flaky-pass").await;
+    ws_session_id("flaky", "flaky-pass").await;
+    let client = create_client();
+    let url = format!("{}/admin/sessions/flaky", get_auth_service_url());
+
+    let response = client
+        .get(&url)
+        .header("Authorization", format!("Bearer {token}"))
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let response = client
+        .get(&url)
+        .header("Authorization", format!("Bearer {admin}"))
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert_eq!(response.status(), StatusCode::OK);
+    let body: serde_json::Value = response.json().await.expect("Failed to parse JSON");
+    assert!(body.as_array().unwrap().len() >= 2);
+    assert!(!body.to_string().contains(&token));
+
+    let response = client
+        .delete(&url)
+        .header("Authorization", format!("Bearer {admin}"))
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = client
+        .get(get_auth_service_url())
+        .header("Authorization", format!("Bearer {token}"))
+        .send()
+        .await
+        .expect("Failed to send request");
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
@@ -0,0 +1,136 @@
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use authware::{
+    auth::sample::Sample,
+    handler,
+    model::{
+        config::{SessionConfig, SessionLimits},
+        service,
+    },
+    store::memory::InMemorySessionStore,
+    utils::ip_extractor,
+};
+use axum::{
+    routing::{get, post},
+    Router,
+};
+use futures::StreamExt;
+use reqwest::{Client, StatusCode};
+use serde_json::{json, Value};
+
+const IP_HEADER_KEY: &str = "x-forwarded-for";
+const USERS: usize = 50;
+const SESSIONS_PER_USER: usize = 4;
+const CALLS: usize = 10_000;
+const CONCURRENCY: usize = 200;
+
+// the service with the in-memory store capped at the sessions of the test,
+// swept all the time while serving
+async fn start_server() -> SocketAddr {
+    let users: Vec<String> = (0..USERS)
+        .map(|i| format!("user{i}:pass{i}:IT:USER"))
+        .collect();
+    let store = InMemorySessionStore::with_limits(60_000, USERS * SESSIONS_PER_USER);
+    store.start_sweeper(Duration::from_millis(5));
+    let data = service::Data {
+        config: SessionConfig {
+            inactivity: 60_000,
+            session_timeout: 600_000,
+            refresh: 0,
+            limits: SessionLimits::default(),
+        },
+        store: Box::new(store.clone()),
+        auth_service: Box::new(Sample::new(&users.join(";")).unwrap()),
+        ip_extractor: Box::new(ip_extractor::Header::new(0)),
+        oidc: None,
+        user_admin: None,
+        mfa: None,
+        webauthn: None,
+        api_keys: None,
+        client_cert: None,
+        role_mapper: None,
+        memory_store: Some(store),
+        admin_role: String::new(),
+        sessions_admin_role: String::new(),
+        is_test_mode: false,
+    };
+    let app = Router::new()
+        .route("/auth/login", post(handler::login::handler))
+        .route("/auth/logout", post(handler::logout::handler))
+        .route("/auth", get(handler::auth::handler))
+        .with_state(Arc::new(data));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    addr
+}
+
+async fn login(client: &Client, addr: SocketAddr, user: usize) -> reqwest::Response {
+    client
+        .post(format!("http://{addr}/auth/login"))
+        .header(IP_HEADER_KEY, "10.0.0.1")
+        .json(&json!({"user": format!("user{user}"), "pass": format!("pass{user}")}))
+        .send()
+        .await
+        .unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_concurrent_auth_calls() {
+    let addr = start_server().await;
+    let client = Client::new();
+    let mut sessions = Vec::new();
+    for user in 0..USERS {
+        for _ in 0..SESSIONS_PER_USER {
+            let res = login(&client, addr, user).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            let body: Value = res.json().await.unwrap();
+            sessions.push(body["session_id"].as_str().unwrap().to_string());
+        }
+    }
+    // the store is full
+    assert_eq!(
+        login(&client, addr, 0).await.status(),
+        StatusCode::SERVICE_UNAVAILABLE
+    );
+
+    let started = Instant::now();
+    let failed = futures::stream::iter(0..CALLS)
+        .map(|i| {
+            let (client, session) = (&client, &sessions[i % sessions.len()]);
+            async move {
+                client
+                    .get(format!("http://{addr}/auth"))
+                    .header(IP_HEADER_KEY, "10.0.0.1")
+                    .bearer_auth(session)
+                    .send()
+                    .await
+                    .map(|res| res.status())
+            }
+        })
+        .buffer_unordered(CONCURRENCY)
+        .filter(|res| futures::future::ready(!matches!(res, Ok(StatusCode::OK))))
+        .count()
+        .await;
+    let elapsed = started.elapsed();
+    println!(
+        "{CALLS} /auth calls in {elapsed:?}, {:.0} calls/s",
+        CALLS as f64 / elapsed.as_secs_f64()
+    );
+    assert_eq!(failed, 0);
+
+    // a logout makes room for a new login
+    let res = client
+        .post(format!("http://{addr}/auth/logout"))
+        .header(IP_HEADER_KEY, "10.0.0.1")
+        .bearer_auth(&sessions[0])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(login(&client, addr, 0).await.status(), StatusCode::OK);
+}
@@ -0,0 +1,266 @@
+use std::{env, sync::Arc, time::Duration};
+
+use authware::{
+    model::{auth::User, config::EvictPolicy, data::SessionData},
+    store::{
+        cache::{CachedSessionStore, SessionCache},
+        encryptor::MagicEncryptor,
+        redis::{RedisInvalidationBus, RedisMode, RedisPool, RedisSessionStore},
+    },
+    SessionStore,
+};
+use chrono::Utc;
+use deadpool_redis::redis;
+use tokio::{
+    task::JoinSet,
+    time::{sleep, Instant},
+};
+
+// the tests need a sentinel or cluster setup, see tests/redis-ha/docker-compose.yml,
+// they are ignored by default, run by `make test/redis-ha` in tests/
+fn urls(name: &str) -> String {
+    env::var(name)
+        .ok()
+        .filter(|s| !s.is_empty())
+        .unwrap_or_else(|| panic!("{name} not set"))
+}
+
+fn make_store(mode: RedisMode, urls: &str) -> RedisSessionStore {
+    RedisSessionStore::new(
+        RedisPool::new(mode, urls, "mymaster").expect("pool"),
+        Box::new(MagicEncryptor::new("0123456789abcdef").unwrap()),
+    )
+}
+
+fn session_data(user: &str) -> SessionData {
+    let now = Utc::now().timestamp_millis();
+    SessionData {
+        user: User {
+            id: user.to_string(),
+            name: user.to_string(),
+            department: String::new(),
+            roles: vec!["USER".to_string()],
+            backend: String::new(),
+        },
+        ip: "1.1.1.1".to_string(),
+        valid_till: now + 60_000,
+        last_access: now,
+        refreshed: now,
+    }
+}
+
+fn unique(prefix: &str) -> String {
+    format!("{prefix}-{}", Utc::now().timestamp_nanos_opt().unwrap())
+}
+
+// waits until the deployment answers, e.g. while the cluster is being created
+async fn wait_ready(store: &RedisSessionStore) {
+    let start = Instant::now();
+    let id = unique("ready");
+    loop {
+        match store.add(&id, session_data(&id)).await {
+            Ok(()) => return,
+            Err(err) if start.elapsed() < Duration::from_secs(60) => {
+                eprintln!("not ready: {err}");
+                sleep(Duration::from_secs(1)).await;
+            }
+            Err(err) => panic!("redis not ready: {err}"),
+        }
+    }
+}
+
+async fn check_store(store: &RedisSessionStore) {
+    // users spread over many cluster slots
+    for i in 0..20 {
+        let user = unique(&format!("user{i}"));
+        let s1 = unique("s1");
+        let s2 = unique("s2");
+        store.add(&s1, session_data(&user)).await.unwrap();
+        store.add(&s2, session_data(&user)).await.unwrap();
+        assert_eq!(store.get(&s1).await.unwrap().user.id, user);
+        store.mark_last_used(&s1, 1).await.unwrap();
+        assert_eq!(store.list_by_user(&user).await.unwrap().len(), 2);
+
+        let s3 = unique("s3");
+        let evicted = store
+            .add_limited(&s3, session_data(&user), 2, EvictPolicy::LeastRecentlyUsed)
+            .await
+            .unwrap();
+        assert_eq!(evicted, 1);
+        assert!(store.get(&s1).await.is_err());
+        let res = store
+            .add_limited(&unique("s4"), session_data(&user), 2, EvictPolicy::Reject)
+            .await;
+        assert!(res.is_err());
+
+        store.remove(&s2).await.unwrap();
+        assert_eq!(store.list_by_user(&user).await.unwrap().len(), 1);
+        assert_eq!(store.remove_all_for_user(&user).await.unwrap(), 1);
+        assert!(store.get(&s3).await.is_err());
+    }
+}
+
+// concurrent touches and a refresh of one session, none of the updates may get lost
+async fn check_concurrent_updates(store: Arc<RedisSessionStore>) {
+    let user = unique("touch");
+    let id = unique("session");
+    let mut data = session_data(&user);
+    data.valid_till = data.last_access + 3_000;
+    store.add(&id, data.clone()).await.unwrap();
+
+    let mut tasks = JoinSet::new();
+    for i in 1..=100 {
+        let (store, id) = (store.clone(), id.clone());
+        tasks.spawn(async move { store.mark_last_used(&id, data.last_access + i).await });
+    }
+    let mut refreshed = data.clone();
+    refreshed.user.roles = vec!["ADMIN".to_string()];
+    store.add(&id, refreshed).await.unwrap();
+    while let Some(res) = tasks.join_next().await {
+        res.unwrap().unwrap();
+    }
+
+    let res = store.get(&id).await.unwrap();
+    assert_eq!(res.last_access, data.last_access + 100);
+    assert_eq!(res.user.roles, vec!["ADMIN".to_string()]);
+    assert_eq!(res.valid_till, data.valid_till);
+    // an older touch does not move the access time back
+    store.mark_last_used(&id, data.last_access).await.unwrap();
+    assert_eq!(
+        store.get(&id).await.unwrap().last_access,
+        data.last_access + 100
+    );
+    assert!(store.mark_last_used(&unique("none"), 1).await.is_err());
+
+    // touches keep the expiration
+    let wait = data.valid_till - Utc::now().timestamp_millis() + 200;
+    sleep(Duration::from_millis(wait.max(0) as u64)).await;
+    assert!(store.get(&id).await.is_err());
+}
+
+// an instance with a session cache
+async fn make_cached_store(mode: RedisMode, urls: &str) -> CachedSessionStore {
+    let pool = RedisPool::new(mode, urls, "mymaster").expect("pool");
+    let cache = SessionCache::new(100, Duration::from_secs(60), "0123456789abcdef");
+    let bus = RedisInvalidationBus::new(pool.clone(), mode, urls, "mymaster").unwrap();
+    bus.start_subscriber(cache.clone());
+    let start = Instant::now();
+    while !cache.is_active() {
+        assert!(start.elapsed() < Duration::from_secs(30), "not subscribed");
+        sleep(Duration::from_millis(50)).await;
+    }
+    CachedSessionStore::new(
+        Box::new(RedisSessionStore::new(
+            pool,
+            Box::new(MagicEncryptor::new("0123456789abcdef").unwrap()),
+        )),
+        cache,
+        Box::new(bus),
+    )
+}
+
+async fn gone(store: &CachedSessionStore, id: &str) {
+    let start = Instant::now();
+    while store.get(id).await.is_ok() {
+        assert!(start.elapsed() < Duration::from_millis(500), "still cached");
+        sleep(Duration::from_millis(5)).await;
+    }
+}
+
+// a logout or revocation on one instance drops the cached session of the others
+async fn check_cache_invalidation(mode: RedisMode, urls: &str) {
+    let a = make_cached_store(mode, urls).await;
+    let b = make_cached_store(mode, urls).await;
+    let user = unique("cached");
+    let s1 = unique("s1");
+    let s2 = unique("s2");
+    a.add(&s1, session_data(&user)).await.unwrap();
+    a.add(&s2, session_data(&user)).await.unwrap();
+    for id in [&s1, &s2] {
+        b.get(id).await.unwrap();
+        b.get(id).await.unwrap();
+    }
+    a.remove(&s1).await.unwrap();
+    gone(&b, &s1).await;
+    assert!(b.get(&s2).await.is_ok());
+    a.remove_all_for_user(&user).await.unwrap();
+    gone(&b, &s2).await;
+}
+
+#[tokio::test]
+#[ignore = "needs a redis cluster, run by make test/redis-ha"]
+async fn test_cluster_store() {
+    let urls = urls("REDIS_CLUSTER_URLS");
+    let store = make_store(RedisMode::Cluster, &urls);
+    wait_ready(&store).await;
+    check_store(&store).await;
+    check_concurrent_updates(Arc::new(store)).await;
+    check_cache_invalidation(RedisMode::Cluster, &urls).await;
+}
+
+#[tokio::test]
+#[ignore = "needs redis sentinels, run by make test/redis-ha"]
+async fn test_sentinel_store() {
+    let urls = urls("REDIS_SENTINEL_URLS");
+    let store = make_store(RedisMode::Sentinel, &urls);
+    wait_ready(&store).await;
+    check_store(&store).await;
+    check_concurrent_updates(Arc::new(store)).await;
+    check_cache_invalidation(RedisMode::Sentinel, &urls).await;
+}
+
+#[tokio::test]
+#[ignore = "needs redis sentinels, run by make test/redis-ha"]
+async fn test_sentinel_failover() {
+    let urls = urls("REDIS_SENTINEL_URLS");
+    let store = make_store(RedisMode::Sentinel, &urls);
+    wait_ready(&store).await;
+    let user = unique("failover");
+    let before = unique("before");
+    store.add(&before, session_data(&user)).await.unwrap();
+    // let the replica catch up
+    sleep(Duration::from_secs(1)).await;
+
+    let sentinel = urls.split(',').next().unwrap();
+    let client = redis::Client::open(sentinel).unwrap();
+    let mut conn = client.get_multiplexed_async_connection().await.unwrap();
+    let master = |mut conn: redis::aio::MultiplexedConnection| async move {
+        let res: Vec<String> = redis::cmd("SENTINEL")
+            .arg("GET-MASTER-ADDR-BY-NAME")
+            .arg("mymaster")
+            .query_async(&mut conn)
+            .await
+            .unwrap();
+        res
+    };
+    let old = master(conn.clone()).await;
+    let _: String = redis::cmd("SENTINEL")
+        .arg("FAILOVER")
+        .arg("mymaster")
+        .query_async(&mut conn)
+        .await
+        .unwrap();
+    let start = Instant::now();
+    while master(conn.clone()).await == old {
+        assert!(start.elapsed() < Duration::from_secs(60), "no new master");
+        sleep(Duration::from_millis(200)).await;
+    }
+
+    // the pooled connections to the old master break or turn read only,
+    // the store has to reconnect to the promoted one
+    let start = Instant::now();
+    let after = unique("after");
+    loop {
+        match store.add(&after, session_data(&user)).await {
+            Ok(()) => break,
+            Err(err) if start.elapsed() < Duration::from_secs(60) => {
+                eprintln!("during failover: {err}");
+                sleep(Duration::from_millis(500)).await;
+            }
+            Err(err) => panic!("no failover: {err}"),
+        }
+    }
+    assert_eq!(store.get(&after).await.unwrap().user.id, user);
+    assert_eq!(store.get(&before).await.unwrap().user.id, user);
+    assert_eq!(store.list_by_user(&user).await.unwrap().len(), 2);
+}